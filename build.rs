@@ -0,0 +1,42 @@
+//! Bakes the git commit and build time into the binary (see src/version.rs).
+
+use std::process::Command;
+
+fn main() {
+    // Docker builds have no .git; pass `--build-arg GIT_COMMIT=...` instead
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Ok(head) = std::fs::read_to_string(".git/HEAD") {
+        if let Some(branch) = head.trim().strip_prefix("ref: ") {
+            println!("cargo:rerun-if-changed=.git/{}", branch);
+        }
+    }
+
+    let commit = std::env::var("GIT_COMMIT")
+        .ok()
+        .filter(|c| !c.trim().is_empty())
+        .or_else(|| {
+            let out = Command::new("git")
+                .args(["rev-parse", "--short=10", "HEAD"])
+                .output()
+                .ok()?;
+            out.status
+                .success()
+                .then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let built_at = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .unwrap_or_else(chrono::Utc::now);
+
+    println!("cargo:rustc-env=EVE_LOOTER_GIT_COMMIT={}", commit.trim());
+    println!(
+        "cargo:rustc-env=EVE_LOOTER_BUILT_AT={}",
+        built_at.format("%Y-%m-%dT%H:%M:%SZ")
+    );
+}
@@ -0,0 +1,111 @@
+use crate::auth::{self, Viewer};
+use crate::base_path;
+use crate::models::*;
+use crate::store::ApiToken;
+use crate::views::render_page;
+
+use askama::Template;
+use axum::{
+    extract::{Form, State},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::info;
+
+#[derive(Template)]
+#[template(path = "account.html")]
+struct AccountTemplate {
+    viewer: Viewer,
+    /// (token prefix, created at) of the viewer's tokens.
+    tokens: Vec<(String, String)>,
+    /// Shown exactly once, right after creation.
+    new_token: Option<String>,
+    /// When the viewer asked for their data to be erased, if they did.
+    deletion_requested_at: Option<String>,
+}
+
+async fn render(state: &AppState, viewer: Viewer, new_token: Option<String>) -> Response {
+    let store = state.store.lock().await;
+    let tokens = match &viewer.user {
+        Some(user) => store
+            .data
+            .api_tokens
+            .iter()
+            .filter(|(_, t)| t.character_id == user.character_id)
+            .map(|(token, t)| {
+                (
+                    token[..8].to_string(),
+                    t.created_at.format("%Y-%m-%d %H:%M").to_string(),
+                )
+            })
+            .collect(),
+        None => vec![],
+    };
+    let deletion_requested_at = viewer.user.as_ref().and_then(|user| {
+        store
+            .data
+            .deletion_requests
+            .get(&user.character_id)
+            .map(|r| r.requested_at.format("%Y-%m-%d %H:%M").to_string())
+    });
+    drop(store);
+    let template = AccountTemplate {
+        viewer,
+        tokens,
+        new_token,
+        deletion_requested_at,
+    };
+    render_page(&template)
+}
+
+pub async fn show_account(State(state): State<Arc<AppState>>, viewer: Viewer) -> Response {
+    render(&state, viewer, None).await
+}
+
+/// Issues a personal API token for the logged-in character.
+pub async fn create_token(State(state): State<Arc<AppState>>, viewer: Viewer) -> Response {
+    let Some(user) = viewer.user.clone() else {
+        return base_path::redirect("/account").into_response();
+    };
+    let token = auth::random_token();
+    {
+        let mut store = state.store.lock().await;
+        store.data.api_tokens.insert(
+            token.clone(),
+            ApiToken {
+                character_id: user.character_id,
+                character_name: user.character_name.to_string(),
+                created_at: Utc::now(),
+            },
+        );
+        if let Err(e) = store.save().await {
+            return e.into_response();
+        }
+    }
+    info!("Issued API token for {}", user.character_name);
+    render(&state, viewer, Some(token)).await
+}
+
+#[derive(Deserialize)]
+pub struct RevokeForm {
+    prefix: String,
+}
+
+pub async fn revoke_token(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Form(form): Form<RevokeForm>,
+) -> Response {
+    if let Some(user) = &viewer.user {
+        let mut store = state.store.lock().await;
+        store.data.api_tokens.retain(|token, t| {
+            t.character_id != user.character_id || !token.starts_with(&form.prefix)
+        });
+        if let Err(e) = store.save().await {
+            return e.into_response();
+        }
+    }
+    base_path::redirect("/account").into_response()
+}
@@ -0,0 +1,162 @@
+use crate::auth::{self, Role, Viewer};
+use crate::models::*;
+use crate::request_id;
+use crate::retention;
+use crate::store::Backup;
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Admits SSO admins, or callers presenting the configured admin token as
+/// `Authorization: Bearer <token>`. Without SSO nobody is an admin (see
+/// Viewer::role), so the token is the only way in.
+pub fn authorize(
+    admin_token: Option<&str>,
+    viewer: &Viewer,
+    headers: &HeaderMap,
+) -> Result<(), (StatusCode, &'static str)> {
+    if viewer.has_role(Role::Admin) {
+        return Ok(());
+    }
+    let Some(expected) = admin_token else {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Admin actions are disabled. Set EVE_LOOTER_ADMIN_TOKEN to enable them.",
+        ));
+    };
+
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    if !provided.is_some_and(|token| auth::secret_eq(token, expected)) {
+        warn!("Rejected admin request with missing or invalid token");
+        return Err((StatusCode::UNAUTHORIZED, "Invalid admin token"));
+    }
+    Ok(())
+}
+
+/// Dumps the whole data store as a single JSON archive.
+pub async fn backup(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = authorize(state.config.admin_token.as_deref(), &viewer, &headers) {
+        return request_id::api_error(rejection.0, rejection.1);
+    }
+
+    let backup = state.store.lock().await.backup();
+    let filename = format!(
+        "eve-looter-backup-{}.json",
+        backup.created_at.format("%Y%m%d-%H%M%S")
+    );
+    (
+        [(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        )],
+        Json(backup),
+    )
+        .into_response()
+}
+
+/// Replaces the whole data store with an uploaded archive.
+pub async fn restore(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    headers: HeaderMap,
+    Json(backup): Json<Backup>,
+) -> Response {
+    if let Err(rejection) = authorize(state.config.admin_token.as_deref(), &viewer, &headers) {
+        return request_id::api_error(rejection.0, rejection.1);
+    }
+
+    let operations = backup.data.operations.len();
+    match state.store.lock().await.restore(backup).await {
+        Ok(()) => {
+            state.result_cache.lock().unwrap().clear();
+            info!(
+                "Restored data store from backup ({} operations)",
+                operations
+            );
+            (
+                StatusCode::OK,
+                format!("Restored {} operations", operations),
+            )
+                .into_response()
+        }
+        Err(e) => request_id::api_error(StatusCode::BAD_REQUEST, &e),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PruneQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Applies the retention policy now; `?dry_run=true` only reports what
+/// would be deleted.
+pub async fn prune(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    headers: HeaderMap,
+    Query(query): Query<PruneQuery>,
+) -> Response {
+    if let Err(rejection) = authorize(state.config.admin_token.as_deref(), &viewer, &headers) {
+        return request_id::api_error(rejection.0, rejection.1);
+    }
+    Json(retention::prune(&state, query.dry_run).await).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::User;
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn without_sso_only_the_token_admits() {
+        let anyone = Viewer {
+            user: None,
+            sso_enabled: false,
+            read_only: false,
+        };
+        assert!(authorize(Some("secret"), &anyone, &HeaderMap::new()).is_err());
+        assert!(authorize(Some("secret"), &anyone, &bearer("guess")).is_err());
+        assert!(authorize(Some("secret"), &anyone, &bearer("secret")).is_ok());
+        assert!(authorize(None, &anyone, &bearer("secret")).is_err());
+    }
+
+    #[test]
+    fn sso_admins_need_no_token() {
+        let viewer = |role| Viewer {
+            user: Some(User {
+                character_id: CharacterId(1),
+                character_name: SafeName::new("Pilot"),
+                role,
+            }),
+            sso_enabled: true,
+            read_only: false,
+        };
+        assert!(authorize(None, &viewer(Role::Admin), &HeaderMap::new()).is_ok());
+        assert!(authorize(None, &viewer(Role::Fc), &HeaderMap::new()).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use crate::models::{CorporationId, Killmail, KillmailId, SafeName};
+use crate::names::NameBook;
+use crate::store::Operation;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/// A mapping line we think is missing, with why.
+#[derive(Clone)]
+pub struct AltSuggestion {
+    pub alt: SafeName,
+    pub main: SafeName,
+    pub reason: String,
+    pub kill_count: usize,
+}
+
+/// Suffix tokens pilots commonly tack onto alt names.
+const ALT_SUFFIXES: &[&str] = &["alt", "jr", "sr", "i", "ii", "iii", "iv", "v", "vi"];
+
+/// Lowercased name with trailing numerals and alt-ish suffixes removed, so
+/// "Bob Alt 2" and "Bob" compare equal.
+fn base_name(name: &str) -> String {
+    let mut tokens: Vec<String> = name
+        .to_lowercase()
+        .split_whitespace()
+        .map(|t| t.to_string())
+        .collect();
+    while let Some(last) = tokens.last_mut() {
+        let trimmed = last.trim_end_matches(|c: char| c.is_ascii_digit());
+        if trimmed.is_empty() || ALT_SUFFIXES.contains(&trimmed) {
+            tokens.pop();
+        } else if trimmed.len() != last.len() {
+            *last = trimmed.to_string();
+        } else {
+            break;
+        }
+    }
+    tokens.join(" ")
+}
+
+/// Looks for the same main being counted twice on a kill through an
+/// unmapped alt. Two heuristics:
+/// - the unmapped pilot shares a corporation with exactly one other attacker
+///   on the kill, and that attacker is a mapped alt (alt corps);
+/// - the names differ only by numerals or alt suffixes.
+pub fn detect_duplicate_alts(
+    kills: &[Killmail],
+    names: &NameBook,
+    mapping: &HashMap<String, String>,
+) -> Vec<AltSuggestion> {
+    let mapping = names.canonical_mapping(mapping);
+    let mains: HashSet<&String> = mapping.values().collect();
+    let mut found: BTreeMap<(String, String), (String, HashSet<KillmailId>)> = BTreeMap::new();
+
+    for kill in kills.iter().filter(|k| k.is_active) {
+        let pilots: Vec<(String, Option<CorporationId>)> = kill
+            .attackers
+            .iter()
+            .filter_map(|a| Some((names.attacker_name(a)?, a.corporation_id)))
+            .collect();
+
+        for (name, corp) in &pilots {
+            // Only pilots that are neither mapped alts nor known mains are suspects
+            if mapping.contains_key(name) || mains.contains(name) {
+                continue;
+            }
+
+            let mut suggest = |main: String, reason: &str| {
+                if &main != name {
+                    found
+                        .entry((name.clone(), main))
+                        .or_insert_with(|| (reason.to_string(), HashSet::new()))
+                        .1
+                        .insert(kill.killmail_id);
+                }
+            };
+
+            if corp.is_some() {
+                let corp_mates: Vec<&String> = pilots
+                    .iter()
+                    .filter(|(other, other_corp)| other != name && other_corp == corp)
+                    .map(|(other, _)| other)
+                    .collect();
+                if let [mate] = corp_mates.as_slice() {
+                    if let Some(main) = mapping.get(*mate) {
+                        suggest(
+                            main.clone(),
+                            &format!("Only corp-mate on the kill is {}, a mapped alt", mate),
+                        );
+                    }
+                }
+            }
+
+            let base = base_name(name);
+            if base.is_empty() {
+                continue;
+            }
+            for (other, _) in &pilots {
+                // The shorter (or, on a tie, alphabetically first) name is taken as the main
+                let other_is_main =
+                    mapping.contains_key(other) || (other.len(), other) < (name.len(), name);
+                if other != name && other_is_main && base_name(other) == base {
+                    let main = mapping.get(other).cloned().unwrap_or_else(|| other.clone());
+                    suggest(main, &format!("Name looks like a variant of {}", other));
+                }
+            }
+        }
+    }
+
+    found
+        .into_iter()
+        .map(|((alt, main), (reason, kills))| AltSuggestion {
+            alt: alt.into(),
+            main: main.into(),
+            reason,
+            kill_count: kills.len(),
+        })
+        .collect()
+}
+
+/// Minimum number of shared operations before co-occurrence means anything.
+const MIN_SHARED_OPS: usize = 3;
+
+/// Mines saved operations for pilots that fly in exactly the same set of
+/// operations yet never appear on the same killmail - the signature of one
+/// player swapping between characters. Only pairs involving `present`
+/// pilots are returned; the pilot with more kills is suggested as the main.
+pub fn suggest_from_history(
+    operations: &[Operation],
+    names: &NameBook,
+    mapping: &HashMap<String, String>,
+    present: &HashSet<String>,
+) -> Vec<AltSuggestion> {
+    let mapping = names.canonical_mapping(mapping);
+    let mut ops_by_pilot: HashMap<String, BTreeSet<u64>> = HashMap::new();
+    let mut kills_by_pilot: HashMap<String, HashSet<KillmailId>> = HashMap::new();
+
+    for op in operations {
+        for kill in op.kills.iter().filter(|k| k.is_active) {
+            for name in kill.attackers.iter().filter_map(|a| names.attacker_name(a)) {
+                if mapping.contains_key(&name) {
+                    continue;
+                }
+                ops_by_pilot.entry(name.clone()).or_default().insert(op.id);
+                kills_by_pilot
+                    .entry(name)
+                    .or_default()
+                    .insert(kill.killmail_id);
+            }
+        }
+    }
+
+    let mut by_ops: HashMap<&BTreeSet<u64>, Vec<&String>> = HashMap::new();
+    for (name, ops) in &ops_by_pilot {
+        if ops.len() >= MIN_SHARED_OPS {
+            by_ops.entry(ops).or_default().push(name);
+        }
+    }
+
+    let mut suggestions = Vec::new();
+    for (ops, pilots) in by_ops {
+        for (i, a) in pilots.iter().enumerate() {
+            for b in &pilots[i + 1..] {
+                if !present.contains(*a) && !present.contains(*b) {
+                    continue;
+                }
+                let (a_kills, b_kills) = (&kills_by_pilot[*a], &kills_by_pilot[*b]);
+                if !a_kills.is_disjoint(b_kills) {
+                    continue;
+                }
+                let (main, alt) = if (a_kills.len(), b) > (b_kills.len(), a) {
+                    (a, b)
+                } else {
+                    (b, a)
+                };
+                suggestions.push(AltSuggestion {
+                    alt: SafeName::new(alt),
+                    main: SafeName::new(main),
+                    reason: format!(
+                        "Flew the same {} operations as {} but never on the same kill",
+                        ops.len(),
+                        main
+                    ),
+                    kill_count: alt_kills(&kills_by_pilot, alt),
+                });
+            }
+        }
+    }
+    suggestions.sort_by(|x, y| x.alt.cmp(&y.alt));
+    suggestions
+}
+
+fn alt_kills(kills_by_pilot: &HashMap<String, HashSet<KillmailId>>, alt: &str) -> usize {
+    kills_by_pilot.get(alt).map(|k| k.len()).unwrap_or(0)
+}
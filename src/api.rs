@@ -0,0 +1,338 @@
+use crate::auth::{self, Role, Viewer};
+use crate::logic::http_client;
+use crate::models::*;
+use crate::ops::operation_payout;
+use crate::payout;
+use crate::request_id;
+use crate::routing;
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+pub struct PilotPayouts {
+    pub character_id: CharacterId,
+    pub character_name: Option<SafeName>,
+    pub operations: Vec<PilotOperationShare>,
+    pub total_earned: Isk,
+    /// Unpaid shares of published operations.
+    pub outstanding_balance: Isk,
+}
+
+#[derive(Serialize)]
+pub struct PilotOperationShare {
+    pub operation_id: u64,
+    pub operation_name: String,
+    pub end_date: String,
+    /// The main the character's share was paid to in this operation.
+    pub main: String,
+    pub share: Isk,
+    pub published: bool,
+    pub paid_at: Option<DateTime<Utc>>,
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// Admits FCs and admins by session, or callers with the admin token.
+pub fn authorize_fc(
+    state: &AppState,
+    viewer: &Viewer,
+    headers: &HeaderMap,
+) -> Result<(), (StatusCode, &'static str)> {
+    match bearer(headers) {
+        Some(token) if auth::is_admin_token(&state.config, token) => Ok(()),
+        Some(_) => Err((StatusCode::UNAUTHORIZED, "Invalid API token")),
+        None if viewer.has_role(Role::Fc) => Ok(()),
+        None if viewer.user.is_some() => Err((StatusCode::FORBIDDEN, "Requires the FC role")),
+        None => Err((
+            StatusCode::UNAUTHORIZED,
+            "Log in or present the admin token",
+        )),
+    }
+}
+
+/// Who may read a pilot's payouts: FCs and admins (by session or the admin
+/// token) see everyone, members and personal API tokens only themselves.
+async fn authorize(
+    state: &AppState,
+    viewer: &Viewer,
+    headers: &HeaderMap,
+    character_id: CharacterId,
+) -> Result<(), (StatusCode, &'static str)> {
+    if let Some(token) = bearer(headers) {
+        if auth::is_admin_token(&state.config, token) {
+            return Ok(());
+        }
+        let store = state.store.lock().await;
+        return match store.data.api_token(token) {
+            Some(t) if t.character_id == character_id => Ok(()),
+            Some(_) => Err((StatusCode::FORBIDDEN, "Token belongs to another character")),
+            None => Err((StatusCode::UNAUTHORIZED, "Invalid API token")),
+        };
+    }
+
+    if viewer.has_role(Role::Fc) {
+        return Ok(());
+    }
+    match &viewer.user {
+        Some(u) if u.character_id == character_id => Ok(()),
+        Some(_) => Err((StatusCode::FORBIDDEN, "You can only view your own payouts")),
+        None => Err((StatusCode::UNAUTHORIZED, "Log in or present an API token")),
+    }
+}
+
+/// `GET /api/v1/pilots/{character_id}/payouts`
+pub async fn pilot_payouts(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    headers: HeaderMap,
+    Path(character_id): Path<CharacterId>,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &viewer, &headers, character_id).await {
+        return request_id::api_error(rejection.0, rejection.1);
+    }
+
+    let store = state.store.lock().await;
+    let names = store.name_book();
+    let mut character_name = names.current_name(character_id).map(SafeName::from);
+
+    let mut operations = Vec::new();
+    for op in &store.data.operations {
+        let flew = op
+            .kills
+            .iter()
+            .flat_map(|k| &k.attackers)
+            .find(|a| a.character_id == Some(character_id));
+        let mapping = names.canonical_mapping(&payout::parse_mapping(&op.mapping_text));
+        let Some(attacker) = flew else {
+            continue;
+        };
+        if character_name.is_none() {
+            character_name = attacker.character_name.clone();
+        }
+        let Some(main) = payout::attacker_main(&names, &mapping, attacker) else {
+            continue;
+        };
+        let result = match operation_payout(op, &names, &store.data.settings.rules) {
+            Ok(result) => result,
+            Err(e) => {
+                return request_id::api_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+            }
+        };
+        let Some(share) = result.wallets.get(&main).copied() else {
+            continue;
+        };
+        let paid_at = op
+            .payments
+            .iter()
+            .find(|(paid_main, _)| names.canonical(paid_main) == main)
+            .map(|(_, p)| p.paid_at);
+        operations.push(PilotOperationShare {
+            operation_id: op.id,
+            operation_name: op.name.clone(),
+            end_date: op.end_date.clone(),
+            main,
+            share,
+            published: op.published_at.is_some(),
+            paid_at,
+        });
+    }
+
+    let outstanding = operations
+        .iter()
+        .filter(|o| o.published && o.paid_at.is_none())
+        .map(|o| o.share);
+    let (total_earned, outstanding_balance) = match (
+        Isk::total(operations.iter().map(|o| o.share)),
+        Isk::total(outstanding),
+    ) {
+        (Ok(earned), Ok(outstanding)) => (earned, outstanding),
+        (Err(e), _) | (_, Err(e)) => {
+            return request_id::api_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+        }
+    };
+
+    Json(PilotPayouts {
+        character_id,
+        character_name,
+        operations,
+        total_earned,
+        outstanding_balance,
+    })
+    .into_response()
+}
+
+/// Admits anyone logged in, and callers with the admin token or a personal
+/// API token.
+async fn authorize_member(
+    state: &AppState,
+    viewer: &Viewer,
+    headers: &HeaderMap,
+) -> Result<(), (StatusCode, &'static str)> {
+    match bearer(headers) {
+        Some(token) if auth::is_admin_token(&state.config, token) => Ok(()),
+        Some(token) if state.store.lock().await.data.api_token(token).is_some() => Ok(()),
+        Some(_) => Err((StatusCode::UNAUTHORIZED, "Invalid API token")),
+        None if viewer.has_role(Role::Member) => Ok(()),
+        None => Err((StatusCode::UNAUTHORIZED, "Log in or present an API token")),
+    }
+}
+
+#[derive(Serialize)]
+pub struct RouteLength {
+    pub from: SystemId,
+    pub to: SystemId,
+    /// Gate jumps on the shortest route; `null` without one, or when it
+    /// couldn't be looked up.
+    pub jumps: Option<u32>,
+}
+
+/// `GET /api/v1/route/{from}/{to}`
+pub async fn route_length(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    headers: HeaderMap,
+    Path((from, to)): Path<(SystemId, SystemId)>,
+) -> Response {
+    if let Err(rejection) = authorize_member(&state, &viewer, &headers).await {
+        return request_id::api_error(rejection.0, rejection.1);
+    }
+    // A read-only instance doesn't ask ESI, so it only knows what's stored
+    let jumps = match routing::known_jumps(&state, from, to).await {
+        Some(jumps) => jumps,
+        None if state.config.read_only => None,
+        None => match http_client(&state.config) {
+            Ok(client) => routing::jumps_between(&client, &state, &[(from, to)])
+                .await
+                .get(&(from, to))
+                .copied(),
+            Err(e) => return request_id::api_error(StatusCode::INTERNAL_SERVER_ERROR, &e),
+        },
+    };
+    Json(RouteLength { from, to, jumps }).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::User;
+    use crate::config::Config;
+    use crate::storage::MemoryStorage;
+    use crate::store::ApiToken;
+
+    async fn state() -> AppState {
+        let mut config = Config::from_env();
+        config.data_file = None;
+        config.storage_url = None;
+        config.admin_token = Some("admin-secret".to_string());
+        let state = AppState::new(config, Arc::new(MemoryStorage::default()))
+            .await
+            .unwrap();
+        state.store.lock().await.data.api_tokens.insert(
+            "pilot-token".to_string(),
+            ApiToken {
+                character_id: CharacterId(7),
+                character_name: "Pilot".to_string(),
+                created_at: Utc::now(),
+            },
+        );
+        state
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        headers
+    }
+
+    fn member(character_id: i64, role: Role) -> Viewer {
+        Viewer {
+            user: Some(User {
+                character_id: CharacterId(character_id),
+                character_name: SafeName::new("Pilot"),
+                role,
+            }),
+            sso_enabled: true,
+            read_only: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn tokens_admit_only_their_pilot() {
+        let state = state().await;
+        let anonymous = Viewer {
+            user: None,
+            sso_enabled: true,
+            read_only: false,
+        };
+        assert!(
+            authorize(&state, &anonymous, &bearer("admin-secret"), CharacterId(9))
+                .await
+                .is_ok()
+        );
+        assert!(
+            authorize(&state, &anonymous, &bearer("pilot-token"), CharacterId(7))
+                .await
+                .is_ok()
+        );
+        assert_eq!(
+            authorize(&state, &anonymous, &bearer("pilot-token"), CharacterId(9))
+                .await
+                .unwrap_err()
+                .0,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            authorize(&state, &anonymous, &bearer("guess"), CharacterId(7))
+                .await
+                .unwrap_err()
+                .0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            authorize(&state, &anonymous, &HeaderMap::new(), CharacterId(7))
+                .await
+                .unwrap_err()
+                .0,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn sessions_see_themselves_and_fcs_everyone() {
+        let state = state().await;
+        let headers = HeaderMap::new();
+        assert!(
+            authorize(&state, &member(7, Role::Member), &headers, CharacterId(7))
+                .await
+                .is_ok()
+        );
+        assert_eq!(
+            authorize(&state, &member(7, Role::Member), &headers, CharacterId(9))
+                .await
+                .unwrap_err()
+                .0,
+            StatusCode::FORBIDDEN
+        );
+        assert!(
+            authorize(&state, &member(7, Role::Fc), &headers, CharacterId(9))
+                .await
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,670 @@
+use crate::admin;
+use crate::base_path;
+use crate::config::Config;
+use crate::models::*;
+use crate::store::{EsiGrant, GrantKind};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query, Request, State},
+    http::{request::Parts, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tracing::{error, info, warn};
+
+pub const SESSION_COOKIE: &str = "eve_looter_session";
+/// Identifies a browser's saved calculator form (see Store::remember_form).
+pub const FORM_COOKIE: &str = "eve_looter_form";
+
+const SSO_AUTHORIZE_URL: &str = "https://login.eveonline.com/v2/oauth/authorize/";
+const SSO_TOKEN_URL: &str = "https://login.eveonline.com/v2/oauth/token";
+
+/// Ordered so that `role >= Role::Fc` reads as "at least FC".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Role {
+    Member,
+    Fc,
+    Admin,
+}
+
+impl Role {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Role::Member => "Member",
+            Role::Fc => "FC",
+            Role::Admin => "Admin",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub character_id: CharacterId,
+    pub character_name: SafeName,
+    pub role: Role,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub user: Option<User>,
+    /// CSRF state of an SSO login in progress.
+    pub sso_state: Option<String>,
+    /// The SSO flow in progress grants ESI access rather than logging in
+    /// (see grant_market_access).
+    #[serde(default)]
+    pub esi_grant: Option<GrantKind>,
+    pub last_seen: DateTime<Utc>,
+}
+
+impl Session {
+    fn new() -> Self {
+        Self {
+            user: None,
+            sso_state: None,
+            esi_grant: None,
+            last_seen: Utc::now(),
+        }
+    }
+}
+
+/// Storage namespace of sessions, so any instance can serve a logged-in browser.
+const SESSIONS: &str = "session";
+/// `last_seen` is only written back once it is this stale, not on every request.
+const LAST_SEEN_RESOLUTION_SECS: i64 = 300;
+
+async fn load_session(state: &AppState, id: &str) -> Option<Session> {
+    let raw = match state.storage.get(SESSIONS, id).await {
+        Ok(raw) => raw?,
+        Err(e) => {
+            error!("Failed to load session: {}", e);
+            return None;
+        }
+    };
+    let session: Session = serde_json::from_str(&raw)
+        .map_err(|e| warn!("Discarding unreadable session: {}", e))
+        .ok()?;
+    // Idle sessions expire when next seen
+    if Utc::now() - session.last_seen > Duration::hours(state.config.session_idle_hours) {
+        drop_session(state, id).await;
+        return None;
+    }
+    Some(session)
+}
+
+async fn store_session(state: &AppState, id: &str, session: &Session) {
+    let raw = match serde_json::to_string(session) {
+        Ok(raw) => raw,
+        Err(e) => return error!("Failed to save session: {}", e),
+    };
+    if let Err(e) = state.storage.put(SESSIONS, id, &raw).await {
+        error!("Failed to save session: {}", e);
+    }
+}
+
+async fn drop_session(state: &AppState, id: &str) {
+    if let Err(e) = state.storage.remove(SESSIONS, id).await {
+        error!("Failed to remove session: {}", e);
+    }
+}
+
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Who is making the request. Without SSO configured, everyone gets FC
+/// rights, matching the tool's original open behaviour. On a read-only
+/// instance, everyone is an anonymous member.
+#[derive(Clone)]
+pub struct Viewer {
+    pub user: Option<User>,
+    pub sso_enabled: bool,
+    pub read_only: bool,
+}
+
+impl Viewer {
+    pub fn role(&self) -> Option<Role> {
+        if self.read_only {
+            return Some(Role::Member);
+        }
+        if !self.sso_enabled {
+            return Some(Role::Fc);
+        }
+        self.user.as_ref().map(|u| u.role)
+    }
+
+    pub fn has_role(&self, required: Role) -> bool {
+        self.role().is_some_and(|r| r >= required)
+    }
+
+    pub fn can_edit(&self) -> bool {
+        self.has_role(Role::Fc)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Viewer {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let jar = CookieJar::from_headers(&parts.headers);
+        let read_only = state.config.read_only;
+        let mut user = None;
+        if let Some(cookie) = jar.get(SESSION_COOKIE).filter(|_| !read_only) {
+            if let Some(mut session) = load_session(state, cookie.value()).await {
+                let now = Utc::now();
+                if (now - session.last_seen).num_seconds() > LAST_SEEN_RESOLUTION_SECS {
+                    session.last_seen = now;
+                    store_session(state, cookie.value(), &session).await;
+                }
+                user = session.user;
+            }
+        }
+        Ok(Viewer {
+            user,
+            sso_enabled: state.config.sso_enabled() && !read_only,
+            read_only,
+        })
+    }
+}
+
+fn session_cookie(state: &AppState, id: String) -> Cookie<'static> {
+    app_cookie(state, SESSION_COOKIE, id)
+}
+
+/// Keeps the form cookie for as long as its form is kept.
+pub fn form_cookie(state: &AppState, id: String) -> Cookie<'static> {
+    let mut cookie = app_cookie(state, FORM_COOKIE, id);
+    cookie.set_max_age(time::Duration::days(30));
+    cookie
+}
+
+fn app_cookie(state: &AppState, name: &'static str, value: String) -> Cookie<'static> {
+    Cookie::build((name, value))
+        .path(match base_path::base() {
+            "" => "/",
+            base => base,
+        })
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(state.config.tls.is_some() || state.config.sso_callback_url.starts_with("https://"))
+        .build()
+}
+
+fn role_for(state: &AppState, character_id: CharacterId, character_name: &str) -> Role {
+    let matches = |entries: &[String]| {
+        entries
+            .iter()
+            .any(|e| e.eq_ignore_ascii_case(character_name) || e.parse() == Ok(character_id.0))
+    };
+    if matches(&state.config.admins) {
+        Role::Admin
+    } else if matches(&state.config.fcs) {
+        Role::Fc
+    } else {
+        Role::Member
+    }
+}
+
+// --- Middleware ---
+
+async fn enforce(viewer: Viewer, required: Role, request: Request, next: Next) -> Response {
+    if viewer.has_role(required) {
+        return next.run(request).await;
+    }
+    if viewer.user.is_none() {
+        return base_path::redirect("/auth/login").into_response();
+    }
+    (
+        StatusCode::FORBIDDEN,
+        format!("This action requires the {} role.", required.label()),
+    )
+        .into_response()
+}
+
+pub async fn require_member(viewer: Viewer, request: Request, next: Next) -> Response {
+    enforce(viewer, Role::Member, request, next).await
+}
+
+pub async fn require_fc(viewer: Viewer, request: Request, next: Next) -> Response {
+    enforce(viewer, Role::Fc, request, next).await
+}
+
+/// Without SSO nobody has the admin role, so admin pages take the admin
+/// token as `Authorization: Bearer <token>` instead (see admin::authorize).
+pub async fn require_admin(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    request: Request,
+    next: Next,
+) -> Response {
+    if viewer.sso_enabled {
+        return enforce(viewer, Role::Admin, request, next).await;
+    }
+    match admin::authorize(
+        state.config.admin_token.as_deref(),
+        &viewer,
+        request.headers(),
+    ) {
+        Ok(()) => next.run(request).await,
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+// --- Handlers ---
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: String,
+    state: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    /// Only issued when the login asked for ESI scopes.
+    #[serde(default)]
+    refresh_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenClaims {
+    sub: String,
+    name: String,
+    iss: String,
+}
+
+/// Compares a presented secret in constant time, so response timing gives
+/// away nothing about how much of a guess was right.
+pub fn secret_eq(provided: &str, expected: &str) -> bool {
+    provided.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+/// Whether `token` is the configured admin token.
+pub fn is_admin_token(config: &Config, token: &str) -> bool {
+    config
+        .admin_token
+        .as_deref()
+        .is_some_and(|expected| secret_eq(token, expected))
+}
+
+/// Starts the EVE SSO authorization-code flow.
+pub async fn login(State(state): State<Arc<AppState>>, jar: CookieJar) -> Response {
+    start_sso(&state, jar, &state.config.sso_scopes, None).await
+}
+
+/// `GET /settings/market-access`: has an admin grant the structure market
+/// scope with a character that can dock at the pricing structure. Its
+/// refresh token is kept in the store; the admin stays logged in as before.
+pub async fn grant_market_access(State(state): State<Arc<AppState>>, jar: CookieJar) -> Response {
+    start_sso(
+        &state,
+        jar,
+        GrantKind::Market.scope(),
+        Some(GrantKind::Market),
+    )
+    .await
+}
+
+/// `GET /settings/wallet-access`: the same for reading the corp wallet's
+/// market transactions; the character needs an accountant role.
+pub async fn grant_wallet_access(State(state): State<Arc<AppState>>, jar: CookieJar) -> Response {
+    start_sso(
+        &state,
+        jar,
+        GrantKind::Wallet.scope(),
+        Some(GrantKind::Wallet),
+    )
+    .await
+}
+
+/// `GET /settings/assets-access`: the same for reading the corp's assets,
+/// i.e. the loot container; the character needs a director role.
+pub async fn grant_assets_access(State(state): State<Arc<AppState>>, jar: CookieJar) -> Response {
+    start_sso(
+        &state,
+        jar,
+        GrantKind::Assets.scope(),
+        Some(GrantKind::Assets),
+    )
+    .await
+}
+
+async fn start_sso(
+    state: &AppState,
+    jar: CookieJar,
+    scopes: &str,
+    esi_grant: Option<GrantKind>,
+) -> Response {
+    let Some(client_id) = &state.config.sso_client_id else {
+        return (StatusCode::NOT_FOUND, "EVE SSO is not configured").into_response();
+    };
+
+    let sso_state = random_token();
+    let existing = match jar.get(SESSION_COOKIE) {
+        Some(cookie) => load_session(state, cookie.value())
+            .await
+            .map(|session| (cookie.value().to_string(), session)),
+        None => None,
+    };
+    let (session_id, mut session) = existing.unwrap_or_else(|| (random_token(), Session::new()));
+    session.sso_state = Some(sso_state.clone());
+    session.esi_grant = esi_grant;
+    store_session(state, &session_id, &session).await;
+
+    let url = reqwest::Url::parse_with_params(
+        SSO_AUTHORIZE_URL,
+        &[
+            ("response_type", "code"),
+            ("redirect_uri", state.config.sso_callback_url.as_str()),
+            ("client_id", client_id.as_str()),
+            ("scope", scopes),
+            ("state", sso_state.as_str()),
+        ],
+    )
+    .expect("static SSO URL is valid");
+
+    (
+        jar.add(session_cookie(state, session_id)),
+        Redirect::to(url.as_str()),
+    )
+        .into_response()
+}
+
+/// Completes the SSO flow: exchanges the code and signs the character in.
+pub async fn callback(
+    State(state): State<Arc<AppState>>,
+    jar: CookieJar,
+    Query(query): Query<CallbackQuery>,
+) -> Response {
+    let (Some(client_id), Some(secret)) =
+        (&state.config.sso_client_id, &state.config.sso_client_secret)
+    else {
+        return (StatusCode::NOT_FOUND, "EVE SSO is not configured").into_response();
+    };
+
+    let Some(session_id) = jar.get(SESSION_COOKIE).map(|c| c.value().to_string()) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Missing session, please log in again",
+        )
+            .into_response();
+    };
+    let mut session = load_session(&state, &session_id).await;
+    let expected_state = session.as_mut().and_then(|s| s.sso_state.take());
+    let esi_grant = session.as_mut().and_then(|s| s.esi_grant.take());
+    if let Some(session) = &session {
+        store_session(&state, &session_id, session).await;
+    }
+    if expected_state.as_deref() != Some(query.state.as_str()) {
+        warn!("SSO callback with mismatched state");
+        return (StatusCode::BAD_REQUEST, "Login expired, please try again").into_response();
+    }
+
+    let (claims, refresh_token) =
+        match exchange_code(&state.config, client_id, secret, &query.code).await {
+            Ok(c) => c,
+            Err(e) => {
+                error!("EVE SSO token exchange failed: {}", e);
+                return (StatusCode::BAD_GATEWAY, "EVE SSO login failed").into_response();
+            }
+        };
+
+    let Some(character_id) = claims
+        .sub
+        .strip_prefix("CHARACTER:EVE:")
+        .and_then(|id| id.parse().ok())
+        .map(CharacterId)
+    else {
+        return (StatusCode::BAD_GATEWAY, "Unexpected SSO subject").into_response();
+    };
+
+    if let Some(kind) = esi_grant {
+        let granted_by_admin = session
+            .as_ref()
+            .and_then(|s| s.user.as_ref())
+            .is_some_and(|u| u.role == Role::Admin);
+        let Some(refresh_token) = refresh_token.filter(|_| granted_by_admin) else {
+            return (StatusCode::FORBIDDEN, "Only admins can grant ESI access").into_response();
+        };
+        info!(
+            "{} ({}) granted {} access",
+            claims.name,
+            character_id,
+            kind.label()
+        );
+        let mut store = state.store.lock().await;
+        *store.data.grant_mut(kind) = Some(EsiGrant {
+            character_id,
+            character_name: claims.name.into(),
+            refresh_token,
+            granted_at: Utc::now(),
+        });
+        if let Err(e) = store.save().await {
+            return e.into_response();
+        }
+        drop(store);
+        state.structure_prices.lock().unwrap().take();
+        return base_path::redirect("/settings").into_response();
+    }
+
+    let user = User {
+        character_id,
+        role: role_for(&state, character_id, &claims.name),
+        character_name: claims.name.into(),
+    };
+    info!(
+        "{} ({}) logged in as {}",
+        user.character_name,
+        user.character_id,
+        user.role.label()
+    );
+
+    // Rotate the session ID on login to rule out session fixation
+    let new_id = random_token();
+    drop_session(&state, &session_id).await;
+    let mut session = session.unwrap_or_else(Session::new);
+    session.user = Some(user);
+    store_session(&state, &new_id, &session).await;
+
+    (
+        jar.add(session_cookie(&state, new_id)),
+        base_path::redirect("/"),
+    )
+        .into_response()
+}
+
+pub async fn logout(State(state): State<Arc<AppState>>, jar: CookieJar) -> Response {
+    if let Some(cookie) = jar.get(SESSION_COOKIE) {
+        drop_session(&state, cookie.value()).await;
+    }
+    (
+        jar.remove(session_cookie(&state, String::new())),
+        base_path::redirect("/"),
+    )
+        .into_response()
+}
+
+/// Exchanges an authorization code for an access token and reads the
+/// character from its claims. The JWT arrives straight from CCP's token
+/// endpoint over TLS, so its payload is trusted without re-verifying the signature.
+/// The refresh token comes along when ESI scopes were asked for.
+async fn exchange_code(
+    config: &Config,
+    client_id: &str,
+    secret: &str,
+    code: &str,
+) -> Result<(TokenClaims, Option<String>), String> {
+    let resp = crate::logic::http_client(config)?
+        .post(SSO_TOKEN_URL)
+        .basic_auth(client_id, Some(secret))
+        .form(&[("grant_type", "authorization_code"), ("code", code)])
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !resp.status().is_success() {
+        return Err(format!("token endpoint returned {}", resp.status()));
+    }
+    let token: TokenResponse = resp.json().await.map_err(|e| e.to_string())?;
+
+    let payload = token
+        .access_token
+        .split('.')
+        .nth(1)
+        .ok_or("access token is not a JWT")?;
+    let raw = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload)
+        .map_err(|e| e.to_string())?;
+    let claims: TokenClaims = serde_json::from_slice(&raw).map_err(|e| e.to_string())?;
+
+    if !matches!(
+        claims.iss.as_str(),
+        "login.eveonline.com" | "https://login.eveonline.com"
+    ) {
+        return Err(format!("unexpected token issuer {}", claims.iss));
+    }
+    Ok((claims, token.refresh_token))
+}
+
+/// A fresh access token for the `kind` grant's character. SSO may rotate
+/// the refresh token, in which case the new one is kept.
+pub async fn grant_access_token(state: &AppState, kind: GrantKind) -> Result<String, String> {
+    let Some(grant) = state.store.lock().await.data.grant_mut(kind).clone() else {
+        return Err(format!("no {} access has been granted", kind.label()));
+    };
+    match refresh_access_token(&state.config, &grant.refresh_token).await {
+        Ok((access_token, refresh_token)) => {
+            if refresh_token != grant.refresh_token {
+                let mut store = state.store.lock().await;
+                let update = store.update(|data| {
+                    if let Some(grant) = data.grant_mut(kind) {
+                        grant.refresh_token = refresh_token.clone();
+                    }
+                });
+                if let Err(e) = update.await {
+                    error!(
+                        "Failed to keep the rotated {} refresh token: {}",
+                        kind.label(),
+                        e
+                    );
+                }
+            }
+            Ok(access_token)
+        }
+        Err(e) => {
+            state.diagnostics.upstream_error("EVE SSO", e.clone());
+            Err(format!(
+                "can't refresh {}'s {} access: {}",
+                grant.character_name,
+                kind.label(),
+                e
+            ))
+        }
+    }
+}
+
+/// A fresh access token for a refresh token, and the refresh token to use
+/// next time.
+async fn refresh_access_token(
+    config: &Config,
+    refresh_token: &str,
+) -> Result<(String, String), String> {
+    let (Some(client_id), Some(secret)) = (&config.sso_client_id, &config.sso_client_secret) else {
+        return Err("EVE SSO is not configured".to_string());
+    };
+    let resp = crate::logic::http_client(config)?
+        .post(SSO_TOKEN_URL)
+        .basic_auth(client_id, Some(secret))
+        .form(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+        ])
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !resp.status().is_success() {
+        return Err(format!("token endpoint returned {}", resp.status()));
+    }
+    let token: TokenResponse = resp.json().await.map_err(|e| e.to_string())?;
+    let next = token
+        .refresh_token
+        .unwrap_or_else(|| refresh_token.to_string());
+    Ok((token.access_token, next))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::Service;
+
+    fn viewer(role: Option<Role>, sso_enabled: bool) -> Viewer {
+        Viewer {
+            user: role.map(|role| User {
+                character_id: CharacterId(1),
+                character_name: SafeName::new("Pilot"),
+                role,
+            }),
+            sso_enabled,
+            read_only: false,
+        }
+    }
+
+    /// Status of a request to a route guarded for `required`, made by `viewer`.
+    async fn status(viewer: Viewer, required: Role) -> StatusCode {
+        let mut app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .route_layer(middleware::from_fn(move |request: Request, next: Next| {
+                enforce(viewer.clone(), required, request, next)
+            }));
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        app.call(request).await.unwrap().status()
+    }
+
+    #[test]
+    fn without_sso_everyone_is_fc() {
+        let anyone = viewer(None, false);
+        assert_eq!(anyone.role(), Some(Role::Fc));
+        assert!(anyone.can_edit());
+        assert!(!anyone.has_role(Role::Admin));
+    }
+
+    #[tokio::test]
+    async fn routes_admit_by_role() {
+        assert_eq!(
+            status(viewer(Some(Role::Fc), true), Role::Fc).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(viewer(Some(Role::Admin), true), Role::Fc).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(viewer(Some(Role::Member), true), Role::Fc).await,
+            StatusCode::FORBIDDEN
+        );
+        // Signed-out visitors are sent to log in
+        assert_eq!(
+            status(viewer(None, true), Role::Member).await,
+            StatusCode::SEE_OTHER
+        );
+        assert_eq!(status(viewer(None, false), Role::Fc).await, StatusCode::OK);
+        assert_eq!(
+            status(viewer(None, false), Role::Admin).await,
+            StatusCode::SEE_OTHER
+        );
+    }
+}
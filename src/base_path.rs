@@ -0,0 +1,24 @@
+use axum::response::Redirect;
+use once_cell::sync::OnceCell;
+
+/// URL prefix the app is mounted under, e.g. "/looter" ("" at the root).
+/// Set once at startup from `Config::base_path`; templates read it through
+/// base(), which is why it isn't threaded through every view model.
+static BASE_PATH: OnceCell<String> = OnceCell::new();
+
+pub fn init(base_path: &str) {
+    let _ = BASE_PATH.set(base_path.to_string());
+}
+
+pub fn base() -> &'static str {
+    BASE_PATH.get().map(String::as_str).unwrap_or("")
+}
+
+/// An absolute path within the app, e.g. url("/ops") -> "/looter/ops".
+pub fn url(path: &str) -> String {
+    format!("{}{}", base(), path)
+}
+
+pub fn redirect(path: &str) -> Redirect {
+    Redirect::to(&url(path))
+}
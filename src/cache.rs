@@ -0,0 +1,253 @@
+use crate::models::{EsiKillmail, Killmail, KillmailId};
+use crate::payout;
+use crate::views::{FetchParams, ResultView};
+
+use chrono::{DateTime, Utc};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How long a computed result is served to identical requests.
+const RESULT_TTL: Duration = Duration::from_secs(60);
+
+/// A computed /process result. The rendered page differs per viewer (nav,
+/// edit controls), so the result is cached rather than the HTML.
+#[derive(Clone)]
+pub struct CachedResult {
+    pub kills: Vec<Killmail>,
+    pub result: ResultView,
+}
+
+/// Short-lived results keyed by the normalized request, so a fleet opening
+/// the same link at once costs one fetch and calculation.
+#[derive(Default)]
+pub struct ResultCache {
+    entries: HashMap<u64, (Instant, CachedResult)>,
+}
+
+impl ResultCache {
+    pub fn get(&self, key: u64) -> Option<CachedResult> {
+        self.entries
+            .get(&key)
+            .filter(|(at, _)| at.elapsed() < RESULT_TTL)
+            .map(|(_, cached)| cached.clone())
+    }
+
+    pub fn insert(&mut self, key: u64, cached: CachedResult) {
+        self.prune();
+        self.entries.insert(key, (Instant::now(), cached));
+    }
+
+    /// Drops expired results, returning how many.
+    pub fn prune(&mut self) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|_, (at, _)| at.elapsed() < RESULT_TTL);
+        before - self.entries.len()
+    }
+
+    /// Drops everything, e.g. after settings or name changes alter results.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Hash of everything that affects the result: entity, window, mapping,
+/// exclusions, whether an aborted fetch is resumed or may replace the
+/// calculator's kills, and how many of its kills the calculator keeps. Ordering and whitespace differences hash the same.
+/// The payout preset only matters when the operation is saved, so it is
+/// left out.
+pub fn request_key(params: &FetchParams, max_kills: usize) -> u64 {
+    let (start, end) = params.window();
+    let mapping: BTreeMap<String, String> = payout::parse_mapping(&params.mapping_input)
+        .into_iter()
+        .collect();
+    let excluded_kills: BTreeSet<KillmailId> = params.excluded_ids().into_iter().collect();
+    let excluded_names: BTreeSet<String> = params.excluded_names().into_iter().collect();
+
+    let mut hasher = DefaultHasher::new();
+    params
+        .zkill_link
+        .trim()
+        .trim_end_matches('/')
+        .hash(&mut hasher);
+    (start, end).hash(&mut hasher);
+    mapping.hash(&mut hasher);
+    excluded_kills.hash(&mut hasher);
+    excluded_names.hash(&mut hasher);
+    params.op_start.trim().hash(&mut hasher);
+    params.op_end.trim().hash(&mut hasher);
+    params.resume.hash(&mut hasher);
+    params.confirm_replace.hash(&mut hasher);
+    max_kills.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A killmail as the cache holds it: deflated JSON, plus its time so
+/// fetches and retention needn't inflate it to ask.
+struct PackedKillmail {
+    killed_at: DateTime<Utc>,
+    data: Box<[u8]>,
+}
+
+/// ESI killmails by kill ID. They are kept deflated, as a big alliance's
+/// kills take hundreds of MB as structs but a fraction of that packed;
+/// every read inflates its kill again, which costs far less than fetching
+/// it.
+#[derive(Default)]
+pub struct KillmailCache {
+    entries: HashMap<KillmailId, PackedKillmail>,
+    /// Packed size of all entries.
+    bytes: usize,
+}
+
+impl KillmailCache {
+    pub fn get(&self, killmail_id: &KillmailId) -> Option<EsiKillmail> {
+        let packed = self.entries.get(killmail_id)?;
+        match serde_json::from_reader(DeflateDecoder::new(&packed.data[..])) {
+            Ok(killmail) => Some(killmail),
+            Err(e) => {
+                // Fetched again like any kill not cached
+                warn!("Cached killmail {} is unreadable: {}", killmail_id, e);
+                None
+            }
+        }
+    }
+
+    pub fn contains_key(&self, killmail_id: &KillmailId) -> bool {
+        self.entries.contains_key(killmail_id)
+    }
+
+    /// When the kill happened, without inflating it.
+    pub fn killed_at(&self, killmail_id: &KillmailId) -> Option<DateTime<Utc>> {
+        self.entries.get(killmail_id).map(|packed| packed.killed_at)
+    }
+
+    pub fn insert(&mut self, killmail_id: KillmailId, killmail: &EsiKillmail) {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        let data = match serde_json::to_writer(&mut encoder, killmail)
+            .map_err(|e| e.to_string())
+            .and_then(|_| encoder.finish().map_err(|e| e.to_string()))
+        {
+            Ok(data) => data.into_boxed_slice(),
+            Err(e) => {
+                warn!("Failed to pack killmail {}: {}", killmail_id, e);
+                return;
+            }
+        };
+        let packed = PackedKillmail {
+            killed_at: killmail.killmail_time,
+            data,
+        };
+        self.bytes += packed.data.len();
+        if let Some(old) = self.entries.insert(killmail_id, packed) {
+            self.bytes -= old.data.len();
+        }
+    }
+
+    pub fn extend(&mut self, killmails: impl IntoIterator<Item = (KillmailId, EsiKillmail)>) {
+        for (killmail_id, killmail) in killmails {
+            self.insert(killmail_id, &killmail);
+        }
+    }
+
+    /// Drops killmails of kills before `before`, returning how many. With
+    /// `dry_run` they are only counted.
+    pub fn prune_before(&mut self, before: DateTime<Utc>, dry_run: bool) -> usize {
+        let expired = |packed: &PackedKillmail| packed.killed_at < before;
+        if dry_run {
+            return self.entries.values().filter(|p| expired(p)).count();
+        }
+        let count = self.entries.len();
+        let mut freed = 0;
+        self.entries.retain(|_, packed| {
+            let keep = !expired(packed);
+            if !keep {
+                freed += packed.data.len();
+            }
+            keep
+        });
+        self.bytes -= freed;
+        count - self.entries.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Memory the killmails take packed, roughly.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_KILLS: usize = 20_000;
+
+    fn params(overrides: serde_json::Value) -> FetchParams {
+        let mut params = serde_json::json!({
+            "zkill_link": "https://zkillboard.com/system/30000142/",
+            "mapping_input": "Alt = Main\nOther = Main",
+            "excluded_kills": "1,2",
+            "excluded_beneficiaries": null,
+            "start_date": "2024-01-01",
+            "end_date": "2024-01-02",
+        });
+        for (field, value) in overrides.as_object().unwrap() {
+            params[field] = value.clone();
+        }
+        serde_json::from_value(params).unwrap()
+    }
+
+    #[test]
+    fn equivalent_requests_share_a_key() {
+        let key = request_key(&params(serde_json::json!({})), MAX_KILLS);
+        let reordered = params(serde_json::json!({
+            "zkill_link": " https://zkillboard.com/system/30000142 ",
+            "mapping_input": "Other = Main\nAlt = Main",
+            "excluded_kills": "2, 1",
+            "operation_name": "Named later",
+            "payout_preset": "Wormhole eviction",
+        }));
+        assert_eq!(request_key(&reordered, MAX_KILLS), key);
+    }
+
+    #[test]
+    fn fields_that_change_the_result_change_the_key() {
+        let key = request_key(&params(serde_json::json!({})), MAX_KILLS);
+        for overrides in [
+            serde_json::json!({"zkill_link": "https://zkillboard.com/system/30000144/"}),
+            serde_json::json!({"mapping_input": "Alt = Other"}),
+            serde_json::json!({"excluded_kills": "1"}),
+            serde_json::json!({"excluded_beneficiaries": "Main"}),
+            serde_json::json!({"start_date": "2023-12-31"}),
+            serde_json::json!({"end_date": "2024-01-03"}),
+            serde_json::json!({"op_start": "2024-01-01T18:00"}),
+            serde_json::json!({"op_end": "2024-01-01T20:00"}),
+            serde_json::json!({"resume": true}),
+            serde_json::json!({"confirm_replace": true}),
+        ] {
+            assert_ne!(
+                request_key(&params(overrides.clone()), MAX_KILLS),
+                key,
+                "{}",
+                overrides
+            );
+        }
+        assert_ne!(
+            request_key(&params(serde_json::json!({})), MAX_KILLS / 2),
+            key
+        );
+    }
+}
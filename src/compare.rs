@@ -0,0 +1,286 @@
+use crate::api;
+use crate::auth::Viewer;
+use crate::logic;
+use crate::models::*;
+use crate::payout;
+use crate::request_id;
+use crate::store::Store;
+use crate::views::{date_window, isk_error, render_page};
+
+use askama::Template;
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+/// Two windows of the same entity. Missing dates make it the last seven
+/// days against the seven before.
+#[derive(Deserialize)]
+pub struct CompareQuery {
+    /// A zKillboard link of the entity; every saved operation when empty.
+    #[serde(default)]
+    pub link: String,
+    #[serde(default)]
+    pub start_date: String,
+    #[serde(default)]
+    pub end_date: String,
+    #[serde(default)]
+    pub previous_start: String,
+    #[serde(default)]
+    pub previous_end: String,
+}
+
+/// Totals over the saved operations' kills inside one window.
+#[derive(Serialize)]
+pub struct WindowSummary {
+    pub start_date: String,
+    pub end_date: String,
+    /// Operations with at least one kill in the window.
+    pub operations: usize,
+    /// Kills whose loot was split.
+    pub kills: u32,
+    pub loot: f64,
+    /// Mains with a share.
+    pub pilots: usize,
+}
+
+impl WindowSummary {
+    pub fn loot_str(&self) -> String {
+        format_isk(self.loot)
+    }
+}
+
+#[derive(Serialize)]
+pub struct PilotDelta {
+    pub main: String,
+    pub current: f64,
+    pub previous: f64,
+    pub delta: f64,
+    /// Kills the main was on, in each window.
+    pub current_kills: u32,
+    pub previous_kills: u32,
+}
+
+impl PilotDelta {
+    pub fn current_str(&self) -> String {
+        format_isk(self.current)
+    }
+
+    pub fn previous_str(&self) -> String {
+        format_isk(self.previous)
+    }
+
+    pub fn delta_str(&self) -> String {
+        signed_isk(self.delta)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Comparison {
+    /// e.g. "corporation/98000001"; none for the whole workspace.
+    pub entity: Option<String>,
+    pub current: WindowSummary,
+    pub previous: WindowSummary,
+    /// Change of the loot against the previous window; none when it had
+    /// none.
+    pub loot_change_percent: Option<f64>,
+    /// By earnings in the current window, most first.
+    pub pilots: Vec<PilotDelta>,
+}
+
+#[derive(Template)]
+#[template(path = "compare.html")]
+struct CompareTemplate {
+    viewer: Viewer,
+    link: String,
+    comparison: Comparison,
+}
+
+impl CompareTemplate {
+    fn loot_change(&self) -> String {
+        let (current, previous) = (&self.comparison.current, &self.comparison.previous);
+        match self.comparison.loot_change_percent {
+            Some(percent) => format!(
+                "{} ({:+.0}%)",
+                signed_isk(current.loot - previous.loot),
+                percent
+            ),
+            None => signed_isk(current.loot - previous.loot),
+        }
+    }
+}
+
+fn signed_isk(amount: f64) -> String {
+    if amount > 0.0 {
+        format!("+{}", format_isk(amount))
+    } else {
+        format_isk(amount)
+    }
+}
+
+fn parse_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+/// The two windows as (start, end) dates, the previous one as long as the
+/// current one and right before it unless given.
+fn windows(query: &CompareQuery) -> ((NaiveDate, NaiveDate), (NaiveDate, NaiveDate)) {
+    let end = parse_date(&query.end_date).unwrap_or_else(|| Utc::now().date_naive());
+    let start = parse_date(&query.start_date)
+        .filter(|start| *start <= end)
+        .unwrap_or(end - Duration::days(6));
+    let days = end - start;
+    let previous_end = parse_date(&query.previous_end).unwrap_or(start - Duration::days(1));
+    let previous_start = parse_date(&query.previous_start)
+        .filter(|start| *start <= previous_end)
+        .unwrap_or(previous_end - days);
+    ((start, end), (previous_start, previous_end))
+}
+
+#[derive(Default)]
+struct PilotWindow {
+    earned: f64,
+    kills: u32,
+}
+
+/// Splits the saved operations' kills inside the window, each operation
+/// under its own rules, like the leaderboard does for a month.
+fn summarize(
+    store: &Store,
+    entity: Option<&str>,
+    (start, end): (NaiveDate, NaiveDate),
+) -> Result<(WindowSummary, HashMap<String, PilotWindow>), IskOutOfRange> {
+    let names = store.name_book();
+    let (start_cutoff, end_cutoff) = date_window(
+        &start.format("%Y-%m-%d").to_string(),
+        &end.format("%Y-%m-%d").to_string(),
+    );
+    let in_window =
+        |kill: &Killmail| kill.killmail_time >= start_cutoff && kill.killmail_time <= end_cutoff;
+
+    let mut summary = WindowSummary {
+        start_date: start.format("%Y-%m-%d").to_string(),
+        end_date: end.format("%Y-%m-%d").to_string(),
+        operations: 0,
+        kills: 0,
+        loot: 0.0,
+        pilots: 0,
+    };
+    let mut pilots: HashMap<String, PilotWindow> = HashMap::new();
+    for op in &store.data.operations {
+        if entity.is_some() && logic::entity_of(&op.zkill_link).as_deref() != entity {
+            continue;
+        }
+        let kills: Vec<Killmail> = op
+            .payout_kills()?
+            .iter()
+            .filter(|k| in_window(k))
+            .cloned()
+            .collect();
+        if kills.is_empty() {
+            continue;
+        }
+        summary.operations += 1;
+
+        let mapping = names.canonical_mapping(&payout::parse_mapping(&op.mapping_text));
+        let result = payout::calculate(
+            &kills,
+            &names,
+            &mapping,
+            &op.excluded_beneficiaries,
+            &op.payout_rules(&store.data.settings.rules),
+        )?;
+        summary.kills += result.loot_fairy.kills;
+        summary.loot += result.total_dropped_value.to_f64();
+        for (main, amount) in result.wallets {
+            pilots.entry(main).or_default().earned += amount.to_f64();
+        }
+        for (main, fairy) in result.pilot_loot_fairy {
+            pilots.entry(main).or_default().kills += fairy.kills;
+        }
+    }
+    summary.pilots = pilots.values().filter(|p| p.earned != 0.0).count();
+    Ok((summary, pilots))
+}
+
+fn compare(store: &Store, query: &CompareQuery) -> Result<Comparison, IskOutOfRange> {
+    let entity = logic::entity_of(&query.link);
+    let (current_window, previous_window) = windows(query);
+    let (current, mut current_pilots) = summarize(store, entity.as_deref(), current_window)?;
+    let (previous, mut previous_pilots) = summarize(store, entity.as_deref(), previous_window)?;
+
+    let mains: BTreeSet<String> = current_pilots
+        .keys()
+        .chain(previous_pilots.keys())
+        .cloned()
+        .collect();
+    let mut pilots: Vec<PilotDelta> = mains
+        .into_iter()
+        .map(|main| {
+            let now = current_pilots.remove(&main).unwrap_or_default();
+            let then = previous_pilots.remove(&main).unwrap_or_default();
+            PilotDelta {
+                delta: now.earned - then.earned,
+                current: now.earned,
+                previous: then.earned,
+                current_kills: now.kills,
+                previous_kills: then.kills,
+                main,
+            }
+        })
+        .collect();
+    pilots.sort_by(|a, b| {
+        b.current
+            .total_cmp(&a.current)
+            .then(b.previous.total_cmp(&a.previous))
+    });
+
+    Ok(Comparison {
+        loot_change_percent: (previous.loot > 0.0)
+            .then(|| (current.loot - previous.loot) * 100.0 / previous.loot),
+        entity,
+        current,
+        previous,
+        pilots,
+    })
+}
+
+/// `GET /compare`: loot, earnings and activity of an entity's saved
+/// operations in two windows side by side, e.g. this week against last.
+pub async fn show_comparison(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Query(query): Query<CompareQuery>,
+) -> Response {
+    let comparison = match compare(&*state.store.lock().await, &query) {
+        Ok(comparison) => comparison,
+        Err(e) => return isk_error(e),
+    };
+    render_page(&CompareTemplate {
+        viewer,
+        link: query.link,
+        comparison,
+    })
+}
+
+/// `GET /api/v1/compare`: the same comparison as JSON, for dashboards.
+pub async fn comparison_json(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    headers: HeaderMap,
+    Query(query): Query<CompareQuery>,
+) -> Response {
+    if let Err(rejection) = api::authorize_fc(&state, &viewer, &headers) {
+        return request_id::api_error(rejection.0, rejection.1);
+    }
+    match compare(&*state.store.lock().await, &query) {
+        Ok(comparison) => Json(comparison).into_response(),
+        Err(e) => request_id::api_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
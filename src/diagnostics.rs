@@ -0,0 +1,194 @@
+use crate::auth::Viewer;
+use crate::models::*;
+use crate::ratelimit::LimiterState;
+use crate::request_id;
+use crate::sweep::{SweepReport, SweepTotals};
+use crate::version;
+use crate::views::render_page;
+
+use askama::Template;
+use axum::{extract::State, response::Response};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Upstream errors kept for the diagnostics page.
+const RECENT_ERRORS: usize = 50;
+
+/// Something running right now: a fetch, a prefetch or a pruning run.
+#[derive(Debug, Clone)]
+pub struct ActiveJob {
+    pub kind: &'static str,
+    pub detail: String,
+    pub request_id: Option<String>,
+    pub started_at: DateTime<Utc>,
+}
+
+/// A failed call to zKillboard or ESI.
+#[derive(Debug, Clone)]
+pub struct UpstreamError {
+    pub at: DateTime<Utc>,
+    pub service: &'static str,
+    pub detail: String,
+    pub request_id: Option<String>,
+}
+
+/// Process-wide state shown on `/admin`.
+pub struct Diagnostics {
+    pub started_at: DateTime<Utc>,
+    next_job: AtomicU64,
+    jobs: Mutex<BTreeMap<u64, ActiveJob>>,
+    errors: Mutex<VecDeque<UpstreamError>>,
+    sweeps: Mutex<SweepTotals>,
+}
+
+/// Unregisters its job when dropped, however the job ends.
+pub struct JobGuard<'a> {
+    diagnostics: &'a Diagnostics,
+    id: u64,
+}
+
+impl Drop for JobGuard<'_> {
+    fn drop(&mut self) {
+        self.diagnostics.jobs.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self {
+            started_at: Utc::now(),
+            next_job: AtomicU64::new(0),
+            jobs: Mutex::new(BTreeMap::new()),
+            errors: Mutex::new(VecDeque::new()),
+            sweeps: Mutex::new(SweepTotals::default()),
+        }
+    }
+
+    /// Lists a job as active until the returned guard is dropped.
+    pub fn start_job(&self, kind: &'static str, detail: impl Into<String>) -> JobGuard<'_> {
+        let id = self.next_job.fetch_add(1, Ordering::Relaxed);
+        self.jobs.lock().unwrap().insert(
+            id,
+            ActiveJob {
+                kind,
+                detail: detail.into(),
+                request_id: request_id::current(),
+                started_at: Utc::now(),
+            },
+        );
+        JobGuard {
+            diagnostics: self,
+            id,
+        }
+    }
+
+    pub fn upstream_error(&self, service: &'static str, detail: impl Into<String>) {
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() == RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(UpstreamError {
+            at: Utc::now(),
+            service,
+            detail: detail.into(),
+            request_id: request_id::current(),
+        });
+    }
+
+    pub fn record_sweep(&self, report: SweepReport) {
+        self.sweeps.lock().unwrap().add(report);
+    }
+
+    fn jobs(&self) -> Vec<ActiveJob> {
+        self.jobs.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Newest first.
+    fn errors(&self) -> Vec<UpstreamError> {
+        self.errors.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin.html")]
+struct DiagnosticsTemplate {
+    viewer: Viewer,
+    version: String,
+    started_at: DateTime<Utc>,
+    uptime: String,
+    storage: &'static str,
+    config: Vec<(&'static str, String)>,
+    jobs: Vec<ActiveJob>,
+    /// Fetches cut short by rate limiting, waiting to be resumed.
+    partial_fetches: Vec<String>,
+    errors: Vec<UpstreamError>,
+    limiters: Vec<LimiterState>,
+    /// (cache, entries)
+    caches: Vec<(&'static str, usize)>,
+    /// What the idle sweeper reclaimed since the start.
+    sweeps: SweepTotals,
+}
+
+fn format_uptime(since: DateTime<Utc>) -> String {
+    let total = (Utc::now() - since).num_minutes().max(0);
+    let (days, hours, minutes) = (total / 1440, total / 60 % 24, total % 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else {
+        format!("{}h {}m", hours, minutes)
+    }
+}
+
+/// `GET /admin`: what the instance is doing and how it is configured.
+pub async fn show_diagnostics(State(state): State<Arc<AppState>>, viewer: Viewer) -> Response {
+    let diagnostics = &state.diagnostics;
+    let limiters = vec![
+        state.zkill_limiter.state().await,
+        state.esi_limiter.state().await,
+    ];
+    let (killmails, killmail_bytes) = {
+        let esi_cache = state.esi_cache.lock().unwrap();
+        (esi_cache.len(), esi_cache.bytes())
+    };
+    let template = DiagnosticsTemplate {
+        viewer,
+        version: format!(
+            "{} ({}, built {})",
+            version::VERSION,
+            version::GIT_COMMIT,
+            version::BUILT_AT
+        ),
+        started_at: diagnostics.started_at,
+        uptime: format_uptime(diagnostics.started_at),
+        storage: state.storage.name(),
+        config: state.config.describe(),
+        jobs: diagnostics.jobs(),
+        partial_fetches: state
+            .partial_fetches
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect(),
+        errors: diagnostics.errors(),
+        limiters,
+        caches: vec![
+            ("ESI killmails", killmails),
+            ("ESI killmails (KiB packed)", killmail_bytes / 1024),
+            ("Names", state.name_cache.lock().unwrap().len()),
+            ("Interned names", interned_names()),
+            (
+                "Affiliations",
+                state.affiliation_cache.lock().unwrap().len(),
+            ),
+            (
+                "Calculator kills",
+                state.current_kills.lock().unwrap().len(),
+            ),
+        ],
+        sweeps: *diagnostics.sweeps.lock().unwrap(),
+    };
+    render_page(&template)
+}
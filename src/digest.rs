@@ -0,0 +1,239 @@
+use crate::logic::fetch_zkill_data;
+use crate::models::*;
+use crate::notify::{self, Event};
+use crate::request_id;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+/// How often the schedule is looked at; a digest goes out within this of
+/// being due.
+const CHECK_EVERY: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+
+/// Kills and pilots listed in a digest.
+const TOP_N: usize = 5;
+
+/// Discord rejects messages longer than this.
+const DISCORD_MAX_LENGTH: usize = 2000;
+
+/// How often the loot digest is posted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DigestSchedule {
+    #[default]
+    Off,
+    Daily,
+    Weekly,
+}
+
+impl DigestSchedule {
+    /// The window a digest covers, which is also the time between two.
+    fn period(self) -> Option<Duration> {
+        match self {
+            DigestSchedule::Off => None,
+            DigestSchedule::Daily => Some(Duration::days(1)),
+            DigestSchedule::Weekly => Some(Duration::days(7)),
+        }
+    }
+}
+
+/// A summary of the watched entities' loot posted to Discord on a
+/// schedule, as an Event::LootDigest so webhooks can take it too. Unlike
+/// the payout notifications it is about activity, not operations.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DigestSettings {
+    #[serde(default)]
+    pub schedule: DigestSchedule,
+    /// Discord webhook URL ("Integrations" in the channel settings).
+    #[serde(default)]
+    pub discord_webhook: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_sent: Option<DateTime<Utc>>,
+}
+
+/// Sends the digest whenever it is due and there is a Discord webhook or
+/// email to send it to. Entities come from the watched links, so the
+/// prefetcher has usually fetched their kills already.
+pub fn spawn(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        loop {
+            let due = {
+                let store = state.store.lock().await;
+                let settings = &store.data.settings;
+                let digest = &settings.digest;
+                (!digest.discord_webhook.is_empty() || !state.notifiers.is_empty())
+                    && !settings.watched.is_empty()
+                    && digest.schedule.period().is_some_and(|period| {
+                        digest
+                            .last_sent
+                            .is_none_or(|sent| Utc::now() - sent >= period)
+                    })
+            };
+            if due {
+                request_id::job("digest", async {
+                    // Failures are retried at the next check
+                    if let Err(e) = send(&state).await {
+                        error!("Failed to post the loot digest: {}", e);
+                    }
+                })
+                .await;
+            }
+            tokio::time::sleep(CHECK_EVERY).await;
+        }
+    });
+}
+
+/// Builds the digest for the schedule's window and dispatches it,
+/// recording when. A schedule that is off covers the last day. Delivery
+/// happens in the background; failures are logged (see notify::dispatch).
+pub async fn send(state: &Arc<AppState>) -> Result<(), String> {
+    let (digest, watched) = {
+        let store = state.store.lock().await;
+        let settings = &store.data.settings;
+        (settings.digest.clone(), settings.watched.clone())
+    };
+    if digest.discord_webhook.is_empty() && state.notifiers.is_empty() {
+        return Err("No Discord webhook or email is set".to_string());
+    }
+    let _job = state
+        .diagnostics
+        .start_job("digest", format!("{} watched links", watched.len()));
+    let period = digest.schedule.period().unwrap_or(Duration::days(1));
+    let since = Utc::now() - period;
+
+    let mut kills: Vec<Killmail> = Vec::new();
+    let mut seen: HashSet<KillmailId> = HashSet::new();
+    let mut failed = Vec::new();
+    for link in &watched {
+        match fetch_zkill_data(link, state, since, true).await {
+            Ok(fetched) => kills.extend(
+                fetched
+                    .kills
+                    .into_iter()
+                    .filter(|k| k.killmail_time >= since)
+                    .filter(|k| seen.insert(k.killmail_id)),
+            ),
+            Err(e) => {
+                warn!("Digest: fetching {} failed: {}", link, e);
+                failed.push(link.clone());
+            }
+        }
+    }
+
+    let total_dropped_value = Isk::total(kills.iter().map(|k| k.zkb.dropped_value))?;
+    let title = match digest.schedule {
+        DigestSchedule::Weekly => "Weekly loot digest",
+        _ => "Daily loot digest",
+    };
+    notify::dispatch(
+        state,
+        Event::LootDigest {
+            title: title.to_string(),
+            since,
+            kill_count: kills.len(),
+            total_dropped_value,
+            content: message(title, since, total_dropped_value, &kills, &failed),
+        },
+    );
+    info!("Sent the loot digest ({} kills)", kills.len());
+
+    let mut store = state.store.lock().await;
+    store.data.settings.digest.last_sent = Some(Utc::now());
+    store.save().await.map_err(|e| e.to_string())
+}
+
+/// The digest as Discord markdown.
+fn message(
+    title: &str,
+    since: DateTime<Utc>,
+    total: Isk,
+    kills: &[Killmail],
+    failed: &[String],
+) -> String {
+    let mut lines = vec![format!(
+        "**{}** since {} UTC",
+        title,
+        since.format("%Y-%m-%d %H:%M")
+    )];
+
+    lines.push(format!(
+        "{} ISK dropped on {} kill{}",
+        total,
+        kills.len(),
+        if kills.len() == 1 { "" } else { "s" }
+    ));
+
+    let mut top: Vec<&Killmail> = kills.iter().collect();
+    top.sort_by_key(|k| std::cmp::Reverse(k.zkb.dropped_value));
+    if !top.is_empty() {
+        lines.push(String::new());
+        lines.push("**Top kills**".to_string());
+    }
+    for (i, kill) in top.iter().take(TOP_N).enumerate() {
+        let (ship, victim) = kill
+            .victim
+            .as_ref()
+            .map(|v| {
+                (
+                    v.ship_type_name.as_deref().unwrap_or("Unknown Ship"),
+                    v.display_name(),
+                )
+            })
+            .unwrap_or(("Unknown Ship", "Unknown"));
+        lines.push(format!(
+            "{}. {} ({}) in {}: {} ISK <https://zkillboard.com/kill/{}/>",
+            i + 1,
+            ship,
+            victim,
+            kill.solar_system_name.as_deref().unwrap_or("?"),
+            kill.zkb.dropped_value,
+            kill.killmail_id
+        ));
+    }
+
+    let mut activity: HashMap<&str, usize> = HashMap::new();
+    for kill in kills {
+        let pilots: HashSet<&str> = kill
+            .attackers
+            .iter()
+            .filter_map(|a| a.character_name.as_deref())
+            .collect();
+        for pilot in pilots {
+            *activity.entry(pilot).or_default() += 1;
+        }
+    }
+    let mut active: Vec<(&str, usize)> = activity.into_iter().collect();
+    active.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    if !active.is_empty() {
+        lines.push(String::new());
+        lines.push("**Most active pilots**".to_string());
+    }
+    for (i, (pilot, count)) in active.iter().take(TOP_N).enumerate() {
+        lines.push(format!(
+            "{}. {}: {} kill{}",
+            i + 1,
+            pilot,
+            count,
+            if *count == 1 { "" } else { "s" }
+        ));
+    }
+
+    if !failed.is_empty() {
+        lines.push(String::new());
+        lines.push(format!("_Could not fetch {}_", failed.join(", ")));
+    }
+
+    let mut content = lines.join("\n");
+    if content.len() > DISCORD_MAX_LENGTH {
+        let mut end = DISCORD_MAX_LENGTH - 1;
+        while !content.is_char_boundary(end) {
+            end -= 1;
+        }
+        content.truncate(end);
+        content.push('…');
+    }
+    content
+}
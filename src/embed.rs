@@ -0,0 +1,79 @@
+use crate::models::*;
+use crate::ops::operation_payout;
+use crate::views::{isk_error, render_page};
+
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+
+const TOP_BENEFICIARIES: usize = 5;
+
+#[derive(Template)]
+#[template(path = "embed.html")]
+struct EmbedTemplate {
+    name: String,
+    end_date: String,
+    total: String,
+    pilot_count: usize,
+    /// (main, formatted share), largest first.
+    top: Vec<(String, String)>,
+}
+
+/// Compact, control-free payout summary for iframes, addressed by the
+/// operation's share token rather than its guessable ID.
+pub async fn show_embed(State(state): State<Arc<AppState>>, Path(token): Path<String>) -> Response {
+    let template = {
+        let store = state.store.lock().await;
+        let Some(op) = store.operation_by_token(&token) else {
+            return (StatusCode::NOT_FOUND, "Unknown embed link").into_response();
+        };
+        let payout = match operation_payout(op, &store.name_book(), &store.data.settings.rules) {
+            Ok(payout) => payout,
+            Err(e) => return isk_error(e),
+        };
+        let mut shares: Vec<(String, Isk)> = payout.wallets.into_iter().collect();
+        shares.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        EmbedTemplate {
+            name: op.name.clone(),
+            end_date: op.end_date.clone(),
+            total: payout.total_dropped_value.to_string(),
+            pilot_count: shares.len(),
+            top: shares
+                .into_iter()
+                .take(TOP_BENEFICIARIES)
+                .map(|(main, amount)| (main, amount.to_string()))
+                .collect(),
+        }
+    };
+    (
+        [(header::CACHE_CONTROL, "public, max-age=300")],
+        render_page(&template),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::views::tests::{assert_escaped, hostile_names};
+
+    #[test]
+    fn embed_escapes_hostile_names() {
+        let names = hostile_names();
+        let page = EmbedTemplate {
+            name: names[0].to_string(),
+            end_date: "2024-01-01".to_string(),
+            total: "100,000,000".to_string(),
+            pilot_count: names.len(),
+            top: names
+                .iter()
+                .map(|name| (name.to_string(), "25,000,000".to_string()))
+                .collect(),
+        };
+        assert_escaped(&page.render().unwrap());
+    }
+}
@@ -0,0 +1,106 @@
+use crate::auth::{self, Viewer};
+use crate::base_path;
+use crate::models::*;
+use crate::ops::operation_payout;
+use crate::views::{fallback_page, isk_error};
+
+use askama::Template;
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::sync::Arc;
+
+const FEED_ENTRIES: usize = 50;
+
+#[derive(Template)]
+#[template(path = "feed.xml")]
+struct FeedTemplate {
+    base_url: String,
+    updated: String,
+    entries: Vec<FeedEntry>,
+}
+
+struct FeedEntry {
+    id: u64,
+    title: String,
+    updated: String,
+    total: String,
+    pilot_count: usize,
+}
+
+#[derive(Deserialize)]
+pub struct FeedQuery {
+    token: Option<String>,
+}
+
+/// Atom feed of published operations. RSS bots can't log in, so with SSO
+/// enabled the feed takes the admin token or a personal API token as
+/// `?token=`.
+pub async fn atom_feed(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Query(query): Query<FeedQuery>,
+) -> Response {
+    let store = state.store.lock().await;
+    let token_ok = query.token.as_deref().is_some_and(|t| {
+        auth::is_admin_token(&state.config, t) || store.data.api_token(t).is_some()
+    });
+    if !token_ok && viewer.role().is_none() {
+        return (StatusCode::UNAUTHORIZED, "Log in or pass ?token=").into_response();
+    }
+
+    let names = store.name_book();
+    let mut published: Vec<_> = store
+        .data
+        .operations
+        .iter()
+        .filter_map(|op| Some((op, op.published_at?)))
+        .collect();
+    published.sort_by_key(|(_, at)| std::cmp::Reverse(*at));
+
+    let entries: Result<Vec<FeedEntry>, _> = published
+        .iter()
+        .take(FEED_ENTRIES)
+        .map(|(op, at)| {
+            let payout = operation_payout(op, &names, &store.data.settings.rules)?;
+            Ok(FeedEntry {
+                id: op.id,
+                title: op.name.clone(),
+                updated: at.to_rfc3339(),
+                total: payout.total_dropped_value.to_string(),
+                pilot_count: payout.wallets.len(),
+            })
+        })
+        .collect();
+    let entries = match entries {
+        Ok(entries) => entries,
+        Err(e) => return isk_error(e),
+    };
+    let updated: DateTime<Utc> = published
+        .first()
+        .map(|(_, at)| *at)
+        .unwrap_or_else(Utc::now);
+
+    let template = FeedTemplate {
+        base_url: state
+            .config
+            .public_url
+            .as_deref()
+            .map(|url| url.trim_end_matches('/').to_string())
+            .unwrap_or_else(|| format!("http://localhost:3000{}", base_path::base())),
+        updated: updated.to_rfc3339(),
+        entries,
+    };
+    match template.render() {
+        Ok(xml) => (
+            [(header::CONTENT_TYPE, "application/atom+xml; charset=utf-8")],
+            xml,
+        )
+            .into_response(),
+        Err(e) => fallback_page(e),
+    }
+}
@@ -0,0 +1,335 @@
+use crate::api;
+use crate::auth::Viewer;
+use crate::base_path;
+use crate::models::{killmail_time, AppState, Killmail};
+use crate::ops::operation_payout;
+use crate::request_id;
+use crate::store::Operation;
+
+use async_graphql::http::GraphiQLSource;
+use async_graphql::{Context, EmptyMutation, EmptySubscription, Object, Schema, SimpleObject};
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+
+pub type LooterSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+pub fn schema() -> LooterSchema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription).finish()
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Saved operations, newest first.
+    async fn operations(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 50)] limit: usize,
+        #[graphql(default = 0)] offset: usize,
+    ) -> Vec<GqlOperation> {
+        let state = ctx.data_unchecked::<Arc<AppState>>();
+        let store = state.store.lock().await;
+        store
+            .data
+            .operations
+            .iter()
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .map(GqlOperation)
+            .collect()
+    }
+
+    async fn operation(&self, ctx: &Context<'_>, id: u64) -> Option<GqlOperation> {
+        let state = ctx.data_unchecked::<Arc<AppState>>();
+        let store = state.store.lock().await;
+        store.operation(id).cloned().map(GqlOperation)
+    }
+
+    /// Payments recorded across all operations, optionally for one main.
+    async fn ledger(&self, ctx: &Context<'_>, main: Option<String>) -> Vec<LedgerEntry> {
+        let state = ctx.data_unchecked::<Arc<AppState>>();
+        let store = state.store.lock().await;
+        store
+            .data
+            .operations
+            .iter()
+            .flat_map(|op| {
+                op.payments.iter().map(|(paid_main, p)| LedgerEntry {
+                    operation_id: op.id,
+                    operation_name: op.name.clone(),
+                    main: paid_main.clone(),
+                    amount: p.amount.to_f64(),
+                    paid_at: p.paid_at,
+                    paid_by: p.paid_by.clone(),
+                })
+            })
+            .filter(|e| main.as_ref().is_none_or(|m| &e.main == m))
+            .collect()
+    }
+}
+
+pub struct GqlOperation(Operation);
+
+#[Object(name = "Operation")]
+impl GqlOperation {
+    async fn id(&self) -> u64 {
+        self.0.id
+    }
+
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    async fn created_at(&self) -> DateTime<Utc> {
+        self.0.created_at
+    }
+
+    async fn start_date(&self) -> &str {
+        &self.0.start_date
+    }
+
+    async fn end_date(&self) -> &str {
+        &self.0.end_date
+    }
+
+    async fn published_at(&self) -> Option<DateTime<Utc>> {
+        self.0.published_at
+    }
+
+    async fn mapping(&self) -> &str {
+        &self.0.mapping_text
+    }
+
+    async fn notes(&self) -> &str {
+        &self.0.notes
+    }
+
+    async fn fc(&self) -> &str {
+        &self.0.fc
+    }
+
+    async fn tags(&self) -> Vec<String> {
+        self.0.tags.iter().cloned().collect()
+    }
+
+    /// One of roam, home_defense, gank, structure_bash.
+    async fn op_type(&self) -> Option<&str> {
+        self.0.op_type.map(|t| t.key())
+    }
+
+    async fn kills(&self) -> Vec<Kill> {
+        self.0.kills.iter().map(Kill::from).collect()
+    }
+
+    /// Beneficiaries with their computed shares, sorted by name.
+    async fn beneficiaries(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Beneficiary>> {
+        let state = ctx.data_unchecked::<Arc<AppState>>();
+        let (names, rules) = {
+            let store = state.store.lock().await;
+            (store.name_book(), store.data.settings.rules.clone())
+        };
+        let payout = operation_payout(&self.0, &names, &rules)?;
+        let excluded = names.canonical_set(&self.0.excluded_beneficiaries);
+        let mut beneficiaries: Vec<Beneficiary> = payout
+            .seen_mains
+            .iter()
+            .map(|main| Beneficiary {
+                main: main.clone(),
+                share: payout.wallets.get(main).map_or(0.0, |share| share.to_f64()),
+                merit_points: payout.merit_points.get(main).copied().unwrap_or(0.0),
+                losses: payout.losses.get(main).map_or(0.0, |loss| loss.to_f64()),
+                excluded: excluded.contains(main),
+                paid: self
+                    .0
+                    .payments
+                    .keys()
+                    .any(|paid| names.canonical(paid) == *main),
+            })
+            .collect();
+        beneficiaries.sort_by(|a, b| a.main.cmp(&b.main));
+        Ok(beneficiaries)
+    }
+
+    async fn total_dropped_value(&self, ctx: &Context<'_>) -> async_graphql::Result<f64> {
+        let state = ctx.data_unchecked::<Arc<AppState>>();
+        let store = state.store.lock().await;
+        let payout = operation_payout(&self.0, &store.name_book(), &store.data.settings.rules)?;
+        Ok(payout.total_dropped_value.to_f64())
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Kill {
+    killmail_id: i64,
+    time: String,
+    solar_system_id: i64,
+    solar_system_name: Option<String>,
+    security_status: Option<f64>,
+    /// "high", "low", "null", "wormhole" or "unknown".
+    security_class: String,
+    dropped_value: f64,
+    total_value: f64,
+    points: i32,
+    solo: bool,
+    npc: bool,
+    awox: bool,
+    labels: Vec<String>,
+    active: bool,
+    victim_name: Option<String>,
+    ship_type_name: Option<String>,
+    final_blow_name: Option<String>,
+    attacker_names: Vec<String>,
+}
+
+impl From<&Killmail> for Kill {
+    fn from(kill: &Killmail) -> Self {
+        Self {
+            killmail_id: kill.killmail_id.0,
+            time: killmail_time::format(&kill.killmail_time),
+            solar_system_id: kill.solar_system_id.0,
+            solar_system_name: kill.solar_system_name.clone().map(String::from),
+            security_status: kill.solar_system_security,
+            security_class: kill.security_class().to_string(),
+            dropped_value: kill.zkb.dropped_value.to_f64(),
+            total_value: kill.zkb.total_value.to_f64(),
+            points: kill.zkb.points,
+            solo: kill.zkb.solo,
+            npc: kill.zkb.npc,
+            awox: kill.zkb.awox,
+            labels: kill.zkb.labels.clone(),
+            active: kill.is_active,
+            victim_name: kill
+                .victim
+                .as_ref()
+                .and_then(|v| v.character_name.clone().map(String::from)),
+            ship_type_name: kill
+                .victim
+                .as_ref()
+                .and_then(|v| v.ship_type_name.clone().map(String::from)),
+            final_blow_name: kill
+                .attackers
+                .iter()
+                .find(|a| a.final_blow)
+                .and_then(|a| a.character_name.clone().map(String::from)),
+            attacker_names: kill
+                .attackers
+                .iter()
+                .filter_map(|a| a.character_name.clone().map(String::from))
+                .collect(),
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Beneficiary {
+    main: String,
+    share: f64,
+    merit_points: f64,
+    losses: f64,
+    excluded: bool,
+    paid: bool,
+}
+
+#[derive(SimpleObject)]
+pub struct LedgerEntry {
+    operation_id: u64,
+    operation_name: String,
+    main: String,
+    amount: f64,
+    paid_at: DateTime<Utc>,
+    paid_by: Option<String>,
+}
+
+/// `POST /api/graphql`, for FCs and admins (by session or the admin token).
+pub async fn execute(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    headers: HeaderMap,
+    Json(request): Json<async_graphql::Request>,
+) -> Response {
+    if let Err(rejection) = api::authorize_fc(&state, &viewer, &headers) {
+        return request_id::api_error(rejection.0, rejection.1);
+    }
+    let request = request.data(state.clone());
+    Json(state.graphql.execute(request).await).into_response()
+}
+
+/// `GET /api/graphql` serves GraphiQL for exploring the schema.
+pub async fn graphiql() -> Html<String> {
+    Html(
+        GraphiQLSource::build()
+            .endpoint(&base_path::url("/api/graphql"))
+            .finish(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::models::Isk;
+    use crate::storage::MemoryStorage;
+    use crate::store::tests::{kill, operation};
+    use crate::store::Payment;
+
+    #[tokio::test]
+    async fn operations_resolve_with_their_payout() {
+        let mut config = Config::from_env();
+        config.data_file = None;
+        config.storage_url = None;
+        let state = AppState::new(config, Arc::new(MemoryStorage::default()))
+            .await
+            .unwrap();
+        let mut op = operation(vec![
+            kill(1, 100_000_000.0, &[(100, "Alice"), (101, "Bob")]),
+            kill(2, 50_000_000.0, &[(101, "Bob")]),
+        ]);
+        op.payments.insert(
+            "Alice".to_string(),
+            Payment {
+                amount: Isk::from_f64(50_000_000.0).unwrap(),
+                paid_at: Utc::now(),
+                paid_by: None,
+            },
+        );
+        state.store.lock().await.insert_operation(op).await.unwrap();
+        let state = Arc::new(state);
+
+        let query = r#"{
+            operations { name totalDroppedValue kills { killmailId finalBlowName }
+                beneficiaries { main share paid } }
+            ledger(main: "Alice") { operationName amount }
+        }"#;
+        let response = schema()
+            .execute(async_graphql::Request::new(query).data(state))
+            .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        assert_eq!(
+            response.data.into_json().unwrap(),
+            serde_json::json!({
+                "operations": [{
+                    "name": "Op",
+                    "totalDroppedValue": 150_000_000.0,
+                    "kills": [
+                        {"killmailId": 1, "finalBlowName": "Alice"},
+                        {"killmailId": 2, "finalBlowName": "Bob"},
+                    ],
+                    "beneficiaries": [
+                        {"main": "Alice", "share": 50_000_000.0, "paid": true},
+                        {"main": "Bob", "share": 100_000_000.0, "paid": false},
+                    ],
+                }],
+                "ledger": [{"operationName": "Op", "amount": 50_000_000.0}],
+            })
+        );
+    }
+}
@@ -0,0 +1,410 @@
+use crate::logic;
+use crate::models::*;
+use crate::views::render_page;
+
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+pub struct AttackerDetail {
+    pub character_id: Option<CharacterId>,
+    pub name: String,
+    pub tickers: String,
+    pub ship_type_id: Option<TypeId>,
+    pub ship: String,
+    pub weapon: String,
+    pub damage: i64,
+    /// Percent of the damage the victim took.
+    pub damage_share: f64,
+    pub final_blow: bool,
+}
+
+pub struct ItemDetail {
+    pub type_id: TypeId,
+    pub name: String,
+    pub dropped: i64,
+    pub destroyed: i64,
+}
+
+/// The detail pane of a kill, loaded into the kill list when it is opened.
+#[derive(Template)]
+#[template(path = "partials/kill_detail.html")]
+struct KillDetailTemplate {
+    kill: Killmail,
+    damage_taken: Option<i64>,
+    /// By damage done, most first.
+    attackers: Vec<AttackerDetail>,
+    items: Vec<ItemDetail>,
+    /// Why only the stored summary is shown.
+    unavailable: Option<String>,
+}
+
+impl KillDetailTemplate {
+    fn esi_url(&self) -> String {
+        format!(
+            "https://esi.evetech.net/v1/killmails/{}/{}/",
+            self.kill.killmail_id, self.kill.zkb.hash
+        )
+    }
+}
+
+/// The kill as held by the calculator or a saved operation; its zKillboard
+/// hash is what ESI needs to hand out the killmail.
+async fn find_kill(state: &AppState, killmail_id: KillmailId) -> Option<Killmail> {
+    let current = state
+        .current_kills
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|k| k.killmail_id == killmail_id)
+        .cloned();
+    if current.is_some() {
+        return current;
+    }
+    let store = state.store.lock().await;
+    let mut kill = store
+        .data
+        .operations
+        .iter()
+        .flat_map(|op| &op.kills)
+        .find(|k| k.killmail_id == killmail_id)
+        .cloned()?;
+    store.name_book().refresh_kill(&mut kill);
+    Some(kill)
+}
+
+async fn detail_template(
+    state: &AppState,
+    kill: Killmail,
+    esi_data: &EsiKillmail,
+) -> KillDetailTemplate {
+    let tickers = state.store.lock().await.data.tickers.clone();
+    let name_cache = state.name_cache.lock().unwrap();
+    let name = |id: Option<EveId>| id.and_then(|id| name_cache.get(&id)).map(|n| n.to_string());
+    let ticker = |id: Option<EveId>| id.and_then(|id| tickers.get(&id).cloned());
+    // The names the kill list shows, for when ESI can't be asked right now
+    let listed = |id: Option<CharacterId>| {
+        kill.attackers
+            .iter()
+            .find(|a| id.is_some() && a.character_id == id)
+            .map(|a| a.display_name().to_string())
+    };
+
+    let damage_taken = esi_data.victim.damage_taken;
+    let mut attackers: Vec<AttackerDetail> = esi_data
+        .attackers
+        .iter()
+        .map(|att| AttackerDetail {
+            character_id: att.character_id,
+            name: name(att.character_id.map(EveId::from))
+                .or_else(|| listed(att.character_id))
+                // NPCs and structures have no character
+                .unwrap_or_else(|| "Unknown".to_string()),
+            tickers: ticker_label(
+                &ticker(att.corporation_id.map(EveId::from)),
+                &ticker(att.alliance_id.map(EveId::from)),
+            ),
+            ship_type_id: att.ship_type_id,
+            ship: name(att.ship_type_id.map(EveId::from)).unwrap_or_else(|| "-".to_string()),
+            weapon: name(att.weapon_type_id.map(EveId::from)).unwrap_or_else(|| "-".to_string()),
+            damage: att.damage_done,
+            damage_share: if damage_taken > 0 {
+                att.damage_done as f64 * 100.0 / damage_taken as f64
+            } else {
+                0.0
+            },
+            final_blow: att.final_blow,
+        })
+        .collect();
+    attackers.sort_by(|a, b| {
+        b.damage
+            .cmp(&a.damage)
+            .then(b.final_blow.cmp(&a.final_blow))
+    });
+
+    let mut flat = Vec::new();
+    for item in &esi_data.victim.items {
+        item.flatten(&mut flat);
+    }
+    let items = flat
+        .into_iter()
+        .map(|(type_id, dropped, destroyed)| ItemDetail {
+            type_id,
+            name: name(Some(type_id.into())).unwrap_or_else(|| format!("Type {}", type_id)),
+            dropped,
+            destroyed,
+        })
+        .collect();
+
+    KillDetailTemplate {
+        kill,
+        damage_taken: Some(damage_taken),
+        attackers,
+        items,
+        unavailable: None,
+    }
+}
+
+/// Falls back to what the kill list already knows: who was on it and the
+/// damage they did.
+fn summary_template(kill: Killmail, reason: String) -> KillDetailTemplate {
+    let damage_taken: i64 = kill.attackers.iter().map(|att| att.damage_done).sum();
+    let mut attackers: Vec<AttackerDetail> = kill
+        .attackers
+        .iter()
+        .map(|att| AttackerDetail {
+            character_id: att.character_id,
+            name: att.display_name().to_string(),
+            tickers: att.tickers(),
+            ship_type_id: att.ship_type_id,
+            ship: att.ship_type_name.as_deref().unwrap_or("-").to_string(),
+            weapon: "-".to_string(),
+            damage: att.damage_done,
+            damage_share: if damage_taken > 0 {
+                att.damage_done as f64 * 100.0 / damage_taken as f64
+            } else {
+                0.0
+            },
+            final_blow: att.final_blow,
+        })
+        .collect();
+    attackers.sort_by(|a, b| {
+        b.damage
+            .cmp(&a.damage)
+            .then(b.final_blow.cmp(&a.final_blow))
+    });
+    KillDetailTemplate {
+        kill,
+        damage_taken: (damage_taken > 0).then_some(damage_taken),
+        attackers,
+        items: Vec::new(),
+        unavailable: Some(reason),
+    }
+}
+
+/// The killmails of `kills`, from ESI (through the cache) or, on a
+/// read-only instance, from the cache alone. Kills without one are missing.
+pub async fn load_killmails(
+    state: &Arc<AppState>,
+    kills: &[Killmail],
+) -> Result<HashMap<KillmailId, EsiKillmail>, String> {
+    if state.config.read_only {
+        // No fetching here; only what is cached already
+        let esi_cache = state.esi_cache.lock().unwrap();
+        return Ok(kills
+            .iter()
+            .filter_map(|k| Some((k.killmail_id, esi_cache.get(&k.killmail_id)?)))
+            .collect());
+    }
+    let items: Vec<RawZKillItem> = kills
+        .iter()
+        .map(|k| RawZKillItem {
+            killmail_id: k.killmail_id,
+            zkb: k.zkb.clone(),
+        })
+        .collect();
+    logic::kill_details(state, &items)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Quantity of each item type that dropped from `kills`.
+pub async fn dropped_items(
+    state: &Arc<AppState>,
+    kills: &[Killmail],
+) -> Result<HashMap<TypeId, i64>, String> {
+    let killmails = load_killmails(state, kills)
+        .await
+        .map_err(|e| format!("Can't load the operation's killmails: {}", e))?;
+    let mut items = Vec::new();
+    for esi_data in killmails.values() {
+        for item in &esi_data.victim.items {
+            item.dropped(&mut items);
+        }
+    }
+    let mut dropped: HashMap<TypeId, i64> = HashMap::new();
+    for (type_id, quantity) in items {
+        *dropped.entry(type_id).or_default() += quantity;
+    }
+    Ok(dropped)
+}
+
+/// The killmail of one kill; the error says why there is none.
+async fn load_killmail(state: &Arc<AppState>, kill: &Killmail) -> Result<EsiKillmail, String> {
+    let mut killmails = load_killmails(state, std::slice::from_ref(kill)).await?;
+    killmails.remove(&kill.killmail_id).ok_or_else(|| {
+        if state.config.read_only {
+            "Ship, damage and item details are not loaded on this copy.".to_string()
+        } else {
+            "ESI did not hand out this killmail; try again later.".to_string()
+        }
+    })
+}
+
+/// `GET /kills/:id`: an HTML fragment with a kill's attackers (ships and
+/// damage), items and links, so the kill list doesn't carry every attacker
+/// of a 200-pilot blob kill up front. Only kills this instance holds are
+/// served.
+pub async fn show_kill(
+    State(state): State<Arc<AppState>>,
+    Path(killmail_id): Path<KillmailId>,
+) -> Response {
+    let Some(kill) = find_kill(&state, killmail_id).await else {
+        return (StatusCode::NOT_FOUND, "Unknown kill").into_response();
+    };
+    let template = match load_killmail(&state, &kill).await {
+        Ok(esi_data) => detail_template(&state, kill, &esi_data).await,
+        Err(reason) => summary_template(kill, reason),
+    };
+    render_page(&template)
+}
+
+/// Sections of an EFT fit by ESI item flag, in EFT's order.
+fn eft_section(flag: i32) -> usize {
+    match flag {
+        11..=18 => 0,   // low slots
+        19..=26 => 1,   // mid slots
+        27..=34 => 2,   // high slots
+        92..=99 => 3,   // rigs
+        125..=132 => 4, // subsystems
+        87 | 158 => 5,  // drone and fighter bays
+        _ => 6,         // cargo and every other hold
+    }
+}
+
+/// The victim's fit in EFT format, which the game's fitting window and
+/// most fitting tools import. Slots are as ESI reports them, so empty ones
+/// are missing, and a loaded charge follows its module.
+fn eft(kill: &Killmail, esi_data: &EsiKillmail, names: &HashMap<EveId, SafeName>) -> String {
+    let name = |type_id: TypeId| {
+        names
+            .get(&type_id.into())
+            .map(|n| n.to_string())
+            .unwrap_or_else(|| format!("Type {}", type_id))
+    };
+    let ship = kill
+        .victim
+        .as_ref()
+        .and_then(|v| v.ship_type_name.as_deref().map(str::to_string))
+        .unwrap_or_else(|| name(esi_data.victim.ship_type_id));
+    let pilot = kill
+        .victim
+        .as_ref()
+        .map(|v| v.display_name().to_string())
+        .unwrap_or_default();
+
+    // flag -> (type, quantity); containers' contents count as cargo
+    let mut by_flag: BTreeMap<i32, Vec<(TypeId, i64)>> = BTreeMap::new();
+    for item in &esi_data.victim.items {
+        let mut flat = Vec::new();
+        item.flatten(&mut flat);
+        for (i, (type_id, dropped, destroyed)) in flat.into_iter().enumerate() {
+            let flag = if i == 0 { item.flag } else { 5 };
+            by_flag
+                .entry(flag)
+                .or_default()
+                .push((type_id, dropped + destroyed));
+        }
+    }
+
+    let mut sections: Vec<Vec<String>> = vec![Vec::new(); 7];
+    for (flag, mut items) in by_flag {
+        let section = eft_section(flag);
+        if section < 5 {
+            // A slot holds a module and maybe its charges; ESI doesn't say
+            // which is which, but charges come by the stack
+            items.sort_by_key(|(_, quantity)| *quantity);
+            let line = items
+                .iter()
+                .map(|(type_id, _)| name(*type_id))
+                .collect::<Vec<_>>()
+                .join(", ");
+            sections[section].push(line);
+        } else {
+            for (type_id, quantity) in items {
+                sections[section].push(format!("{} x{}", name(type_id), quantity));
+            }
+        }
+    }
+
+    let mut fit = format!("[{}, {} (kill {})]\n", ship, pilot, kill.killmail_id);
+    for lines in sections.iter().filter(|lines| !lines.is_empty()) {
+        for line in lines {
+            fit.push_str(line);
+            fit.push('\n');
+        }
+        fit.push('\n');
+    }
+    fit
+}
+
+/// `GET /kills/:id/fit.txt`: the victim's fit in EFT format, to judge
+/// whether the dropped value is plausible.
+pub async fn show_fit(
+    State(state): State<Arc<AppState>>,
+    Path(killmail_id): Path<KillmailId>,
+) -> Response {
+    let Some(kill) = find_kill(&state, killmail_id).await else {
+        return (StatusCode::NOT_FOUND, "Unknown kill").into_response();
+    };
+    match load_killmail(&state, &kill).await {
+        Ok(esi_data) => {
+            let fit = eft(&kill, &esi_data, &state.name_cache.lock().unwrap());
+            ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], fit).into_response()
+        }
+        Err(reason) => (StatusCode::SERVICE_UNAVAILABLE, reason).into_response(),
+    }
+}
+
+/// `GET /ops/:id/fits.txt`: every victim fit of an operation in EFT
+/// format, one after another, for theorycrafting what we killed.
+pub async fn operation_fits(State(state): State<Arc<AppState>>, Path(id): Path<u64>) -> Response {
+    let Some(mut kills) = state
+        .store
+        .lock()
+        .await
+        .operation(id)
+        .map(|op| op.kills.clone())
+    else {
+        return (StatusCode::NOT_FOUND, "Operation not found").into_response();
+    };
+    let killmails = match load_killmails(&state, &kills).await {
+        Ok(killmails) => killmails,
+        Err(reason) => return (StatusCode::SERVICE_UNAVAILABLE, reason).into_response(),
+    };
+    kills.sort_by_key(|k| k.killmail_time);
+    let names = state.name_cache.lock().unwrap();
+    let mut fits = String::new();
+    let mut missing = 0;
+    for kill in &kills {
+        match killmails.get(&kill.killmail_id) {
+            Some(esi_data) => fits.push_str(&eft(kill, esi_data, &names)),
+            None => missing += 1,
+        }
+    }
+    if missing > 0 {
+        fits.push_str(&format!(
+            "# {} kill(s) left out: ESI did not hand out their killmails\n",
+            missing
+        ));
+    }
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                "text/plain; charset=utf-8".to_string(),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"operation-{}-fits.txt\"", id),
+            ),
+        ],
+        fits,
+    )
+        .into_response()
+}
@@ -0,0 +1,205 @@
+use crate::auth::{Role, Viewer};
+use crate::base_path;
+use crate::models::*;
+use crate::payout::{self, LootFairy};
+use crate::views::{isk_error, render_page};
+
+use askama::Template;
+use axum::{
+    extract::{Form, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+const TOP_N: usize = 10;
+
+pub struct LeaderboardEntry {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Template)]
+#[template(path = "leaderboard.html")]
+struct LeaderboardTemplate {
+    viewer: Viewer,
+    month: String,
+    operation_count: usize,
+    top_earners: Vec<LeaderboardEntry>,
+    top_final_blows: Vec<LeaderboardEntry>,
+    top_participation: Vec<LeaderboardEntry>,
+    top_merit_points: Vec<LeaderboardEntry>,
+    /// By drop rate on their kills.
+    top_loot_fairy: Vec<LeaderboardEntry>,
+    opt_outs: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LeaderboardQuery {
+    month: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct OptOutForm {
+    name: String,
+    month: String,
+}
+
+#[derive(Default)]
+struct PilotStats {
+    earned: f64,
+    final_blows: u32,
+    kills: u32,
+    merit_points: f64,
+    loot_fairy: LootFairy,
+}
+
+/// Monthly rankings across saved operations, dated by their end date.
+pub async fn show_leaderboard(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Query(query): Query<LeaderboardQuery>,
+) -> Response {
+    let month = query
+        .month
+        .filter(|m| !m.is_empty())
+        .unwrap_or_else(|| Utc::now().format("%Y-%m").to_string());
+
+    let store = state.store.lock().await;
+    let names = store.name_book();
+    let opt_outs = names.canonical_set(&store.data.leaderboard_opt_outs.iter().cloned().collect());
+    let mut stats: HashMap<String, PilotStats> = HashMap::new();
+    let mut operation_count = 0;
+
+    for op in store
+        .data
+        .operations
+        .iter()
+        .filter(|op| op.end_date.starts_with(&month))
+    {
+        operation_count += 1;
+        let mapping = names.canonical_mapping(&payout::parse_mapping(&op.mapping_text));
+        let result = match op.payout_kills().and_then(|kills| {
+            payout::calculate(
+                &kills,
+                &names,
+                &mapping,
+                &op.excluded_beneficiaries,
+                &op.payout_rules(&store.data.settings.rules),
+            )
+        }) {
+            Ok(result) => result,
+            Err(e) => return isk_error(e),
+        };
+        for (main, amount) in result.wallets {
+            stats.entry(main).or_default().earned += amount.to_f64();
+        }
+        for (main, points) in result.merit_points {
+            stats.entry(main).or_default().merit_points += points;
+        }
+        for (main, fairy) in result.pilot_loot_fairy {
+            if let Err(e) = stats.entry(main).or_default().loot_fairy.merge(fairy) {
+                return isk_error(e);
+            }
+        }
+
+        for kill in op.kills.iter().filter(|k| k.is_active) {
+            let mut participants: HashSet<String> = HashSet::new();
+            for attacker in &kill.attackers {
+                if let Some(main) = payout::attacker_main(&names, &mapping, attacker) {
+                    if attacker.final_blow {
+                        stats.entry(main.clone()).or_default().final_blows += 1;
+                    }
+                    participants.insert(main);
+                }
+            }
+            for main in participants {
+                stats.entry(main).or_default().kills += 1;
+            }
+        }
+    }
+
+    stats.retain(|name, _| !opt_outs.contains(name));
+
+    let rank = |key: &dyn Fn(&PilotStats) -> f64, fmt: &dyn Fn(f64) -> String| {
+        let mut ranked: Vec<(&String, f64)> = stats
+            .iter()
+            .map(|(name, s)| (name, key(s)))
+            .filter(|(_, v)| *v > 0.0)
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        ranked
+            .into_iter()
+            .take(TOP_N)
+            .map(|(name, v)| LeaderboardEntry {
+                name: name.clone(),
+                value: fmt(v),
+            })
+            .collect::<Vec<_>>()
+    };
+    let count = |v: f64| format!("{:.0}", v);
+
+    let template = LeaderboardTemplate {
+        viewer,
+        top_earners: rank(&|s| s.earned, &|v| format!("{} ISK", format_isk(v))),
+        top_final_blows: rank(&|s| s.final_blows as f64, &count),
+        top_participation: rank(&|s| s.kills as f64, &count),
+        top_merit_points: if store.data.settings.merit_points {
+            rank(&|s| s.merit_points, &|v| format!("{:.1}", v))
+        } else {
+            vec![]
+        },
+        top_loot_fairy: rank(
+            &|s| {
+                if s.loot_fairy.kills >= LootFairy::MIN_KILLS {
+                    s.loot_fairy.drop_rate().unwrap_or(0.0)
+                } else {
+                    0.0
+                }
+            },
+            &|v| format!("{:.0}% dropped", v),
+        ),
+        opt_outs: store.data.leaderboard_opt_outs.iter().cloned().collect(),
+        operation_count,
+        month,
+    };
+    render_page(&template)
+}
+
+/// Toggles whether a pilot appears on the leaderboard. Members may only
+/// toggle themselves; FCs can toggle anyone.
+pub async fn toggle_opt_out(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Form(form): Form<OptOutForm>,
+) -> Response {
+    let name = form.name.trim().to_string();
+    if !viewer.has_role(Role::Fc)
+        && viewer.user.as_ref().map(|u| u.character_name.as_str()) != Some(name.as_str())
+    {
+        return (
+            StatusCode::FORBIDDEN,
+            "Members can only opt themselves out.",
+        )
+            .into_response();
+    }
+    if !name.is_empty() {
+        let mut store = state.store.lock().await;
+        let opt_outs = &mut store.data.leaderboard_opt_outs;
+        if !opt_outs.remove(&name) {
+            opt_outs.insert(name);
+        }
+        if let Err(e) = store.save().await {
+            return e.into_response();
+        }
+    }
+    let month: String = form
+        .month
+        .chars()
+        .filter(|c| c.is_ascii_digit() || *c == '-')
+        .collect();
+    base_path::redirect(&format!("/leaderboard?month={}", month)).into_response()
+}
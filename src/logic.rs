@@ -1,26 +1,334 @@
+use crate::auth;
+use crate::config::Config;
 use crate::models::*;
-use chrono::{DateTime, Utc};
-use futures::future::join_all;
+use crate::settings::PricingBackend;
+use crate::store::{self, GrantKind};
+use chrono::{DateTime, Timelike, Utc};
+use futures::future::{join_all, BoxFuture, FutureExt, Shared};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use reqwest::{Client, StatusCode};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::sync::Arc;
-use std::time::Duration;
-use tracing::{debug, error, info, warn};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
 
 static ZKILL_URL_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"zkillboard\.com/(?P<type>\w+)/(?P<id>\d+)").unwrap());
 
+const AFFILIATION_TTL_SECS: i64 = 3600;
+const MARKET_PRICE_TTL: Duration = Duration::from_secs(3600);
+
+pub fn http_client(config: &Config) -> Result<Client, String> {
+    Client::builder()
+        .user_agent(config.user_agent())
+        .gzip(true)
+        .brotli(true)
+        .deflate(true)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+/// Why a fetch failed. Display gives the wording for the error banner;
+/// Debug keeps the details for the log.
+#[derive(Debug, Clone)]
+pub enum FetchError {
+    InvalidLink,
+    UnsupportedEntity(String),
+    /// zKillboard answered 429 (or 420).
+    ZkillRateLimited,
+    /// ESI's error or rate limit (420/429) kicked in; the fetch can be resumed.
+    EsiRateLimited,
+    /// ESI is down for its daily maintenance.
+    EsiDowntime,
+    /// The fetch worked but nothing in the window dropped loot.
+    NoKills,
+    /// An unexpected status from zKillboard or ESI.
+    Upstream {
+        service: &'static str,
+        status: StatusCode,
+    },
+    /// The upstream could not be reached at all.
+    Network {
+        service: &'static str,
+        detail: String,
+    },
+    /// The upstream answered with something we could not read.
+    BadResponse {
+        service: &'static str,
+        detail: String,
+    },
+    /// Setting up the HTTP client failed.
+    Client(String),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::InvalidLink => write!(
+                f,
+                "That is not a zKillboard link. Paste one like https://zkillboard.com/corporation/98000001/"
+            ),
+            FetchError::UnsupportedEntity(kind) => write!(
+                f,
+                "zKillboard {} links are not supported; use a corporation, alliance, character, system or region link",
+                kind
+            ),
+            FetchError::ZkillRateLimited => {
+                write!(f, "zKillboard is rate limiting this server (try again in ~60s)")
+            }
+            FetchError::EsiRateLimited => write!(
+                f,
+                "ESI is rate limiting this server (try again in ~60s; the fetch resumes where it stopped)"
+            ),
+            FetchError::EsiDowntime => write!(
+                f,
+                "ESI is in daily downtime (11:00\u{2013}11:15 UTC); try again once it is back"
+            ),
+            FetchError::NoKills => write!(f, "The entity has no kills with dropped loot in this window"),
+            FetchError::Upstream { service, status } if status.is_server_error() => write!(
+                f,
+                "{} is having problems right now (HTTP {}); try again later",
+                service,
+                status.as_u16()
+            ),
+            FetchError::Upstream { service, status } => write!(
+                f,
+                "{} refused the request (HTTP {})",
+                service,
+                status.as_u16()
+            ),
+            FetchError::Network { service, .. } => {
+                write!(f, "Could not reach {} from this server; try again later", service)
+            }
+            FetchError::BadResponse { service, .. } => write!(
+                f,
+                "{} sent a response this server could not read; try again later",
+                service
+            ),
+            FetchError::Client(_) => write!(f, "The HTTP client could not be set up; see the server log"),
+        }
+    }
+}
+
+impl FetchError {
+    /// The upstream at fault and what happened, for the diagnostics page;
+    /// `None` when the request itself was the problem.
+    pub fn upstream(&self) -> Option<(&'static str, String)> {
+        match self {
+            FetchError::ZkillRateLimited => Some(("zKillboard", "rate limited".to_string())),
+            FetchError::EsiRateLimited => Some(("ESI", "rate limited".to_string())),
+            FetchError::EsiDowntime => Some(("ESI", "daily downtime".to_string())),
+            FetchError::Upstream { service, status } => Some((service, format!("HTTP {}", status))),
+            FetchError::Network { service, detail }
+            | FetchError::BadResponse { service, detail } => Some((service, detail.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// ESI goes down daily at 11:00 UTC; errors around then are the downtime.
+fn in_esi_downtime(now: DateTime<Utc>) -> bool {
+    let minutes = now.hour() * 60 + now.minute();
+    (10 * 60 + 58..=11 * 60 + 20).contains(&minutes)
+}
+
+/// Maps a failed ESI status to the matching error.
+fn esi_status_error(status: StatusCode) -> FetchError {
+    if status.as_u16() == 420 || status == StatusCode::TOO_MANY_REQUESTS {
+        FetchError::EsiRateLimited
+    } else if status.is_server_error() && in_esi_downtime(Utc::now()) {
+        FetchError::EsiDowntime
+    } else {
+        FetchError::Upstream {
+            service: "ESI",
+            status,
+        }
+    }
+}
+
+/// Kills from a zKillboard fetch.
+#[derive(Clone)]
+pub struct Fetched {
+    pub kills: Vec<Killmail>,
+    pub coverage: Coverage,
+    pub report: FetchReport,
+}
+
+/// What a fetch cost upstream, shown under the results. A resumed fetch
+/// carries on with the report of the attempt it resumes.
+#[derive(Debug, Clone, Default)]
+pub struct FetchReport {
+    pub pages_fetched: u32,
+    /// Kills whose ESI details were already cached.
+    pub kills_cached: usize,
+    pub kills_from_esi: usize,
+    pub names_resolved: usize,
+    /// Time spent waiting on zKillboard and ESI, rate limiter waits included.
+    pub api_time: Duration,
+    /// Requests held back by this server's own rate limiters.
+    pub throttled: u32,
+    /// 420/429 answers from zKillboard or ESI.
+    pub rate_limited: u32,
+    /// Kills left out because ESI refused their zKillboard hash.
+    pub rejected_kills: Vec<RejectedKill>,
+}
+
+/// A kill zKillboard listed that ESI wouldn't hand out (404 or 422), as
+/// when zKillboard holds a wrong hash for it. It can't be valued, so it is
+/// left out of the payout.
+#[derive(Debug, Clone)]
+pub struct RejectedKill {
+    pub killmail_id: KillmailId,
+    pub hash: String,
+    pub status: u16,
+}
+
+impl RejectedKill {
+    pub fn esi_url(&self) -> String {
+        format!(
+            "https://esi.evetech.net/v1/killmails/{}/{}/",
+            self.killmail_id, self.hash
+        )
+    }
+}
+
+impl FetchReport {
+    pub fn api_time_display(&self) -> String {
+        format!("{:.1}s", self.api_time.as_secs_f64())
+    }
+}
+
+/// What time span a fetch is known to be complete for, from the reason
+/// pagination stopped.
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    /// Every kill from here to now was fetched. `None` when the board had
+    /// no kills at all.
+    pub complete_from: Option<DateTime<Utc>>,
+    /// The page limit stopped the fetch before it reached the start date,
+    /// so kills before `complete_from` are missing from the window.
+    pub truncated: bool,
+}
+
+impl Coverage {
+    pub fn complete_from_display(&self) -> String {
+        match self.complete_from {
+            Some(t) => t.format("%Y-%m-%d %H:%M").to_string(),
+            None => "the first kill on record".to_string(),
+        }
+    }
+}
+
+/// Progress of a fetch aborted by ESI rate limiting, kept so it can be
+/// resumed after the cool-down instead of starting over.
+pub struct PartialFetch {
+    pub start_cutoff: DateTime<Utc>,
+    /// The page being hydrated when the fetch stopped.
+    pub page: u32,
+    /// Kills listed on the pages before it.
+    pub raw_items: Vec<RawZKillItem>,
+    /// That page's kills; the ones already hydrated sit in the ESI cache.
+    pub pending: Vec<RawZKillItem>,
+    pub oldest_fetched: Option<DateTime<Utc>>,
+    pub report: FetchReport,
+    /// Unresumed fetches are let go of after a while (see sweep).
+    pub stopped_at: DateTime<Utc>,
+}
+
+/// A fetch other submits of the same form can wait on.
+pub type SharedFetch = Shared<BoxFuture<'static, Result<Fetched, FetchError>>>;
+
+/// fetch_zkill_data, run once per submit token: a double-clicked submit
+/// waits for the first submit's fetch instead of starting another one.
+pub async fn fetch_once(
+    state: &Arc<AppState>,
+    submit_token: &str,
+    user_url: &str,
+    start_cutoff: DateTime<Utc>,
+    resume: bool,
+) -> Result<Fetched, FetchError> {
+    if submit_token.is_empty() {
+        return fetch_zkill_data(user_url, state, start_cutoff, resume).await;
+    }
+    let fetch = {
+        let mut inflight = state.inflight_fetches.lock().unwrap();
+        match inflight.get(submit_token) {
+            Some(fetch) => {
+                info!(
+                    "Duplicate submit for {}, waiting for the running fetch",
+                    user_url
+                );
+                fetch.clone()
+            }
+            None => {
+                let (state, url, token) = (
+                    state.clone(),
+                    user_url.to_string(),
+                    submit_token.to_string(),
+                );
+                // Browsers abandon the first request on a double click, so
+                // whichever submit finishes the fetch cleans up after it
+                let fetch = async move {
+                    let result = fetch_zkill_data(&url, &state, start_cutoff, resume).await;
+                    state.inflight_fetches.lock().unwrap().remove(&token);
+                    result
+                }
+                .boxed()
+                .shared();
+                inflight.insert(submit_token.to_string(), fetch.clone());
+                fetch
+            }
+        }
+    };
+    fetch.await
+}
+
+/// Fetches kills of a zKillboard entity back to `start_cutoff`. With
+/// `resume`, continues a matching fetch aborted by rate limiting.
 pub async fn fetch_zkill_data(
     user_url: &str,
     state: &Arc<AppState>,
     start_cutoff: DateTime<Utc>,
-) -> Result<Vec<Killmail>, String> {
+    resume: bool,
+) -> Result<Fetched, FetchError> {
+    let _job = state.diagnostics.start_job("fetch", user_url);
+    let result = fetch_entity(user_url, state, start_cutoff, resume).await;
+    if let Err(e) = &result {
+        if let Some((service, detail)) = e.upstream() {
+            state.diagnostics.upstream_error(service, detail);
+        }
+    }
+    result
+}
+
+/// The entity a zKillboard link is about, e.g. "corporation/98000001",
+/// whatever else the link says.
+pub fn entity_of(user_url: &str) -> Option<String> {
+    let caps = ZKILL_URL_REGEX.captures(user_url)?;
+    Some(format!("{}/{}", &caps["type"], &caps["id"]))
+}
+
+/// The zKillboard link of the losses of the entity behind `user_url`, which
+/// the fetch pipeline takes like any other link.
+pub fn losses_link(user_url: &str) -> Option<String> {
+    Some(format!(
+        "https://zkillboard.com/{}/losses/",
+        entity_of(user_url)?
+    ))
+}
+
+async fn fetch_entity(
+    user_url: &str,
+    state: &Arc<AppState>,
+    start_cutoff: DateTime<Utc>,
+    resume: bool,
+) -> Result<Fetched, FetchError> {
     // 1. Regex Parse
     let caps = ZKILL_URL_REGEX
         .captures(user_url)
-        .ok_or("Invalid ZKillboard Link format")?;
+        .ok_or(FetchError::InvalidLink)?;
     let entity_type = caps.name("type").map(|m| m.as_str()).unwrap_or("");
     let entity_id = caps.name("id").map(|m| m.as_str()).unwrap_or("");
 
@@ -30,139 +338,88 @@ pub async fn fetch_zkill_data(
         "character" => "characterID",
         "system" => "solarSystemID",
         "region" => "regionID",
-        _ => return Err(format!("Unsupported entity type: {}", entity_type)),
+        _ => return Err(FetchError::UnsupportedEntity(entity_type.to_string())),
+    };
+    // A losses link (see losses_link) asks zKillboard for the losses alone
+    let losses_only = user_url.trim_end_matches('/').ends_with("/losses");
+    let api_type = if losses_only {
+        format!("losses/{}", api_type)
+    } else {
+        api_type.to_string()
     };
 
-    let client = Client::builder()
-        .user_agent("EveLooter (maintainer: lu.nemec@gmail.com)")
-        .gzip(true)
-        .brotli(true)
-        .deflate(true)
-        .build()
-        .map_err(|e| e.to_string())?;
-
-    let mut all_raw_items: Vec<RawZKillItem> = Vec::new();
-    let max_pages = 10;
+    let client = http_client(&state.config).map_err(FetchError::Client)?;
 
-    // 2. PAGINATION LOOP
-    for page in 1..=max_pages {
-        let page_url = if page == 1 {
-            format!("https://zkillboard.com/api/{}/{}/", api_type, entity_id)
-        } else {
-            format!(
-                "https://zkillboard.com/api/{}/{}/page/{}/",
-                api_type, entity_id, page
+    let max_pages = state.config.max_pages;
+    let resumed = if resume {
+        state
+            .partial_fetches
+            .lock()
+            .unwrap()
+            .remove(user_url)
+            .filter(|p| p.start_cutoff == start_cutoff)
+    } else {
+        None
+    };
+    let (first_page, mut all_raw_items, mut oldest_fetched, mut carried, mut report) = match resumed
+    {
+        Some(p) => {
+            info!(
+                "Resuming fetch at page {} ({} kills pending)",
+                p.page,
+                p.pending.len()
+            );
+            (
+                p.page,
+                p.raw_items,
+                p.oldest_fetched,
+                Some(p.pending),
+                p.report,
             )
-        };
-
-        info!("Fetching Page {} from ZKill: {}", page, page_url);
-
-        let resp = client
-            .get(&page_url)
-            .send()
-            .await
-            .map_err(|e| e.to_string())?;
-        if !resp.status().is_success() {
-            return Err(format!(
-                "ZKillboard Error on page {}: {}",
-                page,
-                resp.status()
-            ));
         }
+        None => (1, Vec::new(), None, None, FetchReport::default()),
+    };
+    let mut covered = false;
 
-        let page_items: Vec<RawZKillItem> = resp
-            .json()
-            .await
-            .map_err(|e| format!("Failed to parse ZKill JSON on page {}: {}", page, e))?;
+    // 2. PAGINATION LOOP
+    for page in first_page..=max_pages {
+        let started = Instant::now();
+        let page_items = match carried.take() {
+            Some(pending) => pending,
+            None => {
+                let items =
+                    fetch_zkill_page(&client, state, &api_type, entity_id, page, &mut report).await;
+                report.api_time += started.elapsed();
+                items?
+            }
+        };
 
         if page_items.is_empty() {
             info!("Page {} was empty, stopping fetch.", page);
+            covered = true;
             break;
         }
 
         // --- HYDRATE IMMEDIATELY TO CHECK DATES ---
-
-        let mut to_fetch = Vec::new();
-        {
-            let cache = state.esi_cache.lock().unwrap();
-            for item in &page_items {
-                if !cache.contains_key(&item.killmail_id) {
-                    to_fetch.push(item);
-                }
-            }
-        }
-
-        if !to_fetch.is_empty() {
-            info!(
-                "Page {}: Fetching details for {} new kills from ESI...",
-                page,
-                to_fetch.len()
+        let started = Instant::now();
+        let hydrated = hydrate_kills(&client, state, &page_items, page, &mut report).await;
+        report.api_time += started.elapsed();
+        if let Err(e) = hydrated {
+            error!("ESI stopped the fetch ({:?}). Aborting fetch.", e);
+            // Hydrated kills are cached; keep the rest to resume after the cool-down
+            state.partial_fetches.lock().unwrap().insert(
+                user_url.to_string(),
+                PartialFetch {
+                    start_cutoff,
+                    page,
+                    raw_items: all_raw_items,
+                    pending: page_items,
+                    oldest_fetched,
+                    report,
+                    stopped_at: Utc::now(),
+                },
             );
-            let mut tasks = Vec::new();
-
-            for item in to_fetch.iter() {
-                let client_clone = client.clone();
-                let id = item.killmail_id;
-                let hash = item.zkb.hash.clone();
-
-                tasks.push(async move {
-                    let esi_url = format!(
-                        "https://esi.evetech.net/v1/killmails/{}/{}/?datasource=tranquility",
-                        id, hash
-                    );
-                    match client_clone.get(&esi_url).send().await {
-                        Ok(r) => {
-                            let status = r.status();
-                            if status.is_success() {
-                                match r.json::<EsiKillmail>().await {
-                                    Ok(d) => Ok(Some((id, d))),
-                                    Err(e) => {
-                                        error!("Failed to parse ESI JSON for {}: {}", id, e);
-                                        Ok(None)
-                                    }
-                                }
-                            } else {
-                                // CRITICAL: Return the error status so we can check for rate limits
-                                Err(status)
-                            }
-                        }
-                        Err(e) => {
-                            error!("Network error for {}: {}", id, e);
-                            Ok(None)
-                        }
-                    }
-                });
-            }
-
-            let results = join_all(tasks).await;
-
-            // Check for RATE LIMITS (420 or 429) or Server Errors
-            for res in &results {
-                if let Err(status) = res {
-                    if status.as_u16() == 420 || *status == StatusCode::TOO_MANY_REQUESTS {
-                        error!(
-                            "ESI Rate Limit Triggered (Status {}). Aborting fetch.",
-                            status
-                        );
-                        return Err(format!(
-                            "ESI Rate Limit Triggered (Status {}). Try again later.",
-                            status
-                        ));
-                    }
-                    if status.is_server_error() {
-                        warn!("ESI Server Error encountered: {}", status);
-                    }
-                }
-            }
-
-            {
-                let mut cache = state.esi_cache.lock().unwrap();
-                for res in results {
-                    if let Ok(Some((id, data))) = res {
-                        cache.insert(id, data);
-                    }
-                }
-            }
+            return Err(e);
         }
 
         let (oldest_in_batch, batch_valid) = {
@@ -171,12 +428,9 @@ pub async fn fetch_zkill_data(
             let mut valid = false;
 
             for item in &page_items {
-                if let Some(esi_data) = cache.get(&item.killmail_id) {
-                    if let Ok(t) = DateTime::parse_from_rfc3339(&esi_data.killmail_time) {
-                        let t_utc = t.with_timezone(&Utc);
-                        if t_utc < oldest {
-                            oldest = t_utc;
-                        }
+                if cache.contains_key(&item.killmail_id) {
+                    if let Some(t) = cache.killed_at(&item.killmail_id) {
+                        oldest = oldest.min(t);
                     }
                     valid = true;
                 }
@@ -185,144 +439,966 @@ pub async fn fetch_zkill_data(
         };
 
         all_raw_items.extend(page_items);
+        if batch_valid {
+            oldest_fetched =
+                Some(oldest_fetched.map_or(oldest_in_batch, |o| o.min(oldest_in_batch)));
+        }
 
         if batch_valid && oldest_in_batch < start_cutoff {
             info!(
                 "Reached kills older than start date ({} < {}). Stopping fetch.",
                 oldest_in_batch, start_cutoff
             );
+            covered = true;
             break;
         }
-
-        tokio::time::sleep(Duration::from_millis(200)).await;
     }
+    state.partial_fetches.lock().unwrap().remove(user_url);
+
+    // New kills shift zKillboard's pages, so a resumed fetch can list a kill twice
+    let mut seen = HashSet::new();
+    all_raw_items.retain(|item| seen.insert(item.killmail_id));
 
     info!("Total kills fetched from ZKill: {}", all_raw_items.len());
+    let coverage = Coverage {
+        complete_from: oldest_fetched,
+        truncated: !covered,
+    };
+    if coverage.truncated {
+        warn!(
+            "Hit the {} page limit before the start date; kills before {} are missing",
+            max_pages,
+            coverage.complete_from_display()
+        );
+    }
 
-    // 3. Pre-filter zero value kills
-    let worthwhile_kills: Vec<RawZKillItem> = all_raw_items
-        .into_iter()
-        .filter(|k| k.zkb.dropped_value > 0.0)
-        .collect();
+    // The cache holds killmails packed; unpack this fetch's once
+    let killmails: HashMap<KillmailId, EsiKillmail> = {
+        let cache = state.esi_cache.lock().unwrap();
+        all_raw_items
+            .iter()
+            .filter_map(|k| Some((k.killmail_id, cache.get(&k.killmail_id)?)))
+            .collect()
+    };
+
+    // 3. Pre-filter zero value kills, keeping the ships lost by anyone on
+    // the other kills: loss netting charges them (ganking ships rarely drop)
+    let worthwhile_kills: Vec<RawZKillItem> = {
+        let fleet: HashSet<CharacterId> = all_raw_items
+            .iter()
+            .filter_map(|k| killmails.get(&k.killmail_id))
+            .flat_map(|esi_data| &esi_data.attackers)
+            .filter_map(|a| a.character_id)
+            .collect();
+        all_raw_items
+            .into_iter()
+            .filter(|k| {
+                losses_only
+                    || k.zkb.dropped_value > Isk::ZERO
+                    || killmails
+                        .get(&k.killmail_id)
+                        .and_then(|esi_data| esi_data.victim.character_id)
+                        .is_some_and(|id| fleet.contains(&id))
+            })
+            .collect()
+    };
 
     // 4. Resolve Names
+    let started = Instant::now();
     let mut ids_to_resolve = HashSet::new();
     {
-        let esi_cache = state.esi_cache.lock().unwrap();
         let name_cache = state.name_cache.lock().unwrap();
 
         for item in &worthwhile_kills {
-            if let Some(esi_data) = esi_cache.get(&item.killmail_id) {
-                if let Some(id) = esi_data.victim.character_id {
-                    if !name_cache.contains_key(&id) {
-                        ids_to_resolve.insert(id);
-                    }
-                }
-                if let Some(id) = esi_data.victim.corporation_id {
-                    if !name_cache.contains_key(&id) {
-                        ids_to_resolve.insert(id);
-                    }
+            if let Some(esi_data) = killmails.get(&item.killmail_id) {
+                let victim = &esi_data.victim;
+                let mut ids = vec![victim.ship_type_id.into(), esi_data.solar_system_id.into()];
+                ids.extend(victim.character_id.map(EveId::from));
+                ids.extend(victim.corporation_id.map(EveId::from));
+                for att in &esi_data.attackers {
+                    ids.extend(att.character_id.map(EveId::from));
+                    ids.extend(att.ship_type_id.map(EveId::from));
                 }
-                if !name_cache.contains_key(&esi_data.victim.ship_type_id) {
-                    ids_to_resolve.insert(esi_data.victim.ship_type_id);
+                ids_to_resolve.extend(ids.into_iter().filter(|id| !name_cache.contains_key(id)));
+            }
+        }
+    }
+
+    if !ids_to_resolve.is_empty() {
+        resolve_names(&client, state, ids_to_resolve, &mut report).await?;
+    }
+
+    let (corporation_ids, alliance_ids) = {
+        let mut corporations = HashSet::new();
+        let mut alliances = HashSet::new();
+        for esi_data in worthwhile_kills
+            .iter()
+            .filter_map(|item| killmails.get(&item.killmail_id))
+        {
+            corporations.extend(esi_data.victim.corporation_id);
+            alliances.extend(esi_data.victim.alliance_id);
+            for att in &esi_data.attackers {
+                corporations.extend(att.corporation_id);
+                alliances.extend(att.alliance_id);
+            }
+        }
+        (corporations, alliances)
+    };
+    resolve_tickers(&client, state, corporation_ids, alliance_ids).await;
+    let system_ids = {
+        worthwhile_kills
+            .iter()
+            .filter_map(|item| killmails.get(&item.killmail_id))
+            .map(|esi_data| esi_data.solar_system_id)
+            .collect()
+    };
+    resolve_systems(&client, state, system_ids).await;
+    let ship_type_ids = {
+        worthwhile_kills
+            .iter()
+            .filter_map(|item| killmails.get(&item.killmail_id))
+            .map(|esi_data| esi_data.victim.ship_type_id)
+            .collect()
+    };
+    resolve_ship_groups(&client, state, ship_type_ids).await;
+    let (tickers, system_security, system_regions, type_groups, pricing) = {
+        let store = state.store.lock().await;
+        (
+            store.data.tickers.clone(),
+            store.data.system_security.clone(),
+            store.data.system_regions.clone(),
+            store.data.type_groups.clone(),
+            store.data.settings.pricing,
+        )
+    };
+    let prices = match pricing {
+        PricingBackend::Zkillboard => None,
+        PricingBackend::EsiAverage => market_prices(&client, state).await,
+        PricingBackend::StructureMarket => structure_prices(&client, state).await,
+    };
+    report.api_time += started.elapsed();
+    let ticker = |id: Option<EveId>| id.and_then(|id| tickers.get(&id).cloned());
+
+    // 5. Construct Final Objects
+    let mut final_kills = Vec::new();
+    {
+        let name_cache = state.name_cache.lock().unwrap();
+
+        for item in worthwhile_kills {
+            if let Some(esi_data) = killmails.get(&item.killmail_id) {
+                let name = |id: Option<EveId>| id.and_then(|id| name_cache.get(&id).cloned());
+                let victim = &esi_data.victim;
+                let disp_victim = Victim {
+                    character_id: victim.character_id,
+                    character_name: name(victim.character_id.map(EveId::from)),
+                    corporation_name: name(victim.corporation_id.map(EveId::from)),
+                    ship_type_id: victim.ship_type_id,
+                    ship_type_name: name(Some(victim.ship_type_id.into())),
+                    ship_group_id: type_groups.get(&victim.ship_type_id).copied(),
+                    corporation_ticker: ticker(victim.corporation_id.map(EveId::from)),
+                    alliance_ticker: ticker(victim.alliance_id.map(EveId::from)),
+                };
+
+                let mut disp_attackers = Vec::new();
+                for att in &esi_data.attackers {
+                    disp_attackers.push(Attacker {
+                        character_id: att.character_id,
+                        character_name: name(att.character_id.map(EveId::from)),
+                        corporation_id: att.corporation_id,
+                        final_blow: att.final_blow,
+                        corporation_ticker: ticker(att.corporation_id.map(EveId::from)),
+                        alliance_ticker: ticker(att.alliance_id.map(EveId::from)),
+                        ship_type_id: att.ship_type_id,
+                        ship_type_name: name(att.ship_type_id.map(EveId::from)),
+                        weapon_type_id: att.weapon_type_id,
+                        damage_done: att.damage_done,
+                    });
                 }
-                if !name_cache.contains_key(&esi_data.solar_system_id) {
-                    ids_to_resolve.insert(esi_data.solar_system_id);
+
+                // The split reads the dropped value from the zKillboard stats, so
+                // a repriced value replaces it there
+                let mut zkb = item.zkb.clone();
+                if let Some(prices) = &prices {
+                    match dropped_value_at(&esi_data.victim, prices) {
+                        Ok(value) => zkb.dropped_value = value,
+                        Err(e) => warn!(
+                            "Kept zKillboard's value of kill {}: {}",
+                            item.killmail_id, e
+                        ),
+                    }
                 }
-                for att in &esi_data.attackers {
-                    if let Some(id) = att.character_id {
-                        if !name_cache.contains_key(&id) {
-                            ids_to_resolve.insert(id);
-                        }
+                final_kills.push(Killmail {
+                    killmail_id: item.killmail_id,
+                    formatted_dropped: zkb.dropped_value.to_string(),
+                    zkb,
+                    victim: Some(disp_victim),
+                    attackers: disp_attackers,
+                    killmail_time: esi_data.killmail_time,
+                    solar_system_id: esi_data.solar_system_id,
+                    solar_system_name: name(Some(esi_data.solar_system_id.into())),
+                    solar_system_security: system_security.get(&esi_data.solar_system_id).copied(),
+                    region_id: system_regions.get(&esi_data.solar_system_id).copied(),
+                    is_active: true,
+                });
+            }
+        }
+    }
+
+    state
+        .store
+        .lock()
+        .await
+        .record_names(&crate::names::observed_names(&final_kills), Utc::now())
+        .await;
+
+    info!("Fetch report: {:?}", report);
+    Ok(Fetched {
+        kills: final_kills,
+        coverage,
+        report,
+    })
+}
+
+/// ESI killmails of kills we hold, for their detail panes and fits, with
+/// everything on them named. Goes through the killmail cache like a fetch
+/// does, so a pane opened twice (or for a kill fetched this session) costs
+/// nothing. Kills ESI couldn't hand out are missing from the result.
+pub async fn kill_details(
+    state: &Arc<AppState>,
+    items: &[RawZKillItem],
+) -> Result<HashMap<KillmailId, EsiKillmail>, FetchError> {
+    let detail = match items {
+        [item] => item.killmail_id.to_string(),
+        _ => format!("{} kills", items.len()),
+    };
+    let _job = state.diagnostics.start_job("kill detail", detail);
+    let result = load_kills(state, items).await;
+    if let Err(e) = &result {
+        if let Some((service, detail)) = e.upstream() {
+            state.diagnostics.upstream_error(service, detail);
+        }
+    }
+    result
+}
+
+async fn load_kills(
+    state: &Arc<AppState>,
+    items: &[RawZKillItem],
+) -> Result<HashMap<KillmailId, EsiKillmail>, FetchError> {
+    let client = http_client(&state.config).map_err(FetchError::Client)?;
+    let mut report = FetchReport::default();
+    hydrate_kills(&client, state, items, 0, &mut report).await?;
+    // hydrate_kills already logged why any are missing
+    let killmails: HashMap<KillmailId, EsiKillmail> = {
+        let esi_cache = state.esi_cache.lock().unwrap();
+        items
+            .iter()
+            .filter_map(|item| Some((item.killmail_id, esi_cache.get(&item.killmail_id)?)))
+            .collect()
+    };
+
+    let unnamed: HashSet<EveId> = {
+        let name_cache = state.name_cache.lock().unwrap();
+        killmails
+            .values()
+            .flat_map(EsiKillmail::named_ids)
+            .filter(|id| !name_cache.contains_key(id))
+            .collect()
+    };
+    if !unnamed.is_empty() {
+        resolve_names(&client, state, unnamed, &mut report).await?;
+    }
+    let attackers = || killmails.values().flat_map(|k| &k.attackers);
+    let corporations = attackers().filter_map(|a| a.corporation_id).collect();
+    let alliances = attackers().filter_map(|a| a.alliance_id).collect();
+    resolve_tickers(&client, state, corporations, alliances).await;
+    Ok(killmails)
+}
+
+/// Resolves IDs to names via ESI, caching the results in memory and in the
+/// store. The names endpoint answers 404 for a whole request if any one ID
+/// is invalid, so rejected chunks are bisected down to the offending IDs,
+/// which are remembered instead of sinking the rest of the batch.
+async fn resolve_names(
+    client: &Client,
+    state: &Arc<AppState>,
+    ids: HashSet<EveId>,
+    report: &mut FetchReport,
+) -> Result<(), FetchError> {
+    let retry_cutoff = Utc::now() - chrono::Duration::hours(state.config.unresolvable_retry_hours);
+    let mut retried = Vec::new();
+    for id in ids {
+        let rejected_at = store::rejected_at(state.storage.as_ref(), id).await;
+        if rejected_at.is_none_or(|at| at < retry_cutoff) {
+            retried.push(id);
+        }
+    }
+    let ids = retried;
+    if ids.is_empty() {
+        return Ok(());
+    }
+    info!("Resolving names for {} new entities via ESI", ids.len());
+
+    let url = "https://esi.evetech.net/v1/universe/names/?datasource=tranquility";
+    let mut queue: Vec<Vec<EveId>> = ids.chunks(1000).map(<[EveId]>::to_vec).collect();
+    let mut resolved = Vec::new();
+    let mut invalid = Vec::new();
+    let mut result = Ok(());
+    while let Some(chunk) = queue.pop() {
+        if state.esi_limiter.acquire().await {
+            report.throttled += 1;
+        }
+        let r = match client.post(url).json(&chunk).send().await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Failed to contact ESI Name Resolution endpoint: {}", e);
+                state
+                    .diagnostics
+                    .upstream_error("ESI", format!("name resolution: {}", e));
+                continue;
+            }
+        };
+        let status = r.status();
+        if status.is_success() {
+            match r.json::<Vec<EsiNameEntry>>().await {
+                Ok(entries) => resolved.extend(entries.into_iter().map(|e| (e.id, e.name))),
+                Err(e) => error!("Failed to parse ESI names: {}", e),
+            }
+        } else if status == StatusCode::NOT_FOUND {
+            if let [id] = chunk[..] {
+                warn!("ESI could not resolve ID {}", id);
+                invalid.push(id);
+            } else {
+                let (a, b) = chunk.split_at(chunk.len() / 2);
+                queue.push(a.to_vec());
+                queue.push(b.to_vec());
+            }
+        } else if status.as_u16() == 420 || status == StatusCode::TOO_MANY_REQUESTS {
+            // Handle Rate Limit on Name Resolution
+            error!(
+                "ESI Rate Limit Triggered during Name Resolution. Status: {}",
+                status
+            );
+            report.rate_limited += 1;
+            result = Err(FetchError::EsiRateLimited);
+            break;
+        } else {
+            warn!("ESI Name Resolution failed: {}", status);
+            state
+                .diagnostics
+                .upstream_error("ESI", format!("name resolution: HTTP {}", status));
+        }
+    }
+
+    // Keep whatever was resolved, even when rate limiting cut the run short
+    report.names_resolved += resolved.len();
+    {
+        let mut name_cache = state.name_cache.lock().unwrap();
+        for (id, name) in &resolved {
+            name_cache.insert(*id, name.clone());
+        }
+    }
+    store::record_resolved(state.storage.as_ref(), &resolved, &invalid).await;
+    result
+}
+
+/// Names item types not in the name cache yet, e.g. loot never seen on a
+/// killmail page. Failures just leave the type unnamed.
+pub async fn resolve_type_names(client: &Client, state: &Arc<AppState>, type_ids: HashSet<TypeId>) {
+    let missing: HashSet<EveId> = {
+        let name_cache = state.name_cache.lock().unwrap();
+        type_ids
+            .into_iter()
+            .map(EveId::from)
+            .filter(|id| !name_cache.contains_key(id))
+            .collect()
+    };
+    if let Err(e) = resolve_names(client, state, missing, &mut FetchReport::default()).await {
+        warn!("Item type names stopped early: {:?}", e);
+    }
+}
+
+/// Looks up tickers of corporations and alliances not seen before and
+/// keeps them in the store.
+async fn resolve_tickers(
+    client: &Client,
+    state: &Arc<AppState>,
+    corporation_ids: HashSet<CorporationId>,
+    alliance_ids: HashSet<AllianceId>,
+) {
+    let missing: Vec<(&str, EveId)> = {
+        let store = state.store.lock().await;
+        let known = |id: &EveId| store.data.tickers.contains_key(id);
+        corporation_ids
+            .into_iter()
+            .map(|id| ("corporations", id.into()))
+            .chain(alliance_ids.into_iter().map(|id| ("alliances", id.into())))
+            .filter(|(_, id)| !known(id))
+            .collect()
+    };
+    if missing.is_empty() {
+        return;
+    }
+    info!("Resolving {} tickers via ESI", missing.len());
+    let resolved: Vec<(EveId, EsiTickerEntry)> = esi_lookups(client, state, missing).await;
+
+    let mut store = state.store.lock().await;
+    let update = store.update(|data| {
+        data.tickers
+            .extend(resolved.iter().map(|(id, e)| (*id, e.ticker.clone())))
+    });
+    update.await.ok();
+}
+
+/// Looks up security status and region of systems not seen before and
+/// keeps them in the store. The region takes a second lookup, of the
+/// system's constellation.
+async fn resolve_systems(client: &Client, state: &Arc<AppState>, system_ids: HashSet<SystemId>) {
+    let missing: Vec<(&str, SystemId)> = {
+        let store = state.store.lock().await;
+        system_ids
+            .into_iter()
+            .filter(|id| {
+                !store.data.system_security.contains_key(id)
+                    || !store.data.system_regions.contains_key(id)
+            })
+            .map(|id| ("universe/systems", id))
+            .collect()
+    };
+    if missing.is_empty() {
+        return;
+    }
+    info!(
+        "Resolving security status and region of {} systems via ESI",
+        missing.len()
+    );
+    let systems: Vec<(SystemId, EsiSystemEntry)> = esi_lookups(client, state, missing).await;
+    let constellation_ids: HashSet<i64> = systems.iter().map(|(_, e)| e.constellation_id).collect();
+    let constellations: HashMap<i64, EsiConstellationEntry> = esi_lookups(
+        client,
+        state,
+        constellation_ids
+            .into_iter()
+            .map(|id| ("universe/constellations", id))
+            .collect(),
+    )
+    .await
+    .into_iter()
+    .collect();
+
+    let mut store = state.store.lock().await;
+    let update = store.update(|data| {
+        for (id, system) in &systems {
+            data.system_security.insert(*id, system.security_status);
+            if let Some(constellation) = constellations.get(&system.constellation_id) {
+                data.system_regions.insert(*id, constellation.region_id);
+            }
+        }
+    });
+    update.await.ok();
+}
+
+async fn resolve_ship_groups(client: &Client, state: &Arc<AppState>, type_ids: HashSet<TypeId>) {
+    let missing: Vec<(&str, TypeId)> = {
+        let store = state.store.lock().await;
+        type_ids
+            .into_iter()
+            .filter(|id| !store.data.type_groups.contains_key(id))
+            .map(|id| ("universe/types", id))
+            .collect()
+    };
+    if missing.is_empty() {
+        return;
+    }
+    info!("Resolving groups of {} ship types via ESI", missing.len());
+    let resolved: Vec<(TypeId, EsiTypeEntry)> = esi_lookups(client, state, missing).await;
+
+    let mut store = state.store.lock().await;
+    let update = store.update(|data| {
+        data.type_groups
+            .extend(resolved.iter().map(|(id, e)| (*id, e.group_id)))
+    });
+    update.await.ok();
+}
+
+/// ESI's average market price per type ID, refreshed hourly. None when ESI
+/// can't be reached, in which case zKillboard's values are kept.
+pub async fn market_prices(client: &Client, state: &Arc<AppState>) -> Option<MarketPrices> {
+    if let Some((fetched_at, prices)) = &*state.market_prices.lock().unwrap() {
+        if fetched_at.elapsed() < MARKET_PRICE_TTL {
+            return Some(prices.clone());
+        }
+    }
+    state.esi_limiter.acquire().await;
+    let url = "https://esi.evetech.net/latest/markets/prices/?datasource=tranquility";
+    let entries: Vec<EsiMarketPrice> = match client.get(url).send().await {
+        Ok(r) if r.status().is_success() => match r.json().await {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Failed to parse ESI market prices: {}", e);
+                return None;
+            }
+        },
+        Ok(r) => {
+            warn!("ESI market prices failed: {}", r.status());
+            return None;
+        }
+        Err(e) => {
+            error!("Network error for ESI market prices: {}", e);
+            return None;
+        }
+    };
+    let prices: MarketPrices = Arc::new(
+        entries
+            .into_iter()
+            .filter_map(|e| Some((e.type_id, e.average_price?)))
+            .collect(),
+    );
+    info!("Loaded {} ESI market prices", prices.len());
+    *state.market_prices.lock().unwrap() = Some((Instant::now(), prices.clone()));
+    Some(prices)
+}
+
+/// The lowest sell order per type ID on the workspace's pricing structure,
+/// read with the market grant's character and refreshed hourly. Types
+/// nobody sells there keep ESI's average price, and without a structure,
+/// grant or answer from ESI every type does.
+async fn structure_prices(client: &Client, state: &Arc<AppState>) -> Option<MarketPrices> {
+    if let Some((fetched_at, prices)) = &*state.structure_prices.lock().unwrap() {
+        if fetched_at.elapsed() < MARKET_PRICE_TTL {
+            return Some(prices.clone());
+        }
+    }
+    let average = market_prices(client, state).await;
+    let Some(structure_id) = state.store.lock().await.data.settings.price_structure_id else {
+        warn!("Structure market pricing needs a structure ID; using ESI averages");
+        return average;
+    };
+    let access_token = match auth::grant_access_token(state, GrantKind::Market).await {
+        Ok(access_token) => access_token,
+        Err(e) => {
+            error!("No structure market access, using ESI averages: {}", e);
+            return average;
+        }
+    };
+
+    let mut lowest: HashMap<TypeId, f64> = HashMap::new();
+    let mut page = 1;
+    loop {
+        state.esi_limiter.acquire().await;
+        let url = format!(
+            "https://esi.evetech.net/latest/markets/structures/{}/?datasource=tranquility&page={}",
+            structure_id, page
+        );
+        let (orders, pages) = match client.get(&url).bearer_auth(&access_token).send().await {
+            Ok(r) if r.status().is_success() => {
+                let pages: u32 = r
+                    .headers()
+                    .get("x-pages")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(1);
+                match r.json::<Vec<EsiMarketOrder>>().await {
+                    Ok(orders) => (orders, pages),
+                    Err(e) => {
+                        error!("Failed to parse structure {} orders: {}", structure_id, e);
+                        return average;
                     }
                 }
             }
+            Ok(r) => {
+                // 403: the character can't dock there, or lost the scope
+                warn!("Structure {} market failed: {}", structure_id, r.status());
+                state.diagnostics.upstream_error(
+                    "ESI",
+                    format!("structure {} market: {}", structure_id, r.status()),
+                );
+                return average;
+            }
+            Err(e) => {
+                error!("Network error for structure {} market: {}", structure_id, e);
+                return average;
+            }
+        };
+        for order in orders.into_iter().filter(|o| !o.is_buy_order) {
+            let price = lowest.entry(order.type_id).or_insert(order.price);
+            *price = price.min(order.price);
+        }
+        if page >= pages {
+            break;
         }
+        page += 1;
     }
+    info!(
+        "Loaded prices of {} types from structure {}",
+        lowest.len(),
+        structure_id
+    );
+    let mut prices: HashMap<TypeId, f64> = average.map(|a| (*a).clone()).unwrap_or_default();
+    prices.extend(lowest);
+    let prices: MarketPrices = Arc::new(prices);
+    *state.structure_prices.lock().unwrap() = Some((Instant::now(), prices.clone()));
+    Some(prices)
+}
 
-    if !ids_to_resolve.is_empty() {
-        info!(
-            "Resolving names for {} new entities via ESI",
-            ids_to_resolve.len()
+#[derive(serde::Deserialize)]
+struct PublicCharacter {
+    corporation_id: CorporationId,
+}
+
+/// The corporation a character is in, per ESI's public character record.
+pub async fn corporation_of(
+    client: &Client,
+    state: &AppState,
+    character_id: CharacterId,
+) -> Result<CorporationId, String> {
+    state.esi_limiter.acquire().await;
+    let url = format!(
+        "https://esi.evetech.net/latest/characters/{}/?datasource=tranquility",
+        character_id
+    );
+    let resp = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| format!("Network error for character {}: {}", character_id, e))?;
+    if !resp.status().is_success() {
+        return Err(format!(
+            "ESI character {} failed: {}",
+            character_id,
+            resp.status()
+        ));
+    }
+    let character: PublicCharacter = resp
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse character {}: {}", character_id, e))?;
+    Ok(character.corporation_id)
+}
+
+/// Value of everything that dropped from the victim's ship at `prices`.
+fn dropped_value_at(
+    victim: &EsiVictim,
+    prices: &HashMap<TypeId, f64>,
+) -> Result<Isk, IskOutOfRange> {
+    let mut dropped = Vec::new();
+    for item in &victim.items {
+        item.dropped(&mut dropped);
+    }
+    let mut value = Isk::ZERO;
+    for (type_id, qty) in dropped {
+        let price = Isk::from_f64(prices.get(&type_id).copied().unwrap_or(0.0))?;
+        value.increase(price.checked_mul(qty.into())?)?;
+    }
+    Ok(value)
+}
+
+/// GETs `/latest/{path}/{id}/` for each (path, ID), one rate-limited request
+/// each. Failures only cost that entry.
+async fn esi_lookups<Id: Copy + fmt::Display, T: serde::de::DeserializeOwned>(
+    client: &Client,
+    state: &Arc<AppState>,
+    requests: Vec<(&str, Id)>,
+) -> Vec<(Id, T)> {
+    let limiter = &state.esi_limiter;
+    let tasks = requests.into_iter().map(|(path, id)| async move {
+        limiter.acquire().await;
+        let url = format!(
+            "https://esi.evetech.net/latest/{}/{}/?datasource=tranquility",
+            path, id
         );
-        let ids_vec: Vec<i32> = ids_to_resolve.into_iter().collect();
+        match client.get(&url).send().await {
+            Ok(r) if r.status().is_success() => match r.json::<T>().await {
+                Ok(entry) => Some((id, entry)),
+                Err(e) => {
+                    error!("Failed to parse ESI {} {}: {}", path, id, e);
+                    None
+                }
+            },
+            Ok(r) => {
+                warn!("ESI {} lookup for {} failed: {}", path, id, r.status());
+                None
+            }
+            Err(e) => {
+                error!("Network error for {} {}: {}", path, id, e);
+                None
+            }
+        }
+    });
+    join_all(tasks).await.into_iter().flatten().collect()
+}
+
+async fn fetch_zkill_page(
+    client: &Client,
+    state: &AppState,
+    api_type: &str,
+    entity_id: &str,
+    page: u32,
+    report: &mut FetchReport,
+) -> Result<Vec<RawZKillItem>, FetchError> {
+    let page_url = if page == 1 {
+        format!("https://zkillboard.com/api/{}/{}/", api_type, entity_id)
+    } else {
+        format!(
+            "https://zkillboard.com/api/{}/{}/page/{}/",
+            api_type, entity_id, page
+        )
+    };
+
+    info!("Fetching Page {} from ZKill: {}", page, page_url);
+
+    if state.zkill_limiter.acquire().await {
+        report.throttled += 1;
+    }
+    report.pages_fetched += 1;
+    let resp = client
+        .get(&page_url)
+        .send()
+        .await
+        .map_err(|e| FetchError::Network {
+            service: "zKillboard",
+            detail: e.to_string(),
+        })?;
+    let status = resp.status();
+    if status.as_u16() == 420 || status == StatusCode::TOO_MANY_REQUESTS {
+        report.rate_limited += 1;
+        return Err(FetchError::ZkillRateLimited);
+    }
+    if !status.is_success() {
+        warn!("zKillboard answered {} for {}", status, page_url);
+        return Err(FetchError::Upstream {
+            service: "zKillboard",
+            status,
+        });
+    }
+
+    resp.json().await.map_err(|e| FetchError::BadResponse {
+        service: "zKillboard",
+        detail: format!("page {}: {}", page, e),
+    })
+}
+
+/// Fetches ESI details for the kills not cached yet. Whatever arrived is
+/// cached even when ESI rate limits us (420/429) or is in downtime, which is
+/// returned as the error so the caller can stop and resume later.
+async fn hydrate_kills(
+    client: &Client,
+    state: &Arc<AppState>,
+    items: &[RawZKillItem],
+    page: u32,
+    report: &mut FetchReport,
+) -> Result<(), FetchError> {
+    let mut to_fetch: Vec<&RawZKillItem> = {
+        let cache = state.esi_cache.lock().unwrap();
+        items
+            .iter()
+            .filter(|item| !cache.contains_key(&item.killmail_id))
+            .collect()
+    };
+    // Backends with a killmail cache (Postgres) keep them across restarts
+    if !to_fetch.is_empty() {
+        let ids: Vec<KillmailId> = to_fetch.iter().map(|item| item.killmail_id).collect();
+        match state.storage.cached_killmails(&ids).await {
+            Ok(stored) if !stored.is_empty() => {
+                to_fetch.retain(|item| !stored.contains_key(&item.killmail_id));
+                state.esi_cache.lock().unwrap().extend(stored);
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to read cached killmails: {}", e),
+        }
+    }
+    report.kills_cached += items.len() - to_fetch.len();
+    if to_fetch.is_empty() {
+        return Ok(());
+    }
+
+    info!(
+        "Page {}: Fetching details for {} new kills from ESI...",
+        page,
+        to_fetch.len()
+    );
+    let mut tasks = Vec::new();
+
+    for item in to_fetch.iter().copied() {
+        let client_clone = client.clone();
+        let id = item.killmail_id;
+        let hash = item.zkb.hash.clone();
+        let limiter = &state.esi_limiter;
 
-        for chunk in ids_vec.chunks(1000) {
-            let url = "https://esi.evetech.net/v1/universe/names/?datasource=tranquility";
-            let resp = client.post(url).json(&chunk).send().await;
-            match resp {
+        tasks.push(async move {
+            let throttled = limiter.acquire().await;
+            let esi_url = format!(
+                "https://esi.evetech.net/v1/killmails/{}/{}/?datasource=tranquility",
+                id, hash
+            );
+            let result = match client_clone.get(&esi_url).send().await {
                 Ok(r) => {
-                    if r.status().is_success() {
-                        if let Ok(entries) = r.json::<Vec<EsiNameEntry>>().await {
-                            let mut name_cache = state.name_cache.lock().unwrap();
-                            for entry in entries {
-                                name_cache.insert(entry.id, entry.name);
+                    let status = r.status();
+                    if status.is_success() {
+                        match r.json::<EsiKillmail>().await {
+                            Ok(d) => Ok(Some((id, d))),
+                            Err(e) => {
+                                error!("Failed to parse ESI JSON for {}: {}", id, e);
+                                Ok(None)
                             }
                         }
                     } else {
-                        // Handle Rate Limit on Name Resolution
-                        if r.status().as_u16() == 420 || r.status() == StatusCode::TOO_MANY_REQUESTS
-                        {
-                            error!(
-                                "ESI Rate Limit Triggered during Name Resolution. Status: {}",
-                                r.status()
-                            );
-                            return Err(
-                                "ESI Rate Limit Exceeded during name resolution.".to_string()
-                            );
-                        }
-                        warn!("ESI Name Resolution failed: {}", r.status());
+                        // CRITICAL: Return the error status so we can check for rate limits
+                        Err(status)
                     }
                 }
-                Err(e) => error!("Failed to contact ESI Name Resolution endpoint: {}", e),
+                Err(e) => {
+                    error!("Network error for {}: {}", id, e);
+                    Ok(None)
+                }
+            };
+            (throttled, item, result)
+        });
+    }
+
+    let results = join_all(tasks).await;
+
+    let mut stop = None;
+    let mut hydrated = Vec::new();
+    for (throttled, item, res) in results {
+        if throttled {
+            report.throttled += 1;
+        }
+        match res {
+            Ok(Some(entry)) => {
+                report.kills_from_esi += 1;
+                hydrated.push(entry);
+            }
+            Ok(None) => {}
+            // ESI doesn't know the kill by that hash; retrying won't help
+            Err(status)
+                if status == StatusCode::NOT_FOUND
+                    || status == StatusCode::UNPROCESSABLE_ENTITY =>
+            {
+                warn!(
+                    "ESI rejected kill {} with hash {} ({}); leaving it out",
+                    item.killmail_id, item.zkb.hash, status
+                );
+                // A resumed fetch hydrates its page again
+                if !report
+                    .rejected_kills
+                    .iter()
+                    .any(|r| r.killmail_id == item.killmail_id)
+                {
+                    report.rejected_kills.push(RejectedKill {
+                        killmail_id: item.killmail_id,
+                        hash: item.zkb.hash.clone(),
+                        status: status.as_u16(),
+                    });
+                }
             }
+            // Rate limits and downtime stop the fetch; other errors cost one kill
+            Err(status) => match esi_status_error(status) {
+                e @ FetchError::EsiRateLimited => {
+                    report.rate_limited += 1;
+                    stop = Some(e);
+                }
+                e @ FetchError::EsiDowntime => stop = Some(e),
+                _ => warn!("ESI Error encountered: {}", status),
+            },
         }
     }
+    if let Err(e) = state.storage.cache_killmails(&hydrated).await {
+        warn!("Failed to store hydrated killmails: {}", e);
+    }
+    state.esi_cache.lock().unwrap().extend(hydrated);
+    stop.map_or(Ok(()), Err)
+}
 
-    // 5. Construct Final Objects
-    let mut final_kills = Vec::new();
-    let esi_cache = state.esi_cache.lock().unwrap();
-    let name_cache = state.name_cache.lock().unwrap();
-
-    for item in worthwhile_kills {
-        if let Some(esi_data) = esi_cache.get(&item.killmail_id) {
-            let disp_victim = Victim {
-                character_id: esi_data.victim.character_id,
-                character_name: esi_data
-                    .victim
-                    .character_id
-                    .and_then(|id| name_cache.get(&id).cloned()),
-                corporation_name: esi_data
-                    .victim
-                    .corporation_id
-                    .and_then(|id| name_cache.get(&id).cloned()),
-                ship_type_id: esi_data.victim.ship_type_id,
-                ship_type_name: name_cache.get(&esi_data.victim.ship_type_id).cloned(),
-            };
+/// Returns the subset of `mains` whose character is no longer in any of the
+/// configured home corporations/alliances, per ESI affiliation. Characters we
+/// cannot identify are left out rather than flagged.
+pub async fn find_ex_members(
+    state: &Arc<AppState>,
+    mains: &[(String, Option<CharacterId>)],
+) -> Result<HashSet<String>, String> {
+    let config = &state.config;
+    if config.home_corporation_ids.is_empty() && config.home_alliance_ids.is_empty() {
+        return Ok(HashSet::new());
+    }
 
-            let mut disp_attackers = Vec::new();
-            for att in &esi_data.attackers {
-                disp_attackers.push(Attacker {
-                    character_id: att.character_id,
-                    character_name: att.character_id.and_then(|id| name_cache.get(&id).cloned()),
-                    corporation_id: att.corporation_id,
-                    final_blow: att.final_blow,
-                });
+    let client = http_client(&state.config)?;
+
+    // 1. Resolve IDs for mains that never appeared on a killmail themselves
+    let mut ids: HashMap<String, CharacterId> = HashMap::new();
+    let mut unknown = Vec::new();
+    for (name, id) in mains {
+        match id {
+            Some(id) => {
+                ids.insert(name.clone(), *id);
+            }
+            None => unknown.push(name.clone()),
+        }
+    }
+    if !unknown.is_empty() {
+        let url = "https://esi.evetech.net/v1/universe/ids/?datasource=tranquility";
+        state.esi_limiter.acquire().await;
+        let resp = client
+            .post(url)
+            .json(&unknown)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if resp.status().is_success() {
+            let found: EsiIdsResponse = resp.json().await.map_err(|e| e.to_string())?;
+            for entry in found.characters.unwrap_or_default() {
+                ids.insert(entry.name.into(), entry.id);
             }
+        } else {
+            warn!("ESI ID lookup failed: {}", resp.status());
+        }
+    }
 
-            final_kills.push(Killmail {
-                killmail_id: item.killmail_id,
-                zkb: item.zkb.clone(),
-                victim: Some(disp_victim),
-                attackers: disp_attackers,
-                killmail_time: esi_data.killmail_time.clone(),
-                formatted_dropped: format_isk(item.zkb.dropped_value),
-                solar_system_id: esi_data.solar_system_id,
-                solar_system_name: name_cache.get(&esi_data.solar_system_id).cloned(),
-                is_active: true,
-            });
+    // 2. Refresh stale affiliations
+    let now = Utc::now();
+    let stale: Vec<CharacterId> = {
+        let cache = state.affiliation_cache.lock().unwrap();
+        ids.values()
+            .filter(|id| {
+                cache
+                    .get(id)
+                    .is_none_or(|(_, at)| (now - *at).num_seconds() > AFFILIATION_TTL_SECS)
+            })
+            .copied()
+            .collect()
+    };
+    for chunk in stale.chunks(1000) {
+        let url = "https://esi.evetech.net/v2/characters/affiliation/?datasource=tranquility";
+        state.esi_limiter.acquire().await;
+        let resp = client
+            .post(url)
+            .json(&chunk)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !resp.status().is_success() {
+            return Err(format!("ESI affiliation lookup failed: {}", resp.status()));
+        }
+        let entries: Vec<EsiAffiliation> = resp.json().await.map_err(|e| e.to_string())?;
+        let mut cache = state.affiliation_cache.lock().unwrap();
+        for entry in entries {
+            cache.insert(entry.character_id, (entry.clone(), now));
         }
     }
 
-    Ok(final_kills)
+    // 3. Compare against the configured home entities
+    let cache = state.affiliation_cache.lock().unwrap();
+    let ex_members = ids
+        .iter()
+        .filter(|(_, id)| {
+            cache.get(id).is_some_and(|(aff, _)| {
+                !config.home_corporation_ids.contains(&aff.corporation_id)
+                    && !aff
+                        .alliance_id
+                        .is_some_and(|a| config.home_alliance_ids.contains(&a))
+            })
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+    Ok(ex_members)
 }
@@ -0,0 +1,248 @@
+//! The loot actually collected, read from the corp container (or office
+//! hangar division) the haulers fill, against what dropped from the
+//! operation's kills. Whatever dropped but never reached the can was left
+//! behind or looted by someone else; whatever is there but never dropped
+//! came from elsewhere. An imported can also bounds wallet matching (see
+//! sales::match_wallet).
+
+use crate::auth::{self, Viewer};
+use crate::base_path;
+use crate::kills;
+use crate::logic::{self, http_client};
+use crate::models::*;
+use crate::ops::not_found;
+use crate::store::{GrantKind, LootCan, Operation};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use chrono::Utc;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// The part of a corp asset we sum.
+#[derive(Deserialize)]
+struct CorpAsset {
+    location_id: i64,
+    location_flag: String,
+    type_id: TypeId,
+    quantity: i64,
+}
+
+/// `POST /ops/:id/loot-can`: snapshots the loot container's contents,
+/// with what dropped from the active kills for comparison. A new import
+/// replaces the last one.
+pub async fn import_loot_can(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Path(id): Path<u64>,
+) -> Response {
+    let (kills, location_id, flag) = {
+        let store = state.store.lock().await;
+        let Some(op) = store.operation(id) else {
+            return not_found();
+        };
+        let settings = &store.data.settings;
+        let Some(location_id) = settings.loot_location_id else {
+            return (
+                StatusCode::BAD_REQUEST,
+                Html("Set the loot container under Settings first".to_string()),
+            )
+                .into_response();
+        };
+        let kills: Vec<Killmail> = op.kills.iter().filter(|k| k.is_active).cloned().collect();
+        (kills, location_id, settings.loot_location_flag.clone())
+    };
+
+    let collected = match container_contents(&state, location_id, &flag).await {
+        Ok(collected) => collected,
+        Err(e) => return upstream(e),
+    };
+    let dropped: BTreeMap<TypeId, i64> = match kills::dropped_items(&state, &kills).await {
+        Ok(dropped) => dropped.into_iter().collect(),
+        Err(e) => return upstream(e),
+    };
+    if let Ok(client) = http_client(&state.config) {
+        let types: HashSet<TypeId> = collected.keys().chain(dropped.keys()).copied().collect();
+        logic::resolve_type_names(&client, &state, types).await;
+    }
+
+    let mut store = state.store.lock().await;
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    let by = viewer.user.map(|u| u.character_name.into());
+    let items: i64 = collected.values().sum();
+    info!(
+        "Imported {} items of {} types from location {} into operation {}",
+        items,
+        collected.len(),
+        location_id,
+        id
+    );
+    op.log(
+        by.clone(),
+        format!(
+            "Imported the loot can: {} items of {} types",
+            items,
+            collected.len()
+        ),
+    );
+    op.loot_can = Some(LootCan {
+        imported_at: Utc::now(),
+        imported_by: by,
+        location_id,
+        collected,
+        dropped,
+    });
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+fn upstream(message: String) -> Response {
+    error!("Loot can import failed: {}", message);
+    (StatusCode::BAD_GATEWAY, Html(message)).into_response()
+}
+
+/// Quantity of each item type at `location_id` among the assets grant's
+/// corporation's, under `flag` unless empty.
+async fn container_contents(
+    state: &Arc<AppState>,
+    location_id: i64,
+    flag: &str,
+) -> Result<BTreeMap<TypeId, i64>, String> {
+    let character_id = state
+        .store
+        .lock()
+        .await
+        .data
+        .assets_grant
+        .as_ref()
+        .map(|g| g.character_id)
+        .ok_or("Grant corp assets access under Settings first")?;
+    let access_token = auth::grant_access_token(state, GrantKind::Assets).await?;
+    let client = http_client(&state.config)?;
+    let corporation_id = logic::corporation_of(&client, state, character_id).await?;
+
+    let mut contents = BTreeMap::new();
+    let mut page = 1;
+    loop {
+        state.esi_limiter.acquire().await;
+        let url = format!(
+            "https://esi.evetech.net/latest/corporations/{}/assets/?datasource=tranquility&page={}",
+            corporation_id, page
+        );
+        let resp = client
+            .get(&url)
+            .bearer_auth(&access_token)
+            .send()
+            .await
+            .map_err(|e| format!("Network error for the corp assets: {}", e))?;
+        if !resp.status().is_success() {
+            // 403: the character lacks a director role
+            state
+                .diagnostics
+                .upstream_error("ESI", format!("corp assets: {}", resp.status()));
+            return Err(format!("ESI refused the corp assets: {}", resp.status()));
+        }
+        let pages: u32 = resp
+            .headers()
+            .get("x-pages")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1);
+        let assets: Vec<CorpAsset> = resp
+            .json()
+            .await
+            .map_err(|e| format!("Failed to parse the corp assets: {}", e))?;
+        for asset in assets {
+            if asset.location_id == location_id && (flag.is_empty() || asset.location_flag == flag)
+            {
+                *contents.entry(asset.type_id).or_default() += asset.quantity;
+            }
+        }
+        if page >= pages {
+            break;
+        }
+        page += 1;
+    }
+    Ok(contents)
+}
+
+pub struct LootCanRow {
+    pub name: String,
+    pub dropped: i64,
+    pub collected: i64,
+    /// Collected less dropped, signed.
+    pub difference: String,
+    /// -1 left behind, 0 all collected, 1 not from these kills.
+    pub direction: i8,
+}
+
+/// The loot can panel of an operation with an imported can.
+pub struct LootCanView {
+    pub imported_at: String,
+    pub imported_by: String,
+    /// Item types whose counts differ first, by name.
+    pub rows: Vec<LootCanRow>,
+    /// Value of what dropped but wasn't collected, at ESI average prices
+    /// when they are cached.
+    pub missing_value: Option<String>,
+}
+
+impl LootCanView {
+    pub fn new(state: &AppState, op: &Operation) -> Option<Self> {
+        let can = op.loot_can.as_ref()?;
+        let prices = state
+            .market_prices
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(_, prices)| prices.clone());
+        let name_cache = state.name_cache.lock().unwrap();
+        let types: HashSet<TypeId> = can
+            .collected
+            .keys()
+            .chain(can.dropped.keys())
+            .copied()
+            .collect();
+        let mut missing = 0.0;
+        let mut rows: Vec<LootCanRow> = types
+            .into_iter()
+            .map(|type_id| {
+                let dropped = can.dropped.get(&type_id).copied().unwrap_or(0);
+                let collected = can.collected.get(&type_id).copied().unwrap_or(0);
+                if let Some(price) = prices.as_ref().and_then(|p| p.get(&type_id)) {
+                    missing += price * (dropped - collected).max(0) as f64;
+                }
+                LootCanRow {
+                    name: name_cache
+                        .get(&type_id.into())
+                        .map(|n| n.to_string())
+                        .unwrap_or_else(|| format!("Type {}", type_id)),
+                    dropped,
+                    collected,
+                    difference: format!("{:+}", collected - dropped),
+                    direction: (collected - dropped).signum() as i8,
+                }
+            })
+            .collect();
+        rows.sort_by(|a, b| {
+            (a.direction == 0)
+                .cmp(&(b.direction == 0))
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        Some(LootCanView {
+            imported_at: can.imported_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+            imported_by: can.imported_by.clone().unwrap_or_else(|| "-".to_string()),
+            rows,
+            missing_value: prices.map(|_| format_isk(missing)),
+        })
+    }
+}
@@ -0,0 +1,123 @@
+use crate::logic::{self, fetch_zkill_data};
+use crate::models::*;
+use crate::views::{date_window, isk_error, render_page};
+
+use askama::Template;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub struct LossesQuery {
+    #[serde(default)]
+    link: String,
+    #[serde(default)]
+    start_date: String,
+    #[serde(default)]
+    end_date: String,
+}
+
+pub struct PilotLosses {
+    pub name: String,
+    pub ships: usize,
+    pub value: String,
+}
+
+pub struct LossRow {
+    pub kill: Killmail,
+    /// Date and time in the workspace timezone.
+    pub time: String,
+    pub value: String,
+}
+
+#[derive(Template)]
+#[template(path = "partials/losses.html")]
+struct LossesTemplate {
+    /// Newest first.
+    losses: Vec<LossRow>,
+    total_value: String,
+    timezone: Tz,
+    /// By value lost, most first.
+    pilots: Vec<PilotLosses>,
+}
+
+fn victim_name(kill: &Killmail) -> String {
+    kill.victim
+        .as_ref()
+        .map(|v| v.display_name().to_string())
+        .unwrap_or_else(|| "Unknown".to_string())
+}
+
+/// `GET /losses?link=...&start_date=...&end_date=...`: an HTML fragment with
+/// the ships the entity lost in the window, for the kill log's losses tab.
+/// They come through the same pipeline (and caches) as the kills, from
+/// zKillboard's losses endpoint; fetched when the tab is first opened.
+pub async fn show_losses(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<LossesQuery>,
+) -> Response {
+    let Some(link) = logic::losses_link(&query.link) else {
+        return (StatusCode::BAD_REQUEST, "Not a zKillboard link").into_response();
+    };
+    let (start_cutoff, end_cutoff) = date_window(&query.start_date, &query.end_date);
+    let fetched = match fetch_zkill_data(&link, &state, start_cutoff, false).await {
+        Ok(fetched) => fetched,
+        Err(e) => return (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+    };
+
+    let mut losses: Vec<Killmail> = fetched
+        .kills
+        .into_iter()
+        .filter(|k| k.killmail_time >= start_cutoff && k.killmail_time <= end_cutoff)
+        .collect();
+    losses.sort_by_key(|k| std::cmp::Reverse(k.killmail_time));
+
+    let mut by_pilot: HashMap<String, (usize, Isk)> = HashMap::new();
+    for kill in &losses {
+        let (ships, value) = by_pilot.entry(victim_name(kill)).or_default();
+        *ships += 1;
+        if let Err(e) = value.increase(kill.zkb.total_value) {
+            return isk_error(e);
+        }
+    }
+    let total_value = match Isk::total(losses.iter().map(|k| k.zkb.total_value)) {
+        Ok(total_value) => total_value,
+        Err(e) => return isk_error(e),
+    };
+    let mut pilots: Vec<(String, usize, Isk)> = by_pilot
+        .into_iter()
+        .map(|(name, (ships, value))| (name, ships, value))
+        .collect();
+    pilots.sort_by_key(|p| std::cmp::Reverse(p.2));
+
+    let timezone = state.store.lock().await.data.settings.timezone;
+    render_page(&LossesTemplate {
+        timezone,
+        total_value: total_value.to_string(),
+        losses: losses
+            .into_iter()
+            .map(|kill| LossRow {
+                time: kill
+                    .local_time(timezone)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string(),
+                value: kill.zkb.total_value.to_string(),
+                kill,
+            })
+            .collect(),
+        pilots: pilots
+            .into_iter()
+            .map(|(name, ships, value)| PilotLosses {
+                name,
+                ships,
+                value: value.to_string(),
+            })
+            .collect(),
+    })
+}
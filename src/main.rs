@@ -1,63 +1,73 @@
+mod account;
+mod admin;
+mod alts;
+mod api;
+mod auth;
+mod base_path;
+mod cache;
+mod client;
+mod compare;
+mod config;
+mod diagnostics;
+mod digest;
+mod embed;
+mod feed;
+mod graphql;
+mod kills;
+mod leaderboard;
 mod logic;
+mod loot_can;
+mod losses;
+mod migrate;
 mod models;
-
-use crate::logic::fetch_zkill_data;
+mod names;
+mod notify;
+mod oplist;
+mod ops;
+mod payout;
+mod prefetch;
+mod privacy;
+mod pwa;
+mod qr;
+mod ratelimit;
+mod request_id;
+mod retention;
+mod routing;
+mod sales;
+mod script;
+mod server;
+mod settings;
+mod storage;
+mod store;
+mod sweep;
+mod transfers;
+mod transparency;
+mod version;
+mod views;
+mod whatif;
+
+use crate::auth::Viewer;
+use crate::client::ClientIp;
+use crate::config::Config;
+use crate::logic::{fetch_once, find_ex_members, FetchError};
 use crate::models::*;
+use crate::store::FormState;
+use crate::views::*;
 
-use askama::Template;
 use axum::{
-    extract::{Form, State},
-    response::Html,
+    extract::{Form, Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Router,
 };
-use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Utc};
-use serde::Deserialize;
-use std::collections::{HashMap, HashSet};
-use std::net::SocketAddr;
+use axum_extra::extract::cookie::CookieJar;
+use chrono::{Duration, Utc};
 use std::sync::Arc;
 use tower_http::compression::CompressionLayer;
 use tower_http::trace::TraceLayer;
-use tracing::{debug, error, info};
-
-// --- View Models ---
-
-struct BeneficiaryDisplay {
-    name: String,
-    formatted_amount: String,
-    is_active: bool,
-}
-
-struct DailyGroup {
-    date_display: String,
-    kills: Vec<Killmail>,
-}
-
-#[derive(Template)]
-#[template(path = "index.html")]
-struct IndexTemplate {
-    daily_groups: Vec<DailyGroup>,
-    mapping_text: String,
-    zkill_link: String,
-    start_date: String,
-    end_date: String,
-    total_payout_str: String,
-    total_humans: usize,
-    beneficiaries: Vec<BeneficiaryDisplay>,
-    error_msg: Option<String>,
-}
-
-#[derive(Deserialize, Debug)]
-struct FetchParams {
-    zkill_link: String,
-    mapping_input: String,
-    excluded_kills: Option<String>,
-    excluded_beneficiaries: Option<String>,
-    #[serde(default)]
-    start_date: String,
-    #[serde(default)]
-    end_date: String,
-}
+use tracing::{debug, error, info, warn};
 
 // --- Main ---
 
@@ -68,237 +78,572 @@ async fn main() {
     }
 
     tracing_subscriber::fmt::init();
-    let state = Arc::new(AppState::new());
+    let config = Config::from_env();
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        if let Err(e) = migrate::run(&config).await {
+            error!("Migration failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Err(e) = config.validate() {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    let storage = match storage::from_config(&config).await {
+        Ok(storage) => Arc::from(storage),
+        Err(e) => {
+            error!("Failed to open storage: {}", e);
+            std::process::exit(1);
+        }
+    };
+    base_path::init(&config.base_path);
+    let state = match AppState::new(config, storage).await {
+        Ok(state) => Arc::new(state),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if state.config.read_only {
+        info!("Read-only mode: serving saved operations, no fetching or editing");
+    } else {
+        prefetch::spawn(state.clone());
+        retention::spawn(state.clone());
+        digest::spawn(state.clone());
+        sweep::spawn(state.clone());
+    }
 
-    let app = Router::new()
-        .route("/", get(show_index))
+    // Role enforcement only bites when EVE SSO is configured (see auth::Viewer)
+    let fc_routes = Router::new()
         .route("/process", post(process_data))
-        .layer(TraceLayer::new_for_http())
+        .route("/losses", get(losses::show_losses))
+        .route("/ops", post(ops::save_operation))
+        .route("/ops/import", post(ops::import_operation))
+        .route("/ops/:id", post(ops::update_operation))
+        .route("/ops/:id/mapping/default", post(ops::make_default_mapping))
+        .route("/ops/:id/mapping/reset", post(ops::reset_mapping))
+        .route("/ops/:id/bulk", post(ops::bulk_edit))
+        .route("/ops/:id/pods", post(ops::toggle_pods))
+        .route("/ops/:id/whatif", get(whatif::preview))
+        .route("/ops/:id/adjustments", post(ops::add_adjustment))
+        .route("/ops/:id/holdings", post(ops::update_holdings))
+        .route("/ops/:id/details", post(ops::update_details))
+        .route("/ops/:id/rerun", post(ops::rerun_operation))
+        .route("/ops/:id/publish", post(ops::publish_operation))
+        .route("/ops/:id/finalize", post(ops::finalize_operation))
+        .route("/ops/:id/sign-off", post(ops::sign_off_operation))
+        .route(
+            "/ops/:id/disputes/:index/resolve",
+            post(ops::resolve_dispute),
+        )
+        .route("/ops/:id/paid", post(ops::mark_paid))
+        .route("/ops/:id/sales", post(sales::record_sale))
+        .route("/ops/:id/sales/match", post(sales::match_wallet))
+        .route("/ops/:id/loot-can", post(loot_can::import_loot_can))
+        .route("/ops/:id/sales/split", post(sales::toggle_realized_split))
+        .route("/ops/:id/sales/:index/delete", post(sales::remove_sale))
+        .route("/ops/:id/share", post(ops::share_operation))
+        .route(
+            "/ops/:id/adjustments/:index/delete",
+            post(ops::remove_adjustment),
+        )
+        .route("/ops/:id/undo", post(ops::undo_edit))
+        .route("/ops/:id/redo", post(ops::redo_edit))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_fc,
+        ));
+
+    let member_routes = Router::new()
+        .route("/ops", get(oplist::list_operations))
+        .route("/ops/:id", get(ops::show_operation))
+        .route("/ops/:id/export.json", get(ops::export_operation))
+        .route("/ops/:id/kills", get(ops::search_kills))
+        .route("/ops/:id/fits.txt", get(kills::operation_fits))
+        .route("/ops/:id/disputes", post(ops::raise_dispute))
+        .route("/kills/:id", get(kills::show_kill))
+        .route("/kills/:id/fit.txt", get(kills::show_fit))
+        .route("/leaderboard", get(leaderboard::show_leaderboard))
+        .route("/compare", get(compare::show_comparison))
+        .route("/leaderboard/opt-out", post(leaderboard::toggle_opt_out))
+        .route("/account", get(account::show_account))
+        .route("/account/tokens", post(account::create_token))
+        .route("/account/tokens/revoke", post(account::revoke_token))
+        .route("/account/data.json", get(privacy::export_data))
+        .route("/account/deletion", post(privacy::request_deletion))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_member,
+        ));
+
+    let admin_routes = Router::new()
+        .route("/admin", get(diagnostics::show_diagnostics))
+        .route(
+            "/settings",
+            get(settings::show_settings).post(settings::update_settings),
+        )
+        .route("/settings/presets", post(settings::save_preset))
+        .route("/settings/presets/delete", post(settings::remove_preset))
+        .route("/settings/webhooks", post(settings::add_webhook))
+        .route("/settings/digest", post(settings::send_digest))
+        .route("/settings/market-access", get(auth::grant_market_access))
+        .route(
+            "/settings/market-access/revoke",
+            post(settings::revoke_market_access),
+        )
+        .route("/settings/wallet-access", get(auth::grant_wallet_access))
+        .route(
+            "/settings/wallet-access/revoke",
+            post(settings::revoke_wallet_access),
+        )
+        .route("/settings/assets-access", get(auth::grant_assets_access))
+        .route(
+            "/settings/assets-access/revoke",
+            post(settings::revoke_assets_access),
+        )
+        .route(
+            "/settings/webhooks/:id/delete",
+            post(settings::remove_webhook),
+        )
+        .route(
+            "/settings/deletions/:character_id/erase",
+            post(privacy::erase_character),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_admin,
+        ));
+
+    let app = if state.config.read_only {
+        read_only_routes()
+    } else {
+        Router::new()
+            .route("/", get(show_index))
+            .route("/auth/login", get(auth::login))
+            .route("/auth/callback", get(auth::callback))
+            .route("/auth/logout", get(auth::logout))
+            .route("/admin/backup", get(admin::backup))
+            .route("/admin/restore", post(admin::restore))
+            .route("/admin/prune", post(admin::prune))
+            .route("/embed/:token", get(embed::show_embed))
+            .route("/transparency/:token", get(transparency::show_transparency))
+            .route("/share/:token/qr.svg", get(qr::share_qr))
+            .route("/feed.atom", get(feed::atom_feed))
+            .route("/version", get(version::show_version))
+            .route("/manifest.webmanifest", get(pwa::manifest))
+            .route("/sw.js", get(pwa::service_worker))
+            .route("/icon.svg", get(pwa::icon))
+            .route(
+                "/api/graphql",
+                get(graphql::graphiql).post(graphql::execute),
+            )
+            .route(
+                "/api/v1/pilots/:character_id/payouts",
+                get(api::pilot_payouts),
+            )
+            .route("/api/v1/compare", get(compare::comparison_json))
+            .route("/api/v1/route/:from/:to", get(api::route_length))
+            .merge(admin_routes)
+            .merge(fc_routes)
+            .merge(member_routes)
+    };
+    // Behind a proxy mounting us under a prefix, everything moves with it
+    let app = match state.config.base_path.as_str() {
+        "" => app,
+        // Nesting serves "/looter" but not "/looter/", which proxies often send
+        base => Router::new()
+            .route(&format!("{}/", base), get(show_index))
+            .nest(base, app),
+    };
+    let app = app
+        .layer(middleware::from_fn_with_state(state.clone(), sync_store))
+        .layer(TraceLayer::new_for_http().make_span_with(request_id::span))
+        // Inside compression, so request IDs are appended to plain bodies
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            request_id::assign,
+        ))
         .layer(CompressionLayer::new())
-        .with_state(state);
+        .with_state(state.clone());
+    if let Err(e) = server::serve(app, &state.config).await {
+        error!("Server failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Picks up what other instances saved before the request reads the store,
+/// so locking it later needs no storage round trip.
+async fn sync_store(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    state.store.sync().await;
+    next.run(request).await
+}
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-    info!("EVE Looter running on http://{}", addr);
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+/// The public face of the payout record: saved operations and what is
+/// derived from them, open to anyone. The working instance stays private.
+fn read_only_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(|| async { base_path::redirect("/ops") }))
+        .route("/ops", get(oplist::list_operations))
+        .route("/ops/:id", get(ops::show_operation))
+        .route("/ops/:id/export.json", get(ops::export_operation))
+        .route("/ops/:id/kills", get(ops::search_kills))
+        .route("/ops/:id/fits.txt", get(kills::operation_fits))
+        .route("/kills/:id", get(kills::show_kill))
+        .route("/kills/:id/fit.txt", get(kills::show_fit))
+        .route("/leaderboard", get(leaderboard::show_leaderboard))
+        .route("/compare", get(compare::show_comparison))
+        .route("/embed/:token", get(embed::show_embed))
+        .route("/transparency/:token", get(transparency::show_transparency))
+        .route("/share/:token/qr.svg", get(qr::share_qr))
+        .route("/feed.atom", get(feed::atom_feed))
+        .route("/version", get(version::show_version))
+        .route("/manifest.webmanifest", get(pwa::manifest))
+        .route("/sw.js", get(pwa::service_worker))
+        .route("/icon.svg", get(pwa::icon))
+        .route(
+            "/api/graphql",
+            get(graphql::graphiql).post(graphql::execute),
+        )
+        .route(
+            "/api/v1/pilots/:character_id/payouts",
+            get(api::pilot_payouts),
+        )
+        .route("/api/v1/compare", get(compare::comparison_json))
+        .route("/api/v1/route/:from/:to", get(api::route_length))
 }
 
 // --- Handlers ---
 
-async fn show_index() -> Html<String> {
+async fn show_index(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    jar: CookieJar,
+) -> Response {
     let now = Utc::now();
     let start = now - Duration::days(7);
 
-    let template = IndexTemplate {
-        daily_groups: vec![],
-        mapping_text: "".to_string(),
-        zkill_link: "".to_string(),
-        start_date: start.format("%Y-%m-%d").to_string(),
-        end_date: now.format("%Y-%m-%d").to_string(),
-        total_payout_str: "0".to_string(),
-        total_humans: 0,
-        beneficiaries: vec![],
-        error_msg: None,
+    // Bring back what this browser last submitted
+    let form = match jar.get(auth::FORM_COOKIE) {
+        Some(cookie) => store::load_form(state.storage.as_ref(), cookie.value()).await,
+        None => None,
+    };
+    let template = {
+        let store = state.store.lock().await;
+        let mut template = IndexTemplate {
+            viewer,
+            operation: None,
+            mapping_text: store.data.default_mapping.clone(),
+            zkill_link: "".to_string(),
+            start_date: start.format("%Y-%m-%d").to_string(),
+            end_date: now.format("%Y-%m-%d").to_string(),
+            op_start: "".to_string(),
+            op_end: "".to_string(),
+            result: ResultView::empty(),
+            error_msg: None,
+            can_resume: false,
+            replace_prompt: None,
+            fetch_report: None,
+        };
+        if let Some(form) = form {
+            template.mapping_text = form.mapping_text;
+            template.zkill_link = form.zkill_link;
+            template.start_date = form.start_date;
+            template.end_date = form.end_date;
+            template.op_start = form.op_start;
+            template.op_end = form.op_end;
+        }
+        template
     };
-    Html(template.render().unwrap())
+    render_page(&template)
 }
 
+/// Remembers the submitted form for this browser so revisiting `/` brings
+/// it back, then runs the calculation.
 async fn process_data(
     State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    client: ClientIp,
+    jar: CookieJar,
     Form(params): Form<FetchParams>,
-) -> Html<String> {
+) -> Response {
+    if !params.zkill_link.is_empty() && !state.client_fetches.allow(client.0) {
+        warn!("{} is over its fetch budget", client.0);
+        let mut template = IndexTemplate::from_params(&params, viewer);
+        template.error_msg = Some(format!(
+            "You have started {} fetches in the last minute; wait a moment before the next one",
+            state.config.client_fetches_per_minute
+        ));
+        return (StatusCode::TOO_MANY_REQUESTS, render_page(&template)).into_response();
+    }
+    let key = jar
+        .get(auth::FORM_COOKIE)
+        .map(|c| c.value().to_string())
+        .unwrap_or_else(auth::random_token);
+    store::remember_form(
+        state.storage.as_ref(),
+        &key,
+        &FormState {
+            zkill_link: params.zkill_link.clone(),
+            mapping_text: params.mapping_input.clone(),
+            start_date: params.start_date.clone(),
+            end_date: params.end_date.clone(),
+            op_start: params.op_start.clone(),
+            op_end: params.op_end.clone(),
+            updated_at: Utc::now(),
+        },
+    )
+    .await;
+    let jar = jar.add(auth::form_cookie(&state, key));
+    (jar, calculate(state, viewer, params).await).into_response()
+}
+
+async fn calculate(state: Arc<AppState>, viewer: Viewer, params: FetchParams) -> Response {
     info!("Processing request for: {}", params.zkill_link);
 
     // 1. Time Filter Setup
-    let start_cutoff = NaiveDate::parse_from_str(&params.start_date, "%Y-%m-%d")
-        .unwrap_or_else(|_| (Utc::now() - Duration::days(7)).date_naive())
-        .and_time(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
-        .and_utc();
-
-    let end_cutoff = NaiveDate::parse_from_str(&params.end_date, "%Y-%m-%d")
-        .unwrap_or_else(|_| Utc::now().date_naive())
-        .and_time(NaiveTime::from_hms_opt(23, 59, 59).unwrap())
-        .and_utc();
+    let (start_cutoff, end_cutoff) = params.window();
 
     debug!("Time window: {} to {}", start_cutoff, end_cutoff);
 
     if (end_cutoff - start_cutoff).num_days() > 30 {
-        let template = IndexTemplate {
-            daily_groups: vec![],
-            mapping_text: params.mapping_input,
-            zkill_link: params.zkill_link,
-            start_date: params.start_date,
-            end_date: params.end_date,
-            total_payout_str: "0".to_string(),
-            total_humans: 0,
-            beneficiaries: vec![],
-            error_msg: Some(
-                "Timeframe exceeds 30 days. Please select a shorter range.".to_string(),
-            ),
-        };
-        return Html(template.render().unwrap());
+        let mut template = IndexTemplate::from_params(&params, viewer);
+        template.error_msg =
+            Some("Timeframe exceeds 30 days. Please select a shorter range.".to_string());
+        return render_page(&template);
     }
 
     // 2. Update Mapping
     {
         let mut map_guard = state.character_map.lock().unwrap();
-        map_guard.clear();
-        for line in params.mapping_input.lines() {
-            if let Some((alt, main)) = line.split_once([':', '=']) {
-                map_guard.insert(alt.trim().to_string(), main.trim().to_string());
+        *map_guard = payout::parse_mapping(&params.mapping_input);
+    }
+
+    state.pull_calculator().await;
+    *state.calculator_used.lock().unwrap() = Utc::now();
+
+    // Identical requests within the TTL reuse the computed result
+    let cache_key = cache::request_key(&params, state.config.max_kills);
+    let cached = if params.zkill_link.is_empty() {
+        None
+    } else {
+        state.result_cache.lock().unwrap().get(cache_key)
+    };
+    let source = params.source();
+    if let Some(cached) = cached {
+        debug!("Serving cached result for: {}", params.zkill_link);
+        let prompt = if params.confirm_replace {
+            None
+        } else {
+            ReplacePrompt::check(
+                &state.current_kills.lock().unwrap(),
+                state.current_source.lock().unwrap().as_ref(),
+                &source,
+                &cached.kills,
+            )
+        };
+        let mut template = IndexTemplate::from_params(&params, viewer);
+        match prompt {
+            Some(prompt) => template.replace_prompt = Some(prompt),
+            None => {
+                *state.current_kills.lock().unwrap() = cached.kills;
+                *state.current_source.lock().unwrap() = Some(source);
+                state.push_calculator().await;
+                template.result = cached.result;
             }
         }
+        return render_page(&template);
     }
 
-    // 3. Fetch Data
-    let fetch_result = if !params.zkill_link.is_empty() {
-        Some(fetch_zkill_data(&params.zkill_link, &state, start_cutoff).await)
+    // 3. Fetch Data (or take the one waiting for this confirmation)
+    let pending = state
+        .pending_replace
+        .lock()
+        .unwrap()
+        .take()
+        .filter(|(pending_source, _)| params.confirm_replace && *pending_source == source);
+    let fetch_result = if let Some((_, fetched)) = pending {
+        Some(Ok(fetched))
+    } else if !params.zkill_link.is_empty() {
+        Some(
+            fetch_once(
+                &state,
+                &params.submit_token,
+                &params.zkill_link,
+                start_cutoff,
+                params.resume,
+            )
+            .await,
+        )
     } else {
         None
     };
 
-    let mut kills_guard = state.current_kills.lock().unwrap();
+    let can_resume = state
+        .partial_fetches
+        .lock()
+        .unwrap()
+        .contains_key(&params.zkill_link);
     let mut error_msg = None;
-
-    if let Some(res) = fetch_result {
-        match res {
-            Ok(fetched_kills) => {
-                *kills_guard = fetched_kills;
-            }
-            Err(e) => {
-                error!("Error fetching data: {}", e);
-                if kills_guard.is_empty() {
-                    error_msg = Some(format!("Failed to fetch: {}", e));
+    let mut fetched = false;
+    let mut coverage = None;
+    let mut fetch_report = None;
+    let rules = state.store.lock().await.data.settings.rules.clone();
+    let mapping = state.character_map.lock().unwrap().clone();
+    let final_kills = {
+        let mut kills_guard = state.current_kills.lock().unwrap();
+
+        if let Some(res) = fetch_result {
+            match res {
+                Ok(result) => {
+                    let prompt = if params.confirm_replace {
+                        None
+                    } else {
+                        ReplacePrompt::check(
+                            &kills_guard,
+                            state.current_source.lock().unwrap().as_ref(),
+                            &source,
+                            &result.kills,
+                        )
+                    };
+                    if let Some(prompt) = prompt {
+                        info!(
+                            "Holding fetch for {} until the user confirms replacing {} kills",
+                            params.zkill_link,
+                            kills_guard.len()
+                        );
+                        *state.pending_replace.lock().unwrap() = Some((source, result));
+                        let mut template = IndexTemplate::from_params(&params, viewer);
+                        template.replace_prompt = Some(prompt);
+                        return render_page(&template);
+                    }
+                    let mut kills = result.kills;
+                    let max_kills = state.config.max_kills;
+                    if kills.len() > max_kills {
+                        warn!("Keeping the newest {} of {} kills", max_kills, kills.len());
+                        error_msg = Some(format!(
+                            "Only the newest {} of {} kills are kept; choose a shorter range for the rest",
+                            max_kills,
+                            kills.len()
+                        ));
+                        kills.sort_by_key(|k| std::cmp::Reverse(k.killmail_time));
+                        kills.truncate(max_kills);
+                    }
+                    *kills_guard = kills;
+                    *state.current_source.lock().unwrap() = Some(source);
+                    coverage = Some(result.coverage);
+                    fetch_report = Some(result.report);
+                    fetched = true;
+                }
+                Err(e) => {
+                    error!("Error fetching data: {:?}", e);
+                    if kills_guard.is_empty() || can_resume {
+                        error_msg = Some(e.to_string());
+                    }
                 }
             }
         }
-    }
-
-    let excluded_ids: HashSet<i32> = params
-        .excluded_kills
-        .as_deref()
-        .unwrap_or_default()
-        .split(',')
-        .filter_map(|s| s.trim().parse().ok())
-        .collect();
-
-    let excluded_names: HashSet<String> = params
-        .excluded_beneficiaries
-        .as_deref()
-        .unwrap_or_default()
-        .split(',')
-        .map(|s| s.trim().to_string())
-        .filter(|s| !s.is_empty())
-        .collect();
 
-    // 4. Filter Active Kills
-    let final_kills: Vec<Killmail> = kills_guard
-        .iter()
-        .filter(|k| {
-            if k.zkb.dropped_value <= 0.0 {
-                return false;
-            }
-            if let Ok(t) = DateTime::parse_from_rfc3339(&k.killmail_time) {
-                let t_utc = t.with_timezone(&Utc);
-                t_utc >= start_cutoff && t_utc <= end_cutoff
-            } else {
-                false
-            }
-        })
-        .map(|k| {
-            let mut km = k.clone();
-            km.is_active = !excluded_ids.contains(&k.killmail_id);
-            km
-        })
-        .collect();
+        // 4. Filter Active Kills
+        let loss_candidates = rules.loss_candidates(&mapping, &kills_guard);
+        select_kills(
+            &kills_guard,
+            start_cutoff,
+            end_cutoff,
+            &params.excluded_ids(),
+            &loss_candidates,
+        )
+    };
 
     debug!("Active kills in range: {}", final_kills.len());
+    if fetched {
+        state.push_calculator().await;
+    }
+    if fetched && final_kills.is_empty() {
+        error_msg = Some(FetchError::NoKills.to_string());
+    }
 
     // 5. Calculate Payout
     let current_map = state.character_map.lock().unwrap().clone();
-    let mut all_seen_mains: HashSet<String> = HashSet::new();
-    let mut main_wallets: HashMap<String, f64> = HashMap::new();
-    let mut total_dropped_value = 0.0;
-
-    for kill in &final_kills {
-        if !kill.is_active {
-            continue;
-        }
-
-        total_dropped_value += kill.zkb.dropped_value;
-
-        let mut kill_participants: HashSet<String> = HashSet::new();
-        for attacker in &kill.attackers {
-            if let Some(name) = &attacker.character_name {
-                let main = current_map.get(name).unwrap_or(name);
-                all_seen_mains.insert(main.clone());
-                if !excluded_names.contains(main) {
-                    kill_participants.insert(main.clone());
-                }
-            }
-        }
-
-        if kill_participants.is_empty() {
-            continue;
-        }
+    let (names, settings) = {
+        let store = state.store.lock().await;
+        (store.name_book(), store.data.settings.clone())
+    };
+    let result = build_result(
+        final_kills,
+        &ResultInputs {
+            names: &names,
+            settings: &settings,
+            mapping: &current_map,
+            excluded_names: &params.excluded_names(),
+            adjustments: &[],
+            holdings: &[],
+            op_start: &params.op_start,
+            op_end: &params.op_end,
+            rules: &settings.rules,
+        },
+    );
+    let mut result = match result {
+        Ok(result) => result,
+        Err(e) => return isk_error(e),
+    };
+    result.coverage = coverage;
+    flag_ex_members(&state, &mut result).await;
+    routing::mark_distances(&state, &mut result, settings.hauling_fee_per_jump).await;
+    add_history_suggestions(&state, &mut result, &current_map).await;
+
+    if fetched {
+        state.result_cache.lock().unwrap().insert(
+            cache_key,
+            cache::CachedResult {
+                kills: state.current_kills.lock().unwrap().clone(),
+                result: result.clone(),
+            },
+        );
+    }
 
-        let participant_count = kill_participants.len() as f64;
-        let share_per_pilot = kill.zkb.dropped_value / participant_count;
+    let mut template = IndexTemplate::from_params(&params, viewer);
+    template.result = result;
+    template.error_msg = error_msg;
+    template.can_resume = can_resume;
+    template.fetch_report = fetch_report;
 
-        for main in kill_participants {
-            *main_wallets.entry(main).or_insert(0.0) += share_per_pilot;
-        }
-    }
+    render_page(&template)
+}
 
-    // 6. Beneficiaries List
-    let mut beneficiaries = Vec::new();
-    for main in all_seen_mains {
-        let amount = *main_wallets.get(&main).unwrap_or(&0.0);
-        beneficiaries.push(BeneficiaryDisplay {
-            name: main.clone(),
-            formatted_amount: format_isk(amount),
-            is_active: !excluded_names.contains(&main),
-        });
-    }
-    beneficiaries.sort_by(|a, b| a.name.cmp(&b.name));
-    let active_humans = beneficiaries.iter().filter(|b| b.is_active).count();
-
-    // 7. Grouping
-    let mut groups_map: HashMap<String, Vec<Killmail>> = HashMap::new();
-    for kill in final_kills {
-        let date_str = kill
-            .killmail_time
-            .split('T')
-            .next()
-            .unwrap_or("Unknown")
-            .to_string();
-        groups_map.entry(date_str).or_default().push(kill);
+/// Marks beneficiaries who have left the home corp/alliance since the op.
+pub async fn flag_ex_members(state: &Arc<AppState>, result: &mut ResultView) {
+    match find_ex_members(state, &result.membership_candidates()).await {
+        Ok(ex_members) => result.mark_ex_members(&ex_members),
+        Err(e) => warn!("Corp membership check failed: {}", e),
     }
+}
 
-    let mut daily_groups = Vec::new();
-    let mut dates: Vec<String> = groups_map.keys().cloned().collect();
-    dates.sort_by(|a, b| b.cmp(a));
-
-    for date in dates {
-        if let Some(kills) = groups_map.remove(&date) {
-            daily_groups.push(DailyGroup {
-                date_display: date,
-                kills,
-            });
+/// Adds "possible alt of" suggestions mined from saved operations.
+pub async fn add_history_suggestions(
+    state: &AppState,
+    result: &mut ResultView,
+    mapping: &std::collections::HashMap<String, String>,
+) {
+    let present = result
+        .beneficiaries
+        .iter()
+        .map(|b| b.name.to_string())
+        .collect();
+    let store = state.store.lock().await;
+    let suggestions = alts::suggest_from_history(
+        &store.data.operations,
+        &store.name_book(),
+        mapping,
+        &present,
+    );
+    for s in suggestions {
+        let duplicate = result
+            .alt_suggestions
+            .iter()
+            .any(|existing| existing.alt == s.alt && existing.main == s.main);
+        if !duplicate {
+            result.alt_suggestions.push(s);
         }
     }
-
-    let template = IndexTemplate {
-        daily_groups,
-        mapping_text: params.mapping_input,
-        zkill_link: params.zkill_link,
-        start_date: params.start_date,
-        end_date: params.end_date,
-        total_payout_str: format_isk(total_dropped_value),
-        total_humans: active_humans,
-        beneficiaries,
-        error_msg,
-    };
-
-    Html(template.render().unwrap())
 }
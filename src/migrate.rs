@@ -0,0 +1,80 @@
+use crate::config::Config;
+use crate::storage::{self, Storage};
+use crate::store::{Backup, Store, StoreData};
+
+use std::path::Path;
+use std::sync::Arc;
+use tracing::info;
+
+/// `eve-looter migrate [--import <file>] [--replace]`: brings the configured
+/// storage up to what this build expects, then exits.
+///
+/// Opening a backend creates or upgrades its tables, and loading the store
+/// fills in fields older versions didn't write, so writing it back leaves
+/// it in the current format. With `--import`, a backup archive or the JSON data
+/// file of an older or in-memory instance takes its place instead; a store
+/// that already holds operations is only overwritten with `--replace`.
+pub async fn run(config: &Config) -> Result<(), String> {
+    let storage: Arc<dyn Storage> = storage::from_config(config).await?.into();
+    if storage.name() == "in-memory" {
+        return Err(
+            "Nothing to migrate into: set EVE_LOOTER_STORAGE or EVE_LOOTER_DATA_FILE".to_string(),
+        );
+    }
+    let mut store = Store::open(storage.clone()).await?;
+
+    if let Some(path) = import_path() {
+        let imported = read_dump(Path::new(&path))?;
+        let existing = store.data.operations.len();
+        if existing > 0 && !std::env::args().any(|a| a == "--replace") {
+            return Err(format!(
+                "{} storage already holds {} operations; pass --replace to overwrite them",
+                storage.name(),
+                existing
+            ));
+        }
+        info!(
+            "Importing {} operations from {}",
+            imported.operations.len(),
+            path
+        );
+        store.replace(imported).await?;
+    }
+    store.rewrite().await.map_err(|e| e.to_string())?;
+    info!(
+        "Migrated {} storage: {} operations",
+        storage.name(),
+        store.data.operations.len()
+    );
+    Ok(())
+}
+
+fn import_path() -> Option<String> {
+    let mut args = std::env::args().skip(2);
+    while let Some(a) = args.next() {
+        if a == "--import" {
+            return args.next();
+        }
+        if let Some(path) = a.strip_prefix("--import=") {
+            return Some(path.to_string());
+        }
+    }
+    None
+}
+
+/// A backup archive (`/admin/backup`) or a bare data store, which is what
+/// `EVE_LOOTER_DATA_FILE` holds.
+fn read_dump(path: &Path) -> Result<StoreData, String> {
+    let raw = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let value: serde_json::Value =
+        serde_json::from_str(&raw).map_err(|e| format!("{} is not JSON: {}", path.display(), e))?;
+    if value.get("format").is_some() {
+        let backup: Backup = serde_json::from_value(value).map_err(|e| e.to_string())?;
+        backup.check()?;
+        Ok(backup.data)
+    } else {
+        serde_json::from_value(value)
+            .map_err(|e| format!("{} is not an EVE Looter data file: {}", path.display(), e))
+    }
+}
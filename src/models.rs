@@ -1,6 +1,148 @@
+use crate::cache::{KillmailCache, ResultCache};
+use crate::config::Config;
+use crate::diagnostics::Diagnostics;
+use crate::graphql::{self, LooterSchema};
+use crate::logic::{Fetched, PartialFetch, SharedFetch};
+use crate::notify::{self, Notifier};
+use crate::prefetch::PrefetchStatus;
+use crate::ratelimit::{self, ClientThrottle, RateLimiter};
+use crate::routing::{self, SystemGraph};
+use crate::storage::Storage;
+use crate::store::{self, SharedStore, Store};
+use crate::views::KillSource;
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::{Deref, Neg};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::error;
+
+/// ESI average market price by type ID.
+pub type MarketPrices = Arc<HashMap<TypeId, f64>>;
+
+/// Persisted ESI names older than this are resolved again on startup, so
+/// character renames eventually show up.
+const NAME_TTL_DAYS: i64 = 7;
+
+/// Longest name we keep; EVE caps character names at 37 and corporation
+/// names at 50 characters, so anything longer is not a real name.
+const MAX_NAME_LEN: usize = 64;
+
+/// A name from outside (ESI, zKillboard, SSO, the mapping textarea).
+///
+/// Construction strips control and bidi/zero-width characters, collapses
+/// whitespace and caps the length, so a crafted name can't break out of
+/// layout or spoof its text direction. Output stays plain text: templates
+/// escape it through askama's auto-escaping and nothing marks it `|safe`.
+///
+/// Names are interned: the thousands of kills naming the same pilot,
+/// corporation, ship or system share one copy, and cloning a kill copies
+/// pointers rather than strings.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub struct SafeName(Arc<str>);
+
+/// Every name in use (see SafeName). Sharded, so resolving names for one
+/// fetch doesn't serialize every other request that builds a kill.
+static NAMES: Lazy<DashMap<Arc<str>, ()>> = Lazy::new(DashMap::new);
+
+fn intern(name: String) -> Arc<str> {
+    if let Some(interned) = NAMES.get(name.as_str()) {
+        return interned.key().clone();
+    }
+    NAMES.entry(name.into()).or_insert(()).key().clone()
+}
+
+pub fn interned_names() -> usize {
+    NAMES.len()
+}
+
+/// Forgets interned names nothing uses any more, returning how many (see
+/// sweep).
+pub fn forget_unused_names() -> usize {
+    let before = NAMES.len();
+    NAMES.retain(|name, _| Arc::strong_count(name) > 1);
+    before.saturating_sub(NAMES.len())
+}
+
+impl SafeName {
+    pub fn new(raw: &str) -> Self {
+        let cleaned = raw
+            .chars()
+            .filter(|c| !c.is_control() && !is_invisible(*c))
+            .collect::<String>()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        SafeName(intern(cleaned.chars().take(MAX_NAME_LEN).collect()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Zero-width and text-direction characters that render as nothing but
+/// change how the surrounding text is displayed.
+fn is_invisible(c: char) -> bool {
+    matches!(c, '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{2069}' | '\u{FEFF}')
+}
+
+impl From<String> for SafeName {
+    fn from(raw: String) -> Self {
+        SafeName::new(&raw)
+    }
+}
+
+impl From<&str> for SafeName {
+    fn from(raw: &str) -> Self {
+        SafeName::new(raw)
+    }
+}
+
+impl From<SafeName> for String {
+    fn from(name: SafeName) -> Self {
+        name.0.to_string()
+    }
+}
+
+impl Deref for SafeName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for SafeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Parses ISK amounts as typed by people: "1500000", "-50m", "1.2b", "250k".
+pub fn parse_isk(input: &str) -> Option<Isk> {
+    let input = input.trim().replace([',', ' '], "").to_lowercase();
+    let (number, multiplier) = match input.chars().last()? {
+        'k' => (&input[..input.len() - 1], 1_000),
+        'm' => (&input[..input.len() - 1], 1_000_000),
+        'b' => (&input[..input.len() - 1], 1_000_000_000),
+        't' => (&input[..input.len() - 1], 1_000_000_000_000i64),
+        _ => (input.as_str(), 1),
+    };
+    number
+        .parse::<Decimal>()
+        .ok()
+        .and_then(|n| n.checked_mul(Decimal::from(multiplier)))
+        .map(Isk)
+}
 
 pub fn format_isk(amount: f64) -> String {
     let abs_amount = amount.abs();
@@ -17,109 +159,841 @@ pub fn format_isk(amount: f64) -> String {
     }
 }
 
+/// An amount of ISK, kept as a decimal so sums and splits are exact to the
+/// cent however large the payout. Serializes as a JSON number, so stored
+/// data and the API keep the shape they had with an `f64`; a reader that
+/// parses numbers as doubles gets every amount exact to the cent below
+/// about 90 trillion ISK.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Isk(pub Decimal);
+
+/// Arithmetic on ISK the accounting can't do: a result past what a decimal
+/// holds, a division by zero, or a factor (or float amount) that is NaN or
+/// infinite. Payouts fail with it rather than count the amount as zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IskOutOfRange;
+
+impl fmt::Display for IskOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("an ISK amount is out of range")
+    }
+}
+
+impl std::error::Error for IskOutOfRange {}
+
+impl From<IskOutOfRange> for String {
+    fn from(e: IskOutOfRange) -> String {
+        e.to_string()
+    }
+}
+
+impl Isk {
+    pub const ZERO: Isk = Isk(Decimal::ZERO);
+    pub const ONE: Isk = Isk(Decimal::ONE);
+
+    /// An error for NaN and infinities, which only a broken price feed
+    /// would produce.
+    pub fn from_f64(amount: f64) -> Result<Isk, IskOutOfRange> {
+        Decimal::from_f64(amount).map(Isk).ok_or(IskOutOfRange)
+    }
+
+    /// For display and ratios only; the accounting stays in decimal.
+    pub fn to_f64(self) -> f64 {
+        self.0.to_f64().unwrap_or_default()
+    }
+
+    pub fn abs(self) -> Isk {
+        Isk(self.0.abs())
+    }
+
+    pub fn checked_add(self, other: Isk) -> Result<Isk, IskOutOfRange> {
+        self.0.checked_add(other.0).map(Isk).ok_or(IskOutOfRange)
+    }
+
+    pub fn checked_sub(self, other: Isk) -> Result<Isk, IskOutOfRange> {
+        self.0.checked_sub(other.0).map(Isk).ok_or(IskOutOfRange)
+    }
+
+    pub fn checked_mul(self, factor: Decimal) -> Result<Isk, IskOutOfRange> {
+        self.0.checked_mul(factor).map(Isk).ok_or(IskOutOfRange)
+    }
+
+    pub fn checked_div(self, divisor: Decimal) -> Result<Isk, IskOutOfRange> {
+        self.0.checked_div(divisor).map(Isk).ok_or(IskOutOfRange)
+    }
+
+    /// `percent` per cent of this amount.
+    pub fn percent(self, percent: Decimal) -> Result<Isk, IskOutOfRange> {
+        self.checked_mul(percent)?.checked_div(Decimal::ONE_HUNDRED)
+    }
+
+    /// This amount times `numerator / denominator`, without going through a
+    /// ratio that would have to be rounded first.
+    pub fn scale(self, numerator: Isk, denominator: Isk) -> Result<Isk, IskOutOfRange> {
+        self.checked_mul(numerator.0)?.checked_div(denominator.0)
+    }
+
+    /// How many times `other` this amount is, for display; none when
+    /// `other` is zero.
+    pub fn ratio(self, other: Isk) -> Option<f64> {
+        self.0.checked_div(other.0).and_then(|ratio| ratio.to_f64())
+    }
+
+    /// Rounds toward zero to a whole multiple of `unit` ISK.
+    pub fn trunc_to(self, unit: Decimal) -> Result<Isk, IskOutOfRange> {
+        let units = self.checked_div(unit)?.0.trunc();
+        Isk(units).checked_mul(unit)
+    }
+
+    /// Rounded half away from zero to the cent, as exports show it.
+    pub fn cents(self) -> Isk {
+        Isk(self.0.round_dp(2))
+    }
+
+    /// The sum of `amounts`.
+    pub fn total(amounts: impl IntoIterator<Item = Isk>) -> Result<Isk, IskOutOfRange> {
+        amounts
+            .into_iter()
+            .try_fold(Isk::ZERO, |total, amount| total.checked_add(amount))
+    }
+
+    /// Adds `amount` to this one in place.
+    pub fn increase(&mut self, amount: Isk) -> Result<(), IskOutOfRange> {
+        *self = self.checked_add(amount)?;
+        Ok(())
+    }
+
+    /// Takes `amount` off this one in place.
+    pub fn decrease(&mut self, amount: Isk) -> Result<(), IskOutOfRange> {
+        *self = self.checked_sub(amount)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Isk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&format_isk(self.to_f64()))
+    }
+}
+
+impl Neg for Isk {
+    type Output = Isk;
+
+    fn neg(self) -> Isk {
+        Isk(-self.0)
+    }
+}
+
+/// A killmail's ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct KillmailId(#[serde(deserialize_with = "lenient_id")] pub i64);
+
+/// A character's ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct CharacterId(#[serde(deserialize_with = "lenient_id")] pub i64);
+
+/// A corporation's ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct CorporationId(#[serde(deserialize_with = "lenient_id")] pub i64);
+
+/// An alliance's ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AllianceId(#[serde(deserialize_with = "lenient_id")] pub i64);
+
+/// An item type's ID: ships, modules, charges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TypeId(#[serde(deserialize_with = "lenient_id")] pub i64);
+
+/// A solar system's ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SystemId(#[serde(deserialize_with = "lenient_id")] pub i64);
+
+/// Where in a system a kill happened: a celestial, or a structure, whose
+/// IDs don't fit an `i32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct LocationId(#[serde(deserialize_with = "lenient_id")] pub i64);
+
+/// How an ID may come in: a JSON integer, a whole float from encoders that
+/// write every number as one, or a string from clients that can't hold a
+/// 64-bit integer exactly.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawId {
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+fn lenient_id<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+    use serde::de::Error;
+    match RawId::deserialize(deserializer)? {
+        RawId::Int(id) => Ok(id),
+        RawId::Float(id) if id.fract() == 0.0 && id.abs() < 2f64.powi(53) => Ok(id as i64),
+        RawId::Float(id) => Err(D::Error::custom(format!("invalid ID {}", id))),
+        RawId::Text(id) => id
+            .trim()
+            .parse()
+            .map_err(|_| D::Error::custom(format!("invalid ID {:?}", id))),
+    }
+}
+
+/// The ID of anything ESI's names endpoint resolves: a character,
+/// corporation, alliance, system or item type. The kinds share one ID space,
+/// so names are cached under it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct EveId(#[serde(deserialize_with = "lenient_id")] pub i64);
+
+impl From<CharacterId> for EveId {
+    fn from(id: CharacterId) -> Self {
+        EveId(id.0)
+    }
+}
+
+impl From<CorporationId> for EveId {
+    fn from(id: CorporationId) -> Self {
+        EveId(id.0)
+    }
+}
+
+impl From<AllianceId> for EveId {
+    fn from(id: AllianceId) -> Self {
+        EveId(id.0)
+    }
+}
+
+impl From<TypeId> for EveId {
+    fn from(id: TypeId) -> Self {
+        EveId(id.0)
+    }
+}
+
+impl From<SystemId> for EveId {
+    fn from(id: SystemId) -> Self {
+        EveId(id.0)
+    }
+}
+
+impl fmt::Display for KillmailId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for CharacterId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for CorporationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for AllianceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for TypeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for SystemId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for LocationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for EveId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 pub struct AppState {
+    pub config: Config,
     pub current_kills: Mutex<Vec<Killmail>>,
+    /// When the calculator was last used; its kills are let go of once it
+    /// has been idle for a while (see sweep).
+    pub calculator_used: Mutex<DateTime<Utc>>,
     pub character_map: Mutex<HashMap<String, String>>,
-    pub esi_cache: Mutex<HashMap<i32, EsiKillmail>>,
-    pub name_cache: Mutex<HashMap<i32, String>>,
+    pub esi_cache: Mutex<KillmailCache>,
+    pub name_cache: Mutex<HashMap<EveId, SafeName>>,
+    pub affiliation_cache: Mutex<HashMap<CharacterId, (EsiAffiliation, DateTime<Utc>)>>,
+    pub store: SharedStore,
+    /// Backs the store, sessions and, across instances, the calculator's kills.
+    pub storage: Arc<dyn Storage>,
+    pub result_cache: Mutex<ResultCache>,
+    /// Fetches aborted by rate limiting, by zKillboard link.
+    pub partial_fetches: Mutex<HashMap<String, PartialFetch>>,
+    /// Running fetches by submit token (see logic::fetch_once).
+    pub inflight_fetches: Mutex<HashMap<String, SharedFetch>>,
+    /// What `current_kills` was fetched for.
+    pub current_source: Mutex<Option<KillSource>>,
+    /// A fetch waiting for the user to confirm it may replace `current_kills`.
+    pub pending_replace: Mutex<Option<(KillSource, Fetched)>>,
+    /// ESI average prices by type ID and when they were fetched.
+    pub market_prices: Mutex<Option<(Instant, MarketPrices)>>,
+    /// Prices on the workspace's structure market (see
+    /// `PricingBackend::StructureMarket`) and when they were fetched.
+    pub structure_prices: Mutex<Option<(Instant, MarketPrices)>>,
+    /// Outcome of the last warming run per watched link.
+    pub prefetch_status: Mutex<HashMap<String, PrefetchStatus>>,
+    /// Upstream request budgets, shared by every request and background job.
+    pub zkill_limiter: RateLimiter,
+    pub esi_limiter: RateLimiter,
+    /// Fetches started per client (`EVE_LOOTER_CLIENT_FETCHES_PER_MINUTE`).
+    pub client_fetches: ClientThrottle,
+    /// Running jobs and recent upstream errors (see `/admin`).
+    pub diagnostics: Diagnostics,
+    pub notifiers: Vec<Box<dyn Notifier>>,
+    pub graphql: LooterSchema,
+    /// Stargates from the SDE, when configured (see routing).
+    pub system_graph: Option<SystemGraph>,
 }
 
 impl AppState {
-    pub fn new() -> Self {
-        Self {
+    pub async fn new(config: Config, storage: Arc<dyn Storage>) -> Result<Self, String> {
+        let store = Store::open(storage.clone()).await?;
+        let names = store::fresh_names(storage.as_ref(), Duration::days(NAME_TTL_DAYS)).await;
+        Ok(Self {
             current_kills: Mutex::new(Vec::new()),
+            calculator_used: Mutex::new(Utc::now()),
             character_map: Mutex::new(HashMap::new()),
-            esi_cache: Mutex::new(HashMap::new()),
-            name_cache: Mutex::new(HashMap::new()),
+            esi_cache: Mutex::new(KillmailCache::default()),
+            name_cache: Mutex::new(names),
+            affiliation_cache: Mutex::new(HashMap::new()),
+            store: SharedStore::new(store),
+            storage,
+            result_cache: Mutex::new(ResultCache::default()),
+            partial_fetches: Mutex::new(HashMap::new()),
+            inflight_fetches: Mutex::new(HashMap::new()),
+            current_source: Mutex::new(None),
+            pending_replace: Mutex::new(None),
+            market_prices: Mutex::new(None),
+            structure_prices: Mutex::new(None),
+            prefetch_status: Mutex::new(HashMap::new()),
+            zkill_limiter: RateLimiter::new("zKillboard", ratelimit::ZKILL_PER_SECOND, 1),
+            esi_limiter: RateLimiter::new("ESI", ratelimit::ESI_PER_SECOND, ratelimit::ESI_BURST),
+            client_fetches: ClientThrottle::new(config.client_fetches_per_minute),
+            diagnostics: Diagnostics::new(),
+            notifiers: notify::from_config(&config),
+            graphql: graphql::schema(),
+            system_graph: routing::load_graph(config.sde_jumps.as_deref()),
+            config,
+        })
+    }
+
+    /// Picks up the calculator's kills if another instance replaced them.
+    pub async fn pull_calculator(&self) {
+        if !self.storage.is_shared() {
+            return;
+        }
+        let calculator = match self.storage.get(CALCULATOR, "current").await {
+            Ok(Some(raw)) => {
+                serde_json::from_str::<CalculatorState>(&raw).map_err(|e| e.to_string())
+            }
+            Ok(None) => return,
+            Err(e) => Err(e),
+        };
+        match calculator {
+            Ok(calculator) => {
+                *self.current_kills.lock().unwrap() = calculator.kills;
+                *self.current_source.lock().unwrap() = calculator.source;
+            }
+            Err(e) => error!("Failed to load the calculator's kills: {}", e),
+        }
+    }
+
+    /// Shares the calculator's kills with the other instances.
+    pub async fn push_calculator(&self) {
+        if !self.storage.is_shared() {
+            return;
+        }
+        let calculator = CalculatorState {
+            kills: self.current_kills.lock().unwrap().clone(),
+            source: self.current_source.lock().unwrap().clone(),
+        };
+        let raw = match serde_json::to_string(&calculator) {
+            Ok(raw) => raw,
+            Err(e) => return error!("Failed to save the calculator's kills: {}", e),
+        };
+        if let Err(e) = self.storage.put(CALCULATOR, "current", &raw).await {
+            error!("Failed to save the calculator's kills: {}", e);
         }
     }
 }
 
+const CALCULATOR: &str = "calculator";
+
+#[derive(Serialize, Deserialize)]
+struct CalculatorState {
+    kills: Vec<Killmail>,
+    source: Option<KillSource>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Killmail {
-    pub killmail_id: i32,
+    pub killmail_id: KillmailId,
     pub zkb: ZkbStats,
     pub victim: Option<Victim>,
     pub attackers: Vec<Attacker>,
-    pub killmail_time: String,
+    #[serde(
+        serialize_with = "killmail_time::serialize",
+        deserialize_with = "killmail_time::deserialize_stored"
+    )]
+    pub killmail_time: DateTime<Utc>,
     pub formatted_dropped: String,
     // NEW: System info
-    pub solar_system_id: i32,
-    pub solar_system_name: Option<String>,
+    pub solar_system_id: SystemId,
+    pub solar_system_name: Option<SafeName>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub solar_system_security: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region_id: Option<i64>,
     #[serde(default = "default_true")]
     pub is_active: bool,
 }
 
-fn default_true() -> bool {
+/// ESI group ID of capsules.
+pub const CAPSULE_GROUP_ID: i64 = 29;
+
+/// Region ID of Pochven, the Triglavian space.
+pub const POCHVEN_REGION_ID: i64 = 10000070;
+
+impl Killmail {
+    /// "high", "low", "null" or "wormhole" (J-space), following the game's
+    /// rounding: anything above 0.0 is at least 0.1 (lowsec), 0.45 and up is
+    /// 0.5 (highsec). "unknown" when the security status isn't resolved.
+    pub fn security_class(&self) -> &'static str {
+        if (31_000_000..32_000_000).contains(&self.solar_system_id.0) {
+            return "wormhole";
+        }
+        match self.solar_system_security {
+            Some(sec) if sec >= 0.45 => "high",
+            Some(sec) if sec > 0.0 => "low",
+            Some(_) => "null",
+            None => "unknown",
+        }
+    }
+
+    /// Whether the kill was in Pochven; unknown regions (kills stored
+    /// before regions were resolved) count as elsewhere.
+    pub fn in_pochven(&self) -> bool {
+        self.region_id == Some(POCHVEN_REGION_ID)
+    }
+
+    /// Kill time in the workspace timezone.
+    pub fn local_time(&self, tz: Tz) -> DateTime<Tz> {
+        self.killmail_time.with_timezone(&tz)
+    }
+
+    /// Day of the kill in the workspace timezone, as used for grouping.
+    pub fn local_date(&self, tz: Tz) -> String {
+        self.local_time(tz).format("%Y-%m-%d").to_string()
+    }
+
+    pub fn local_time_display(&self, tz: &Tz) -> String {
+        self.local_time(*tz).format("%H:%M:%S").to_string()
+    }
+
+    /// ESI group of the victim's ship, once resolved.
+    pub fn victim_ship_group(&self) -> Option<i64> {
+        self.victim.as_ref()?.ship_group_id
+    }
+
+    /// Whether the victim was in a pod, whose value is mostly implants
+    /// that are destroyed rather than dropped.
+    pub fn is_pod(&self) -> bool {
+        self.victim_ship_group() == Some(CAPSULE_GROUP_ID)
+    }
+
+    /// Security status as shown in game, e.g. "0.4".
+    pub fn security_display(&self) -> String {
+        match self.solar_system_security {
+            Some(sec) if sec > 0.0 && sec < 0.05 => "0.1".to_string(),
+            Some(sec) => format!("{:.1}", sec),
+            None => String::new(),
+        }
+    }
+}
+
+pub fn default_true() -> bool {
     true
 }
 
+/// Killmail times as ESI writes them ("2026-10-10T12:00:00Z"), also reading
+/// the in-game "2026.10.10 12:00:00" form. Anything else fails the kill
+/// where it comes in from ESI, with the time in the error, instead of
+/// leaving it out of every date window later on.
+pub mod killmail_time {
+    use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::borrow::Cow;
+    use tracing::warn;
+
+    pub fn serialize<S: Serializer>(
+        time: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format(time))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        let raw = Cow::<str>::deserialize(deserializer)?;
+        parse(&raw).ok_or_else(|| D::Error::custom(format!("malformed killmail time {:?}", raw)))
+    }
+
+    /// For saved kills, where failing would lose the whole store: a
+    /// malformed time is logged and the kill kept at the Unix epoch, outside
+    /// any operation's dates.
+    pub fn deserialize_stored<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        let raw = Cow::<str>::deserialize(deserializer)?;
+        Ok(parse(&raw).unwrap_or_else(|| {
+            warn!(
+                "Saved kill has a malformed time {:?}; dating it 1970-01-01",
+                raw
+            );
+            DateTime::UNIX_EPOCH
+        }))
+    }
+
+    pub fn parse(raw: &str) -> Option<DateTime<Utc>> {
+        let raw = raw.trim();
+        DateTime::parse_from_rfc3339(raw)
+            .map(|t| t.with_timezone(&Utc))
+            .or_else(|_| {
+                NaiveDateTime::parse_from_str(raw, "%Y.%m.%d %H:%M:%S").map(|t| t.and_utc())
+            })
+            .ok()
+    }
+
+    pub fn format(time: &DateTime<Utc>) -> String {
+        time.to_rfc3339_opts(SecondsFormat::Secs, true)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ZkbStats {
     #[serde(rename = "locationID")]
-    pub location_id: i32,
+    pub location_id: LocationId,
     pub hash: String,
     #[serde(rename = "fittedValue")]
-    pub fitted_value: f64,
+    pub fitted_value: Isk,
     #[serde(rename = "droppedValue")]
-    pub dropped_value: f64,
+    pub dropped_value: Isk,
     #[serde(rename = "destroyedValue")]
-    pub destroyed_value: f64,
+    pub destroyed_value: Isk,
     #[serde(rename = "totalValue")]
-    pub total_value: f64,
+    pub total_value: Isk,
+    #[serde(default)]
+    pub points: i32,
+    #[serde(default)]
+    pub solo: bool,
+    /// Killed by NPCs only.
+    #[serde(default)]
+    pub npc: bool,
+    /// Killed by its own corp- or alliance-mates.
+    #[serde(default)]
+    pub awox: bool,
+    /// zKillboard's tags, e.g. "pvp", "ganked", "loc:highsec".
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Victim {
-    pub character_id: Option<i32>,
-    pub character_name: Option<String>,
-    pub corporation_name: Option<String>,
+    pub character_id: Option<CharacterId>,
+    pub character_name: Option<SafeName>,
+    pub corporation_name: Option<SafeName>,
     // NEW: Ship info
-    pub ship_type_id: i32,
-    pub ship_type_name: Option<String>,
+    pub ship_type_id: TypeId,
+    pub ship_type_name: Option<SafeName>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ship_group_id: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub corporation_ticker: Option<SafeName>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alliance_ticker: Option<SafeName>,
+}
+
+/// Shown for IDs ESI's names endpoint rejects: biomassed characters and
+/// closed corporations. Resolution runs for every ID on a kill before it is
+/// built, so an ID without a name is one ESI could not resolve.
+pub const DELETED_CHARACTER: &str = "[deleted character]";
+pub const DELETED_CORPORATION: &str = "[deleted corporation]";
+
+impl Victim {
+    pub fn display_name(&self) -> &str {
+        match (&self.character_name, self.character_id) {
+            (Some(name), _) => name,
+            (None, Some(_)) => DELETED_CHARACTER,
+            (None, None) => "Unknown",
+        }
+    }
+
+    pub fn display_corporation(&self) -> &str {
+        match &self.corporation_name {
+            Some(name) => name,
+            None => DELETED_CORPORATION,
+        }
+    }
+
+    pub fn tickers(&self) -> String {
+        ticker_label(&self.corporation_ticker, &self.alliance_ticker)
+    }
+}
+
+/// "[CORP] <ALLY>" the way the game client shows it; empty when unknown.
+pub fn ticker_label(corporation: &Option<SafeName>, alliance: &Option<SafeName>) -> String {
+    let mut label = String::new();
+    if let Some(corp) = corporation {
+        label.push_str(&format!("[{}]", corp));
+    }
+    if let Some(ally) = alliance {
+        if !label.is_empty() {
+            label.push(' ');
+        }
+        label.push_str(&format!("<{}>", ally));
+    }
+    label
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Attacker {
-    pub character_id: Option<i32>,
-    pub character_name: Option<String>,
-    pub corporation_id: Option<i32>,
+    pub character_id: Option<CharacterId>,
+    pub character_name: Option<SafeName>,
+    pub corporation_id: Option<CorporationId>,
     // NEW: Killer flag
     pub final_blow: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub corporation_ticker: Option<SafeName>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alliance_ticker: Option<SafeName>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ship_type_id: Option<TypeId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ship_type_name: Option<SafeName>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weapon_type_id: Option<TypeId>,
+    /// Zero on kills saved before it was kept.
+    #[serde(default)]
+    pub damage_done: i64,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl Attacker {
+    pub fn display_name(&self) -> &str {
+        match (&self.character_name, self.character_id) {
+            (Some(name), _) => name,
+            (None, Some(_)) => DELETED_CHARACTER,
+            (None, None) => "Unknown",
+        }
+    }
+
+    pub fn tickers(&self) -> String {
+        ticker_label(&self.corporation_ticker, &self.alliance_ticker)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RawZKillItem {
-    pub killmail_id: i32,
+    pub killmail_id: KillmailId,
     pub zkb: ZkbStats,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EsiKillmail {
-    pub killmail_time: String,
-    pub solar_system_id: i32, // NEW
+    #[serde(with = "killmail_time")]
+    pub killmail_time: DateTime<Utc>,
+    pub solar_system_id: SystemId, // NEW
     pub victim: EsiVictim,
     pub attackers: Vec<EsiAttacker>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl EsiKillmail {
+    /// IDs on the kill that ESI's names endpoint resolves: characters,
+    /// corporations, the system, ships, weapons and items.
+    pub fn named_ids(&self) -> HashSet<EveId> {
+        let mut ids = HashSet::new();
+        ids.insert(self.solar_system_id.into());
+        ids.insert(self.victim.ship_type_id.into());
+        ids.extend(self.victim.character_id.map(EveId::from));
+        ids.extend(self.victim.corporation_id.map(EveId::from));
+        for att in &self.attackers {
+            ids.extend(att.character_id.map(EveId::from));
+            ids.extend(att.corporation_id.map(EveId::from));
+            ids.extend(att.ship_type_id.map(EveId::from));
+            ids.extend(att.weapon_type_id.map(EveId::from));
+        }
+        let mut items = Vec::new();
+        for item in &self.victim.items {
+            item.flatten(&mut items);
+        }
+        ids.extend(
+            items
+                .into_iter()
+                .map(|(type_id, _, _)| EveId::from(type_id)),
+        );
+        ids
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EsiVictim {
-    pub character_id: Option<i32>,
-    pub corporation_id: Option<i32>,
-    pub ship_type_id: i32, // NEW
+    pub character_id: Option<CharacterId>,
+    pub corporation_id: Option<CorporationId>,
+    pub alliance_id: Option<AllianceId>,
+    pub ship_type_id: TypeId, // NEW
+    #[serde(default)]
+    pub damage_taken: i64,
+    #[serde(default)]
+    pub items: Vec<EsiItem>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// A fitted or cargo item on the victim's ship; containers nest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EsiItem {
+    pub item_type_id: TypeId,
+    /// Where on the ship it was: a slot, the drone bay, cargo...
+    #[serde(default)]
+    pub flag: i32,
+    #[serde(default)]
+    pub quantity_dropped: Option<i64>,
+    #[serde(default)]
+    pub quantity_destroyed: Option<i64>,
+    #[serde(default)]
+    pub items: Vec<EsiItem>,
+}
+
+impl EsiItem {
+    /// (type ID, quantity) of everything that dropped, containers included.
+    pub fn dropped(&self, out: &mut Vec<(TypeId, i64)>) {
+        if let Some(qty) = self.quantity_dropped.filter(|q| *q > 0) {
+            out.push((self.item_type_id, qty));
+        }
+        for item in &self.items {
+            item.dropped(out);
+        }
+    }
+
+    /// (type ID, dropped, destroyed) of this item and what it contains.
+    pub fn flatten(&self, out: &mut Vec<(TypeId, i64, i64)>) {
+        out.push((
+            self.item_type_id,
+            self.quantity_dropped.unwrap_or(0),
+            self.quantity_destroyed.unwrap_or(0),
+        ));
+        for item in &self.items {
+            item.flatten(out);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EsiAttacker {
-    pub character_id: Option<i32>,
-    pub corporation_id: Option<i32>,
+    pub character_id: Option<CharacterId>,
+    pub corporation_id: Option<CorporationId>,
+    pub alliance_id: Option<AllianceId>,
     pub final_blow: bool, // NEW
+    // Only the kill detail pane uses these; killmails cached before it have none
+    #[serde(default)]
+    pub ship_type_id: Option<TypeId>,
+    #[serde(default)]
+    pub weapon_type_id: Option<TypeId>,
+    #[serde(default)]
+    pub damage_done: i64,
+}
+
+/// The part of ESI's solar system record we use.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EsiSystemEntry {
+    pub security_status: f64,
+    pub constellation_id: i64,
+}
+
+/// The part of ESI's constellation record we use.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EsiConstellationEntry {
+    pub region_id: i64,
+}
+
+/// The part of ESI's type record we use.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EsiTypeEntry {
+    pub group_id: i64,
+}
+
+/// The part of a structure market order we price with.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EsiMarketOrder {
+    pub type_id: TypeId,
+    pub price: f64,
+    pub is_buy_order: bool,
+}
+
+/// One entry of ESI's market price list.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EsiMarketPrice {
+    pub type_id: TypeId,
+    #[serde(default)]
+    pub average_price: Option<f64>,
+}
+
+/// The part of ESI's corporation and alliance records we use.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EsiTickerEntry {
+    pub ticker: SafeName,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct EsiNameEntry {
-    pub id: i32,
-    pub name: String,
+    pub id: EveId,
+    pub name: SafeName,
     #[allow(dead_code)]
     pub category: String,
 }
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EsiAffiliation {
+    pub character_id: CharacterId,
+    pub corporation_id: CorporationId,
+    pub alliance_id: Option<AllianceId>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EsiIdEntry {
+    pub id: CharacterId,
+    pub name: SafeName,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EsiIdsResponse {
+    pub characters: Option<Vec<EsiIdEntry>>,
+}
@@ -0,0 +1,168 @@
+use crate::models::{Attacker, CharacterId, Killmail, Victim};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{BuildHasher, BuildHasherDefault};
+
+/// A name a character was observed under, and when we last saw it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NameRecord {
+    pub name: String,
+    pub seen_at: DateTime<Utc>,
+}
+
+/// Every name each character ID has been seen with. Pilots rename; the ID is
+/// the only stable identity, so accounting resolves names through this table.
+pub type NameHistory = BTreeMap<CharacterId, Vec<NameRecord>>;
+
+/// Records `name` for `id`, returning whether the history changed.
+pub fn record(
+    history: &mut NameHistory,
+    id: CharacterId,
+    name: &str,
+    seen_at: DateTime<Utc>,
+) -> bool {
+    let records = history.entry(id).or_default();
+    match records.iter_mut().find(|r| r.name == name) {
+        Some(r) if r.seen_at >= seen_at => false,
+        Some(r) => {
+            r.seen_at = seen_at;
+            true
+        }
+        None => {
+            records.push(NameRecord {
+                name: name.to_string(),
+                seen_at,
+            });
+            true
+        }
+    }
+}
+
+/// Read-side index over the name history.
+#[derive(Default)]
+pub struct NameBook {
+    current: HashMap<CharacterId, String>,
+    former: HashMap<CharacterId, Vec<String>>,
+    ids: HashMap<String, CharacterId>,
+    /// Changes with any name (see fingerprint).
+    fingerprint: u64,
+}
+
+impl NameBook {
+    pub fn from_history(history: &NameHistory) -> Self {
+        let mut book = NameBook::default();
+        for (id, records) in history {
+            let mut sorted: Vec<&NameRecord> = records.iter().collect();
+            sorted.sort_by_key(|r| std::cmp::Reverse(r.seen_at));
+            if let Some((latest, older)) = sorted.split_first() {
+                book.current.insert(*id, latest.name.clone());
+                book.former
+                    .insert(*id, older.iter().map(|r| r.name.clone()).collect());
+            }
+            for r in records {
+                book.ids.insert(r.name.clone(), *id);
+            }
+        }
+        // Order-independent, as the maps iterate in any order
+        let hasher = BuildHasherDefault::<DefaultHasher>::default();
+        book.fingerprint = book
+            .current
+            .iter()
+            .map(|entry| hasher.hash_one(entry))
+            .chain(book.ids.iter().map(|entry| hasher.hash_one(entry)))
+            .fold(0, |acc, h| acc ^ h);
+        book
+    }
+
+    /// Differs between books resolving any name differently, so what was
+    /// resolved through one can be kept until the names change.
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    pub fn current_name(&self, id: CharacterId) -> Option<&str> {
+        self.current.get(&id).map(|n| n.as_str())
+    }
+
+    pub fn id_of(&self, name: &str) -> Option<CharacterId> {
+        self.ids.get(name).copied()
+    }
+
+    /// The current name of whoever has ever been called `name`.
+    pub fn canonical(&self, name: &str) -> String {
+        self.id_of(name)
+            .and_then(|id| self.current.get(&id))
+            .cloned()
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    pub fn former_names(&self, name: &str) -> &[String] {
+        self.id_of(name)
+            .and_then(|id| self.former.get(&id))
+            .map(|v| v.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Current name of an attacker, preferring its character ID.
+    pub fn attacker_name(&self, attacker: &Attacker) -> Option<String> {
+        attacker
+            .character_id
+            .and_then(|id| self.current.get(&id).cloned())
+            .or_else(|| {
+                attacker
+                    .character_name
+                    .as_deref()
+                    .map(|n| self.canonical(n))
+            })
+    }
+
+    /// Current name of a kill's victim, preferring its character ID.
+    pub fn victim_name(&self, victim: &Victim) -> Option<String> {
+        victim
+            .character_id
+            .and_then(|id| self.current.get(&id).cloned())
+            .or_else(|| victim.character_name.as_deref().map(|n| self.canonical(n)))
+    }
+
+    /// Rewrites an "Alt = Main" mapping in terms of current names, so a
+    /// mapping typed before (or after) a rename still matches.
+    pub fn canonical_mapping(&self, mapping: &HashMap<String, String>) -> HashMap<String, String> {
+        mapping
+            .iter()
+            .map(|(alt, main)| (self.canonical(alt), self.canonical(main)))
+            .collect()
+    }
+
+    pub fn canonical_set(&self, names: &HashSet<String>) -> HashSet<String> {
+        names.iter().map(|n| self.canonical(n)).collect()
+    }
+
+    /// Replaces the names stored on a kill with current ones for display.
+    pub fn refresh_kill(&self, kill: &mut Killmail) {
+        for attacker in &mut kill.attackers {
+            if let Some(name) = self.attacker_name(attacker) {
+                attacker.character_name = Some(name.into());
+            }
+        }
+        if let Some(victim) = &mut kill.victim {
+            if let Some(name) = victim.character_id.and_then(|id| self.current.get(&id)) {
+                victim.character_name = Some(name.as_str().into());
+            }
+        }
+    }
+}
+
+/// (character ID, name) pairs observed on a set of kills.
+pub fn observed_names(kills: &[Killmail]) -> Vec<(CharacterId, String)> {
+    let attackers = kills
+        .iter()
+        .flat_map(|k| &k.attackers)
+        .filter_map(|a| Some((a.character_id?, a.character_name.as_deref()?.to_string())));
+    let victims = kills
+        .iter()
+        .filter_map(|k| k.victim.as_ref())
+        .filter_map(|v| Some((v.character_id?, v.character_name.as_deref()?.to_string())));
+    attackers.chain(victims).collect()
+}
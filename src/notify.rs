@@ -0,0 +1,430 @@
+use crate::config::Config;
+use crate::models::{AppState, Isk};
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use lettre::message::Mailbox;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use tracing::{error, info, warn, Instrument};
+
+/// Something worth telling the outside world about. Serialized as the
+/// webhook payload, tagged with its kind.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    OperationCreated {
+        operation_id: u64,
+        operation_name: String,
+        kill_count: usize,
+    },
+    PayoutPublished {
+        operation_id: u64,
+        operation_name: String,
+        total_dropped_value: Isk,
+        /// (main, amount) sorted by name.
+        payouts: Vec<(String, Isk)>,
+        /// The trade hub nearest to where each main was last seen, e.g.
+        /// "Jita, 4 jumps", when the workspace suggests hubs.
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        nearest_hubs: BTreeMap<String, String>,
+    },
+    BeneficiaryPaid {
+        operation_id: u64,
+        operation_name: String,
+        main: String,
+        amount: Isk,
+    },
+    PayoutFinalized {
+        operation_id: u64,
+        operation_name: String,
+        finalized_by: Option<String>,
+        total_dropped_value: Isk,
+        /// The snapshot's audit hash (see Operation::finalize).
+        hash: String,
+    },
+    /// A scheduled (or manually posted) summary of the watched entities'
+    /// loot (see digest).
+    LootDigest {
+        /// "Daily loot digest" or "Weekly loot digest".
+        title: String,
+        since: DateTime<Utc>,
+        kill_count: usize,
+        total_dropped_value: Isk,
+        /// The digest as Discord markdown.
+        content: String,
+    },
+}
+
+/// Event kinds as used in payloads and webhook subscriptions.
+pub const EVENT_KINDS: [&str; 5] = [
+    "operation_created",
+    "payout_published",
+    "beneficiary_paid",
+    "loot_digest",
+    "payout_finalized",
+];
+
+impl Event {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::OperationCreated { .. } => EVENT_KINDS[0],
+            Event::PayoutPublished { .. } => EVENT_KINDS[1],
+            Event::BeneficiaryPaid { .. } => EVENT_KINDS[2],
+            Event::LootDigest { .. } => EVENT_KINDS[3],
+            Event::PayoutFinalized { .. } => EVENT_KINDS[4],
+        }
+    }
+
+    pub fn subject(&self) -> String {
+        match self {
+            Event::OperationCreated { operation_name, .. } => {
+                format!("Operation created: {}", operation_name)
+            }
+            Event::PayoutPublished { operation_name, .. } => {
+                format!("Payout published: {}", operation_name)
+            }
+            Event::BeneficiaryPaid {
+                operation_name,
+                main,
+                ..
+            } => format!("{} paid for {}", main, operation_name),
+            Event::PayoutFinalized { operation_name, .. } => {
+                format!("Payout finalized: {}", operation_name)
+            }
+            Event::LootDigest { title, .. } => title.clone(),
+        }
+    }
+
+    /// Plain-text rendering shared by the text-based backends.
+    pub fn body(&self, public_url: Option<&str>) -> String {
+        let link = |operation_id: &u64| match public_url {
+            Some(url) => format!("\n{}/ops/{}\n", url.trim_end_matches('/'), operation_id),
+            None => String::new(),
+        };
+        match self {
+            Event::OperationCreated {
+                operation_id,
+                operation_name,
+                kill_count,
+            } => format!(
+                "Operation {} was saved with {} kills.\n{}",
+                operation_name,
+                kill_count,
+                link(operation_id)
+            ),
+            Event::BeneficiaryPaid {
+                operation_id,
+                operation_name,
+                main,
+                amount,
+            } => format!(
+                "{} was paid {} ISK for {}.\n{}",
+                main,
+                amount,
+                operation_name,
+                link(operation_id)
+            ),
+            Event::PayoutPublished {
+                operation_id,
+                operation_name,
+                total_dropped_value,
+                payouts,
+                nearest_hubs,
+            } => {
+                let mut body = format!(
+                    "The payout for {} has been published.\n\nTotal dropped value: {} ISK\n\n",
+                    operation_name, total_dropped_value
+                );
+                for (main, amount) in payouts {
+                    match nearest_hubs.get(main) {
+                        Some(hub) => body.push_str(&format!(
+                            "{}: {} ISK (nearest hub: {})\n",
+                            main, amount, hub
+                        )),
+                        None => body.push_str(&format!("{}: {} ISK\n", main, amount)),
+                    }
+                }
+                body.push_str(&link(operation_id));
+                body
+            }
+            Event::PayoutFinalized {
+                operation_id,
+                operation_name,
+                finalized_by,
+                total_dropped_value,
+                hash,
+            } => format!(
+                "The payout for {} was finalized by {}.\n\nTotal dropped value: {} ISK\nAudit hash: {}\n{}",
+                operation_name,
+                finalized_by.as_deref().unwrap_or("an admin"),
+                total_dropped_value,
+                hash,
+                link(operation_id)
+            ),
+            Event::LootDigest { content, .. } => content.clone(),
+        }
+    }
+}
+
+/// A delivery backend for events.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// Whether this backend wants the event at all.
+    fn accepts(&self, event: &Event) -> bool;
+    async fn notify(&self, event: &Event) -> Result<(), String>;
+}
+
+pub struct EmailNotifier {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    public_url: Option<String>,
+}
+
+impl EmailNotifier {
+    fn from_config(config: &Config) -> Result<Option<Self>, String> {
+        let Some(url) = &config.smtp_url else {
+            return Ok(None);
+        };
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(url)
+            .map_err(|e| e.to_string())?
+            .build();
+        let from = config
+            .email_from
+            .as_deref()
+            .ok_or("EVE_LOOTER_EMAIL_FROM is not set")?
+            .parse()
+            .map_err(|e| format!("invalid sender: {}", e))?;
+        let to = config
+            .email_to
+            .iter()
+            .map(|a| {
+                a.parse()
+                    .map_err(|e| format!("invalid recipient {}: {}", a, e))
+            })
+            .collect::<Result<Vec<Mailbox>, String>>()?;
+        if to.is_empty() {
+            return Err("EVE_LOOTER_EMAIL_TO is empty".to_string());
+        }
+        Ok(Some(Self {
+            transport,
+            from,
+            to,
+            public_url: config.public_url.clone(),
+        }))
+    }
+}
+
+#[async_trait]
+impl Notifier for EmailNotifier {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    /// Directors want the publication and the scheduled digest, not every
+    /// save and payment.
+    fn accepts(&self, event: &Event) -> bool {
+        matches!(
+            event,
+            Event::PayoutPublished { .. } | Event::LootDigest { .. }
+        )
+    }
+
+    async fn notify(&self, event: &Event) -> Result<(), String> {
+        let mut builder = Message::builder()
+            .from(self.from.clone())
+            .subject(event.subject());
+        for to in &self.to {
+            builder = builder.to(to.clone());
+        }
+        let message = builder
+            .body(event.body(self.public_url.as_deref()))
+            .map_err(|e| e.to_string())?;
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Notifiers enabled by the configuration. Misconfigured backends are
+/// logged and skipped rather than stopping the server.
+pub fn from_config(config: &Config) -> Vec<Box<dyn Notifier>> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+    match EmailNotifier::from_config(config) {
+        Ok(Some(email)) => {
+            info!(
+                "Email notifications enabled for {} recipients",
+                email.to.len()
+            );
+            notifiers.push(Box::new(email));
+        }
+        Err(e) => warn!("Email notifications disabled: {}", e),
+        Ok(None) => {}
+    }
+    notifiers
+}
+
+/// An outbound webhook registered by an admin.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: u64,
+    pub url: String,
+    /// HMAC-SHA256 key; the hex digest of the body is sent as
+    /// `X-EveLooter-Signature: sha256=<digest>`.
+    pub secret: String,
+    /// Subscribed event kinds (see EVENT_KINDS); empty means all.
+    #[serde(default)]
+    pub events: BTreeSet<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+const WEBHOOK_ATTEMPTS: u32 = 4;
+
+pub struct WebhookNotifier(pub Webhook);
+
+impl WebhookNotifier {
+    fn sign(&self, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.0.secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(body);
+        let digest: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("sha256={}", digest)
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn accepts(&self, event: &Event) -> bool {
+        self.0.events.is_empty() || self.0.events.contains(event.kind())
+    }
+
+    /// POSTs the signed payload, retrying with exponential backoff
+    /// (2s, 4s, 8s) on network errors and non-2xx responses.
+    async fn notify(&self, event: &Event) -> Result<(), String> {
+        let body = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+        let signature = self.sign(&body);
+        let client = reqwest::Client::new();
+        let mut last_error = String::new();
+        for attempt in 0..WEBHOOK_ATTEMPTS {
+            if attempt > 0 {
+                tokio::time::sleep(std::time::Duration::from_secs(1 << attempt)).await;
+            }
+            let result = client
+                .post(&self.0.url)
+                .header("Content-Type", "application/json")
+                .header("X-EveLooter-Event", event.kind())
+                .header("X-EveLooter-Signature", &signature)
+                .timeout(std::time::Duration::from_secs(10))
+                .body(body.clone())
+                .send()
+                .await;
+            match result {
+                Ok(resp) if resp.status().is_success() => return Ok(()),
+                Ok(resp) => last_error = format!("{} returned {}", self.0.url, resp.status()),
+                Err(e) => last_error = e.to_string(),
+            }
+            warn!(
+                "Webhook {} attempt {} failed: {}",
+                self.0.id,
+                attempt + 1,
+                last_error
+            );
+        }
+        Err(last_error)
+    }
+}
+
+/// Posts the loot digest to the Discord channel webhook set in the digest
+/// settings.
+pub struct DiscordNotifier(pub String);
+
+#[async_trait]
+impl Notifier for DiscordNotifier {
+    fn name(&self) -> &'static str {
+        "Discord"
+    }
+
+    fn accepts(&self, event: &Event) -> bool {
+        matches!(event, Event::LootDigest { .. })
+    }
+
+    async fn notify(&self, event: &Event) -> Result<(), String> {
+        let resp = reqwest::Client::new()
+            .post(&self.0)
+            // Pilot names are not mentions, whatever they look like
+            .json(&json!({ "content": event.body(None), "allowed_mentions": { "parse": [] } }))
+            .timeout(std::time::Duration::from_secs(10))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !resp.status().is_success() {
+            return Err(format!("Discord returned {}", resp.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Delivers an event to every interested notifier in the background:
+/// the configured backends, the registered webhooks and the digest's
+/// Discord channel.
+pub fn dispatch(state: &Arc<AppState>, event: Event) {
+    let state = state.clone();
+    tokio::spawn(
+        async move {
+            let (webhooks, discord) = {
+                let store = state.store.lock().await;
+                let webhooks: Vec<WebhookNotifier> = store
+                    .data
+                    .webhooks
+                    .iter()
+                    .cloned()
+                    .map(WebhookNotifier)
+                    .collect();
+                let discord = &store.data.settings.digest.discord_webhook;
+                (webhooks, DiscordNotifier(discord.clone()))
+            };
+            if !discord.0.is_empty() && discord.accepts(&event) {
+                let event = event.clone();
+                tokio::spawn(async move { deliver(&discord, &event).await }.in_current_span());
+            }
+            // Each webhook retries on its own schedule, so one slow receiver
+            // doesn't hold up the others
+            for webhook in webhooks {
+                if webhook.accepts(&event) {
+                    let event = event.clone();
+                    tokio::spawn(async move { deliver(&webhook, &event).await }.in_current_span());
+                }
+            }
+            for notifier in &state.notifiers {
+                if notifier.accepts(&event) {
+                    deliver(notifier.as_ref(), &event).await;
+                }
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+async fn deliver(notifier: &dyn Notifier, event: &Event) {
+    if let Err(e) = notifier.notify(event).await {
+        error!("{} notification failed: {}", notifier.name(), e);
+    }
+}
@@ -0,0 +1,225 @@
+use crate::auth::Viewer;
+use crate::models::*;
+use crate::names::NameBook;
+use crate::ops::operation_payout;
+use crate::payout::Payout;
+use crate::store::{OpType, Operation};
+use crate::views::{isk_error, render_page};
+
+use askama::Template;
+use axum::{
+    extract::{Query, State},
+    response::Response,
+};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+const PAGE_SIZE: usize = 25;
+
+/// Where an operation is in the payout workflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayoutStatus {
+    /// Not published yet; FCs may still be curating it.
+    Draft,
+    /// Published, with beneficiaries still waiting for their ISK.
+    Published,
+    /// Everyone with a positive share has been paid.
+    Paid,
+}
+
+impl PayoutStatus {
+    pub const ALL: [PayoutStatus; 3] = [
+        PayoutStatus::Draft,
+        PayoutStatus::Published,
+        PayoutStatus::Paid,
+    ];
+
+    pub fn key(&self) -> &'static str {
+        match self {
+            PayoutStatus::Draft => "draft",
+            PayoutStatus::Published => "published",
+            PayoutStatus::Paid => "paid",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            PayoutStatus::Draft => "Draft",
+            PayoutStatus::Published => "Published",
+            PayoutStatus::Paid => "Paid",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.key() == key)
+    }
+
+    pub fn of(op: &Operation, payout: &Payout, names: &NameBook) -> Self {
+        if op.published_at.is_none() {
+            return PayoutStatus::Draft;
+        }
+        let paid: BTreeSet<String> = op.payments.keys().map(|m| names.canonical(m)).collect();
+        let all_paid = payout
+            .wallets
+            .iter()
+            .all(|(main, amount)| *amount < Isk::ONE || paid.contains(main));
+        if all_paid {
+            PayoutStatus::Paid
+        } else {
+            PayoutStatus::Published
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct OpsQuery {
+    /// Matches the name, the zKillboard link and the FC.
+    #[serde(default)]
+    q: String,
+    #[serde(default)]
+    tag: String,
+    #[serde(default)]
+    op_type: String,
+    #[serde(default)]
+    status: String,
+    /// Operations overlapping [from, to] (YYYY-MM-DD).
+    #[serde(default)]
+    from: String,
+    #[serde(default)]
+    to: String,
+    #[serde(default)]
+    page: Option<usize>,
+}
+
+pub struct OpRow {
+    pub id: u64,
+    pub name: String,
+    pub start_date: String,
+    pub end_date: String,
+    /// The entity part of the zKillboard link, e.g. "corporation/98000001".
+    pub entity: String,
+    pub fc: String,
+    pub op_type: Option<OpType>,
+    pub tags: Vec<String>,
+    pub kill_count: usize,
+    pub total: String,
+    pub status: PayoutStatus,
+}
+
+#[derive(Template)]
+#[template(path = "ops.html")]
+struct OpsTemplate {
+    viewer: Viewer,
+    q: String,
+    tag: String,
+    op_type: String,
+    status: String,
+    from: String,
+    to: String,
+    /// Every tag in use, for the filter dropdown.
+    all_tags: BTreeSet<String>,
+    rows: Vec<OpRow>,
+    match_count: usize,
+    page: usize,
+    page_count: usize,
+}
+
+fn entity_of(zkill_link: &str) -> String {
+    zkill_link
+        .trim()
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_start_matches("zkillboard.com")
+        .trim_matches('/')
+        .to_string()
+}
+
+/// Saved operations, newest first, with search, filters and pagination.
+pub async fn list_operations(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Query(query): Query<OpsQuery>,
+) -> Response {
+    let store = state.store.lock().await;
+    let names = store.name_book();
+    let rules = &store.data.settings.rules;
+    let needle = query.q.trim().to_lowercase();
+    let tag = query.tag.trim().to_lowercase();
+    let op_type = OpType::from_key(&query.op_type);
+    let status = PayoutStatus::from_key(&query.status);
+
+    let matches: Result<Vec<(&Operation, Isk, PayoutStatus)>, IskOutOfRange> = store
+        .data
+        .operations
+        .iter()
+        .filter(|op| {
+            needle.is_empty()
+                || op.name.to_lowercase().contains(&needle)
+                || op.zkill_link.to_lowercase().contains(&needle)
+                || op.fc.to_lowercase().contains(&needle)
+        })
+        .filter(|op| tag.is_empty() || op.tags.contains(&tag))
+        .filter(|op| op_type.is_none() || op.op_type == op_type)
+        .filter(|op| query.from.is_empty() || op.end_date >= query.from)
+        .filter(|op| query.to.is_empty() || op.start_date <= query.to)
+        .map(|op| {
+            let payout = operation_payout(op, &names, rules)?;
+            let op_status = PayoutStatus::of(op, &payout, &names);
+            Ok((op, payout.total_dropped_value, op_status))
+        })
+        .collect();
+    let mut matches = match matches {
+        Ok(matches) => matches,
+        Err(e) => return isk_error(e),
+    };
+    matches.retain(|(_, _, s)| status.is_none_or(|wanted| *s == wanted));
+    matches.sort_by(|a, b| {
+        b.0.end_date
+            .cmp(&a.0.end_date)
+            .then_with(|| b.0.id.cmp(&a.0.id))
+    });
+
+    let match_count = matches.len();
+    let page_count = match_count.div_ceil(PAGE_SIZE).max(1);
+    let page = query.page.unwrap_or(1).clamp(1, page_count);
+    let rows = matches
+        .into_iter()
+        .skip((page - 1) * PAGE_SIZE)
+        .take(PAGE_SIZE)
+        .map(|(op, total, status)| OpRow {
+            id: op.id,
+            name: op.name.clone(),
+            start_date: op.start_date.clone(),
+            end_date: op.end_date.clone(),
+            entity: entity_of(&op.zkill_link),
+            fc: op.fc.clone(),
+            op_type: op.op_type,
+            tags: op.tags.iter().cloned().collect(),
+            kill_count: op.kills.iter().filter(|k| k.is_active).count(),
+            total: total.to_string(),
+            status,
+        })
+        .collect();
+
+    let template = OpsTemplate {
+        viewer,
+        all_tags: store
+            .data
+            .operations
+            .iter()
+            .flat_map(|op| op.tags.iter().cloned())
+            .collect(),
+        rows,
+        match_count,
+        page,
+        page_count,
+        q: query.q,
+        tag,
+        op_type: query.op_type,
+        status: query.status,
+        from: query.from,
+        to: query.to,
+    };
+    render_page(&template)
+}
@@ -0,0 +1,1449 @@
+use crate::auth::{self, Viewer};
+use crate::base_path;
+use crate::logic::{fetch_zkill_data, FetchError};
+use crate::loot_can::LootCanView;
+use crate::models::*;
+use crate::names::NameBook;
+use crate::notify::{self, Event};
+use crate::payout::{self, Adjustment, Payout, PayoutRules};
+use crate::pwa;
+use crate::routing;
+use crate::settings::WorkspaceSettings;
+use crate::store::{
+    parse_tags, Dispute, EditState, OpType, Operation, Payment, Resolution, SignOff,
+};
+use crate::transfers;
+use crate::views::*;
+use crate::whatif::WhatIf;
+
+use askama::Template;
+use axum::{
+    extract::{Form, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use tracing::{info, Instrument};
+
+/// Machine-readable audit artifact for one operation. The payout section is
+/// informational: imports recompute it from the kills and mapping. Once the
+/// operation is finalized it is the signed-off snapshot instead.
+#[derive(Serialize, Deserialize)]
+pub struct OperationExport {
+    #[serde(default = "Utc::now")]
+    pub exported_at: DateTime<Utc>,
+    pub operation: Operation,
+    #[serde(default)]
+    pub total_dropped_value: Isk,
+    #[serde(default)]
+    pub payouts: BTreeMap<String, Isk>,
+    /// Corp tax and rounding remainder, to the cent, so the payouts and it
+    /// add up to the loot.
+    #[serde(default)]
+    pub corp_tax: Isk,
+    /// The operation is finalized and its snapshot still matches its hash.
+    #[serde(default)]
+    pub official: bool,
+}
+
+pub fn operation_template(
+    op: &Operation,
+    names: &NameBook,
+    settings: &WorkspaceSettings,
+    default_mapping: &str,
+    viewer: Viewer,
+) -> Result<IndexTemplate, IskOutOfRange> {
+    let mapping = payout::parse_mapping(&op.mapping_text);
+    let rules = op.payout_rules(&settings.rules);
+    let mut result = build_result(
+        op.payout_kills()?.into_owned(),
+        &ResultInputs {
+            names,
+            settings,
+            mapping: &mapping,
+            excluded_names: &op.excluded_beneficiaries,
+            adjustments: &op.adjustments,
+            holdings: &transfers::parse_holdings(&op.holdings_text),
+            op_start: &op.op_start,
+            op_end: &op.op_end,
+            rules: &rules,
+        },
+    )?;
+    result.mark_paid(&op.payments, names);
+    let can_sign_off = op.is_finalized() && settings.sign_off.may_sign_off(&viewer);
+    let my_main = viewer
+        .user
+        .as_ref()
+        .map(|u| pilot_main(op, names, u.character_name.as_str()));
+    let estimated = op.estimated_value()?;
+
+    Ok(IndexTemplate {
+        viewer,
+        operation: Some(OperationView {
+            id: op.id,
+            name: op.name.clone(),
+            mapping_diff: payout::diff_mappings(&op.mapping_text, default_mapping),
+            can_undo: !op.undo_stack.is_empty(),
+            can_redo: !op.redo_stack.is_empty(),
+            holdings_text: op.holdings_text.clone(),
+            notes: op.notes.clone(),
+            fc: op.fc.clone(),
+            tags: op.tags.iter().cloned().collect(),
+            op_type: op.op_type,
+            published_at: op
+                .published_at
+                .map(|t| t.format("%Y-%m-%d %H:%M UTC").to_string()),
+            finalized: op.finalized.as_ref().map(|f| {
+                let at = f.finalized_at.format("%Y-%m-%d %H:%M UTC");
+                match &f.finalized_by {
+                    Some(by) => format!("{} by {}", at, by),
+                    None => at.to_string(),
+                }
+            }),
+            audit_hash: op.finalized.as_ref().map(|f| f.hash.clone()),
+            amendments: op
+                .finalized
+                .iter()
+                .flat_map(|f| &f.amendments)
+                .map(|a| {
+                    let at = a.amended_at.format("%Y-%m-%d %H:%M UTC");
+                    match &a.amended_by {
+                        Some(by) => format!("{} by {}: {}", at, by, a.reason),
+                        None => format!("{}: {}", at, a.reason),
+                    }
+                })
+                .collect(),
+            sign_offs: op
+                .sign_offs
+                .iter()
+                .map(|s| {
+                    let at = s.signed_at.format("%Y-%m-%d %H:%M UTC").to_string();
+                    (s.character_name.clone(), at)
+                })
+                .collect(),
+            sign_off_required: settings.sign_off.required,
+            can_sign_off,
+            my_main,
+            disputes: op
+                .disputes
+                .iter()
+                .enumerate()
+                .map(|(index, d)| DisputeView::new(index, d))
+                .collect(),
+            audit_log: op
+                .audit_log
+                .iter()
+                .map(|e| {
+                    (
+                        e.at.format("%Y-%m-%d %H:%M UTC").to_string(),
+                        e.by.clone().unwrap_or_else(|| "-".to_string()),
+                        e.action.clone(),
+                    )
+                })
+                .collect(),
+            sales: op
+                .sales
+                .iter()
+                .map(|s| {
+                    (
+                        s.recorded_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+                        s.recorded_by.clone().unwrap_or_else(|| "-".to_string()),
+                        s.amount.to_string(),
+                        s.note.clone(),
+                    )
+                })
+                .collect(),
+            estimated_str: estimated.to_string(),
+            realized: op.realized_value()?.map(|realized| {
+                let change = match realized.ratio(estimated) {
+                    Some(ratio) => format!("{:+.1}%", (ratio - 1.0) * 100.0),
+                    None => "-".to_string(),
+                };
+                (realized.to_string(), change)
+            }),
+            split_on_realized: op.split_on_realized,
+            can_match_wallet: false,
+            loot_can: None,
+            can_import_loot_can: false,
+            embed_url: None,
+            transparency_url: None,
+            qr_url: None,
+            what_if: WhatIf::current(op, &rules),
+            include_pods: op
+                .kills
+                .iter()
+                .any(Killmail::is_pod)
+                .then_some(op.include_pods),
+            preset: op.preset.as_ref().map(|p| p.name.clone()),
+        }),
+        mapping_text: op.mapping_text.clone(),
+        zkill_link: op.zkill_link.clone(),
+        start_date: op.start_date.clone(),
+        end_date: op.end_date.clone(),
+        op_start: op.op_start.clone(),
+        op_end: op.op_end.clone(),
+        result,
+        error_msg: None,
+        can_resume: false,
+        replace_prompt: None,
+        fetch_report: None,
+    })
+}
+
+/// The payout of a saved operation, adjustments included.
+pub fn operation_payout(
+    op: &Operation,
+    names: &NameBook,
+    rules: &PayoutRules,
+) -> Result<Payout, IskOutOfRange> {
+    let mut payout = payout::calculate_indexed(
+        &op.payout_kills()?,
+        names,
+        &op.participants(names),
+        &payout::parse_mapping(&op.mapping_text),
+        &op.excluded_beneficiaries,
+        &op.payout_rules(rules),
+    )?;
+    payout.apply_adjustments(names, &op.adjustments)?;
+    Ok(payout)
+}
+
+pub fn not_found() -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Html("Operation not found".to_string()),
+    )
+        .into_response()
+}
+
+/// The main a pilot's share is paid to under the operation's mapping.
+fn pilot_main(op: &Operation, names: &NameBook, character_name: &str) -> String {
+    let mapping = names.canonical_mapping(&payout::parse_mapping(&op.mapping_text));
+    let name = names.canonical(character_name);
+    mapping.get(&name).cloned().unwrap_or(name)
+}
+
+/// Refuses payout edits to a finalized operation.
+pub fn finalized() -> Response {
+    (
+        StatusCode::CONFLICT,
+        Html("Operation is finalized; its payout can no longer be edited".to_string()),
+    )
+        .into_response()
+}
+
+/// Snapshots the currently fetched kills (with exclusions) as a saved operation.
+pub async fn save_operation(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Form(params): Form<FetchParams>,
+) -> Response {
+    let (start_cutoff, end_cutoff) = params.window();
+    let (rules, preset) = {
+        let store = state.store.lock().await;
+        let settings = &store.data.settings;
+        let preset = settings.preset(&params.payout_preset).cloned();
+        let rules = preset
+            .as_ref()
+            .map_or(&settings.rules, |p| &p.rules)
+            .clone();
+        (rules, preset)
+    };
+    state.pull_calculator().await;
+    let kills = {
+        let current_kills = state.current_kills.lock().unwrap();
+        let loss_candidates = rules.loss_candidates(
+            &payout::parse_mapping(&params.mapping_input),
+            &current_kills,
+        );
+        select_kills(
+            &current_kills,
+            start_cutoff,
+            end_cutoff,
+            &params.excluded_ids(),
+            &loss_candidates,
+        )
+    };
+
+    if kills.is_empty() {
+        let mut template = IndexTemplate::from_params(&params, viewer);
+        template.error_msg = Some("Nothing to save: fetch some kills first.".to_string());
+        return render_page(&template);
+    }
+
+    let name = match params.operation_name.trim() {
+        "" => format!("Operation {} - {}", params.start_date, params.end_date),
+        n => n.to_string(),
+    };
+
+    let op = Operation {
+        id: 0,
+        name,
+        created_at: Utc::now(),
+        zkill_link: params.zkill_link.clone(),
+        start_date: params.start_date.clone(),
+        end_date: params.end_date.clone(),
+        op_start: params.op_start.clone(),
+        op_end: params.op_end.clone(),
+        mapping_text: params.mapping_input.clone(),
+        kills,
+        excluded_beneficiaries: params.excluded_names(),
+        adjustments: vec![],
+        holdings_text: String::new(),
+        notes: String::new(),
+        fc: String::new(),
+        tags: BTreeSet::new(),
+        op_type: None,
+        include_pods: false,
+        preset,
+        published_at: None,
+        share_token: None,
+        finalized: None,
+        sign_offs: vec![],
+        disputes: vec![],
+        audit_log: vec![],
+        sales: vec![],
+        split_on_realized: false,
+        loot_can: None,
+        payments: BTreeMap::new(),
+        undo_stack: vec![],
+        redo_stack: vec![],
+        participant_index: Default::default(),
+    };
+
+    let (name, kill_count) = (op.name.clone(), op.kills.len());
+    let id = match state.store.lock().await.insert_operation(op).await {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    info!("Saved operation {}", id);
+    notify::dispatch(
+        &state,
+        Event::OperationCreated {
+            operation_id: id,
+            operation_name: name,
+            kill_count,
+        },
+    );
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+/// The kill log filter, `?q=` on an operation page.
+#[derive(Deserialize, Default)]
+pub struct KillSearch {
+    #[serde(default)]
+    q: String,
+}
+
+/// `?view=mobile` or `?view=full` on an operation page, overriding the
+/// layout picked from the user agent.
+#[derive(Deserialize, Default)]
+pub struct LayoutQuery {
+    view: Option<String>,
+}
+
+/// Phones get the condensed page: pilots mostly open the shared link to
+/// check their number, not to scroll the kill log.
+fn wants_mobile(headers: &HeaderMap, layout: &LayoutQuery) -> bool {
+    match layout.view.as_deref() {
+        Some("mobile") => true,
+        Some("full") => false,
+        // "Mobi" is in every phone browser's user agent, not tablets'
+        _ => headers
+            .get(header::USER_AGENT)
+            .and_then(|ua| ua.to_str().ok())
+            .is_some_and(|ua| ua.contains("Mobi")),
+    }
+}
+
+/// Marks the pages of finalized operations for the service worker to keep
+/// (see pwa).
+fn offline_copy(op: &Operation, mut page: Response) -> Response {
+    if let Some(hash) = op.finalized.as_ref().and_then(|f| f.hash.parse().ok()) {
+        page.headers_mut().insert(pwa::OFFLINE_HEADER, hash);
+    }
+    page
+}
+
+/// The condensed operation page: totals and shares, no kill log.
+#[derive(Template)]
+#[template(path = "mobile.html")]
+struct MobileTemplate {
+    operation: OperationView,
+    start_date: String,
+    end_date: String,
+    result: ResultView,
+}
+
+/// A saved operation with its page, or the response for why there is
+/// none.
+async fn load_operation(
+    state: &Arc<AppState>,
+    id: u64,
+    viewer: Viewer,
+) -> Result<(Operation, IndexTemplate), Response> {
+    let (op, mut template, fee_per_jump) = {
+        let store = state.store.lock().await;
+        let op = store.operation(id).ok_or_else(not_found)?.clone();
+        let template = operation_template(
+            &op,
+            &store.name_book(),
+            &store.data.settings,
+            &store.data.default_mapping,
+            viewer,
+        )
+        .map_err(isk_error)?;
+        (op, template, store.data.settings.hauling_fee_per_jump)
+    };
+    routing::mark_distances(state, &mut template.result, fee_per_jump).await;
+    Ok((op, template))
+}
+
+pub async fn show_operation(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Query(search): Query<KillSearch>,
+    Query(layout): Query<LayoutQuery>,
+) -> Response {
+    let (op, mut template) = match load_operation(&state, id, viewer).await {
+        Ok(loaded) => loaded,
+        Err(response) => return response,
+    };
+    if wants_mobile(&headers, &layout) {
+        let Some(operation) = template.operation else {
+            return not_found();
+        };
+        let page = render_page(&MobileTemplate {
+            operation,
+            start_date: template.start_date,
+            end_date: template.end_date,
+            result: template.result,
+        });
+        return offline_copy(&op, page);
+    }
+    template.result.search_kills(&search.q);
+    if let Some(view) = &mut template.operation {
+        let store = state.store.lock().await;
+        view.can_match_wallet = !state.config.read_only && store.data.wallet_grant.is_some();
+        view.can_import_loot_can = !state.config.read_only
+            && store.data.assets_grant.is_some()
+            && store.data.settings.loot_location_id.is_some();
+        drop(store);
+        view.loot_can = LootCanView::new(&state, &op);
+    }
+    if let (Some(view), Some(token)) = (&mut template.operation, &op.share_token) {
+        let public = |path: String| match state.config.public_url.as_deref() {
+            Some(public_url) => format!("{}{}", public_url.trim_end_matches('/'), path),
+            None => base_path::url(&path),
+        };
+        view.embed_url = Some(public(format!("/embed/{}", token)));
+        view.qr_url = Some(base_path::url(&format!("/share/{}/qr.svg", token)));
+        view.transparency_url = op
+            .is_finalized()
+            .then(|| public(format!("/transparency/{}", token)));
+    }
+    // Editing aids; a read-only instance neither fetches nor suggests alts
+    if !state.config.read_only {
+        crate::flag_ex_members(&state, &mut template.result).await;
+        crate::add_history_suggestions(
+            &state,
+            &mut template.result,
+            &payout::parse_mapping(&op.mapping_text),
+        )
+        .await;
+    }
+    offline_copy(&op, render_page(&template))
+}
+
+/// The kill log alone, for the search box to swap in.
+#[derive(Template)]
+#[template(path = "partials/kill_table.html")]
+struct KillTableTemplate {
+    viewer: Viewer,
+    operation: Option<OperationView>,
+    result: ResultView,
+}
+
+impl KillTableTemplate {
+    fn can_edit_payout(&self) -> bool {
+        can_edit_payout(&self.viewer, self.operation.as_ref())
+    }
+}
+
+/// `GET /ops/:id/kills?q=`: the operation's kill log filtered on the
+/// server, since the browser's find misses what the page doesn't show.
+pub async fn search_kills(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Path(id): Path<u64>,
+    Query(search): Query<KillSearch>,
+) -> Response {
+    let template = match load_operation(&state, id, viewer).await {
+        Ok((_, template)) => template,
+        Err(response) => return response,
+    };
+    let mut result = template.result;
+    result.search_kills(&search.q);
+    render_page(&KillTableTemplate {
+        viewer: template.viewer,
+        operation: template.operation,
+        result,
+    })
+}
+
+/// Applies exclusion, mapping and op-time edits to a saved operation.
+/// Never refetches: the stored kills are the operation's record.
+pub async fn update_operation(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    Form(params): Form<FetchParams>,
+) -> Response {
+    let mut store = state.store.lock().await;
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    if op.is_finalized() {
+        return finalized();
+    }
+
+    op.edit(EditState {
+        excluded_kills: params.excluded_ids().into_iter().collect(),
+        excluded_beneficiaries: params.excluded_names().into_iter().collect(),
+        adjustments: op.adjustments.clone(),
+        mapping_text: params.mapping_input,
+        op_start: params.op_start,
+        op_end: params.op_end,
+    });
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+/// Promotes the mapping as currently edited on an operation to the default
+/// for new calculations. The operation itself is left untouched.
+pub async fn make_default_mapping(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    Form(params): Form<FetchParams>,
+) -> Response {
+    let mut store = state.store.lock().await;
+    if store.operation(id).is_none() {
+        return not_found();
+    }
+    store.data.default_mapping = params.mapping_input.trim().to_string();
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    info!("Default mapping replaced from operation {}", id);
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+/// Replaces an operation's mapping snapshot with the current default.
+pub async fn reset_mapping(State(state): State<Arc<AppState>>, Path(id): Path<u64>) -> Response {
+    let mut store = state.store.lock().await;
+    let default_mapping = store.data.default_mapping.clone();
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    if op.is_finalized() {
+        return finalized();
+    }
+    op.edit(EditState {
+        mapping_text: default_mapping,
+        ..op.edit_state()
+    });
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkScope {
+    /// `value` is a `YYYY-MM-DD` date.
+    Day,
+    /// `value` is a solar system ID.
+    System,
+    /// `value` is an ISK amount; matches kills that dropped less.
+    Below,
+}
+
+#[derive(Deserialize)]
+pub struct BulkForm {
+    scope: BulkScope,
+    value: String,
+    /// Re-include the matching kills instead of excluding them.
+    #[serde(default)]
+    include: bool,
+}
+
+/// Excludes (or re-includes) every stored kill matching a scope in one
+/// undoable edit.
+pub async fn bulk_edit(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    Form(form): Form<BulkForm>,
+) -> Response {
+    let mut store = state.store.lock().await;
+    let timezone = store.data.settings.timezone;
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    if op.is_finalized() {
+        return finalized();
+    }
+
+    let value = form.value.trim();
+    let matches = |kill: &Killmail| match form.scope {
+        BulkScope::Day => kill.local_date(timezone) == value,
+        BulkScope::System => value.parse().map(SystemId) == Ok(kill.solar_system_id),
+        BulkScope::Below => value
+            .parse()
+            .is_ok_and(|limit| kill.zkb.dropped_value < Isk(limit)),
+    };
+
+    let mut edit = op.edit_state();
+    for kill in op.kills.iter().filter(|k| matches(k)) {
+        if form.include {
+            edit.excluded_kills.remove(&kill.killmail_id);
+        } else {
+            edit.excluded_kills.insert(kill.killmail_id);
+        }
+    }
+    op.edit(edit);
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct AdjustmentForm {
+    main: String,
+    amount: String,
+    #[serde(default)]
+    note: String,
+}
+
+/// Adds a manual adjustment to one beneficiary's share.
+pub async fn add_adjustment(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    Form(form): Form<AdjustmentForm>,
+) -> Response {
+    let Some(amount) = parse_isk(&form.amount) else {
+        return (StatusCode::BAD_REQUEST, "Invalid ISK amount").into_response();
+    };
+    let mut store = state.store.lock().await;
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    if op.is_finalized() {
+        return finalized();
+    }
+    let mut edit = op.edit_state();
+    edit.adjustments.push(Adjustment {
+        main: form.main.trim().to_string(),
+        amount,
+        note: form.note.trim().to_string(),
+    });
+    op.edit(edit);
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+pub async fn remove_adjustment(
+    State(state): State<Arc<AppState>>,
+    Path((id, index)): Path<(u64, usize)>,
+) -> Response {
+    let mut store = state.store.lock().await;
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    if op.is_finalized() {
+        return finalized();
+    }
+    let mut edit = op.edit_state();
+    if index < edit.adjustments.len() {
+        edit.adjustments.remove(index);
+        op.edit(edit);
+        if let Err(e) = store.save().await {
+            return e.into_response();
+        }
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct HoldingsForm {
+    #[serde(default)]
+    holdings: String,
+}
+
+/// Records who holds the loot ISK, from which transfers are generated.
+pub async fn update_holdings(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    Form(form): Form<HoldingsForm>,
+) -> Response {
+    let mut store = state.store.lock().await;
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    op.holdings_text = form.holdings.trim().to_string();
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct DetailsForm {
+    #[serde(default)]
+    notes: String,
+    #[serde(default)]
+    fc: String,
+    /// Comma-separated doctrine tags.
+    #[serde(default)]
+    tags: String,
+    /// An OpType key; empty clears it.
+    #[serde(default)]
+    op_type: String,
+}
+
+/// Updates the notes and metadata. These describe the operation rather
+/// than the payout, so they stay out of the undo history.
+pub async fn update_details(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    Form(form): Form<DetailsForm>,
+) -> Response {
+    let mut store = state.store.lock().await;
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    op.notes = form.notes.trim().to_string();
+    op.fc = SafeName::from(form.fc).to_string();
+    op.tags = parse_tags(&form.tags);
+    op.op_type = OpType::from_key(&form.op_type);
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct RerunForm {
+    #[serde(default)]
+    start_date: String,
+    #[serde(default)]
+    end_date: String,
+}
+
+/// Runs an operation's configuration again for a new date range (by default
+/// the last seven days): fetches the entity's kills and saves them as a new
+/// operation with the same mapping, excluded beneficiaries, adjustments and
+/// details. Weekly payout runs reuse the same setup every time.
+pub async fn rerun_operation(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Path(id): Path<u64>,
+    Form(form): Form<RerunForm>,
+) -> Response {
+    let Some(source) = state.store.lock().await.operation(id).cloned() else {
+        return not_found();
+    };
+    let (week_start, week_end) = this_week();
+    let non_empty = |date: String, default: String| if date.is_empty() { default } else { date };
+    let params = FetchParams {
+        zkill_link: source.zkill_link.clone(),
+        mapping_input: source.mapping_text.clone(),
+        excluded_kills: None,
+        excluded_beneficiaries: None,
+        start_date: non_empty(form.start_date, week_start),
+        end_date: non_empty(form.end_date, week_end),
+        op_start: String::new(),
+        op_end: String::new(),
+        operation_name: String::new(),
+        payout_preset: String::new(),
+        resume: false,
+        confirm_replace: false,
+        submit_token: String::new(),
+    };
+    let error_page = |msg: String| {
+        let mut template = IndexTemplate::from_params(&params, viewer.clone());
+        template.error_msg = Some(msg);
+        render_page(&template)
+    };
+
+    let (start_cutoff, end_cutoff) = params.window();
+    if (end_cutoff - start_cutoff).num_days() > 30 {
+        return error_page("Timeframe exceeds 30 days. Please select a shorter range.".to_string());
+    }
+    info!(
+        "Re-running operation {} for {} - {}",
+        id, params.start_date, params.end_date
+    );
+    let fetched = match fetch_zkill_data(&params.zkill_link, &state, start_cutoff, false).await {
+        Ok(fetched) => fetched,
+        Err(e) => return error_page(e.to_string()),
+    };
+    let loss_candidates = source
+        .payout_rules(&state.store.lock().await.data.settings.rules)
+        .loss_candidates(
+            &payout::parse_mapping(&params.mapping_input),
+            &fetched.kills,
+        );
+    let kills = select_kills(
+        &fetched.kills,
+        start_cutoff,
+        end_cutoff,
+        &HashSet::new(),
+        &loss_candidates,
+    );
+    if kills.is_empty() {
+        return error_page(format!(
+            "{} ({} - {}).",
+            FetchError::NoKills,
+            params.start_date,
+            params.end_date
+        ));
+    }
+
+    let op = Operation {
+        id: 0,
+        name: format!(
+            "{} ({} - {})",
+            source.name, params.start_date, params.end_date
+        ),
+        created_at: Utc::now(),
+        start_date: params.start_date.clone(),
+        end_date: params.end_date.clone(),
+        op_start: String::new(),
+        op_end: String::new(),
+        kills,
+        holdings_text: String::new(),
+        published_at: None,
+        share_token: None,
+        finalized: None,
+        sign_offs: vec![],
+        disputes: vec![],
+        audit_log: vec![],
+        sales: vec![],
+        split_on_realized: false,
+        loot_can: None,
+        payments: BTreeMap::new(),
+        undo_stack: vec![],
+        redo_stack: vec![],
+        participant_index: Default::default(),
+        ..source
+    };
+    let (name, kill_count) = (op.name.clone(), op.kills.len());
+    let new_id = match state.store.lock().await.insert_operation(op).await {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    info!("Saved operation {} as a re-run of {}", new_id, id);
+    notify::dispatch(
+        &state,
+        Event::OperationCreated {
+            operation_id: new_id,
+            operation_name: name,
+            kill_count,
+        },
+    );
+    base_path::redirect(&format!("/ops/{}", new_id)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct PaidForm {
+    main: String,
+    #[serde(default)]
+    paid: bool,
+}
+
+/// Marks a beneficiary as paid (recording their finalized share), or
+/// unmarks them. Payments are only tracked against a finalized payout.
+pub async fn mark_paid(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Path(id): Path<u64>,
+    Form(form): Form<PaidForm>,
+) -> Response {
+    let event = {
+        let mut store = state.store.lock().await;
+        let names = store.name_book();
+        let Some(op) = store.operation_mut(id) else {
+            return not_found();
+        };
+        let Some(finalization) = &op.finalized else {
+            return (
+                StatusCode::CONFLICT,
+                Html("Finalize the operation before tracking payments".to_string()),
+            )
+                .into_response();
+        };
+        let main = names.canonical(form.main.trim());
+        let by: Option<String> = viewer.user.map(|u| u.character_name.into());
+        let event = if form.paid {
+            let amount = finalization.payouts.get(&main).copied().unwrap_or_default();
+            op.payments.insert(
+                main.clone(),
+                Payment {
+                    amount,
+                    paid_at: Utc::now(),
+                    paid_by: by.clone(),
+                },
+            );
+            op.log(by, format!("Marked {} paid {} ISK", main, amount));
+            Some(Event::BeneficiaryPaid {
+                operation_id: op.id,
+                operation_name: op.name.clone(),
+                main,
+                amount,
+            })
+        } else {
+            if op.payments.remove(&main).is_some() {
+                op.log(by, format!("Marked {} unpaid", main));
+            }
+            None
+        };
+        if let Err(e) = store.save().await {
+            return e.into_response();
+        }
+        event
+    };
+    if let Some(event) = event {
+        notify::dispatch(&state, event);
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct PodsForm {
+    #[serde(default)]
+    include: bool,
+}
+
+/// Pays out pod kills on this operation, or leaves them out again.
+pub async fn toggle_pods(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    Form(form): Form<PodsForm>,
+) -> Response {
+    let mut store = state.store.lock().await;
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    if op.is_finalized() {
+        return finalized();
+    }
+    op.include_pods = form.include;
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+/// Creates the operation's share token (used by the embed widget) if it
+/// doesn't have one yet.
+pub async fn share_operation(State(state): State<Arc<AppState>>, Path(id): Path<u64>) -> Response {
+    let mut store = state.store.lock().await;
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    if op.share_token.is_none() {
+        op.share_token = Some(auth::random_token());
+        if let Err(e) = store.save().await {
+            return e.into_response();
+        }
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+/// Marks the payout as published and notifies the configured backends.
+/// Workspaces that require sign-off only publish approved payouts.
+pub async fn publish_operation(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Path(id): Path<u64>,
+) -> Response {
+    let (mut event, last_seen) = {
+        let mut store = state.store.lock().await;
+        let names = store.name_book();
+        let rules = store.data.settings.rules.clone();
+        let sign_off_required = store.data.settings.sign_off.required;
+        let suggest_hubs = store.data.settings.suggest_hubs;
+        let Some(op) = store.operation_mut(id) else {
+            return not_found();
+        };
+        if sign_off_required && op.sign_offs.is_empty() {
+            return (
+                StatusCode::CONFLICT,
+                Html("The payout needs a reviewer's sign-off before it is published".to_string()),
+            )
+                .into_response();
+        }
+        let payout = match operation_payout(op, &names, &rules) {
+            Ok(payout) => payout,
+            Err(e) => return isk_error(e),
+        };
+        op.published_at = Some(Utc::now());
+        op.log(
+            viewer.user.map(|u| u.character_name.into()),
+            "Published the payout".to_string(),
+        );
+        let payouts: BTreeMap<String, Isk> = payout.wallets.into_iter().collect();
+        let mut last_seen = HashMap::new();
+        if suggest_hubs {
+            let mapping = names.canonical_mapping(&payout::parse_mapping(&op.mapping_text));
+            last_seen = routing::last_seen(&op.kills, &names, &mapping);
+            last_seen.retain(|main, _| payouts.contains_key(main));
+        }
+        let event = Event::PayoutPublished {
+            operation_id: op.id,
+            operation_name: op.name.clone(),
+            total_dropped_value: payout.total_dropped_value,
+            payouts: payouts.into_iter().collect(),
+            nearest_hubs: BTreeMap::new(),
+        };
+        if let Err(e) = store.save().await {
+            return e.into_response();
+        }
+        (event, last_seen)
+    };
+    info!("Published payout of operation {}", id);
+    if last_seen.is_empty() {
+        notify::dispatch(&state, event);
+    } else {
+        // Routes may have to come from ESI; don't hold up the FC for them
+        let state = state.clone();
+        tokio::spawn(
+            async move {
+                if let Event::PayoutPublished { nearest_hubs, .. } = &mut event {
+                    *nearest_hubs = routing::hub_suggestions(&state, &last_seen).await;
+                }
+                notify::dispatch(&state, event);
+            }
+            .in_current_span(),
+        );
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+/// Locks the operation's payout as it stands: no more exclusions, mapping
+/// changes or adjustments. The snapshot is what payments and official
+/// exports go by.
+pub async fn finalize_operation(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Path(id): Path<u64>,
+) -> Response {
+    let event = {
+        let mut store = state.store.lock().await;
+        let names = store.name_book();
+        let rules = store.data.settings.rules.clone();
+        let Some(op) = store.operation_mut(id) else {
+            return not_found();
+        };
+        if op.is_finalized() {
+            return base_path::redirect(&format!("/ops/{}", id)).into_response();
+        }
+        let payout = match operation_payout(op, &names, &rules) {
+            Ok(payout) => payout,
+            Err(e) => return isk_error(e),
+        };
+        let by: Option<String> = viewer.user.map(|u| u.character_name.into());
+        op.finalize(
+            payout.total_dropped_value,
+            payout.wallets.into_iter().collect(),
+            payout.corp_tax.cents(),
+            by.clone(),
+        );
+        op.log(by.clone(), "Finalized the payout".to_string());
+        let event = Event::PayoutFinalized {
+            operation_id: op.id,
+            operation_name: op.name.clone(),
+            finalized_by: by,
+            total_dropped_value: payout.total_dropped_value,
+            hash: op
+                .finalized
+                .as_ref()
+                .map(|f| f.hash.clone())
+                .unwrap_or_default(),
+        };
+        if let Err(e) = store.save().await {
+            return e.into_response();
+        }
+        event
+    };
+    info!("Finalized operation {}", id);
+    notify::dispatch(&state, event);
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+/// Records the viewer's approval of a finalized payout. The FC who
+/// finalized it can't approve their own payout.
+pub async fn sign_off_operation(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Path(id): Path<u64>,
+) -> Response {
+    let mut store = state.store.lock().await;
+    let may_sign_off = store.data.settings.sign_off.may_sign_off(&viewer);
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    let Some(finalization) = &op.finalized else {
+        return (
+            StatusCode::CONFLICT,
+            Html("Finalize the operation before signing it off".to_string()),
+        )
+            .into_response();
+    };
+    let Some(user) = viewer.user.filter(|_| may_sign_off) else {
+        return (
+            StatusCode::FORBIDDEN,
+            Html("Only designated reviewers can sign off payouts".to_string()),
+        )
+            .into_response();
+    };
+    if finalization.finalized_by.as_deref() == Some(user.character_name.as_str()) {
+        return (
+            StatusCode::CONFLICT,
+            Html("A payout needs a second pair of eyes; ask another reviewer".to_string()),
+        )
+            .into_response();
+    }
+    if !op
+        .sign_offs
+        .iter()
+        .any(|s| s.character_id == user.character_id)
+    {
+        op.sign_offs.push(SignOff {
+            character_id: user.character_id,
+            character_name: user.character_name.to_string(),
+            signed_at: Utc::now(),
+        });
+        op.log(
+            Some(user.character_name.to_string()),
+            "Signed off the payout".to_string(),
+        );
+        if let Err(e) = store.save().await {
+            return e.into_response();
+        }
+        info!("{} signed off operation {}", user.character_name, id);
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct DisputeForm {
+    #[serde(default)]
+    reason: String,
+}
+
+/// Flags the logged-in pilot's payout line as disputed, for the FCs to
+/// resolve. One open dispute per pilot and operation.
+pub async fn raise_dispute(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Path(id): Path<u64>,
+    Form(form): Form<DisputeForm>,
+) -> Response {
+    let Some(user) = viewer.user else {
+        return (StatusCode::UNAUTHORIZED, "Log in to dispute a payout").into_response();
+    };
+    let reason = form.reason.trim();
+    if reason.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Give a reason for the dispute").into_response();
+    }
+    let mut store = state.store.lock().await;
+    let names = store.name_book();
+    let rules = store.data.settings.rules.clone();
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    let main = pilot_main(op, &names, user.character_name.as_str());
+    let on_payout = match operation_payout(op, &names, &rules) {
+        Ok(payout) => payout.wallets.contains_key(&main),
+        Err(e) => return isk_error(e),
+    };
+    if !on_payout && !op.excluded_beneficiaries.contains(&main) {
+        return (StatusCode::FORBIDDEN, "You aren't on this payout").into_response();
+    }
+    if op
+        .disputes
+        .iter()
+        .any(|d| d.character_id == user.character_id && d.resolution.is_none())
+    {
+        return (
+            StatusCode::CONFLICT,
+            "You already have an open dispute on this payout",
+        )
+            .into_response();
+    }
+    op.disputes.push(Dispute {
+        main: main.clone(),
+        raised_by: user.character_name.to_string(),
+        character_id: user.character_id,
+        reason: reason.to_string(),
+        raised_at: Utc::now(),
+        resolution: None,
+    });
+    op.log(
+        Some(user.character_name.to_string()),
+        format!("Disputed the share of {}: {}", main, reason),
+    );
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    info!(
+        "{} disputed their share of operation {}",
+        user.character_name, id
+    );
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ResolveForm {
+    #[serde(default)]
+    note: String,
+    /// An ISK amount to adjust the disputed share by; empty for none.
+    #[serde(default)]
+    adjustment: String,
+}
+
+/// Closes a dispute with a note, optionally adjusting the disputed share.
+/// Finalized payouts can't be adjusted, only answered.
+pub async fn resolve_dispute(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Path((id, index)): Path<(u64, usize)>,
+    Form(form): Form<ResolveForm>,
+) -> Response {
+    let adjustment = match form.adjustment.trim() {
+        "" => None,
+        amount => match parse_isk(amount) {
+            Some(amount) => Some(amount),
+            None => return (StatusCode::BAD_REQUEST, "Invalid ISK amount").into_response(),
+        },
+    };
+    let mut store = state.store.lock().await;
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    let Some(main) = op
+        .disputes
+        .get(index)
+        .filter(|d| d.resolution.is_none())
+        .map(|d| d.main.clone())
+    else {
+        return base_path::redirect(&format!("/ops/{}", id)).into_response();
+    };
+    if adjustment.is_some() && op.is_finalized() {
+        return finalized();
+    }
+    let note = form.note.trim().to_string();
+    if let Some(amount) = adjustment {
+        let mut edit = op.edit_state();
+        edit.adjustments.push(Adjustment {
+            main: main.clone(),
+            amount,
+            note: format!("Dispute: {}", note),
+        });
+        op.edit(edit);
+    }
+    let by: Option<String> = viewer.user.map(|u| u.character_name.into());
+    op.disputes[index].resolution = Some(Resolution {
+        resolved_at: Utc::now(),
+        resolved_by: by.clone(),
+        note: note.clone(),
+        adjustment,
+    });
+    let action = match adjustment {
+        Some(amount) => format!(
+            "Resolved the dispute over {}'s share, adjusting it by {} ISK: {}",
+            main, amount, note
+        ),
+        None => format!("Resolved the dispute over {}'s share: {}", main, note),
+    };
+    op.log(by, action);
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+pub async fn undo_edit(State(state): State<Arc<AppState>>, Path(id): Path<u64>) -> Response {
+    step_history(&state, id, Operation::undo).await
+}
+
+pub async fn redo_edit(State(state): State<Arc<AppState>>, Path(id): Path<u64>) -> Response {
+    step_history(&state, id, Operation::redo).await
+}
+
+async fn step_history(state: &AppState, id: u64, step: fn(&mut Operation) -> bool) -> Response {
+    let mut store = state.store.lock().await;
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    if op.is_finalized() {
+        return finalized();
+    }
+    if step(op) {
+        if let Err(e) = store.save().await {
+            return e.into_response();
+        }
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+pub async fn export_operation(State(state): State<Arc<AppState>>, Path(id): Path<u64>) -> Response {
+    let store = state.store.lock().await;
+    let Some(op) = store.operation(id) else {
+        return not_found();
+    };
+
+    let (total_dropped_value, payouts, corp_tax) = match &op.finalized {
+        Some(f) => (f.total_dropped_value, f.payouts.clone(), f.corp_tax),
+        None => match operation_payout(op, &store.name_book(), &store.data.settings.rules) {
+            Ok(result) => (
+                result.total_dropped_value,
+                result.wallets.into_iter().collect(),
+                result.corp_tax.cents(),
+            ),
+            Err(e) => return isk_error(e),
+        },
+    };
+    let export = OperationExport {
+        exported_at: Utc::now(),
+        // Edit history is local bookkeeping, not part of the audit record
+        operation: Operation {
+            undo_stack: vec![],
+            redo_stack: vec![],
+            participant_index: Default::default(),
+            ..op.clone()
+        },
+        total_dropped_value,
+        payouts,
+        corp_tax,
+        official: op.finalization_intact(),
+    };
+    Json(export).into_response()
+}
+
+/// Imports an exported operation under a fresh ID.
+pub async fn import_operation(
+    State(state): State<Arc<AppState>>,
+    Json(export): Json<OperationExport>,
+) -> Response {
+    let mut op = export.operation;
+    info!(
+        "Importing operation '{}' ({} kills)",
+        op.name,
+        op.kills.len()
+    );
+    op.created_at = Utc::now();
+    op.published_at = None;
+    op.share_token = None;
+    op.finalized = None;
+    op.sign_offs.clear();
+    op.disputes.clear();
+    op.audit_log.clear();
+    op.payments.clear();
+    op.undo_stack.clear();
+    op.redo_stack.clear();
+    let (name, kill_count) = (op.name.clone(), op.kills.len());
+    let id = match state.store.lock().await.insert_operation(op).await {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    notify::dispatch(
+        &state,
+        Event::OperationCreated {
+            operation_id: id,
+            operation_name: name,
+            kill_count,
+        },
+    );
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::{kill, operation};
+    use crate::views::tests::{assert_escaped, hostile_names};
+    use askama::Template;
+
+    #[test]
+    fn operation_page_escapes_hostile_names() {
+        let names = hostile_names();
+        let attackers: Vec<(i64, &str)> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (100 + i as i64, name.as_str()))
+            .collect();
+        let mut op = operation(vec![kill(1, 100_000_000.0, &attackers)]);
+        op.name = names[0].to_string();
+        let viewer = Viewer {
+            user: None,
+            sso_enabled: false,
+            read_only: false,
+        };
+        let page = operation_template(
+            &op,
+            &NameBook::default(),
+            &WorkspaceSettings::default(),
+            "",
+            viewer,
+        )
+        .unwrap();
+        assert_escaped(&page.render().unwrap());
+    }
+
+    #[test]
+    fn mobile_page_escapes_hostile_names() {
+        let names = hostile_names();
+        let attackers: Vec<(i64, &str)> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (100 + i as i64, name.as_str()))
+            .collect();
+        let mut op = operation(vec![kill(1, 100_000_000.0, &attackers)]);
+        op.name = names[0].to_string();
+        let viewer = Viewer {
+            user: None,
+            sso_enabled: false,
+            read_only: false,
+        };
+        let template = operation_template(
+            &op,
+            &NameBook::default(),
+            &WorkspaceSettings::default(),
+            "",
+            viewer,
+        )
+        .unwrap();
+        let page = MobileTemplate {
+            operation: template.operation.unwrap(),
+            start_date: template.start_date,
+            end_date: template.end_date,
+            result: template.result,
+        };
+        assert_escaped(&page.render().unwrap());
+    }
+}
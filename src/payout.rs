@@ -0,0 +1,1448 @@
+use crate::models::{Attacker, Isk, IskOutOfRange, Killmail, KillmailId};
+use crate::names::NameBook;
+use crate::script::{self, Script};
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+/// Outcome of splitting the dropped loot of a set of kills between mains.
+pub struct Payout {
+    pub wallets: HashMap<String, Isk>,
+    pub seen_mains: HashSet<String>,
+    pub total_dropped_value: Isk,
+    /// zkillboard points earned, split evenly among each kill's participants.
+    pub merit_points: HashMap<String, f64>,
+    /// Value charged against mains for their own losses (see LossNetting).
+    pub losses: HashMap<String, Isk>,
+    /// Value of the fleet's losses taken off the loot under
+    /// LossNetting::Fleet.
+    pub fleet_losses: Isk,
+    /// Reimbursed to mains for their own losses, net of the deductible,
+    /// under LossNetting::Settlement. Paid by the corp, not the loot.
+    pub srp: HashMap<String, Isk>,
+    /// Withheld for the corp wallet: the corp tax (see
+    /// PayoutRules::corp_tax_percent) plus what rounding left over.
+    pub corp_tax: Isk,
+    /// Total value of the pod kills left out (see PayoutRules::include_pods).
+    pub pod_value: Isk,
+    /// Dropped vs destroyed over the kills split.
+    pub loot_fairy: LootFairy,
+    /// The same over the kills each main was on.
+    pub pilot_loot_fairy: HashMap<String, LootFairy>,
+    /// Why the share script was set aside and the kills split with equal
+    /// weights (see PayoutRules::share_script).
+    pub script_error: Option<String>,
+}
+
+/// What the loot fairy let drop, summed over kills. Pure morale.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LootFairy {
+    pub kills: u32,
+    pub dropped: Isk,
+    pub destroyed: Isk,
+}
+
+impl LootFairy {
+    /// Pilots on fewer kills than this aren't ranked by their luck.
+    pub const MIN_KILLS: u32 = 3;
+
+    fn add(&mut self, kill: &Killmail) -> Result<(), IskOutOfRange> {
+        self.kills += 1;
+        self.dropped.increase(kill.zkb.dropped_value)?;
+        self.destroyed.increase(kill.zkb.destroyed_value)
+    }
+
+    fn remove(&mut self, kill: &Killmail) -> Result<(), IskOutOfRange> {
+        self.kills -= 1;
+        if self.kills == 0 {
+            *self = LootFairy::default();
+            return Ok(());
+        }
+        self.dropped.decrease(kill.zkb.dropped_value)?;
+        self.destroyed.decrease(kill.zkb.destroyed_value)
+    }
+
+    pub fn merge(&mut self, other: LootFairy) -> Result<(), IskOutOfRange> {
+        self.kills += other.kills;
+        self.dropped.increase(other.dropped)?;
+        self.destroyed.increase(other.destroyed)
+    }
+
+    /// Percent of the loot that dropped.
+    pub fn drop_rate(&self) -> Option<f64> {
+        let total = self.dropped.checked_add(self.destroyed).ok()?;
+        self.dropped.ratio(total).map(|ratio| ratio * 100.0)
+    }
+}
+
+/// A manual correction to one main's share, e.g. "-50m, owes SRP from
+/// last week". Applied after the split.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Adjustment {
+    pub main: String,
+    pub amount: Isk,
+    #[serde(default)]
+    pub note: String,
+}
+
+impl Payout {
+    /// Adds another payout's shares, e.g. of kills split under other rules.
+    fn merge(&mut self, other: Payout) -> Result<(), IskOutOfRange> {
+        for (main, amount) in other.wallets {
+            self.wallets.entry(main).or_default().increase(amount)?;
+        }
+        for (main, points) in other.merit_points {
+            *self.merit_points.entry(main).or_insert(0.0) += points;
+        }
+        for (main, loss) in other.losses {
+            self.losses.entry(main).or_default().increase(loss)?;
+        }
+        for (main, srp) in other.srp {
+            self.srp.entry(main).or_default().increase(srp)?;
+        }
+        self.seen_mains.extend(other.seen_mains);
+        self.total_dropped_value
+            .increase(other.total_dropped_value)?;
+        self.fleet_losses.increase(other.fleet_losses)?;
+        self.corp_tax.increase(other.corp_tax)?;
+        self.pod_value.increase(other.pod_value)?;
+        self.loot_fairy.merge(other.loot_fairy)?;
+        for (main, fairy) in other.pilot_loot_fairy {
+            self.pilot_loot_fairy
+                .entry(main)
+                .or_default()
+                .merge(fairy)?;
+        }
+        self.script_error = self.script_error.take().or(other.script_error);
+        Ok(())
+    }
+
+    pub fn apply_adjustments(
+        &mut self,
+        names: &NameBook,
+        adjustments: &[Adjustment],
+    ) -> Result<(), IskOutOfRange> {
+        for adjustment in adjustments {
+            let main = names.canonical(&adjustment.main);
+            self.seen_mains.insert(main.clone());
+            self.wallets
+                .entry(main)
+                .or_default()
+                .increase(adjustment.amount)?;
+        }
+        Ok(())
+    }
+}
+
+/// How kills whose victim is one of our mapped pilots are settled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LossNetting {
+    /// Friendly losses are treated like any other kill.
+    #[default]
+    Off,
+    /// The loss's full zKillboard value is deducted from the pilot's share.
+    TotalValue,
+    /// A flat SRP amount per loss is deducted from the pilot's share.
+    Srp,
+    /// For ganking: the full value of every ship the fleet lost, whoever
+    /// flew it, comes off the loot before it is split, so shares are net
+    /// profit. Anyone on one of the kills counts as fleet.
+    Fleet,
+    /// Losses are reimbursed (SRP) at their full value less the SRP
+    /// amount as a deductible, in the same settlement as the loot, so each
+    /// pilot gets one net transfer.
+    Settlement,
+}
+
+impl LossNetting {
+    pub fn label(self) -> &'static str {
+        match self {
+            LossNetting::Off => "Treated as normal kills",
+            LossNetting::TotalValue => "Full value deducted from the pilot's share",
+            LossNetting::Srp => "Flat SRP amount deducted from the pilot's share",
+            LossNetting::Fleet => "Fleet losses deducted from the loot",
+            LossNetting::Settlement => "Reimbursed less the SRP amount",
+        }
+    }
+}
+
+/// How the loot is divided between mains.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayoutMode {
+    /// Each kill's loot goes to the mains on that kill.
+    #[default]
+    PerKill,
+    /// All loot is pooled and split evenly between every main who was on
+    /// at least one kill.
+    Even,
+}
+
+impl PayoutMode {
+    pub fn key(self) -> &'static str {
+        match self {
+            PayoutMode::PerKill => "per_kill",
+            PayoutMode::Even => "even",
+        }
+    }
+
+    pub fn strategy(self) -> &'static dyn PayoutStrategy {
+        STRATEGIES
+            .iter()
+            .find(|(mode, _)| *mode == self)
+            .map(|(_, strategy)| *strategy)
+            .unwrap_or(&PerKillSplit)
+    }
+}
+
+/// A kill that counts towards the split, with the mains it is shared
+/// between (excluded beneficiaries already left out) and their share
+/// weights, 1 unless the share script says otherwise.
+pub struct SharedKill<'a> {
+    pub kill: &'a Killmail,
+    pub mains: Vec<String>,
+    pub weights: Vec<f64>,
+}
+
+impl SharedKill<'_> {
+    /// The weights, or equal ones where they add up to nothing.
+    fn effective_weights(&self) -> Result<Vec<Decimal>, IskOutOfRange> {
+        if self.weights.iter().sum::<f64>() > 0.0 {
+            self.weights.iter().map(|w| weight(*w)).collect()
+        } else {
+            Ok(vec![Decimal::ONE; self.mains.len()])
+        }
+    }
+}
+
+/// A share weight as the decimal the ISK is multiplied by.
+fn weight(weight: f64) -> Result<Decimal, IskOutOfRange> {
+    Decimal::from_f64(weight).ok_or(IskOutOfRange)
+}
+
+/// Adds up share weights.
+fn total_weight<'a>(
+    weights: impl IntoIterator<Item = &'a Decimal>,
+) -> Result<Decimal, IskOutOfRange> {
+    weights
+        .into_iter()
+        .try_fold(Decimal::ZERO, |total, w| total.checked_add(*w))
+        .ok_or(IskOutOfRange)
+}
+
+/// The core of a payout: who gets how much of the loot. Everything on top
+/// (solo bonus, corp tax, loss netting, rounding) is applied by calculate()
+/// the same way whatever the strategy, so one with exotic rules only has to
+/// divide the kills and be registered in STRATEGIES under its mode.
+pub trait PayoutStrategy: Send + Sync {
+    /// Short name, e.g. for the Pochven rules.
+    fn label(&self) -> &'static str;
+    /// What it does, for the settings page.
+    fn description(&self) -> &'static str;
+    /// Wallets by main. Kills without mains are never passed in.
+    fn divide(&self, kills: &[SharedKill]) -> Result<HashMap<String, Isk>, IskOutOfRange>;
+}
+
+/// The payout strategy of each mode, in the order the settings page lists
+/// them.
+pub const STRATEGIES: &[(PayoutMode, &dyn PayoutStrategy)] = &[
+    (PayoutMode::PerKill, &PerKillSplit),
+    (PayoutMode::Even, &EvenSplit),
+];
+
+/// Each kill's loot split equally between the mains on it; the default.
+pub struct PerKillSplit;
+
+impl PayoutStrategy for PerKillSplit {
+    fn label(&self) -> &'static str {
+        "Per kill"
+    }
+
+    fn description(&self) -> &'static str {
+        "each kill's loot goes to the pilots on it"
+    }
+
+    fn divide(&self, kills: &[SharedKill]) -> Result<HashMap<String, Isk>, IskOutOfRange> {
+        let mut wallets: HashMap<String, Isk> = HashMap::new();
+        for shared in kills {
+            let weights = shared.effective_weights()?;
+            let total = total_weight(&weights)?;
+            for (main, weight) in shared.mains.iter().zip(weights) {
+                let share = shared
+                    .kill
+                    .zkb
+                    .dropped_value
+                    .checked_mul(weight)?
+                    .checked_div(total)?;
+                wallets.entry(main.clone()).or_default().increase(share)?;
+            }
+        }
+        Ok(wallets)
+    }
+}
+
+/// All loot pooled and split equally between everyone on any kill.
+pub struct EvenSplit;
+
+impl PayoutStrategy for EvenSplit {
+    fn label(&self) -> &'static str {
+        "Even"
+    }
+
+    fn description(&self) -> &'static str {
+        "pool all loot and split it evenly between everyone who got a kill"
+    }
+
+    /// A main's weight is the average of their weights over their kills.
+    fn divide(&self, kills: &[SharedKill]) -> Result<HashMap<String, Isk>, IskOutOfRange> {
+        let pool = Isk::total(kills.iter().map(|s| s.kill.zkb.dropped_value))?;
+        let mut members: HashMap<&String, (f64, u32)> = HashMap::new();
+        for shared in kills {
+            for (main, weight) in shared.mains.iter().zip(&shared.weights) {
+                let (sum, count) = members.entry(main).or_default();
+                *sum += weight;
+                *count += 1;
+            }
+        }
+        let mut weights: HashMap<&String, f64> = members
+            .into_iter()
+            .map(|(main, (sum, count))| (main, sum / count as f64))
+            .collect();
+        if weights.values().sum::<f64>() <= 0.0 {
+            weights.values_mut().for_each(|w| *w = 1.0);
+        }
+        let weights: HashMap<&String, Decimal> = weights
+            .into_iter()
+            .map(|(main, w)| Ok((main, weight(w)?)))
+            .collect::<Result<_, IskOutOfRange>>()?;
+        let total = total_weight(weights.values())?;
+        weights
+            .into_iter()
+            .map(|(main, weight)| Ok((main.clone(), pool.checked_mul(weight)?.checked_div(total)?)))
+            .collect()
+    }
+}
+
+/// What shares are rounded to; the remainder goes to the corp wallet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayoutRounding {
+    #[default]
+    Isk,
+    Thousand,
+    TenThousand,
+    Million,
+}
+
+impl PayoutRounding {
+    pub const ALL: [PayoutRounding; 4] = [
+        PayoutRounding::Isk,
+        PayoutRounding::Thousand,
+        PayoutRounding::TenThousand,
+        PayoutRounding::Million,
+    ];
+
+    fn unit(self) -> Decimal {
+        match self {
+            PayoutRounding::Isk => Decimal::ONE,
+            PayoutRounding::Thousand => Decimal::ONE_THOUSAND,
+            PayoutRounding::TenThousand => Decimal::from(10_000),
+            PayoutRounding::Million => Decimal::from(1_000_000),
+        }
+    }
+
+    /// Form value, same as the serialized name.
+    pub fn key(self) -> &'static str {
+        match self {
+            PayoutRounding::Isk => "isk",
+            PayoutRounding::Thousand => "thousand",
+            PayoutRounding::TenThousand => "ten_thousand",
+            PayoutRounding::Million => "million",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            PayoutRounding::Isk => "1 ISK",
+            PayoutRounding::Thousand => "1k",
+            PayoutRounding::TenThousand => "10k",
+            PayoutRounding::Million => "1m",
+        }
+    }
+
+    /// Rounds toward zero, so nobody is paid (or charged) more than their
+    /// share.
+    fn round(self, amount: Isk) -> Result<Isk, IskOutOfRange> {
+        amount.trunc_to(self.unit())
+    }
+}
+
+/// Workspace-level adjustments on top of the plain split.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PayoutRules {
+    /// Extra share for the pilot who soloed a kill, as a percentage of that
+    /// kill's dropped value. Funded pro-rata from everyone's wallets so the
+    /// total paid out still equals the loot.
+    #[serde(default)]
+    pub solo_bonus_percent: Decimal,
+    /// Kills on pilots named in the mapping (as alt or main) become losses:
+    /// they are left out of the loot split and charged to the pilot's main,
+    /// so each wallet is a net settlement and may go negative.
+    #[serde(default)]
+    pub loss_netting: LossNetting,
+    /// ISK deducted per loss under `LossNetting::Srp`; the deductible under
+    /// `LossNetting::Settlement`.
+    #[serde(default)]
+    pub srp_amount: Isk,
+    #[serde(default)]
+    pub mode: PayoutMode,
+    /// Share of the loot withheld for the corp wallet before anyone is paid.
+    #[serde(default)]
+    pub corp_tax_percent: Decimal,
+    /// Kills that dropped less than this are left out of the split.
+    #[serde(default)]
+    pub min_kill_value: Isk,
+    /// ESI group IDs of victim ships whose kills are left out, e.g. 29
+    /// (Capsule) or 361 (Mobile Warp Disruptor).
+    #[serde(default)]
+    pub excluded_ship_groups: BTreeSet<i64>,
+    #[serde(default)]
+    pub rounding: PayoutRounding,
+    /// Kills in Pochven are split on their own under these, when set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pochven: Option<PochvenRules>,
+    /// An expression weighing each main's share of a kill (see
+    /// script::Script), for rules the settings can't express.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub share_script: Option<String>,
+    /// Pod kills take part in the split. Pods mostly carry implants, which
+    /// zKillboard values highly but rarely drop, so they are left out
+    /// unless an operation opts in (see Operation::payout_rules).
+    #[serde(skip)]
+    pub include_pods: bool,
+}
+
+/// A named set of payout rules, e.g. "Wormhole eviction", picked when an
+/// operation is saved instead of the workspace's own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PayoutPreset {
+    pub name: String,
+    pub rules: PayoutRules,
+}
+
+/// How Pochven loot is shared, where that differs from the rest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct PochvenRules {
+    #[serde(default)]
+    pub mode: PayoutMode,
+    #[serde(default)]
+    pub corp_tax_percent: Decimal,
+}
+
+impl PayoutRules {
+    /// Names whose deaths count as losses, straight from the mapping text,
+    /// plus under LossNetting::Fleet everyone on one of `kills`. Empty when
+    /// loss netting is off.
+    pub fn loss_candidates(
+        &self,
+        mapping: &HashMap<String, String>,
+        kills: &[Killmail],
+    ) -> HashSet<String> {
+        if self.loss_netting == LossNetting::Off {
+            return HashSet::new();
+        }
+        let mut candidates: HashSet<String> = mapping
+            .iter()
+            .flat_map(|(alt, main)| [alt.clone(), main.clone()])
+            .collect();
+        if self.loss_netting == LossNetting::Fleet {
+            candidates.extend(
+                kills
+                    .iter()
+                    .flat_map(|k| &k.attackers)
+                    .filter_map(|a| a.character_name.as_ref())
+                    .map(|name| name.to_string()),
+            );
+        }
+        candidates
+    }
+
+    /// Whether a kill's loot takes part in the split at all.
+    pub fn counts(&self, kill: &Killmail) -> bool {
+        kill.zkb.dropped_value >= self.min_kill_value
+            && kill
+                .victim_ship_group()
+                .is_none_or(|group| !self.excluded_ship_groups.contains(&group))
+    }
+
+    fn loss_value(&self, kill: &Killmail) -> Isk {
+        match self.loss_netting {
+            LossNetting::Off => Isk::ZERO,
+            LossNetting::TotalValue | LossNetting::Fleet => kill.zkb.total_value,
+            LossNetting::Srp => self.srp_amount,
+            LossNetting::Settlement => Isk::ZERO,
+        }
+    }
+
+    /// Withholds the corp tax from each wallet, returning the total.
+    fn tax(&self, wallets: &mut HashMap<String, Isk>) -> Result<Isk, IskOutOfRange> {
+        let mut corp_tax = Isk::ZERO;
+        if self.corp_tax_percent > Decimal::ZERO {
+            let percent = self.corp_tax_percent.min(Decimal::ONE_HUNDRED);
+            for amount in wallets.values_mut() {
+                let tax = amount.percent(percent)?;
+                corp_tax.increase(tax)?;
+                amount.decrease(tax)?;
+            }
+        }
+        Ok(corp_tax)
+    }
+
+    /// What the corp pays back for a loss under LossNetting::Settlement;
+    /// nothing for losses worth less than the deductible.
+    fn reimbursement(&self, kill: &Killmail) -> Result<Isk, IskOutOfRange> {
+        Ok(match self.loss_netting {
+            LossNetting::Settlement => kill
+                .zkb
+                .total_value
+                .checked_sub(self.srp_amount)?
+                .max(Isk::ZERO),
+            _ => Isk::ZERO,
+        })
+    }
+}
+
+/// Parses the "Alt = Main" (or "Alt: Main") mapping textarea.
+pub fn parse_mapping(input: &str) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    for line in input.lines() {
+        if let Some((alt, main)) = line.split_once([':', '=']) {
+            map.insert(alt.trim().to_string(), main.trim().to_string());
+        }
+    }
+    map
+}
+
+/// How an operation's mapping snapshot differs from the default mapping.
+#[derive(Debug, Default)]
+pub struct MappingDiff {
+    /// Lines only present in the snapshot.
+    pub added: Vec<(String, String)>,
+    /// Default lines missing from the snapshot.
+    pub removed: Vec<(String, String)>,
+    /// Alts mapped to a different main: (alt, default main, snapshot main).
+    pub changed: Vec<(String, String, String)>,
+}
+
+impl MappingDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+pub fn diff_mappings(snapshot: &str, default: &str) -> MappingDiff {
+    let snapshot: BTreeMap<String, String> = parse_mapping(snapshot).into_iter().collect();
+    let default: BTreeMap<String, String> = parse_mapping(default).into_iter().collect();
+    let mut diff = MappingDiff::default();
+    for (alt, main) in &snapshot {
+        match default.get(alt) {
+            None => diff.added.push((alt.clone(), main.clone())),
+            Some(d) if d != main => diff.changed.push((alt.clone(), d.clone(), main.clone())),
+            Some(_) => {}
+        }
+    }
+    for (alt, main) in &default {
+        if !snapshot.contains_key(alt) {
+            diff.removed.push((alt.clone(), main.clone()));
+        }
+    }
+    diff
+}
+
+/// The main an attacker flies for, by current name. `mapping` must already
+/// be canonical (see NameBook::canonical_mapping).
+pub fn attacker_main(
+    names: &NameBook,
+    mapping: &HashMap<String, String>,
+    attacker: &Attacker,
+) -> Option<String> {
+    let name = names.attacker_name(attacker)?;
+    Some(mapping.get(&name).cloned().unwrap_or(name))
+}
+
+/// The attackers of a set of kills resolved to their mains, once: toggling
+/// a kill or an excluded beneficiary then recomputes the payout without
+/// resolving anyone again. Excluded beneficiaries are still in; the split
+/// leaves them out.
+#[derive(Debug, Default)]
+pub struct ParticipantIndex {
+    /// The main of each attacker by kill ID, in attacker order.
+    mains: HashMap<KillmailId, Vec<Option<String>>>,
+    /// Where each kill is in the kills indexed.
+    positions: HashMap<KillmailId, usize>,
+    /// The positions of the kills each main was on.
+    kills_of: HashMap<String, Vec<usize>>,
+    /// The last tally, for the next to start from (see tally).
+    last_tally: Mutex<Option<TallyMemo>>,
+}
+
+impl ParticipantIndex {
+    /// `mapping` must already be canonical (see NameBook::canonical_mapping).
+    pub fn build(kills: &[Killmail], names: &NameBook, mapping: &HashMap<String, String>) -> Self {
+        let mut index = ParticipantIndex::default();
+        for (position, kill) in kills.iter().enumerate() {
+            let mains: Vec<Option<String>> = kill
+                .attackers
+                .iter()
+                .map(|a| attacker_main(names, mapping, a))
+                .collect();
+            let distinct: HashSet<&String> = mains.iter().flatten().collect();
+            for main in distinct {
+                index
+                    .kills_of
+                    .entry(main.clone())
+                    .or_default()
+                    .push(position);
+            }
+            index.positions.insert(kill.killmail_id, position);
+            index.mains.insert(kill.killmail_id, mains);
+        }
+        index
+    }
+
+    /// The tally of `kills` (the ones indexed) under their current
+    /// exclusions. Starts from the last tally taken, taking out and putting
+    /// back only the kills an exclusion toggled since touches, so a toggle
+    /// on a 5k-kill operation costs the kills of one pilot at most. Kills
+    /// valued differently since, e.g. scaled to a sale (see
+    /// Operation::payout_kills), are tallied afresh.
+    /// `excluded_names` must already be canonical.
+    fn tally(
+        &self,
+        kills: &[Killmail],
+        excluded_names: &HashSet<String>,
+        rules: &PayoutRules,
+    ) -> Result<Tally, IskOutOfRange> {
+        let mut hasher = DefaultHasher::new();
+        hash_kills(kills, &mut hasher);
+        let kills_key = hasher.finish();
+        let inactive: HashSet<KillmailId> = kills
+            .iter()
+            .filter(|k| !k.is_active)
+            .map(|k| k.killmail_id)
+            .collect();
+
+        let mut last = self.last_tally.lock().unwrap();
+        if let Some(memo) = last
+            .as_mut()
+            .filter(|m| m.rules == *rules && m.kills == kills_key)
+        {
+            let mut touched: BTreeSet<usize> = memo
+                .inactive
+                .symmetric_difference(&inactive)
+                .filter_map(|id| self.positions.get(id).copied())
+                .collect();
+            for main in memo.excluded.symmetric_difference(excluded_names) {
+                touched.extend(self.kills_of.get(main).into_iter().flatten());
+            }
+            // Past half the kills starting over is as quick
+            if touched.len() * 2 <= kills.len() {
+                let retallied = touched.into_iter().try_for_each(|position| {
+                    let kill = &kills[position];
+                    let was_active = !memo.inactive.contains(&kill.killmail_id);
+                    memo.tally
+                        .count(self, kill, was_active, &memo.excluded, rules, false)?;
+                    memo.tally
+                        .count(self, kill, kill.is_active, excluded_names, rules, true)
+                });
+                if let Err(e) = retallied {
+                    // Half counted; the next tally starts over
+                    *last = None;
+                    return Err(e);
+                }
+                memo.inactive = inactive;
+                memo.excluded = excluded_names.clone();
+                return Ok(memo.tally.clone());
+            }
+        }
+
+        *last = None;
+        let mut tally = Tally::default();
+        for kill in kills {
+            tally.count(self, kill, kill.is_active, excluded_names, rules, true)?;
+        }
+        *last = Some(TallyMemo {
+            rules: rules.clone(),
+            kills: kills_key,
+            inactive,
+            excluded: excluded_names.clone(),
+            tally: tally.clone(),
+        });
+        Ok(tally)
+    }
+
+    /// The kill's attackers with their mains; none for a kill not indexed.
+    fn attackers<'a>(
+        &'a self,
+        kill: &'a Killmail,
+    ) -> impl Iterator<Item = (&'a Attacker, Option<&'a String>)> {
+        let mains = self
+            .mains
+            .get(&kill.killmail_id)
+            .map_or(&[][..], Vec::as_slice);
+        kill.attackers
+            .iter()
+            .zip(mains)
+            .map(|(attacker, main)| (attacker, main.as_ref()))
+    }
+}
+
+/// A tally and the exclusions it was taken under.
+#[derive(Debug)]
+struct TallyMemo {
+    rules: PayoutRules,
+    /// The kills tallied (see hash_kills).
+    kills: u64,
+    inactive: HashSet<KillmailId>,
+    excluded: HashSet<String>,
+    tally: Tally,
+}
+
+/// A split added up kill by kill, before tax and rounding. Only rules
+/// under which each kill's part stands on its own can be tallied (see
+/// applies): the per-kill split without solo bonus, share script, Pochven
+/// rules or loss netting. A kill's part can then be taken out again.
+#[derive(Debug, Clone, Default)]
+struct Tally {
+    wallets: HashMap<String, Isk>,
+    merit_points: HashMap<String, f64>,
+    pilot_loot_fairy: HashMap<String, LootFairy>,
+    /// Kills counted per main, to drop a main with the last of them.
+    shares: HashMap<String, u32>,
+    /// Kills seen per main, excluded or not.
+    seen: HashMap<String, u32>,
+    total_dropped_value: Isk,
+    loot_fairy: LootFairy,
+    pod_value: Isk,
+    pods: u32,
+}
+
+impl Tally {
+    fn applies(rules: &PayoutRules) -> bool {
+        rules.mode == PayoutMode::PerKill
+            && rules.solo_bonus_percent <= Decimal::ZERO
+            && rules.share_script.is_none()
+            && rules.pochven.is_none()
+            && rules.loss_netting == LossNetting::Off
+    }
+
+    /// Adds the kill's part (as split by split()), or with `add` false
+    /// takes it out again.
+    fn count(
+        &mut self,
+        index: &ParticipantIndex,
+        kill: &Killmail,
+        active: bool,
+        excluded_names: &HashSet<String>,
+        rules: &PayoutRules,
+        add: bool,
+    ) -> Result<(), IskOutOfRange> {
+        if !active || !rules.counts(kill) {
+            return Ok(());
+        }
+        if kill.is_pod() && !rules.include_pods {
+            if add {
+                self.pods += 1;
+                self.pod_value.increase(kill.zkb.total_value)?;
+            } else {
+                self.pods -= 1;
+                self.pod_value = if self.pods == 0 {
+                    Isk::ZERO
+                } else {
+                    self.pod_value.checked_sub(kill.zkb.total_value)?
+                };
+            }
+            return Ok(());
+        }
+
+        if add {
+            self.loot_fairy.add(kill)?;
+            self.total_dropped_value.increase(kill.zkb.dropped_value)?;
+        } else {
+            self.loot_fairy.remove(kill)?;
+            self.total_dropped_value = if self.loot_fairy.kills == 0 {
+                Isk::ZERO
+            } else {
+                self.total_dropped_value
+                    .checked_sub(kill.zkb.dropped_value)?
+            };
+        }
+
+        let seen: HashSet<&String> = index.attackers(kill).filter_map(|(_, m)| m).collect();
+        for main in &seen {
+            count_kill(&mut self.seen, main, add);
+        }
+        let participants: Vec<&String> = seen
+            .into_iter()
+            .filter(|main| !excluded_names.contains(*main))
+            .collect();
+        if participants.is_empty() {
+            return Ok(());
+        }
+
+        let share = kill
+            .zkb
+            .dropped_value
+            .checked_div(Decimal::from(participants.len()))?;
+        let points = kill.zkb.points as f64 / participants.len() as f64;
+        for main in participants {
+            if add {
+                self.pilot_loot_fairy
+                    .entry(main.clone())
+                    .or_default()
+                    .add(kill)?;
+            } else if let Some(fairy) = self.pilot_loot_fairy.get_mut(main) {
+                fairy.remove(kill)?;
+            }
+            let wallet = self.wallets.entry(main.clone()).or_default();
+            let merit_points = self.merit_points.entry(main.clone()).or_insert(0.0);
+            if add {
+                wallet.increase(share)?;
+                *merit_points += points;
+            } else {
+                wallet.decrease(share)?;
+                *merit_points -= points;
+            }
+            if !count_kill(&mut self.shares, main, add) {
+                self.wallets.remove(main);
+                self.merit_points.remove(main);
+                self.pilot_loot_fairy.remove(main);
+            }
+        }
+        Ok(())
+    }
+
+    fn payout(self, rules: &PayoutRules) -> Result<Payout, IskOutOfRange> {
+        let mut wallets = self.wallets;
+        let corp_tax = rules.tax(&mut wallets)?;
+        Ok(Payout {
+            wallets,
+            seen_mains: self.seen.into_keys().collect(),
+            total_dropped_value: self.total_dropped_value,
+            merit_points: self.merit_points,
+            losses: HashMap::new(),
+            fleet_losses: Isk::ZERO,
+            srp: HashMap::new(),
+            corp_tax,
+            pod_value: self.pod_value,
+            loot_fairy: self.loot_fairy,
+            pilot_loot_fairy: self.pilot_loot_fairy,
+            script_error: None,
+        })
+    }
+}
+
+/// Counts a kill for `main` up or down, returning whether any are left.
+fn count_kill(counts: &mut HashMap<String, u32>, main: &str, add: bool) -> bool {
+    if add {
+        *counts.entry(main.to_string()).or_default() += 1;
+        return true;
+    }
+    let Some(count) = counts.get_mut(main) else {
+        return false;
+    };
+    *count -= 1;
+    if *count == 0 {
+        counts.remove(main);
+        return false;
+    }
+    true
+}
+
+/// Feeds what a split reads from `kills` besides whether they are active
+/// into `hasher`: their values and who was on them. Whether a kill is
+/// active is left out; the index doesn't depend on it and tally takes
+/// toggled kills out and back in by itself.
+fn hash_kills(kills: &[Killmail], hasher: &mut impl Hasher) {
+    for kill in kills {
+        kill.killmail_id.hash(hasher);
+        kill.zkb.dropped_value.hash(hasher);
+        kill.zkb.total_value.hash(hasher);
+        kill.zkb.points.hash(hasher);
+        for attacker in &kill.attackers {
+            attacker.character_id.hash(hasher);
+            attacker.character_name.hash(hasher);
+        }
+    }
+}
+
+/// An operation's ParticipantIndex, kept until its kills (their IDs,
+/// values or attackers), its mapping or the names it was resolved with
+/// change.
+#[derive(Debug, Default)]
+pub struct ParticipantCache(Mutex<Option<(u64, Arc<ParticipantIndex>)>>);
+
+impl Clone for ParticipantCache {
+    fn clone(&self) -> Self {
+        ParticipantCache(Mutex::new(self.0.lock().unwrap().clone()))
+    }
+}
+
+impl ParticipantCache {
+    pub fn get_or_build(
+        &self,
+        kills: &[Killmail],
+        names: &NameBook,
+        mapping_text: &str,
+    ) -> Arc<ParticipantIndex> {
+        let mut hasher = DefaultHasher::new();
+        mapping_text.hash(&mut hasher);
+        names.fingerprint().hash(&mut hasher);
+        hash_kills(kills, &mut hasher);
+        let key = hasher.finish();
+
+        let mut cached = self.0.lock().unwrap();
+        if let Some((_, index)) = cached.as_ref().filter(|(k, _)| *k == key) {
+            return index.clone();
+        }
+        let mapping = names.canonical_mapping(&parse_mapping(mapping_text));
+        let index = Arc::new(ParticipantIndex::build(kills, names, &mapping));
+        *cached = Some((key, index.clone()));
+        index
+    }
+}
+
+/// Capitalist split: each active kill's dropped value is divided among the
+/// mains present on that kill (see PayoutStrategy), skipping excluded
+/// beneficiaries and kills the rules leave out. Mains are
+/// identified through their character IDs, so renames neither split nor
+/// drop a wallet; keys are the mains' current names. With PochvenRules set,
+/// Pochven kills are split separately under them and the shares added up.
+/// Shares are rounded last (see PayoutRounding), so every view and export
+/// of a payout shows the same amounts. Fails rather than pay out amounts
+/// past what the accounting can hold.
+pub fn calculate(
+    kills: &[Killmail],
+    names: &NameBook,
+    mapping: &HashMap<String, String>,
+    excluded_names: &HashSet<String>,
+    rules: &PayoutRules,
+) -> Result<Payout, IskOutOfRange> {
+    let index = ParticipantIndex::build(kills, names, &names.canonical_mapping(mapping));
+    calculate_indexed(kills, names, &index, mapping, excluded_names, rules)
+}
+
+/// calculate with the attackers already resolved, e.g. an operation's
+/// (see Operation::participants).
+pub fn calculate_indexed(
+    kills: &[Killmail],
+    names: &NameBook,
+    index: &ParticipantIndex,
+    mapping: &HashMap<String, String>,
+    excluded_names: &HashSet<String>,
+    rules: &PayoutRules,
+) -> Result<Payout, IskOutOfRange> {
+    let mut payout = if Tally::applies(rules) {
+        index
+            .tally(kills, &names.canonical_set(excluded_names), rules)?
+            .payout(rules)?
+    } else {
+        match rules.pochven {
+            Some(pochven) => {
+                split_pochven(kills, names, index, mapping, excluded_names, rules, pochven)?
+            }
+            None => split(kills, names, index, mapping, excluded_names, rules)?,
+        }
+    };
+    for amount in payout.wallets.values_mut() {
+        let rounded = rules.rounding.round(*amount)?;
+        payout.corp_tax.increase(amount.checked_sub(rounded)?)?;
+        *amount = rounded;
+    }
+    Ok(payout)
+}
+
+fn split_pochven(
+    kills: &[Killmail],
+    names: &NameBook,
+    index: &ParticipantIndex,
+    mapping: &HashMap<String, String>,
+    excluded_names: &HashSet<String>,
+    rules: &PayoutRules,
+    pochven: PochvenRules,
+) -> Result<Payout, IskOutOfRange> {
+    let (in_pochven, elsewhere): (Vec<Killmail>, Vec<Killmail>) =
+        kills.iter().cloned().partition(Killmail::in_pochven);
+    let pochven_rules = PayoutRules {
+        mode: pochven.mode,
+        corp_tax_percent: pochven.corp_tax_percent,
+        ..rules.clone()
+    };
+    let mut payout = split(&elsewhere, names, index, mapping, excluded_names, rules)?;
+    payout.merge(split(
+        &in_pochven,
+        names,
+        index,
+        mapping,
+        excluded_names,
+        &pochven_rules,
+    )?)?;
+    Ok(payout)
+}
+
+fn split(
+    kills: &[Killmail],
+    names: &NameBook,
+    index: &ParticipantIndex,
+    mapping: &HashMap<String, String>,
+    excluded_names: &HashSet<String>,
+    rules: &PayoutRules,
+) -> Result<Payout, IskOutOfRange> {
+    let mapping = names.canonical_mapping(mapping);
+    let excluded_names = names.canonical_set(excluded_names);
+    let mut seen_mains: HashSet<String> = HashSet::new();
+    let mut merit_points: HashMap<String, f64> = HashMap::new();
+    let mut solo_bonuses: HashMap<String, Isk> = HashMap::new();
+    let mut losses: HashMap<String, Isk> = HashMap::new();
+    let mut fleet_losses = Isk::ZERO;
+    let mut srp: HashMap<String, Isk> = HashMap::new();
+    let mut total_dropped_value = Isk::ZERO;
+    let mut pod_value = Isk::ZERO;
+    let mut loot_fairy = LootFairy::default();
+    let mut pilot_loot_fairy: HashMap<String, LootFairy> = HashMap::new();
+    let mut shared_kills: Vec<SharedKill> = Vec::new();
+    let fleet: HashSet<String> = if rules.loss_netting == LossNetting::Fleet {
+        kills
+            .iter()
+            .filter(|k| k.is_active)
+            .flat_map(|k| &k.attackers)
+            .filter_map(|a| names.attacker_name(a))
+            .collect()
+    } else {
+        HashSet::new()
+    };
+    let friendly: HashSet<&String> = if rules.loss_netting == LossNetting::Off {
+        HashSet::new()
+    } else {
+        mapping
+            .keys()
+            .chain(mapping.values())
+            .chain(&fleet)
+            .collect()
+    };
+
+    for kill in kills {
+        if !kill.is_active {
+            continue;
+        }
+
+        let victim = kill.victim.as_ref().and_then(|v| names.victim_name(v));
+        if let Some(victim) = victim.filter(|v| friendly.contains(v)) {
+            let main = mapping.get(&victim).cloned().unwrap_or(victim);
+            seen_mains.insert(main.clone());
+            if rules.loss_netting == LossNetting::Fleet {
+                fleet_losses.increase(rules.loss_value(kill))?;
+            } else if rules.loss_netting == LossNetting::Settlement {
+                if !excluded_names.contains(&main) {
+                    srp.entry(main)
+                        .or_default()
+                        .increase(rules.reimbursement(kill)?)?;
+                }
+            } else if !excluded_names.contains(&main) {
+                losses
+                    .entry(main)
+                    .or_default()
+                    .increase(rules.loss_value(kill))?;
+            }
+            continue;
+        }
+        if !rules.counts(kill) {
+            continue;
+        }
+        if kill.is_pod() && !rules.include_pods {
+            pod_value.increase(kill.zkb.total_value)?;
+            continue;
+        }
+
+        total_dropped_value.increase(kill.zkb.dropped_value)?;
+        loot_fairy.add(kill)?;
+
+        let mut kill_participants: HashSet<String> = HashSet::new();
+        for main in index.attackers(kill).filter_map(|(_, main)| main) {
+            seen_mains.insert(main.clone());
+            if !excluded_names.contains(main) {
+                kill_participants.insert(main.clone());
+            }
+        }
+
+        if kill_participants.is_empty() {
+            continue;
+        }
+
+        let participant_count = kill_participants.len() as f64;
+        let points_per_pilot = kill.zkb.points as f64 / participant_count;
+
+        if kill.zkb.solo && rules.solo_bonus_percent > Decimal::ZERO {
+            let soloist = index
+                .attackers(kill)
+                .filter(|(a, _)| a.character_id.is_some())
+                .max_by_key(|(a, _)| a.final_blow)
+                .and_then(|(_, main)| main.cloned())
+                .filter(|main| kill_participants.contains(main));
+            if let Some(main) = soloist {
+                let bonus = kill.zkb.dropped_value.percent(rules.solo_bonus_percent)?;
+                solo_bonuses.entry(main).or_default().increase(bonus)?;
+            }
+        }
+
+        for main in &kill_participants {
+            pilot_loot_fairy
+                .entry(main.clone())
+                .or_default()
+                .add(kill)?;
+            *merit_points.entry(main.clone()).or_insert(0.0) += points_per_pilot;
+        }
+        let mains: Vec<String> = kill_participants.into_iter().collect();
+        shared_kills.push(SharedKill {
+            kill,
+            weights: vec![1.0; mains.len()],
+            mains,
+        });
+    }
+
+    let mut script_error = None;
+    if let Some(source) = &rules.share_script {
+        if let Err(e) = weigh(&mut shared_kills, kills, names, index, &mapping, source) {
+            for shared in &mut shared_kills {
+                shared.weights.fill(1.0);
+            }
+            script_error = Some(e);
+        }
+    }
+    let mut wallets = rules.mode.strategy().divide(&shared_kills)?;
+
+    // Ganking: only the profit is shared; a losing op pays out nothing
+    let gross = Isk::total(wallets.values().copied())?;
+    if fleet_losses > Isk::ZERO && gross > Isk::ZERO {
+        let net = gross.checked_sub(fleet_losses)?.max(Isk::ZERO);
+        for amount in wallets.values_mut() {
+            *amount = amount.scale(net, gross)?;
+        }
+    }
+
+    if !solo_bonuses.is_empty() {
+        let distributed = Isk::total(wallets.values().copied())?;
+        for (main, bonus) in solo_bonuses {
+            wallets.entry(main).or_default().increase(bonus)?;
+        }
+        let inflated = Isk::total(wallets.values().copied())?;
+        if inflated > Isk::ZERO {
+            for amount in wallets.values_mut() {
+                *amount = amount.scale(distributed, inflated)?;
+            }
+        }
+    }
+
+    let corp_tax = rules.tax(&mut wallets)?;
+
+    for (main, loss) in &losses {
+        wallets.entry(main.clone()).or_default().decrease(*loss)?;
+    }
+    // Untaxed: the corp pays it back, it isn't loot
+    for (main, reimbursement) in &srp {
+        wallets
+            .entry(main.clone())
+            .or_default()
+            .increase(*reimbursement)?;
+    }
+
+    Ok(Payout {
+        wallets,
+        seen_mains,
+        total_dropped_value,
+        merit_points,
+        losses,
+        fleet_losses,
+        srp,
+        corp_tax,
+        pod_value,
+        loot_fairy,
+        pilot_loot_fairy,
+        script_error,
+    })
+}
+
+/// Percent of the kill's damage done by `flown`; 0 when the kill has no
+/// damage on record.
+fn damage_percent(kill: &Killmail, flown: &[&Attacker]) -> f64 {
+    let total: i64 = kill.attackers.iter().map(|a| a.damage_done).sum();
+    if total <= 0 {
+        return 0.0;
+    }
+    flown.iter().map(|a| a.damage_done).sum::<i64>() as f64 * 100.0 / total as f64
+}
+
+/// Sets the share weights of `shared` from the share script. `kills` are
+/// all of the payout's kills, for the mains' own losses.
+fn weigh(
+    shared: &mut [SharedKill],
+    kills: &[Killmail],
+    names: &NameBook,
+    index: &ParticipantIndex,
+    mapping: &HashMap<String, String>,
+    source: &str,
+) -> Result<(), String> {
+    let script = Script::parse(source)?;
+    let mut losses: HashMap<String, u32> = HashMap::new();
+    for kill in kills.iter().filter(|k| k.is_active) {
+        if let Some(victim) = kill.victim.as_ref().and_then(|v| names.victim_name(v)) {
+            let main = mapping.get(&victim).cloned().unwrap_or(victim);
+            *losses.entry(main).or_default() += 1;
+        }
+    }
+
+    let budget = script::Budget::new();
+    for shared in shared.iter_mut() {
+        let kill = shared.kill;
+        let pilots = shared.mains.len() as i64;
+        for (main, weight) in shared.mains.iter().zip(shared.weights.iter_mut()) {
+            let flown: Vec<&Attacker> = index
+                .attackers(kill)
+                .filter(|(_, m)| *m == Some(main))
+                .map(|(a, _)| a)
+                .collect();
+            let ships: rhai::Array = flown
+                .iter()
+                .filter_map(|a| a.ship_type_name.as_deref())
+                .map(|ship| ship.to_string().into())
+                .collect();
+            let mut scope = rhai::Scope::new();
+            scope
+                .push_constant("ship", ships.first().cloned().unwrap_or_else(|| "".into()))
+                .push_constant("ships", ships)
+                .push_constant("final_blow", flown.iter().any(|a| a.final_blow))
+                .push_constant("damage", damage_percent(kill, &flown))
+                .push_constant("pilots", pilots)
+                .push_constant("value", kill.zkb.dropped_value.to_f64())
+                .push_constant("solo", kill.zkb.solo)
+                .push_constant("npc", kill.zkb.npc)
+                .push_constant("awox", kill.zkb.awox)
+                .push_constant("points", kill.zkb.points as i64)
+                .push_constant(
+                    "labels",
+                    kill.zkb
+                        .labels
+                        .iter()
+                        .map(|label| label.clone().into())
+                        .collect::<rhai::Array>(),
+                )
+                .push_constant("pochven", kill.in_pochven())
+                .push_constant("losses", losses.get(main).copied().unwrap_or(0) as i64);
+            *weight = script
+                .weight(&mut scope, &budget)
+                .map_err(|e| format!("{} (kill {}, {})", e, kill.killmail_id, main))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::kill;
+
+    fn isk(amount: i64) -> Isk {
+        Isk(Decimal::from(amount))
+    }
+
+    #[test]
+    fn rounding_leaves_the_remainder_to_the_corp() {
+        let kills: Vec<Killmail> = vec![serde_json::from_value(kill(
+            1,
+            100_000_000.0,
+            &[(100, "Alice"), (101, "Bob"), (102, "Carol")],
+        ))
+        .unwrap()];
+        let rules = PayoutRules {
+            rounding: PayoutRounding::Thousand,
+            ..PayoutRules::default()
+        };
+        let payout = calculate(
+            &kills,
+            &NameBook::default(),
+            &HashMap::new(),
+            &HashSet::new(),
+            &rules,
+        )
+        .unwrap();
+        for main in ["Alice", "Bob", "Carol"] {
+            assert_eq!(payout.wallets[main], isk(33_333_000));
+        }
+        let paid = Isk::total(payout.wallets.values().copied()).unwrap();
+        assert_eq!(paid.checked_add(payout.corp_tax), Ok(isk(100_000_000)));
+        assert_eq!(payout.corp_tax.cents(), isk(1_000));
+    }
+
+    #[test]
+    fn corp_tax_percentages_are_exact_and_stored_as_numbers() {
+        let rules: PayoutRules =
+            serde_json::from_value(serde_json::json!({"corp_tax_percent": 12.5})).unwrap();
+        assert_eq!(rules.corp_tax_percent, Decimal::new(125, 1));
+        assert_eq!(
+            serde_json::to_value(&rules).unwrap()["corp_tax_percent"],
+            serde_json::json!(12.5)
+        );
+
+        let kills: Vec<Killmail> = vec![serde_json::from_value(kill(
+            1,
+            100_000_000.0,
+            &[(100, "Alice"), (101, "Bob"), (102, "Carol")],
+        ))
+        .unwrap()];
+        let payout = calculate(
+            &kills,
+            &NameBook::default(),
+            &HashMap::new(),
+            &HashSet::new(),
+            &rules,
+        )
+        .unwrap();
+        let paid = Isk::total(payout.wallets.values().copied()).unwrap();
+        assert_eq!(payout.corp_tax.cents(), isk(12_500_002));
+        assert_eq!(paid, isk(87_499_998));
+    }
+
+    #[test]
+    fn amounts_out_of_range_fail_the_payout() {
+        let mut kills: Vec<Killmail> = [
+            kill(1, 0.0, &[(100, "Alice")]),
+            kill(2, 1_000_000.0, &[(100, "Alice")]),
+        ]
+        .into_iter()
+        .map(|k| serde_json::from_value(k).unwrap())
+        .collect();
+        kills[0].zkb.dropped_value = Isk(Decimal::MAX);
+        let payout = calculate(
+            &kills,
+            &NameBook::default(),
+            &HashMap::new(),
+            &HashSet::new(),
+            &PayoutRules::default(),
+        );
+        assert_eq!(payout.err(), Some(IskOutOfRange));
+
+        assert_eq!(Isk::from_f64(f64::NAN), Err(IskOutOfRange));
+        assert_eq!(Isk::from_f64(f64::INFINITY), Err(IskOutOfRange));
+        assert_eq!(isk(1).checked_div(Decimal::ZERO), Err(IskOutOfRange));
+    }
+
+    #[test]
+    fn fleet_netting_splits_the_profit_after_the_fleets_losses() {
+        let mut gank = kill(2, 0.0, &[]);
+        gank["zkb"]["totalValue"] = serde_json::json!(30_000_000.0);
+        gank["victim"] = serde_json::json!({"character_id": 100, "character_name": "Alice",
+            "corporation_name": null, "ship_type_id": 17480, "ship_type_name": "Catalyst"});
+        let kills: Vec<Killmail> = vec![
+            serde_json::from_value(kill(1, 100_000_000.0, &[(100, "Alice"), (101, "Bob")]))
+                .unwrap(),
+            serde_json::from_value(gank).unwrap(),
+        ];
+        let rules = PayoutRules {
+            loss_netting: LossNetting::Fleet,
+            ..PayoutRules::default()
+        };
+        assert!(rules
+            .loss_candidates(&HashMap::new(), &kills)
+            .contains("Alice"));
+        let payout = calculate(
+            &kills,
+            &NameBook::default(),
+            &HashMap::new(),
+            &HashSet::new(),
+            &rules,
+        )
+        .unwrap();
+        assert_eq!(payout.fleet_losses, isk(30_000_000));
+        assert!(payout.losses.is_empty());
+        for main in ["Alice", "Bob"] {
+            assert_eq!(payout.wallets[main], isk(35_000_000));
+        }
+    }
+
+    #[test]
+    fn settlement_netting_pays_srp_less_the_deductible_with_the_share() {
+        let mut loss = kill(2, 0.0, &[]);
+        loss["zkb"]["totalValue"] = serde_json::json!(50_000_000.0);
+        loss["victim"] = serde_json::json!({"character_id": 102, "character_name": "Bob Alt",
+            "corporation_name": null, "ship_type_id": 24690, "ship_type_name": "Hurricane"});
+        let kills: Vec<Killmail> = vec![
+            serde_json::from_value(kill(1, 100_000_000.0, &[(100, "Alice"), (101, "Bob")]))
+                .unwrap(),
+            serde_json::from_value(loss).unwrap(),
+        ];
+        let rules = PayoutRules {
+            loss_netting: LossNetting::Settlement,
+            srp_amount: isk(10_000_000),
+            ..PayoutRules::default()
+        };
+        let payout = calculate(
+            &kills,
+            &NameBook::default(),
+            &HashMap::from([("Bob Alt".to_string(), "Bob".to_string())]),
+            &HashSet::new(),
+            &rules,
+        )
+        .unwrap();
+        assert_eq!(payout.srp["Bob"], isk(40_000_000));
+        assert!(payout.losses.is_empty());
+        assert_eq!(payout.wallets["Alice"], isk(50_000_000));
+        assert_eq!(payout.wallets["Bob"], isk(90_000_000));
+    }
+
+    #[test]
+    fn toggling_kills_updates_the_tally_like_a_fresh_split() {
+        let mut kills: Vec<Killmail> = [
+            kill(
+                1,
+                90_000_000.0,
+                &[(100, "Alice"), (101, "Bob"), (102, "Carol")],
+            ),
+            kill(2, 40_000_000.0, &[(100, "Alice"), (101, "Bob")]),
+            kill(3, 10_000_000.0, &[(102, "Carol")]),
+            kill(4, 5_000_000.0, &[(103, "Dave")]),
+        ]
+        .into_iter()
+        .map(|k| serde_json::from_value(k).unwrap())
+        .collect();
+        let names = NameBook::default();
+        let mapping = HashMap::new();
+        let rules = PayoutRules::default();
+        let index = ParticipantIndex::build(&kills, &names, &mapping);
+
+        let mut excluded = HashSet::new();
+        calculate_indexed(&kills, &names, &index, &mapping, &excluded, &rules).unwrap();
+        for step in 0..3 {
+            match step {
+                0 => kills[1].is_active = false,
+                1 => {
+                    excluded.insert("Carol".to_string());
+                }
+                _ => {
+                    kills[1].is_active = true;
+                    kills[3].is_active = false;
+                }
+            }
+            let tallied =
+                calculate_indexed(&kills, &names, &index, &mapping, &excluded, &rules).unwrap();
+            let fresh = calculate(&kills, &names, &mapping, &excluded, &rules).unwrap();
+            assert_eq!(tallied.seen_mains, fresh.seen_mains, "step {}", step);
+            assert_eq!(tallied.wallets, fresh.wallets, "step {}", step);
+            assert_eq!(tallied.total_dropped_value, fresh.total_dropped_value);
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use crate::logic::fetch_zkill_data;
+use crate::models::AppState;
+use crate::request_id;
+
+use chrono::{DateTime, Duration, Utc};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// How far back watched entities are kept warm.
+pub const WARM_DAYS: i64 = 7;
+
+/// How the last warming run of a watched link went.
+#[derive(Debug, Clone)]
+pub struct PrefetchStatus {
+    pub at: DateTime<Utc>,
+    /// "312 kills, 2 pages" or the fetch error.
+    pub outcome: String,
+    pub ok: bool,
+}
+
+/// Re-fetches the watched entities (workspace settings) every
+/// `EVE_LOOTER_PREFETCH_MINUTES`, so their killmails sit in the ESI cache
+/// and their names are resolved before anyone asks for them. Runs through
+/// the shared rate limiters like any interactive fetch.
+pub fn spawn(state: Arc<AppState>) {
+    let every = std::time::Duration::from_secs(state.config.prefetch_minutes * 60);
+    tokio::spawn(async move {
+        loop {
+            request_id::job("prefetch", warm_all(&state)).await;
+            tokio::time::sleep(every).await;
+        }
+    });
+}
+
+async fn warm_all(state: &Arc<AppState>) {
+    // No request syncs the store for this job (see main::sync_store)
+    state.store.sync().await;
+    let watched = state.store.lock().await.data.settings.watched.clone();
+    let _job = state
+        .diagnostics
+        .start_job("prefetch", format!("{} watched links", watched.len()));
+    // Links no longer watched don't keep a stale status around
+    state
+        .prefetch_status
+        .lock()
+        .unwrap()
+        .retain(|link, _| watched.contains(link));
+    for link in watched {
+        let start_cutoff = Utc::now() - Duration::days(WARM_DAYS);
+        // Resuming lets a run cut short by rate limiting continue next time
+        let status = match fetch_zkill_data(&link, state, start_cutoff, true).await {
+            Ok(fetched) => {
+                info!("Warmed {}: {:?}", link, fetched.report);
+                PrefetchStatus {
+                    at: Utc::now(),
+                    outcome: format!(
+                        "{} kills with loot, {} pages, {} from ESI",
+                        fetched.kills.len(),
+                        fetched.report.pages_fetched,
+                        fetched.report.kills_from_esi
+                    ),
+                    ok: true,
+                }
+            }
+            Err(e) => {
+                warn!("Warming {} failed: {:?}", link, e);
+                PrefetchStatus {
+                    at: Utc::now(),
+                    outcome: e.to_string(),
+                    ok: false,
+                }
+            }
+        };
+        state.prefetch_status.lock().unwrap().insert(link, status);
+    }
+}
@@ -0,0 +1,533 @@
+use crate::auth::{self, Viewer};
+use crate::base_path;
+use crate::models::*;
+use crate::store::{self, GrantKind, StoreData};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use tracing::info;
+
+/// A pilot asking for their data to be erased; admins carry it out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeletionRequest {
+    pub character_name: String,
+    pub requested_at: DateTime<Utc>,
+}
+
+/// Everything stored about one character.
+#[derive(Serialize)]
+pub struct CharacterExport {
+    pub character_id: CharacterId,
+    pub character_name: String,
+    /// Names the character was seen with, with when.
+    pub name_history: Vec<(String, DateTime<Utc>)>,
+    pub leaderboard_opted_out: bool,
+    /// (token prefix, created at); the tokens themselves stay secret.
+    pub api_tokens: Vec<(String, DateTime<Utc>)>,
+    pub default_mapping_lines: Vec<String>,
+    pub operations: Vec<OperationExport>,
+    pub deletion_requested_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct OperationExport {
+    pub id: u64,
+    pub name: String,
+    pub start_date: String,
+    pub end_date: String,
+    pub was_fc: bool,
+    /// Kill IDs the character was on, as attacker.
+    pub kills: Vec<KillmailId>,
+    /// Kill IDs the character was the victim of.
+    pub losses: Vec<KillmailId>,
+    pub mapping_lines: Vec<String>,
+    pub holdings_lines: Vec<String>,
+    pub adjustments: Vec<(Isk, String)>,
+    pub excluded_from_payout: bool,
+    pub payment: Option<(Isk, DateTime<Utc>)>,
+}
+
+/// The character's current and former names.
+fn names_of(data: &StoreData, character_id: CharacterId, current: &str) -> HashSet<String> {
+    let mut names: HashSet<String> = data
+        .name_history
+        .get(&character_id)
+        .into_iter()
+        .flatten()
+        .map(|r| r.name.clone())
+        .collect();
+    names.insert(current.to_string());
+    names
+}
+
+/// Replaces `name` in free text where it stands on its own, so erasing
+/// "Al" leaves "Alice" and "Al-Rashid" as they are.
+fn replace_name(text: &str, name: &str, pseudonym: &str) -> String {
+    let part_of_name =
+        |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || matches!(c, '-' | '\'' | '_'));
+    let mut replaced = String::with_capacity(text.len());
+    let mut copied = 0;
+    for (at, _) in text.match_indices(name) {
+        let end = at + name.len();
+        // A possessive still ends the name, as in "Bob's share"
+        let after = text[end..].strip_prefix("'s").unwrap_or(&text[end..]);
+        if at < copied
+            || part_of_name(text[..at].chars().next_back())
+            || part_of_name(after.chars().next())
+        {
+            continue;
+        }
+        replaced.push_str(&text[copied..at]);
+        replaced.push_str(pseudonym);
+        copied = end;
+    }
+    replaced.push_str(&text[copied..]);
+    replaced
+}
+
+/// Lines of "Name = ..." text naming one of `names` on either side.
+fn lines_naming<'a>(text: &'a str, names: &'a HashSet<String>) -> impl Iterator<Item = &'a str> {
+    text.lines().filter(|line| {
+        line.split([':', '='])
+            .any(|part| names.contains(part.trim()))
+    })
+}
+
+fn export(data: &StoreData, character_id: CharacterId, character_name: &str) -> CharacterExport {
+    let names = names_of(data, character_id, character_name);
+    let lines = |text: &str| {
+        lines_naming(text, &names)
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+
+    let operations = data
+        .operations
+        .iter()
+        .filter_map(|op| {
+            let export = OperationExport {
+                id: op.id,
+                name: op.name.clone(),
+                start_date: op.start_date.clone(),
+                end_date: op.end_date.clone(),
+                was_fc: names.contains(op.fc.trim()),
+                kills: op
+                    .kills
+                    .iter()
+                    .filter(|k| {
+                        k.attackers
+                            .iter()
+                            .any(|a| a.character_id == Some(character_id))
+                    })
+                    .map(|k| k.killmail_id)
+                    .collect(),
+                losses: op
+                    .kills
+                    .iter()
+                    .filter(|k| {
+                        k.victim
+                            .as_ref()
+                            .is_some_and(|v| v.character_id == Some(character_id))
+                    })
+                    .map(|k| k.killmail_id)
+                    .collect(),
+                mapping_lines: lines(&op.mapping_text),
+                holdings_lines: lines(&op.holdings_text),
+                adjustments: op
+                    .adjustments
+                    .iter()
+                    .filter(|a| names.contains(a.main.trim()))
+                    .map(|a| (a.amount, a.note.clone()))
+                    .collect(),
+                excluded_from_payout: op.excluded_beneficiaries.iter().any(|b| names.contains(b)),
+                payment: op
+                    .payments
+                    .iter()
+                    .find(|(main, _)| names.contains(*main))
+                    .map(|(_, p)| (p.amount, p.paid_at)),
+            };
+            let involved = export.was_fc
+                || !export.kills.is_empty()
+                || !export.losses.is_empty()
+                || !export.mapping_lines.is_empty()
+                || !export.holdings_lines.is_empty()
+                || !export.adjustments.is_empty()
+                || export.excluded_from_payout
+                || export.payment.is_some();
+            involved.then_some(export)
+        })
+        .collect();
+
+    CharacterExport {
+        character_id,
+        character_name: character_name.to_string(),
+        name_history: data
+            .name_history
+            .get(&character_id)
+            .into_iter()
+            .flatten()
+            .map(|r| (r.name.clone(), r.seen_at))
+            .collect(),
+        leaderboard_opted_out: data.leaderboard_opt_outs.iter().any(|n| names.contains(n)),
+        api_tokens: data
+            .api_tokens
+            .iter()
+            .filter(|(_, t)| t.character_id == character_id)
+            .map(|(token, t)| (token[..8].to_string(), t.created_at))
+            .collect(),
+        default_mapping_lines: lines(&data.default_mapping),
+        operations,
+        deletion_requested_at: data
+            .deletion_requests
+            .get(&character_id)
+            .map(|r| r.requested_at),
+    }
+}
+
+/// Replaces the character with a pseudonym everywhere and drops what is
+/// keyed to them. Operations keep their payouts balanced: the share is
+/// still there, it just no longer says whose it was. Finalized payouts that
+/// were intact get an amendment from `erased_by` rather than a new hash.
+/// Returns the pseudonym.
+fn erase(
+    data: &mut StoreData,
+    character_id: CharacterId,
+    character_name: &str,
+    erased_by: Option<String>,
+) -> String {
+    let names = names_of(data, character_id, character_name);
+    let pseudonym = format!("Redacted Pilot {}", &auth::random_token()[..6]);
+    let rename = |name: &mut String| {
+        if names.contains(name.trim()) {
+            *name = pseudonym.clone();
+        }
+    };
+    // Only the sides naming the character change; every other byte,
+    // spacing and line endings included, stays as the FC typed it
+    let rewrite = |text: &str| -> String {
+        text.split_inclusive('\n')
+            .map(|line| {
+                let Some(at) = line.find([':', '=']) else {
+                    return line.to_string();
+                };
+                let (left, right) = (&line[..at], &line[at + 1..]);
+                let side = |part: &str| {
+                    let name = part.trim();
+                    if names.contains(name) {
+                        part.replacen(name, &pseudonym, 1)
+                    } else {
+                        part.to_string()
+                    }
+                };
+                format!("{}{}{}", side(left), &line[at..at + 1], side(right))
+            })
+            .collect()
+    };
+    // Longest first, so a name that is part of an older, longer one doesn't
+    // leave the rest of it behind
+    let mut by_length: Vec<&String> = names.iter().filter(|n| !n.is_empty()).collect();
+    by_length.sort_by_key(|n| std::cmp::Reverse(n.len()));
+    let rename_tokens = |text: &str| -> String {
+        by_length.iter().fold(text.to_string(), |text, name| {
+            replace_name(&text, name, &pseudonym)
+        })
+    };
+
+    for op in &mut data.operations {
+        let intact = op.finalization_intact();
+        for kill in &mut op.kills {
+            for attacker in &mut kill.attackers {
+                if attacker.character_id == Some(character_id) {
+                    attacker.character_id = None;
+                    attacker.character_name = Some(pseudonym.as_str().into());
+                }
+            }
+            if let Some(victim) = &mut kill.victim {
+                if victim.character_id == Some(character_id) {
+                    victim.character_id = None;
+                    victim.character_name = Some(pseudonym.as_str().into());
+                }
+            }
+        }
+        op.mapping_text = rewrite(&op.mapping_text);
+        op.holdings_text = rewrite(&op.holdings_text);
+        rename(&mut op.fc);
+        for adjustment in &mut op.adjustments {
+            rename(&mut adjustment.main);
+        }
+        op.excluded_beneficiaries = op
+            .excluded_beneficiaries
+            .drain()
+            .map(|mut b| {
+                rename(&mut b);
+                b
+            })
+            .collect();
+        op.payments = std::mem::take(&mut op.payments)
+            .into_iter()
+            .map(|(mut main, p)| {
+                rename(&mut main);
+                (main, p)
+            })
+            .collect::<BTreeMap<_, _>>();
+        for payment in op.payments.values_mut() {
+            if let Some(by) = &mut payment.paid_by {
+                rename(by);
+            }
+        }
+        if let Some(finalized) = &mut op.finalized {
+            finalized.payouts = std::mem::take(&mut finalized.payouts)
+                .into_iter()
+                .map(|(mut main, amount)| {
+                    rename(&mut main);
+                    (main, amount)
+                })
+                .collect();
+            if let Some(by) = &mut finalized.finalized_by {
+                rename(by);
+            }
+        }
+        for sign_off in &mut op.sign_offs {
+            rename(&mut sign_off.character_name);
+        }
+        for dispute in &mut op.disputes {
+            rename(&mut dispute.main);
+            rename(&mut dispute.raised_by);
+            if let Some(by) = dispute
+                .resolution
+                .as_mut()
+                .and_then(|r| r.resolved_by.as_mut())
+            {
+                rename(by);
+            }
+        }
+        for sale in &mut op.sales {
+            if let Some(by) = &mut sale.recorded_by {
+                rename(by);
+            }
+            sale.note = rename_tokens(&sale.note);
+        }
+        if let Some(by) = op.loot_can.as_mut().and_then(|c| c.imported_by.as_mut()) {
+            rename(by);
+        }
+        for entry in &mut op.audit_log {
+            if let Some(by) = &mut entry.by {
+                rename(by);
+            }
+            entry.action = rename_tokens(&entry.action);
+        }
+        // The edit history still holds the old mappings
+        op.undo_stack.clear();
+        op.redo_stack.clear();
+        if intact && !op.finalization_intact() {
+            op.amend(
+                erased_by.clone(),
+                "Erased a pilot's data on their request".to_string(),
+            );
+            op.log(
+                erased_by.clone(),
+                "Amended the finalized payout after erasing a pilot's data".to_string(),
+            );
+        }
+    }
+    data.default_mapping = rewrite(&data.default_mapping);
+    data.leaderboard_opt_outs.retain(|n| !names.contains(n));
+    data.api_tokens
+        .retain(|_, t| t.character_id != character_id);
+    for kind in [GrantKind::Market, GrantKind::Wallet, GrantKind::Assets] {
+        let grant = data.grant_mut(kind);
+        if grant
+            .as_ref()
+            .is_some_and(|g| g.character_id == character_id)
+        {
+            *grant = None;
+        }
+    }
+    data.name_history.remove(&character_id);
+    data.deletion_requests.remove(&character_id);
+    pseudonym
+}
+
+/// `GET /account/data.json`: everything stored about the logged-in character.
+pub async fn export_data(State(state): State<Arc<AppState>>, viewer: Viewer) -> Response {
+    let Some(user) = viewer.user else {
+        return (StatusCode::UNAUTHORIZED, "Log in to export your data").into_response();
+    };
+    let export = export(
+        &state.store.lock().await.data,
+        user.character_id,
+        &user.character_name,
+    );
+    (
+        [(
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"eve-looter-{}.json\"",
+                user.character_id
+            ),
+        )],
+        Json(export),
+    )
+        .into_response()
+}
+
+/// Files (or withdraws) the logged-in character's deletion request.
+pub async fn request_deletion(State(state): State<Arc<AppState>>, viewer: Viewer) -> Response {
+    if let Some(user) = viewer.user {
+        let mut store = state.store.lock().await;
+        let requests = &mut store.data.deletion_requests;
+        if requests.remove(&user.character_id).is_none() {
+            info!("{} requested deletion of their data", user.character_name);
+            requests.insert(
+                user.character_id,
+                DeletionRequest {
+                    character_name: user.character_name.to_string(),
+                    requested_at: Utc::now(),
+                },
+            );
+        }
+        if let Err(e) = store.save().await {
+            return e.into_response();
+        }
+    }
+    base_path::redirect("/account").into_response()
+}
+
+/// Carries out a pending deletion request (admins).
+pub async fn erase_character(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Path(character_id): Path<CharacterId>,
+) -> Response {
+    let mut store = state.store.lock().await;
+    let Some(request) = store.data.deletion_requests.get(&character_id).cloned() else {
+        return (
+            StatusCode::NOT_FOUND,
+            "No deletion request for that character",
+        )
+            .into_response();
+    };
+    let pseudonym = erase(
+        &mut store.data,
+        character_id,
+        &request.character_name,
+        viewer.user.map(|u| u.character_name.into()),
+    );
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    drop(store);
+    store::forget_name(state.storage.as_ref(), character_id.into()).await;
+    state
+        .name_cache
+        .lock()
+        .unwrap()
+        .remove(&character_id.into());
+    state.result_cache.lock().unwrap().clear();
+    info!(
+        "Erased the data of character {} (now {})",
+        character_id, pseudonym
+    );
+    base_path::redirect("/settings").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn erasure_rewrites_only_the_lines_naming_the_character() {
+        let mut data = StoreData::default();
+        data.default_mapping = "Alt Of Alice:Alice\r\nBob  =  Carol\nDave=Alice\n".to_string();
+        let pseudonym = erase(&mut data, CharacterId(100), "Alice", None);
+        assert_eq!(
+            data.default_mapping,
+            format!(
+                "Alt Of Alice:{}\r\nBob  =  Carol\nDave={}\n",
+                pseudonym, pseudonym
+            )
+        );
+    }
+
+    #[test]
+    fn erasure_renames_only_whole_names_in_the_audit_log() {
+        let mut data = StoreData::default();
+        let mut op = crate::store::tests::operation(vec![]);
+        op.log(
+            Some("Al".to_string()),
+            "Disputed the share of Al: Alice and Al-Rashid got more than Al".to_string(),
+        );
+        op.log(
+            None,
+            "Resolved the dispute over Al's share: fine".to_string(),
+        );
+        data.operations.push(op);
+
+        let pseudonym = erase(&mut data, CharacterId(100), "Al", None);
+        let log = &data.operations[0].audit_log;
+        assert_eq!(log[0].by.as_deref(), Some(pseudonym.as_str()));
+        assert_eq!(
+            log[0].action,
+            format!(
+                "Disputed the share of {}: Alice and Al-Rashid got more than {}",
+                pseudonym, pseudonym
+            )
+        );
+        assert_eq!(
+            log[1].action,
+            format!("Resolved the dispute over {}'s share: fine", pseudonym)
+        );
+    }
+
+    #[test]
+    fn erasure_amends_finalized_payouts_instead_of_resealing_them() {
+        let mut data = StoreData::default();
+        let mut op = crate::store::tests::operation(vec![crate::store::tests::kill(
+            1,
+            100.0,
+            &[(100, "Alice"), (200, "Bob")],
+        )]);
+        op.mapping_text = "Alice:Alice\nBob:Bob\n".to_string();
+        let payouts = BTreeMap::from([
+            ("Alice".to_string(), Isk::from_f64(50.0).unwrap()),
+            ("Bob".to_string(), Isk::from_f64(50.0).unwrap()),
+        ]);
+        op.finalize(
+            Isk::from_f64(100.0).unwrap(),
+            payouts,
+            Isk::default(),
+            Some("Alice".to_string()),
+        );
+        let hash = op.finalized.as_ref().unwrap().hash.clone();
+        data.operations.push(op);
+
+        let pseudonym = erase(
+            &mut data,
+            CharacterId(100),
+            "Alice",
+            Some("Admin".to_string()),
+        );
+        let op = &data.operations[0];
+        let finalized = op.finalized.as_ref().unwrap();
+        assert_eq!(finalized.hash, hash);
+        assert!(finalized.payouts.contains_key(&pseudonym));
+        assert!(!finalized.payouts.contains_key("Alice"));
+        assert_eq!(finalized.finalized_by.as_deref(), Some(pseudonym.as_str()));
+        assert_eq!(finalized.amendments.len(), 1);
+        assert_eq!(finalized.amendments[0].amended_by.as_deref(), Some("Admin"));
+        assert!(op.finalization_intact());
+
+        // An amendment edited afterwards no longer verifies
+        let mut tampered = op.clone();
+        tampered.finalized.as_mut().unwrap().amendments[0].reason = "Nothing".to_string();
+        assert!(!tampered.finalization_intact());
+    }
+}
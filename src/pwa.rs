@@ -0,0 +1,83 @@
+use crate::base_path;
+use crate::version::{GIT_COMMIT, VERSION};
+use crate::views::fallback_page;
+
+use askama::Template;
+use axum::{
+    http::header,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+/// Response header on operation pages that may be kept for offline use:
+/// finalized ones, whose payout no longer changes. The value is the
+/// finalization hash.
+pub const OFFLINE_HEADER: &str = "x-looter-finalized";
+
+const ICON: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 64 64"><rect width="64" height="64" rx="12" fill="#111"/><path d="M16 22h32l-4 28H20z" fill="none" stroke="#4caf50" stroke-width="4" stroke-linejoin="round"/><path d="M24 22a8 8 0 0 1 16 0" fill="none" stroke="#4caf50" stroke-width="4"/></svg>"##;
+
+#[derive(Template)]
+#[template(path = "sw.js", escape = "none")]
+struct ServiceWorkerTemplate {
+    /// Rendered pages are cached per build, so a deploy drops pages
+    /// rendered by the old templates.
+    cache_name: String,
+    base: &'static str,
+}
+
+/// `GET /manifest.webmanifest`
+pub async fn manifest() -> Response {
+    let manifest = json!({
+        "name": "EVE Looter",
+        "short_name": "Looter",
+        "start_url": base_path::url("/ops"),
+        "scope": base_path::url("/"),
+        "display": "standalone",
+        "background_color": "#111111",
+        "theme_color": "#111111",
+        "icons": [{
+            "src": base_path::url("/icon.svg"),
+            "sizes": "any",
+            "type": "image/svg+xml",
+        }],
+    });
+    (
+        [(header::CONTENT_TYPE, "application/manifest+json")],
+        Json(manifest),
+    )
+        .into_response()
+}
+
+/// `GET /sw.js`: keeps finalized operation pages for reading offline.
+pub async fn service_worker() -> Response {
+    let template = ServiceWorkerTemplate {
+        cache_name: format!("eve-looter-{}-{}", VERSION, GIT_COMMIT),
+        base: base_path::base(),
+    };
+    match template.render() {
+        Ok(js) => (
+            [
+                (header::CONTENT_TYPE, "text/javascript; charset=utf-8"),
+                // Browsers check for a new worker on navigation; don't let
+                // a cache hold on to the old one
+                (header::CACHE_CONTROL, "no-cache"),
+            ],
+            js,
+        )
+            .into_response(),
+        Err(e) => fallback_page(e),
+    }
+}
+
+/// `GET /icon.svg`
+pub async fn icon() -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "image/svg+xml"),
+            (header::CACHE_CONTROL, "public, max-age=86400"),
+        ],
+        ICON,
+    )
+        .into_response()
+}
@@ -0,0 +1,66 @@
+use crate::base_path;
+use crate::models::*;
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use std::sync::Arc;
+
+/// `GET /share/:token/qr.svg`: a QR code of the operation's link, for an
+/// FC to put on stream or screen for the fleet to scan. Finalized
+/// operations link their public page, the rest the operation page itself.
+pub async fn share_qr(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(token): Path<String>,
+) -> Response {
+    let path = {
+        let store = state.store.lock().await;
+        let Some(op) = store.operation_by_token(&token) else {
+            return (StatusCode::NOT_FOUND, "Unknown share link").into_response();
+        };
+        if op.is_finalized() {
+            format!("/transparency/{}", token)
+        } else {
+            format!("/ops/{}", op.id)
+        }
+    };
+    // A phone needs the full URL; without a configured one, the host the FC
+    // reached us on is the best guess
+    let origin = match state.config.public_url.as_deref() {
+        Some(public_url) => public_url.trim_end_matches('/').to_string(),
+        None => {
+            let host = headers
+                .get(header::HOST)
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("localhost:3000");
+            format!("http://{}{}", host, base_path::base())
+        }
+    };
+    let url = format!("{}{}", origin, path);
+    let Ok(code) = QrCode::new(url.as_bytes()) else {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Link too long for a QR code",
+        )
+            .into_response();
+    };
+    let image = code
+        .render::<svg::Color>()
+        .min_dimensions(256, 256)
+        .dark_color(svg::Color("#000000"))
+        .light_color(svg::Color("#ffffff"))
+        .build();
+    (
+        [
+            (header::CONTENT_TYPE, "image/svg+xml"),
+            (header::CACHE_CONTROL, "private, max-age=300"),
+        ],
+        image,
+    )
+        .into_response()
+}
@@ -0,0 +1,123 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+/// zKillboard asks API users to stay around one request per second.
+pub const ZKILL_PER_SECOND: f64 = 1.0;
+/// ESI has no hard request rate, but bursts of errors get an IP banned;
+/// this keeps a busy instance well inside the error limit.
+pub const ESI_PER_SECOND: f64 = 20.0;
+pub const ESI_BURST: u32 = 20;
+
+/// Token bucket shared by everything in the process that talks to one
+/// upstream. Callers take a token per request and wait when the bucket
+/// is empty.
+pub struct RateLimiter {
+    name: &'static str,
+    per_second: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+/// A limiter's budget right now, for the diagnostics page.
+pub struct LimiterState {
+    pub name: &'static str,
+    pub per_second: f64,
+    pub burst: u32,
+    /// Tokens left; negative when callers are queued for future ones.
+    pub tokens: f64,
+    /// How long a request sent now would wait.
+    pub wait: Duration,
+}
+
+struct Bucket {
+    /// Negative when waiting callers have reserved future tokens.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(name: &'static str, per_second: f64, burst: u32) -> Self {
+        Self {
+            name,
+            per_second,
+            burst: burst as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: burst as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until a request may be sent. The token is reserved under the
+    /// lock and the wait happens outside it, so callers queue up in order.
+    /// Returns whether the caller had to wait.
+    pub async fn acquire(&self) -> bool {
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.updated).as_secs_f64() * self.per_second;
+            bucket.tokens = (bucket.tokens + refill).min(self.burst) - 1.0;
+            bucket.updated = now;
+            (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / self.per_second))
+        };
+        if let Some(wait) = wait {
+            debug!("{} rate limit: waiting {:?}", self.name, wait);
+            tokio::time::sleep(wait).await;
+        }
+        wait.is_some()
+    }
+
+    pub async fn state(&self) -> LimiterState {
+        let bucket = self.bucket.lock().await;
+        let refill = bucket.updated.elapsed().as_secs_f64() * self.per_second;
+        let tokens = (bucket.tokens + refill).min(self.burst);
+        LimiterState {
+            name: self.name,
+            per_second: self.per_second,
+            burst: self.burst as u32,
+            tokens,
+            wait: Duration::from_secs_f64((1.0 - tokens).max(0.0) / self.per_second),
+        }
+    }
+}
+
+/// Caps how many fetches one client starts per minute, so a single visitor
+/// can't spend the whole zKillboard budget. Clients are told apart by
+/// client::ClientIp, which is why it matters behind a proxy.
+pub struct ClientThrottle {
+    per_minute: u32,
+    starts: std::sync::Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+
+impl ClientThrottle {
+    /// `per_minute` of 0 lets everything through.
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            starts: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a fetch by `client`; false when it is over its budget.
+    pub fn allow(&self, client: IpAddr) -> bool {
+        if self.per_minute == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let window = Duration::from_secs(60);
+        let mut starts = self.starts.lock().unwrap();
+        starts.retain(|_, times| {
+            times.retain(|t| now.duration_since(*t) < window);
+            !times.is_empty()
+        });
+        let times = starts.entry(client).or_default();
+        if times.len() >= self.per_minute as usize {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
@@ -0,0 +1,133 @@
+use crate::auth;
+use crate::client::ClientIp;
+use crate::models::AppState;
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{info_span, Instrument, Span};
+
+/// Echoed on every response; taken from the request when a proxy set one.
+pub const HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    static CURRENT: String;
+}
+
+/// The ID of a request, in its extensions for the trace span.
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+fn new_id() -> String {
+    auth::random_token()[..12].to_string()
+}
+
+/// A proxy's ID is kept if it's short and safe to log and echo back.
+fn from_proxy(request: &Request) -> Option<String> {
+    let id = request.headers().get(HEADER)?.to_str().ok()?;
+    let sane = !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    sane.then(|| id.to_string())
+}
+
+/// The ID of the request or job this code runs for.
+pub fn current() -> Option<String> {
+    CURRENT.try_with(|id| id.clone()).ok()
+}
+
+/// The trace span of a request; every log line written while handling it
+/// carries its ID.
+pub fn span(request: &Request) -> Span {
+    let id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.as_str())
+        .unwrap_or_default();
+    let client = request
+        .extensions()
+        .get::<ClientIp>()
+        .map(|c| c.0.to_string())
+        .unwrap_or_default();
+    info_span!(
+        "request",
+        id = %id,
+        client = %client,
+        method = %request.method(),
+        uri = %request.uri(),
+    )
+}
+
+/// Assigns the request its ID (and client::ClientIp) and returns the ID in
+/// the `X-Request-Id` header. Plain-text error bodies (ours and axum's
+/// rejections) get it appended, so whatever a user pastes into a bug report
+/// can be found in the logs.
+pub async fn assign(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let id = from_proxy(&request).unwrap_or_else(new_id);
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| c.0);
+    let client = ClientIp::resolve(&state.config, request.headers(), peer);
+    request.extensions_mut().insert(RequestId(id.clone()));
+    request.extensions_mut().insert(client);
+    let mut response = CURRENT.scope(id.clone(), next.run(request)).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(HEADER, value);
+    }
+
+    let plain_text = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/plain"));
+    if !(response.status().is_client_error() || response.status().is_server_error()) || !plain_text
+    {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, 64 * 1024).await else {
+        return (parts.status, format!("Request ID: {}", id)).into_response();
+    };
+    let text = format!(
+        "{}\n\nRequest ID: {}",
+        String::from_utf8_lossy(&bytes).trim_end(),
+        id
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(text))
+}
+
+/// Runs a background job (one prefetch or pruning run) under its own ID.
+pub async fn job<F: Future>(kind: &'static str, work: F) -> F::Output {
+    let id = new_id();
+    let span = info_span!("job", kind, id = %id);
+    CURRENT.scope(id, work.instrument(span)).await
+}
+
+/// A JSON error body for API clients: `{"error": ..., "request_id": ...}`.
+pub fn api_error(status: StatusCode, message: &str) -> Response {
+    (
+        status,
+        Json(json!({
+            "error": message,
+            "request_id": current(),
+        })),
+    )
+        .into_response()
+}
@@ -0,0 +1,141 @@
+use crate::models::AppState;
+use crate::request_id;
+
+use chrono::{DateTime, Months, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// Retention is enforced once a day (and on demand via /admin/prune).
+const PRUNE_EVERY: std::time::Duration = std::time::Duration::from_secs(24 * 3600);
+
+/// How long data is kept; 0 keeps it forever.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Cached ESI killmails, by kill time.
+    #[serde(default)]
+    pub killmail_months: u32,
+    /// Saved operations, by the end of their date range.
+    #[serde(default)]
+    pub operation_months: u32,
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.killmail_months > 0 || self.operation_months > 0
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PrunedOperation {
+    pub id: u64,
+    pub name: String,
+    pub end_date: String,
+}
+
+/// What a pruning run deleted, or with `dry_run` would delete.
+#[derive(Debug, Serialize)]
+pub struct PruneReport {
+    pub dry_run: bool,
+    pub killmail_cutoff: Option<DateTime<Utc>>,
+    /// Killmails in this instance's memory cache.
+    pub cached_killmails: usize,
+    /// Killmails kept by the storage backend (Postgres).
+    pub stored_killmails: u64,
+    pub operation_cutoff: Option<String>,
+    pub operations: Vec<PrunedOperation>,
+}
+
+fn cutoff(months: u32) -> Option<DateTime<Utc>> {
+    (months > 0)
+        .then(|| Utc::now().checked_sub_months(Months::new(months)))
+        .flatten()
+}
+
+/// Applies the workspace retention policy. With `dry_run` nothing is
+/// deleted, only counted.
+pub async fn prune(state: &AppState, dry_run: bool) -> PruneReport {
+    let _job = (!dry_run).then(|| state.diagnostics.start_job("prune", "retention policy"));
+    let policy = state.store.lock().await.data.settings.retention;
+    let killmail_cutoff = cutoff(policy.killmail_months);
+    let operation_cutoff =
+        cutoff(policy.operation_months).map(|t| t.format("%Y-%m-%d").to_string());
+
+    let mut cached_killmails = 0;
+    let mut stored_killmails = 0;
+    if let Some(before) = killmail_cutoff {
+        cached_killmails = state
+            .esi_cache
+            .lock()
+            .unwrap()
+            .prune_before(before, dry_run);
+        match state.storage.prune_killmails(before, dry_run).await {
+            Ok(count) => stored_killmails = count,
+            Err(e) => error!("Failed to prune stored killmails: {}", e),
+        }
+    }
+
+    let mut operations = Vec::new();
+    if let Some(before) = &operation_cutoff {
+        let mut store = state.store.lock().await;
+        let expired = |end_date: &str| end_date < before.as_str();
+        operations = store
+            .data
+            .operations
+            .iter()
+            .filter(|op| expired(&op.end_date))
+            .map(|op| PrunedOperation {
+                id: op.id,
+                name: op.name.clone(),
+                end_date: op.end_date.clone(),
+            })
+            .collect();
+        if !dry_run && !operations.is_empty() {
+            let update = store.update(|data| data.operations.retain(|op| !expired(&op.end_date)));
+            if let Err(e) = update.await {
+                error!("Failed to prune operations: {}", e);
+                operations.clear();
+            }
+            state.result_cache.lock().unwrap().clear();
+        }
+    }
+
+    let report = PruneReport {
+        dry_run,
+        killmail_cutoff,
+        cached_killmails,
+        stored_killmails,
+        operation_cutoff,
+        operations,
+    };
+    let killmails = report.cached_killmails as u64 + report.stored_killmails;
+    if !dry_run && (killmails > 0 || !report.operations.is_empty()) {
+        info!(
+            "Pruned {} cached and {} stored killmails and {} operations",
+            report.cached_killmails,
+            report.stored_killmails,
+            report.operations.len()
+        );
+    }
+    report
+}
+
+/// Enforces the retention policy once a day.
+pub fn spawn(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        loop {
+            let enabled = state
+                .store
+                .lock()
+                .await
+                .data
+                .settings
+                .retention
+                .is_enabled();
+            if enabled {
+                request_id::job("prune", prune(&state, false)).await;
+            }
+            tokio::time::sleep(PRUNE_EVERY).await;
+        }
+    });
+}
@@ -0,0 +1,345 @@
+//! Jump distances between solar systems, from the SDE's stargate graph
+//! when one is configured (`EVE_LOOTER_SDE_JUMPS`), else from ESI's route
+//! planner. Gate routes don't change, so every distance looked up from ESI
+//! is kept in the store.
+
+use crate::logic::http_client;
+use crate::models::{AppState, Isk, Killmail, SystemId};
+use crate::names::NameBook;
+use crate::payout::attacker_main;
+use crate::views::ResultView;
+
+use futures::future::join_all;
+use reqwest::{Client, StatusCode};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{error, info, warn, Instrument};
+
+pub struct TradeHub {
+    pub name: &'static str,
+    pub system_id: SystemId,
+}
+
+/// The empire trade hubs, busiest first.
+pub const TRADE_HUBS: [TradeHub; 5] = [
+    TradeHub {
+        name: "Jita",
+        system_id: SystemId(30000142),
+    },
+    TradeHub {
+        name: "Amarr",
+        system_id: SystemId(30002187),
+    },
+    TradeHub {
+        name: "Dodixie",
+        system_id: SystemId(30002659),
+    },
+    TradeHub {
+        name: "Rens",
+        system_id: SystemId(30002510),
+    },
+    TradeHub {
+        name: "Hek",
+        system_id: SystemId(30002053),
+    },
+];
+
+pub const JITA: SystemId = TRADE_HUBS[0].system_id;
+
+/// J-space has no gates to route through.
+fn is_wormhole(system: SystemId) -> bool {
+    (31_000_000..32_000_000).contains(&system.0)
+}
+
+/// New Eden's stargates, from the SDE's `mapSolarSystemJumps` table.
+pub struct SystemGraph {
+    gates: HashMap<SystemId, Vec<SystemId>>,
+}
+
+impl SystemGraph {
+    /// Reads a CSV export of `mapSolarSystemJumps`, e.g. Fuzzwork's; only
+    /// its `fromSolarSystemID` and `toSolarSystemID` columns are used.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        let mut lines = text.lines();
+        let header: Vec<&str> = lines
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .collect();
+        let column = |name: &str| {
+            header
+                .iter()
+                .position(|c| c.eq_ignore_ascii_case(name))
+                .ok_or_else(|| format!("{} has no {} column", path.display(), name))
+        };
+        let (from, to) = (column("fromSolarSystemID")?, column("toSolarSystemID")?);
+        let mut gates: HashMap<SystemId, Vec<SystemId>> = HashMap::new();
+        for (n, line) in lines.enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let id = |i: usize| fields.get(i).and_then(|f| f.parse().ok()).map(SystemId);
+            let (Some(a), Some(b)) = (id(from), id(to)) else {
+                return Err(format!("{} line {}: bad system ID", path.display(), n + 2));
+            };
+            gates.entry(a).or_default().push(b);
+        }
+        Ok(Self { gates })
+    }
+
+    pub fn system_count(&self) -> usize {
+        self.gates.len()
+    }
+
+    /// Gate jumps on the shortest route, `None` when there is none.
+    pub fn jumps(&self, from: SystemId, to: SystemId) -> Option<u32> {
+        let mut seen = HashSet::from([from]);
+        let mut queue = VecDeque::from([(from, 0)]);
+        while let Some((system, jumps)) = queue.pop_front() {
+            if system == to {
+                return Some(jumps);
+            }
+            for &next in self.gates.get(&system).into_iter().flatten() {
+                if seen.insert(next) {
+                    queue.push_back((next, jumps + 1));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// The graph at `EVE_LOOTER_SDE_JUMPS`, if set and readable.
+pub fn load_graph(path: Option<&Path>) -> Option<SystemGraph> {
+    match SystemGraph::load(path?) {
+        Ok(graph) => {
+            info!(
+                "Loaded stargates of {} systems from the SDE",
+                graph.system_count()
+            );
+            Some(graph)
+        }
+        Err(e) => {
+            error!("Falling back to ESI for jump counts: {}", e);
+            None
+        }
+    }
+}
+
+/// Jumps already known without asking ESI: `Some(None)` when there is no
+/// route, `None` when it hasn't been looked up yet.
+pub async fn known_jumps(state: &AppState, from: SystemId, to: SystemId) -> Option<Option<u32>> {
+    if from == to {
+        return Some(Some(0));
+    }
+    if is_wormhole(from) || is_wormhole(to) {
+        return Some(None);
+    }
+    if let Some(graph) = &state.system_graph {
+        return Some(graph.jumps(from, to));
+    }
+    let store = state.store.lock().await;
+    store
+        .data
+        .jumps
+        .get(&from)
+        .and_then(|m| m.get(&to))
+        .copied()
+}
+
+/// Gate jumps on the shortest route between each pair of systems. Pairs
+/// without a route, or that ESI couldn't answer for, are left out.
+pub async fn jumps_between(
+    client: &Client,
+    state: &Arc<AppState>,
+    pairs: &[(SystemId, SystemId)],
+) -> HashMap<(SystemId, SystemId), u32> {
+    let mut known = HashMap::new();
+    let mut missing = HashSet::new();
+    for &(from, to) in pairs {
+        match known_jumps(state, from, to).await {
+            Some(jumps) => {
+                known.insert((from, to), jumps);
+            }
+            None => {
+                missing.insert((from, to));
+            }
+        }
+    }
+
+    let lookups = missing
+        .into_iter()
+        .map(|(from, to)| async move { ((from, to), route_length(client, state, from, to).await) });
+    let looked_up: Vec<_> = join_all(lookups).await;
+    if looked_up.iter().any(|(_, jumps)| jumps.is_some()) {
+        let mut store = state.store.lock().await;
+        let update = store.update(|data| {
+            for ((from, to), jumps) in &looked_up {
+                if let Some(jumps) = jumps {
+                    data.jumps.entry(*from).or_default().insert(*to, *jumps);
+                }
+            }
+        });
+        update.await.ok();
+    }
+    known
+        .into_iter()
+        .chain(
+            looked_up
+                .into_iter()
+                .map(|(pair, jumps)| (pair, jumps.flatten())),
+        )
+        .filter_map(|(pair, jumps)| Some((pair, jumps?)))
+        .collect()
+}
+
+/// Jumps from ESI: `Some(None)` when there is no route, `None` when ESI
+/// couldn't say.
+async fn route_length(
+    client: &Client,
+    state: &AppState,
+    from: SystemId,
+    to: SystemId,
+) -> Option<Option<u32>> {
+    state.esi_limiter.acquire().await;
+    let url = format!(
+        "https://esi.evetech.net/latest/route/{}/{}/?datasource=tranquility&flag=shortest",
+        from.0, to.0
+    );
+    match client.get(&url).send().await {
+        Ok(r) if r.status().is_success() => match r.json::<Vec<i64>>().await {
+            // The route lists both ends
+            Ok(route) => Some(Some(route.len().saturating_sub(1) as u32)),
+            Err(e) => {
+                error!("Failed to parse ESI route {} -> {}: {}", from, to, e);
+                None
+            }
+        },
+        Ok(r) if r.status() == StatusCode::NOT_FOUND => Some(None),
+        Ok(r) => {
+            warn!("ESI route {} -> {} failed: {}", from, to, r.status());
+            None
+        }
+        Err(e) => {
+            error!("Network error for route {} -> {}: {}", from, to, e);
+            None
+        }
+    }
+}
+
+/// The closest trade hub to each system, with the jumps to it.
+pub async fn nearest_hubs(
+    client: &Client,
+    state: &Arc<AppState>,
+    systems: &HashSet<SystemId>,
+) -> HashMap<SystemId, (&'static TradeHub, u32)> {
+    let pairs: Vec<_> = systems
+        .iter()
+        .flat_map(|&system| TRADE_HUBS.iter().map(move |hub| (system, hub.system_id)))
+        .collect();
+    let jumps = jumps_between(client, state, &pairs).await;
+    let mut nearest: HashMap<SystemId, (&'static TradeHub, u32)> = HashMap::new();
+    for &system in systems {
+        for hub in &TRADE_HUBS {
+            let Some(&distance) = jumps.get(&(system, hub.system_id)) else {
+                continue;
+            };
+            let best = nearest.entry(system).or_insert((hub, distance));
+            if distance < best.1 {
+                *best = (hub, distance);
+            }
+        }
+    }
+    nearest
+}
+
+/// "Jita, 4 jumps" for each main, from where they were last seen (see
+/// last_seen). Mains in J-space or beyond ESI's reach are left out.
+pub async fn hub_suggestions(
+    state: &Arc<AppState>,
+    last_seen: &HashMap<String, SystemId>,
+) -> BTreeMap<String, String> {
+    let client = match http_client(&state.config) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Can't suggest trade hubs: {}", e);
+            return BTreeMap::new();
+        }
+    };
+    let systems = last_seen.values().copied().collect();
+    let nearest = nearest_hubs(&client, state, &systems).await;
+    last_seen
+        .iter()
+        .filter_map(|(main, system)| {
+            let (hub, jumps) = nearest.get(system)?;
+            let suggestion = match jumps {
+                0 => format!("{}, in system", hub.name),
+                1 => format!("{}, 1 jump", hub.name),
+                n => format!("{}, {} jumps", hub.name, n),
+            };
+            Some((main.clone(), suggestion))
+        })
+        .collect()
+}
+
+/// Where each main was last seen on one of `kills`. `mapping` must already
+/// be canonical (see NameBook::canonical_mapping).
+pub fn last_seen(
+    kills: &[Killmail],
+    names: &NameBook,
+    mapping: &HashMap<String, String>,
+) -> HashMap<String, SystemId> {
+    let mut kills: Vec<&Killmail> = kills.iter().collect();
+    kills.sort_by_key(|k| k.killmail_time);
+    let mut seen = HashMap::new();
+    for kill in kills {
+        for attacker in &kill.attackers {
+            if let Some(main) = attacker_main(names, mapping, attacker) {
+                seen.insert(main, kill.solar_system_id);
+            }
+        }
+    }
+    seen
+}
+
+/// Shows how far each kill is from Jita and, with a hauling fee set, what
+/// moving the loot there would cost. Distances ESI hasn't been asked about
+/// yet are looked up in the background and show on the next view.
+pub async fn mark_distances(state: &Arc<AppState>, result: &mut ResultView, fee_per_jump: Isk) {
+    let systems: HashSet<SystemId> = result
+        .daily_groups
+        .iter()
+        .flat_map(|g| &g.kills)
+        .map(|row| row.kill.solar_system_id)
+        .collect();
+    let mut jumps = HashMap::new();
+    let mut missing = Vec::new();
+    for system in systems {
+        match known_jumps(state, system, JITA).await {
+            Some(Some(n)) => {
+                jumps.insert(system, n);
+            }
+            Some(None) => {}
+            None => missing.push((system, JITA)),
+        }
+    }
+    result.mark_distances(&jumps, fee_per_jump);
+
+    if missing.is_empty() || state.config.read_only {
+        return;
+    }
+    let state = state.clone();
+    tokio::spawn(
+        async move {
+            match http_client(&state.config) {
+                Ok(client) => {
+                    jumps_between(&client, &state, &missing).await;
+                }
+                Err(e) => error!("Can't look up jumps to Jita: {}", e),
+            }
+        }
+        .in_current_span(),
+    );
+}
@@ -0,0 +1,303 @@
+//! What an operation's loot actually sold for, against what it was
+//! estimated at when the kills were fetched. Sales are entered by the
+//! hauler or matched in the corp wallet's market transactions, and the
+//! payout can optionally be re-split on them (see Operation::payout_kills).
+
+use crate::auth::{self, Viewer};
+use crate::base_path;
+use crate::kills;
+use crate::logic::{self, http_client};
+use crate::models::*;
+use crate::ops::{finalized, not_found};
+use crate::store::{GrantKind, Operation, Sale};
+use crate::views::isk_error;
+
+use axum::{
+    extract::{Form, Path, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// Once a payout re-split on its sales is finalized, the sales are locked
+/// with it.
+fn locked(op: &Operation) -> bool {
+    op.is_finalized() && op.split_on_realized
+}
+
+fn recorder(viewer: Viewer) -> Option<String> {
+    viewer.user.map(|u| u.character_name.into())
+}
+
+#[derive(Deserialize)]
+pub struct SaleForm {
+    amount: String,
+    #[serde(default)]
+    note: String,
+}
+
+/// `POST /ops/:id/sales`: records proceeds the hauler entered by hand.
+pub async fn record_sale(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Path(id): Path<u64>,
+    Form(form): Form<SaleForm>,
+) -> Response {
+    let Some(amount) = parse_isk(&form.amount).filter(|a| *a > Isk::ZERO) else {
+        return (StatusCode::BAD_REQUEST, "Invalid ISK amount").into_response();
+    };
+    let mut store = state.store.lock().await;
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    if locked(op) {
+        return finalized();
+    }
+    let by = recorder(viewer);
+    op.sales.push(Sale {
+        amount,
+        note: form.note.trim().to_string(),
+        recorded_by: by.clone(),
+        recorded_at: Utc::now(),
+        transaction_ids: vec![],
+    });
+    op.log(by, format!("Recorded a sale of {} ISK", amount));
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+/// `POST /ops/:id/sales/:index/delete`
+pub async fn remove_sale(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Path((id, index)): Path<(u64, usize)>,
+) -> Response {
+    let mut store = state.store.lock().await;
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    if locked(op) {
+        return finalized();
+    }
+    if index < op.sales.len() {
+        let sale = op.sales.remove(index);
+        op.log(
+            recorder(viewer),
+            format!("Removed a sale of {} ISK", sale.amount),
+        );
+        if let Err(e) = store.save().await {
+            return e.into_response();
+        }
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct SplitForm {
+    #[serde(default)]
+    enabled: bool,
+}
+
+/// `POST /ops/:id/sales/split`: splits the realized proceeds instead of
+/// the estimate, or goes back to the estimate.
+pub async fn toggle_realized_split(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Path(id): Path<u64>,
+    Form(form): Form<SplitForm>,
+) -> Response {
+    let mut store = state.store.lock().await;
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    if op.is_finalized() {
+        return finalized();
+    }
+    if op.split_on_realized != form.enabled {
+        op.split_on_realized = form.enabled;
+        let action = if form.enabled {
+            "Split the realized sale proceeds"
+        } else {
+            "Split the estimated loot value"
+        };
+        op.log(recorder(viewer), action.to_string());
+        if let Err(e) = store.save().await {
+            return e.into_response();
+        }
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+/// The part of a corp wallet transaction we match on.
+#[derive(Deserialize)]
+struct WalletTransaction {
+    transaction_id: i64,
+    date: DateTime<Utc>,
+    type_id: TypeId,
+    quantity: i64,
+    unit_price: f64,
+    is_buy: bool,
+}
+
+/// `POST /ops/:id/sales/match`: finds sales of the operation's loot in the
+/// corp wallet and records them as one sale. Sell transactions since the
+/// operation's last kill count, oldest first, up to the quantity of each
+/// item type that dropped (or reached the loot can, once imported; see
+/// loot_can); transactions another sale already matched are skipped.
+pub async fn match_wallet(
+    State(state): State<Arc<AppState>>,
+    viewer: Viewer,
+    Path(id): Path<u64>,
+) -> Response {
+    let (kills, collected, division) = {
+        let store = state.store.lock().await;
+        let Some(op) = store.operation(id) else {
+            return not_found();
+        };
+        if locked(op) {
+            return finalized();
+        }
+        let kills: Vec<Killmail> = op.kills.iter().filter(|k| k.is_active).cloned().collect();
+        let collected: Option<HashMap<TypeId, i64>> = op
+            .loot_can
+            .as_ref()
+            .map(|c| c.collected.iter().map(|(&t, &q)| (t, q)).collect());
+        (kills, collected, store.data.settings.wallet_division())
+    };
+    let Some(since) = kills.iter().map(|k| k.killmail_time).max() else {
+        return no_match("The operation has no active kills");
+    };
+
+    // What reached the loot can, when it was imported, else what dropped
+    let mut dropped = match collected {
+        Some(collected) => collected,
+        None => match kills::dropped_items(&state, &kills).await {
+            Ok(dropped) => dropped,
+            Err(e) => return upstream(e),
+        },
+    };
+
+    let transactions = match wallet_transactions(&state, division).await {
+        Ok(transactions) => transactions,
+        Err(e) => return upstream(e),
+    };
+
+    let mut store = state.store.lock().await;
+    let claimed: HashSet<i64> = store
+        .data
+        .operations
+        .iter()
+        .flat_map(|op| &op.sales)
+        .flat_map(|s| s.transaction_ids.iter().copied())
+        .collect();
+    let mut candidates: Vec<&WalletTransaction> = transactions
+        .iter()
+        .filter(|t| !t.is_buy && t.date >= since && !claimed.contains(&t.transaction_id))
+        .collect();
+    candidates.sort_by_key(|t| t.date);
+    let mut amount = Isk::ZERO;
+    let mut transaction_ids = Vec::new();
+    for t in candidates {
+        let Some(left) = dropped.get_mut(&t.type_id).filter(|left| **left > 0) else {
+            continue;
+        };
+        let quantity = t.quantity.min(*left);
+        *left -= quantity;
+        if let Err(e) =
+            Isk::from_f64(t.unit_price * quantity as f64).and_then(|a| amount.increase(a))
+        {
+            return isk_error(e);
+        }
+        transaction_ids.push(t.transaction_id);
+    }
+    if transaction_ids.is_empty() {
+        return no_match("No unmatched sales of this operation's loot in the corp wallet");
+    }
+
+    let Some(op) = store.operation_mut(id) else {
+        return not_found();
+    };
+    let by = recorder(viewer);
+    info!(
+        "Matched {} wallet transactions ({} ISK) to operation {}",
+        transaction_ids.len(),
+        amount,
+        id
+    );
+    op.log(
+        by.clone(),
+        format!(
+            "Matched {} ISK of sales in the corp wallet ({} transactions)",
+            amount,
+            transaction_ids.len()
+        ),
+    );
+    op.sales.push(Sale {
+        amount,
+        note: format!("{} corp wallet transactions", transaction_ids.len()),
+        recorded_by: by,
+        recorded_at: Utc::now(),
+        transaction_ids,
+    });
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    base_path::redirect(&format!("/ops/{}", id)).into_response()
+}
+
+fn no_match(message: &str) -> Response {
+    (StatusCode::NOT_FOUND, Html(message.to_string())).into_response()
+}
+
+fn upstream(message: String) -> Response {
+    error!("Wallet matching failed: {}", message);
+    (StatusCode::BAD_GATEWAY, Html(message)).into_response()
+}
+
+/// The last month of market transactions in `division` of the wallet
+/// grant's corporation, newest first.
+async fn wallet_transactions(
+    state: &Arc<AppState>,
+    division: u8,
+) -> Result<Vec<WalletTransaction>, String> {
+    let character_id = state
+        .store
+        .lock()
+        .await
+        .data
+        .wallet_grant
+        .as_ref()
+        .map(|g| g.character_id)
+        .ok_or("Grant corp wallet access under Settings first")?;
+    let access_token = auth::grant_access_token(state, GrantKind::Wallet).await?;
+    let client = http_client(&state.config)?;
+    let corporation_id = logic::corporation_of(&client, state, character_id).await?;
+
+    state.esi_limiter.acquire().await;
+    let url = format!(
+        "https://esi.evetech.net/latest/corporations/{}/wallets/{}/transactions/?datasource=tranquility",
+        corporation_id, division
+    );
+    let resp = client
+        .get(&url)
+        .bearer_auth(&access_token)
+        .send()
+        .await
+        .map_err(|e| format!("Network error for the corp wallet: {}", e))?;
+    if !resp.status().is_success() {
+        // 403: the character lacks an accountant role
+        state
+            .diagnostics
+            .upstream_error("ESI", format!("corp wallet: {}", resp.status()));
+        return Err(format!("ESI refused the corp wallet: {}", resp.status()));
+    }
+    resp.json()
+        .await
+        .map_err(|e| format!("Failed to parse the corp wallet: {}", e))
+}
@@ -0,0 +1,224 @@
+use rhai::{Dynamic, Engine, EvalAltResult, ParseErrorType, Scope, AST};
+use std::time::{Duration, Instant};
+
+/// How long working out one payout's share weights may take.
+pub const TIME_LIMIT: Duration = Duration::from_millis(250);
+/// Steps one main's weight on one kill may take, so a single runaway loop
+/// fails on its own rather than eating the whole payout's time.
+const MAX_OPERATIONS: u64 = 100_000;
+/// Scripts are kept short and shallow enough not to overflow a worker's
+/// stack while Rhai parses and evaluates them.
+const MAX_LENGTH: usize = 1000;
+const MAX_DEPTH: usize = 64;
+const MAX_CALL_LEVELS: usize = 8;
+const MAX_COLLECTION_SIZE: usize = 1000;
+
+/// What a share script can see of a main on a kill.
+pub const VARIABLES: &[(&str, &str)] = &[
+    ("ship", "name of the ship the main flew on the kill"),
+    (
+        "ships",
+        "every ship their characters flew on it, for multiboxers",
+    ),
+    ("final_blow", "whether they got the final blow"),
+    (
+        "damage",
+        "percent of the kill's damage their characters did (0 on kills saved before damage was kept)",
+    ),
+    ("pilots", "how many mains share the kill"),
+    ("value", "the kill's dropped value in ISK"),
+    ("solo", "whether zKillboard calls the kill solo"),
+    ("npc", "whether only NPCs were on the kill"),
+    ("awox", "whether the victim was shot by their own corp or alliance"),
+    ("points", "the kill's zKillboard points"),
+    ("labels", "zKillboard's labels of the kill, e.g. \"ganked\" or \"loc:highsec\""),
+    ("pochven", "whether the kill was in Pochven"),
+    (
+        "losses",
+        "how many of their own ships died in the payout's kills",
+    ),
+];
+
+/// A Rhai engine locked down for share scripts: no `eval`, no printing,
+/// and caps on steps, nesting, calls and the size of strings and arrays.
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_expr_depths(MAX_DEPTH, MAX_DEPTH)
+        .set_max_call_levels(MAX_CALL_LEVELS)
+        .set_max_string_size(MAX_COLLECTION_SIZE)
+        .set_max_array_size(MAX_COLLECTION_SIZE)
+        .set_max_map_size(MAX_COLLECTION_SIZE)
+        .set_strict_variables(true)
+        .disable_symbol("eval");
+    engine.on_print(|_| {});
+    engine.on_debug(|_, _, _| {});
+    engine
+}
+
+/// Keeps a payout's scripts within TIME_LIMIT.
+pub struct Budget {
+    engine: Engine,
+}
+
+impl Budget {
+    pub fn new() -> Self {
+        Budget::until(Instant::now() + TIME_LIMIT)
+    }
+
+    fn until(deadline: Instant) -> Self {
+        let mut engine = engine();
+        engine.on_progress(move |steps| {
+            (steps.is_multiple_of(1024) && Instant::now() > deadline).then_some(Dynamic::UNIT)
+        });
+        Budget { engine }
+    }
+}
+
+/// A workspace's share script: a Rhai expression over the VARIABLES giving
+/// the share weight of a main on a kill, e.g.
+/// `(if ship in ["Guardian", "Oneiros"] { 2 } else { 1 }) * (if losses > 0 { 0.9 } else { 1 })`.
+pub struct Script {
+    ast: AST,
+}
+
+impl Script {
+    pub fn parse(source: &str) -> Result<Script, String> {
+        if source.len() > MAX_LENGTH {
+            return Err(format!(
+                "the script is longer than {} characters",
+                MAX_LENGTH
+            ));
+        }
+        if source.trim().is_empty() {
+            return Err("the script is empty".to_string());
+        }
+        // Strict variables only need the names to be known when compiling;
+        // they are not constants here, or the optimizer would fold them away
+        let mut scope = Scope::new();
+        for (name, _) in VARIABLES {
+            scope.push(*name, ());
+        }
+        let ast = engine()
+            .compile_with_scope(&scope, source)
+            .map_err(|e| match e.err_type() {
+                ParseErrorType::ExprTooDeep => "the script is nested too deeply".to_string(),
+                ParseErrorType::VariableUndefined(name) => format!("unknown name '{}'", name),
+                _ => e.to_string(),
+            })?;
+        Ok(Script { ast })
+    }
+
+    /// The weight for one main on one kill: a number of 0 or more.
+    /// `scope` holds the VARIABLES for them.
+    pub fn weight(&self, scope: &mut Scope, budget: &Budget) -> Result<f64, String> {
+        let weight = budget
+            .engine
+            .eval_ast_with_scope::<Dynamic>(scope, &self.ast)
+            .map_err(|e| match *e {
+                EvalAltResult::ErrorTerminated(..) => {
+                    format!("the script took longer than {} ms", TIME_LIMIT.as_millis())
+                }
+                EvalAltResult::ErrorTooManyOperations(..) => {
+                    format!("the script took more than {} steps", MAX_OPERATIONS)
+                }
+                EvalAltResult::ErrorArithmetic(ref message, _) => message.to_lowercase(),
+                ref other => other.to_string(),
+            })?;
+        let weight = match weight.as_float() {
+            Ok(weight) => weight,
+            Err(_) => weight
+                .as_int()
+                .map(|w| w as f64)
+                .map_err(|type_name| format!("expected a number, got {}", type_name))?,
+        };
+        if !weight.is_finite() || weight < 0.0 {
+            return Err(format!("weights can't be negative, got {}", weight));
+        }
+        Ok(weight)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weight(source: &str) -> Result<f64, String> {
+        let mut scope = Scope::new();
+        scope.push_constant("ship", "Guardian".to_string());
+        scope.push_constant("value", 100.0);
+        Script::parse(source)?.weight(&mut scope, &Budget::new())
+    }
+
+    #[test]
+    fn operators_bind_by_precedence() {
+        assert_eq!(weight("1 + 2 * 3"), Ok(7.0));
+        assert_eq!(weight("(1 + 2) * 3"), Ok(9.0));
+        assert_eq!(weight("-2 * 3 + 10"), Ok(4.0));
+        assert_eq!(weight("10 - 4 - 3"), Ok(3.0));
+        assert_eq!(weight("8 / 4 / 2"), Ok(1.0));
+        assert_eq!(weight("if 1 + 1 == 2 && !false { 3 } else { 0 }"), Ok(3.0));
+        assert_eq!(
+            weight("if false && false || true { 1 } else { 0 }"),
+            Ok(1.0)
+        );
+        assert_eq!(weight("value / 4"), Ok(25.0));
+        assert_eq!(
+            weight("if ship in [\"Guardian\", \"Oneiros\"] { 2 } else { 1 }"),
+            Ok(2.0)
+        );
+    }
+
+    #[test]
+    fn nesting_is_capped() {
+        let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(weight(&nested(MAX_DEPTH / 4)), Ok(1.0));
+        assert_eq!(
+            weight(&nested(MAX_DEPTH * 2)).unwrap_err(),
+            "the script is nested too deeply"
+        );
+    }
+
+    #[test]
+    fn length_is_capped() {
+        let padded = format!("{:<1$}", "1", MAX_LENGTH);
+        assert_eq!(weight(&padded), Ok(1.0));
+        assert_eq!(
+            weight(&format!("{} ", padded)).unwrap_err(),
+            format!("the script is longer than {} characters", MAX_LENGTH)
+        );
+    }
+
+    #[test]
+    fn runaway_scripts_are_stopped() {
+        assert_eq!(
+            weight("loop {}").unwrap_err(),
+            format!("the script took more than {} steps", MAX_OPERATIONS)
+        );
+
+        let script = Script::parse("let n = 0; while n < 1000 { n += 1 } 1").unwrap();
+        let error = script
+            .weight(&mut Scope::new(), &Budget::until(Instant::now()))
+            .unwrap_err();
+        assert_eq!(
+            error,
+            format!("the script took longer than {} ms", TIME_LIMIT.as_millis())
+        );
+    }
+
+    #[test]
+    fn nothing_outside_the_variables_is_reachable() {
+        assert_eq!(weight("kills").unwrap_err(), "unknown name 'kills'");
+        assert!(weight("pilots").is_err());
+        assert!(weight("eval(\"1\")").is_err());
+    }
+
+    #[test]
+    fn bad_weights_are_errors() {
+        assert!(weight("1 / 0").unwrap_err().starts_with("division by zero"));
+        assert!(weight("0 - 1").is_err());
+        assert!(weight("value / 0.0").is_err());
+        assert!(weight("ship").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use crate::config::{Config, Listen};
+
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use std::net::SocketAddr;
+use std::os::fd::{FromRawFd, IntoRawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tracing::{error, info, warn};
+
+/// Renewed certificates (e.g. by certbot) are picked up this often.
+const TLS_RELOAD_EVERY: std::time::Duration = std::time::Duration::from_secs(12 * 3600);
+
+/// The first descriptor systemd passes (SD_LISTEN_FDS_START).
+const SYSTEMD_FIRST_FD: i32 = 3;
+
+enum Listener {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// The socket systemd opened for us (`ListenStream=` in a .socket unit),
+/// if we were started by socket activation.
+fn systemd_listener() -> Option<Listener> {
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let fds: i32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if pid != std::process::id() || fds < 1 {
+        return None;
+    }
+    if fds > 1 {
+        warn!("systemd passed {} sockets; only the first is used", fds);
+    }
+    // Not for any children we might spawn
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    // SAFETY: systemd hands the descriptor over to this process, and
+    // nothing else in it owns fd 3
+    let tcp = unsafe { std::net::TcpListener::from_raw_fd(SYSTEMD_FIRST_FD) };
+    if tcp.local_addr().is_ok() {
+        return Some(Listener::Tcp(tcp));
+    }
+    // Not an IP socket, so it's a Unix one
+    let fd = tcp.into_raw_fd();
+    Some(Listener::Unix(unsafe {
+        std::os::unix::net::UnixListener::from_raw_fd(fd)
+    }))
+}
+
+fn bind_unix(path: &Path, mode: u32) -> std::io::Result<std::os::unix::net::UnixListener> {
+    // A socket left behind by an unclean shutdown would fail the bind
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Serves the app on whatever the configuration (or systemd) asks for:
+/// a TCP port with or without TLS, or a Unix socket for a local proxy.
+pub async fn serve(app: Router, config: &Config) -> std::io::Result<()> {
+    let listener = match systemd_listener() {
+        Some(listener) => {
+            info!("Using the socket passed by systemd");
+            listener
+        }
+        None => match &config.listen {
+            Listen::Tcp(addr) => Listener::Tcp(std::net::TcpListener::bind(addr)?),
+            Listen::Unix(path) => Listener::Unix(bind_unix(path, config.socket_mode)?),
+        },
+    };
+
+    match listener {
+        Listener::Tcp(listener) => {
+            listener.set_nonblocking(true)?;
+            let addr = listener.local_addr()?;
+            // The peer address is the client unless --behind-proxy (see client::ClientIp)
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            match &config.tls {
+                None => {
+                    info!("EVE Looter running on http://{}{}", addr, config.base_path);
+                    axum::serve(tokio::net::TcpListener::from_std(listener)?, app).await
+                }
+                Some(tls) => {
+                    let _ = rustls::crypto::ring::default_provider().install_default();
+                    let tls_config =
+                        RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path).await?;
+                    let (reloaded, cert_path, key_path) = (
+                        tls_config.clone(),
+                        tls.cert_path.clone(),
+                        tls.key_path.clone(),
+                    );
+                    tokio::spawn(async move {
+                        loop {
+                            tokio::time::sleep(TLS_RELOAD_EVERY).await;
+                            if let Err(e) =
+                                reloaded.reload_from_pem_file(&cert_path, &key_path).await
+                            {
+                                error!("Failed to reload the TLS certificate: {}", e);
+                            }
+                        }
+                    });
+                    info!("EVE Looter running on https://{}{}", addr, config.base_path);
+                    axum_server::from_tcp_rustls(listener, tls_config)
+                        .serve(app)
+                        .await
+                }
+            }
+        }
+        Listener::Unix(listener) => {
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::UnixListener::from_std(listener)?;
+            let addr = listener.local_addr()?;
+            let path = addr.as_pathname().unwrap_or(Path::new("?"));
+            info!(
+                "EVE Looter running on unix:{}{}",
+                path.display(),
+                config.base_path
+            );
+            serve_unix(listener, app).await
+        }
+    }
+}
+
+/// axum::serve only takes TCP listeners, so Unix connections are handed
+/// to hyper directly. There is no peer address; put the proxy in front
+/// with --behind-proxy to log client addresses.
+async fn serve_unix(listener: tokio::net::UnixListener, app: Router) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // e.g. out of file descriptors; don't spin on it
+                error!("Failed to accept a connection: {}", e);
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let service = TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                warn!("Connection error: {}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,532 @@
+use crate::auth::{self, Role, Viewer};
+use crate::base_path;
+use crate::digest::{self, DigestSchedule, DigestSettings};
+use crate::models::*;
+use crate::notify::{Webhook, EVENT_KINDS};
+use crate::payout::{
+    LossNetting, PayoutMode, PayoutPreset, PayoutRounding, PayoutRules, PochvenRules,
+};
+use crate::prefetch::{PrefetchStatus, WARM_DAYS};
+use crate::privacy::DeletionRequest;
+use crate::retention::{self, PruneReport, RetentionPolicy};
+use crate::script::{self, Script};
+use crate::store::{EsiGrant, GrantKind};
+use crate::views::render_page;
+
+use askama::Template;
+use axum::{
+    extract::{Form, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use chrono_tz::{Tz, TZ_VARIANTS};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Arc;
+use tracing::info;
+
+/// Defaults shared by everyone using this instance (the "workspace").
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WorkspaceSettings {
+    #[serde(default)]
+    pub rules: PayoutRules,
+    /// Show zkillboard points earned per pilot as merit points.
+    #[serde(default)]
+    pub merit_points: bool,
+    /// Kill times are shown and grouped into days in this timezone.
+    #[serde(default)]
+    pub timezone: Tz,
+    #[serde(default)]
+    pub pricing: PricingBackend,
+    /// zKillboard links kept warm in the background (see prefetch).
+    #[serde(default)]
+    pub watched: Vec<String>,
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+    /// Payout rules saved under a name, by name.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub presets: Vec<PayoutPreset>,
+    /// The scheduled loot digest for Discord.
+    #[serde(default)]
+    pub digest: DigestSettings,
+    #[serde(default)]
+    pub sign_off: SignOffSettings,
+    /// Name pilots on public transparency pages instead of numbering them.
+    #[serde(default)]
+    pub public_names: bool,
+    /// Suggest each pilot's nearest trade hub in published payouts.
+    #[serde(default)]
+    pub suggest_hubs: bool,
+    /// What hauling loot to Jita costs per gate jump; 0 hides the estimate.
+    #[serde(default)]
+    pub hauling_fee_per_jump: Isk,
+    /// The structure whose market prices loot under
+    /// `PricingBackend::StructureMarket`, e.g. the corp's Fortizar.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price_structure_id: Option<i64>,
+    /// Corp wallet division (1-7) loot sales are matched in (see sales);
+    /// 0 is the master wallet, 1.
+    #[serde(default)]
+    pub wallet_division: u8,
+    /// Item ID of the corp container (or office) loot is hauled into,
+    /// read as the loot actually collected (see loot_can).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loot_location_id: Option<i64>,
+    /// ESI location flag within it, e.g. `CorpSAG3` for an office's third
+    /// hangar division; empty takes everything at the location.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub loot_location_flag: String,
+}
+
+impl WorkspaceSettings {
+    pub fn wallet_division(&self) -> u8 {
+        self.wallet_division.clamp(1, 7)
+    }
+
+    pub fn preset(&self, name: &str) -> Option<&PayoutPreset> {
+        self.presets.iter().find(|p| p.name == name)
+    }
+}
+
+/// Who approves finalized payouts before they are announced.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SignOffSettings {
+    /// Publishing a payout waits for at least one sign-off.
+    #[serde(default)]
+    pub required: bool,
+    /// Characters who may sign off, e.g. a second FC or a director. With
+    /// none designated, admins may.
+    #[serde(default)]
+    pub reviewers: BTreeSet<String>,
+}
+
+impl SignOffSettings {
+    /// Sign-offs name a character, so they need an SSO login. Reviewers
+    /// still need FC rights to reach the operation's edit routes.
+    pub fn may_sign_off(&self, viewer: &Viewer) -> bool {
+        let Some(user) = viewer.user.as_ref().filter(|_| viewer.can_edit()) else {
+            return false;
+        };
+        if self.reviewers.is_empty() {
+            return user.role == Role::Admin;
+        }
+        self.reviewers
+            .iter()
+            .any(|r| r.eq_ignore_ascii_case(user.character_name.as_str()))
+    }
+}
+
+/// Where the value of dropped loot comes from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PricingBackend {
+    /// zKillboard's dropped value.
+    #[default]
+    Zkillboard,
+    /// The dropped items priced at ESI's average market prices, applied
+    /// when kills are fetched.
+    EsiAverage,
+    /// The dropped items at the lowest sell order on a player structure's
+    /// market (`WorkspaceSettings::price_structure_id`), for corps selling
+    /// loot at home. Needs an admin's market grant.
+    StructureMarket,
+}
+
+#[derive(Template)]
+#[template(path = "settings.html")]
+struct SettingsTemplate {
+    viewer: Viewer,
+    settings: WorkspaceSettings,
+    default_mapping: String,
+    webhooks: Vec<Webhook>,
+    event_kinds: [&'static str; 5],
+    timezones: &'static [Tz],
+    watched: Vec<(String, Option<PrefetchStatus>)>,
+    prefetch_minutes: u64,
+    /// What the retention policy would delete right now.
+    prune_preview: Option<PruneReport>,
+    deletion_requests: Vec<(CharacterId, DeletionRequest)>,
+    /// Who granted structure market access, and when.
+    market_grant: Option<(SafeName, String)>,
+    /// The same for corp wallet access.
+    wallet_grant: Option<(SafeName, String)>,
+    /// The same for corp assets access.
+    assets_grant: Option<(SafeName, String)>,
+    sso_enabled: bool,
+}
+
+impl SettingsTemplate {
+    fn excluded_ship_groups(&self) -> String {
+        let groups: Vec<String> = self
+            .settings
+            .rules
+            .excluded_ship_groups
+            .iter()
+            .map(|g| g.to_string())
+            .collect();
+        groups.join(", ")
+    }
+
+    fn watched_text(&self) -> String {
+        self.settings.watched.join("\n")
+    }
+
+    fn sign_off_reviewers(&self) -> String {
+        let reviewers: Vec<&str> = self
+            .settings
+            .sign_off
+            .reviewers
+            .iter()
+            .map(String::as_str)
+            .collect();
+        reviewers.join("\n")
+    }
+
+    fn warm_days(&self) -> i64 {
+        WARM_DAYS
+    }
+
+    fn min_kill_value_millions(&self) -> f64 {
+        self.settings.rules.min_kill_value.to_f64() / 1_000_000.0
+    }
+
+    /// The Pochven rules, or the general ones to start from.
+    fn pochven(&self) -> PochvenRules {
+        let rules = &self.settings.rules;
+        rules.pochven.unwrap_or(PochvenRules {
+            mode: rules.mode,
+            corp_tax_percent: rules.corp_tax_percent,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SettingsForm {
+    #[serde(default)]
+    solo_bonus_percent: Decimal,
+    merit_points: Option<String>,
+    #[serde(default)]
+    loss_netting: LossNetting,
+    #[serde(default)]
+    srp_amount: Decimal,
+    #[serde(default)]
+    default_mapping: String,
+    #[serde(default)]
+    payout_mode: PayoutMode,
+    #[serde(default)]
+    corp_tax_percent: Decimal,
+    #[serde(default)]
+    rounding: PayoutRounding,
+    #[serde(default)]
+    share_script: String,
+    pochven_rules: Option<String>,
+    #[serde(default)]
+    pochven_payout_mode: PayoutMode,
+    #[serde(default)]
+    pochven_corp_tax_percent: Decimal,
+    /// Millions of ISK.
+    #[serde(default)]
+    min_kill_value: Decimal,
+    /// Comma-separated ESI group IDs.
+    #[serde(default)]
+    excluded_ship_groups: String,
+    #[serde(default)]
+    timezone: String,
+    #[serde(default)]
+    pricing: PricingBackend,
+    /// One zKillboard link per line.
+    #[serde(default)]
+    watched: String,
+    #[serde(default)]
+    killmail_retention_months: u32,
+    #[serde(default)]
+    operation_retention_months: u32,
+    #[serde(default)]
+    digest_schedule: DigestSchedule,
+    #[serde(default)]
+    discord_webhook: String,
+    require_sign_off: Option<String>,
+    public_names: Option<String>,
+    suggest_hubs: Option<String>,
+    /// ISK.
+    #[serde(default)]
+    hauling_fee_per_jump: Decimal,
+    #[serde(default)]
+    price_structure_id: String,
+    #[serde(default)]
+    wallet_division: u8,
+    #[serde(default)]
+    loot_location_id: String,
+    #[serde(default)]
+    loot_location_flag: String,
+    /// One character name per line.
+    #[serde(default)]
+    sign_off_reviewers: String,
+}
+
+pub async fn show_settings(State(state): State<Arc<AppState>>, viewer: Viewer) -> Response {
+    let prune_preview = retention::prune(&state, true).await;
+    let template = {
+        let store = state.store.lock().await;
+        SettingsTemplate {
+            viewer,
+            settings: store.data.settings.clone(),
+            default_mapping: store.data.default_mapping.clone(),
+            webhooks: store.data.webhooks.clone(),
+            event_kinds: EVENT_KINDS,
+            timezones: &TZ_VARIANTS,
+            watched: {
+                let status = state.prefetch_status.lock().unwrap();
+                store
+                    .data
+                    .settings
+                    .watched
+                    .iter()
+                    .map(|link| (link.clone(), status.get(link).cloned()))
+                    .collect()
+            },
+            prefetch_minutes: state.config.prefetch_minutes,
+            prune_preview: store
+                .data
+                .settings
+                .retention
+                .is_enabled()
+                .then_some(prune_preview),
+            deletion_requests: store
+                .data
+                .deletion_requests
+                .iter()
+                .map(|(id, r)| (*id, r.clone()))
+                .collect(),
+            market_grant: store.data.market_grant.as_ref().map(EsiGrant::describe),
+            wallet_grant: store.data.wallet_grant.as_ref().map(EsiGrant::describe),
+            assets_grant: store.data.assets_grant.as_ref().map(EsiGrant::describe),
+            sso_enabled: state.config.sso_enabled(),
+        }
+    };
+    render_page(&template)
+}
+
+pub async fn update_settings(
+    State(state): State<Arc<AppState>>,
+    Form(form): Form<SettingsForm>,
+) -> Response {
+    let share_script = form.share_script.trim();
+    if !share_script.is_empty() {
+        if let Err(e) = Script::parse(share_script) {
+            return (StatusCode::BAD_REQUEST, format!("Share script: {}", e)).into_response();
+        }
+    }
+    let discord_webhook = form.discord_webhook.trim();
+    if !discord_webhook.is_empty()
+        && reqwest::Url::parse(discord_webhook).map_or(true, |u| u.scheme() != "https")
+    {
+        return (StatusCode::BAD_REQUEST, "Discord webhook URL must be https").into_response();
+    }
+    let Ok(min_kill_value) =
+        Isk(form.min_kill_value.max(Decimal::ZERO)).checked_mul(Decimal::from(1_000_000))
+    else {
+        return (StatusCode::BAD_REQUEST, "Minimum kill value is too large").into_response();
+    };
+    let percent = |p: Decimal| p.clamp(Decimal::ZERO, Decimal::ONE_HUNDRED);
+    let mut store = state.store.lock().await;
+    let settings = &mut store.data.settings;
+    settings.rules.solo_bonus_percent = percent(form.solo_bonus_percent);
+    settings.merit_points = form.merit_points.is_some();
+    settings.rules.loss_netting = form.loss_netting;
+    settings.rules.srp_amount = Isk(form.srp_amount.max(Decimal::ZERO));
+    settings.rules.mode = form.payout_mode;
+    settings.rules.corp_tax_percent = percent(form.corp_tax_percent);
+    settings.rules.rounding = form.rounding;
+    settings.rules.share_script = (!share_script.is_empty()).then(|| share_script.to_string());
+    settings.rules.pochven = form.pochven_rules.is_some().then_some(PochvenRules {
+        mode: form.pochven_payout_mode,
+        corp_tax_percent: percent(form.pochven_corp_tax_percent),
+    });
+    settings.rules.min_kill_value = min_kill_value;
+    settings.rules.excluded_ship_groups = form
+        .excluded_ship_groups
+        .split(',')
+        .filter_map(|g| g.trim().parse().ok())
+        .collect();
+    // Unknown names keep the previous timezone rather than resetting to UTC
+    if let Ok(tz) = form.timezone.trim().parse() {
+        settings.timezone = tz;
+    }
+    settings.pricing = form.pricing;
+    settings.price_structure_id = form.price_structure_id.trim().parse().ok();
+    settings.wallet_division = form.wallet_division.clamp(1, 7);
+    settings.loot_location_id = form.loot_location_id.trim().parse().ok();
+    settings.loot_location_flag = form.loot_location_flag.trim().to_string();
+    settings.retention = RetentionPolicy {
+        killmail_months: form.killmail_retention_months,
+        operation_months: form.operation_retention_months,
+    };
+    settings.digest.schedule = form.digest_schedule;
+    settings.digest.discord_webhook = discord_webhook.to_string();
+    settings.public_names = form.public_names.is_some();
+    settings.suggest_hubs = form.suggest_hubs.is_some();
+    settings.hauling_fee_per_jump = Isk(form.hauling_fee_per_jump.max(Decimal::ZERO));
+    settings.sign_off = SignOffSettings {
+        required: form.require_sign_off.is_some(),
+        reviewers: form
+            .sign_off_reviewers
+            .lines()
+            .map(|l| SafeName::from(l.trim()).to_string())
+            .filter(|l| !l.is_empty())
+            .collect(),
+    };
+    let mut seen = HashSet::new();
+    settings.watched = form
+        .watched
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && seen.insert(*l))
+        .map(str::to_string)
+        .collect();
+    store.data.default_mapping = form.default_mapping.trim().to_string();
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    state.result_cache.lock().unwrap().clear();
+    state.structure_prices.lock().unwrap().take();
+    base_path::redirect("/settings").into_response()
+}
+
+/// `POST /settings/market-access/revoke`: forgets the market grant's
+/// refresh token. Structure pricing falls back to ESI averages until
+/// access is granted again.
+pub async fn revoke_market_access(State(state): State<Arc<AppState>>) -> Response {
+    revoke(&state, GrantKind::Market).await
+}
+
+/// `POST /settings/wallet-access/revoke`: the same for the wallet grant;
+/// sales are then only entered by hand.
+pub async fn revoke_wallet_access(State(state): State<Arc<AppState>>) -> Response {
+    revoke(&state, GrantKind::Wallet).await
+}
+
+/// `POST /settings/assets-access/revoke`: the same for the assets grant;
+/// loot cans imported before keep their snapshot.
+pub async fn revoke_assets_access(State(state): State<Arc<AppState>>) -> Response {
+    revoke(&state, GrantKind::Assets).await
+}
+
+async fn revoke(state: &AppState, kind: GrantKind) -> Response {
+    let mut store = state.store.lock().await;
+    if let Some(grant) = store.data.grant_mut(kind).take() {
+        info!("Revoked {}'s {} access", grant.character_name, kind.label());
+        if let Err(e) = store.save().await {
+            return e.into_response();
+        }
+    }
+    drop(store);
+    state.structure_prices.lock().unwrap().take();
+    base_path::redirect("/settings").into_response()
+}
+
+#[derive(Deserialize)]
+pub struct WebhookForm {
+    url: String,
+    /// Comma-joined event kinds; empty subscribes to everything.
+    #[serde(default)]
+    events: String,
+}
+
+/// Registers a webhook with a freshly generated signing secret.
+pub async fn add_webhook(
+    State(state): State<Arc<AppState>>,
+    Form(form): Form<WebhookForm>,
+) -> Response {
+    let url = form.url.trim();
+    if reqwest::Url::parse(url).map_or(true, |u| !matches!(u.scheme(), "http" | "https")) {
+        return (StatusCode::BAD_REQUEST, "Webhook URL must be http(s)").into_response();
+    }
+    let mut store = state.store.lock().await;
+    store.data.next_webhook_id += 1;
+    let webhook = Webhook {
+        id: store.data.next_webhook_id,
+        url: url.to_string(),
+        secret: auth::random_token(),
+        events: form
+            .events
+            .split(',')
+            .map(str::trim)
+            .filter(|e| EVENT_KINDS.contains(e))
+            .map(str::to_string)
+            .collect(),
+        created_at: Utc::now(),
+    };
+    info!("Registered webhook {} -> {}", webhook.id, webhook.url);
+    store.data.webhooks.push(webhook);
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    base_path::redirect("/settings").into_response()
+}
+
+#[derive(Deserialize)]
+pub struct PresetForm {
+    name: String,
+}
+
+/// Saves the workspace's current payout settings as a preset, replacing
+/// one of the same name.
+pub async fn save_preset(
+    State(state): State<Arc<AppState>>,
+    Form(form): Form<PresetForm>,
+) -> Response {
+    let name = SafeName::from(form.name).to_string();
+    if name.is_empty() {
+        return (StatusCode::BAD_REQUEST, "A preset needs a name").into_response();
+    }
+    let mut store = state.store.lock().await;
+    let settings = &mut store.data.settings;
+    let preset = PayoutPreset {
+        name: name.clone(),
+        rules: settings.rules.clone(),
+    };
+    settings.presets.retain(|p| p.name != name);
+    settings.presets.push(preset);
+    settings.presets.sort_by(|a, b| a.name.cmp(&b.name));
+    info!("Saved payout preset '{}'", name);
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    // Cached results list the presets to save under
+    state.result_cache.lock().unwrap().clear();
+    base_path::redirect("/settings").into_response()
+}
+
+/// Operations saved with the preset keep their copy of its rules.
+pub async fn remove_preset(
+    State(state): State<Arc<AppState>>,
+    Form(form): Form<PresetForm>,
+) -> Response {
+    let mut store = state.store.lock().await;
+    store.data.settings.presets.retain(|p| p.name != form.name);
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    state.result_cache.lock().unwrap().clear();
+    base_path::redirect("/settings").into_response()
+}
+
+/// Posts the loot digest right away, e.g. to try a new webhook.
+pub async fn send_digest(State(state): State<Arc<AppState>>) -> Response {
+    match digest::send(&state).await {
+        Ok(()) => base_path::redirect("/settings").into_response(),
+        Err(e) => (StatusCode::BAD_GATEWAY, e).into_response(),
+    }
+}
+
+pub async fn remove_webhook(State(state): State<Arc<AppState>>, Path(id): Path<u64>) -> Response {
+    let mut store = state.store.lock().await;
+    store.data.webhooks.retain(|w| w.id != id);
+    if let Err(e) = store.save().await {
+        return e.into_response();
+    }
+    base_path::redirect("/settings").into_response()
+}
@@ -0,0 +1,1348 @@
+use crate::config::{Config, PoolConfig};
+use crate::models::{EsiKillmail, KillmailId};
+
+use axum::{
+    async_trait,
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde_json::Value;
+use sqlx::postgres::{PgConnection, PgPool, PgPoolOptions};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tracing::info;
+
+/// Where the data store, sessions and other shared state live. Replicas
+/// pointed at the same SQLite file or Redis server share all of it; the
+/// JSON file and memory backends only suit a single instance.
+///
+/// The data store is kept in rows: one per operation and one document for
+/// everything else. Each row has a revision its saves bump, so a replica
+/// can tell which rows another one changed, and a save only writes the rows
+/// that changed: replicas editing different operations don't get in each
+/// other's way. Everything else goes through a small string key-value space
+/// split into namespaces, written an entry at a time.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// Whether other instances may be using the same storage.
+    fn is_shared(&self) -> bool {
+        false
+    }
+    /// Current revision of the document and of every operation (0 and none
+    /// before the first save).
+    async fn revisions(&self) -> Result<Revisions, String>;
+    /// The saved document: the data store without its operations.
+    async fn document(&self) -> Result<Option<Row>, String>;
+    /// Those of the saved operations `ids` names, by ID.
+    async fn operations(&self, ids: &[u64]) -> Result<BTreeMap<u64, Row>, String>;
+    /// Writes `changes` if every row they touch is still at the revision
+    /// its change was based on, bumping each by one. Writes nothing when
+    /// another instance changed one of them first.
+    async fn save(&self, changes: &Changes) -> Result<(), SaveError>;
+    async fn get(&self, namespace: &str, key: &str) -> Result<Option<String>, String>;
+    async fn put(&self, namespace: &str, key: &str, value: &str) -> Result<(), String>;
+    async fn remove(&self, namespace: &str, key: &str) -> Result<(), String>;
+    /// Every entry of `namespace`, for sweeping it or warming a cache.
+    async fn entries(&self, namespace: &str) -> Result<Vec<(String, String)>, String>;
+    /// Writes several entries of `namespace` at once.
+    async fn put_all(&self, namespace: &str, entries: &[(String, String)]) -> Result<(), String> {
+        for (key, value) in entries {
+            self.put(namespace, key, value).await?;
+        }
+        Ok(())
+    }
+    /// Hydrated ESI killmails the backend kept, of those asked for. Only
+    /// Postgres keeps them; the others rely on the in-memory cache.
+    async fn cached_killmails(
+        &self,
+        _ids: &[KillmailId],
+    ) -> Result<HashMap<KillmailId, EsiKillmail>, String> {
+        Ok(HashMap::new())
+    }
+    async fn cache_killmails(
+        &self,
+        _killmails: &[(KillmailId, EsiKillmail)],
+    ) -> Result<(), String> {
+        Ok(())
+    }
+    /// Deletes (or with `dry_run` counts) kept killmails older than `before`.
+    async fn prune_killmails(&self, _before: DateTime<Utc>, _dry_run: bool) -> Result<u64, String> {
+        Ok(0)
+    }
+}
+
+/// A saved row of the data store: its JSON and revision.
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub data: String,
+    pub revision: u64,
+}
+
+/// Revisions of the data store's rows (see Storage).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Revisions {
+    pub document: u64,
+    pub operations: BTreeMap<u64, u64>,
+}
+
+/// The rows a save writes.
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub document: Option<Change>,
+    pub operations: BTreeMap<u64, Change>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.document.is_none() && self.operations.is_empty()
+    }
+}
+
+/// A row's new JSON, or `None` to delete it, and the revision it was based
+/// on (0 for a row not saved before).
+#[derive(Debug)]
+pub struct Change {
+    pub data: Option<String>,
+    pub base: u64,
+}
+
+/// Why the data store wasn't saved.
+#[derive(Debug)]
+pub enum SaveError {
+    /// Another instance saved first; nothing was written.
+    Conflict,
+    Storage(String),
+}
+
+impl From<String> for SaveError {
+    fn from(e: String) -> Self {
+        SaveError::Storage(e)
+    }
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Conflict => f.write_str("the data store was changed by another instance"),
+            SaveError::Storage(e) => f.write_str(e),
+        }
+    }
+}
+
+/// The answer to an edit that didn't reach storage. After a conflict the
+/// store holds what the other instance saved, so trying again works.
+impl IntoResponse for SaveError {
+    fn into_response(self) -> Response {
+        match self {
+            SaveError::Conflict => (
+                StatusCode::CONFLICT,
+                Html(
+                    "Someone else changed the data at the same time; reload and try again"
+                        .to_string(),
+                ),
+            )
+                .into_response(),
+            SaveError::Storage(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Html(format!("Failed to save: {}", e)),
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// Picks the backend from `EVE_LOOTER_STORAGE` (`sqlite://<path>`,
+/// `redis://...` or `postgres://...`), falling back to the JSON data file,
+/// or memory without one.
+pub async fn from_config(config: &Config) -> Result<Box<dyn Storage>, String> {
+    let storage: Box<dyn Storage> = match config.storage_url.as_deref() {
+        Some(url) if url.starts_with("sqlite://") => {
+            Box::new(SqliteStorage::open(url.trim_start_matches("sqlite://"))?)
+        }
+        Some(url) if url.starts_with("redis://") || url.starts_with("rediss://") => {
+            Box::new(RedisStorage::open(url).await?)
+        }
+        Some(url) if url.starts_with("postgres://") || url.starts_with("postgresql://") => {
+            Box::new(PostgresStorage::connect(url, &config.postgres_pool).await?)
+        }
+        Some(url) => return Err(format!("Unsupported EVE_LOOTER_STORAGE: {}", url)),
+        None => match &config.data_file {
+            Some(path) => Box::new(JsonFileStorage::new(path.clone())),
+            None => Box::new(MemoryStorage::default()),
+        },
+    };
+    info!("Using {} storage", storage.name());
+    Ok(storage)
+}
+
+/// The data store rows of the single-instance backends.
+#[derive(Default)]
+struct LocalRows {
+    document: Option<Row>,
+    operations: BTreeMap<u64, Row>,
+}
+
+impl LocalRows {
+    fn revisions(&self) -> Revisions {
+        Revisions {
+            document: self.document.as_ref().map_or(0, |row| row.revision),
+            operations: self
+                .operations
+                .iter()
+                .map(|(id, row)| (*id, row.revision))
+                .collect(),
+        }
+    }
+
+    fn operations(&self, ids: &[u64]) -> BTreeMap<u64, Row> {
+        ids.iter()
+            .filter_map(|id| Some((*id, self.operations.get(id)?.clone())))
+            .collect()
+    }
+
+    fn save(&mut self, changes: &Changes) -> Result<(), SaveError> {
+        if !is_current(&self.revisions(), changes) {
+            return Err(SaveError::Conflict);
+        }
+        if let Some(Change {
+            data: Some(data),
+            base,
+        }) = &changes.document
+        {
+            self.document = Some(Row {
+                data: data.clone(),
+                revision: base + 1,
+            });
+        }
+        for (id, change) in &changes.operations {
+            match &change.data {
+                Some(data) => {
+                    let row = Row {
+                        data: data.clone(),
+                        revision: change.base + 1,
+                    };
+                    self.operations.insert(*id, row);
+                }
+                None => {
+                    self.operations.remove(id);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether every row `changes` touch is still at the revision its change
+/// was based on.
+fn is_current(revisions: &Revisions, changes: &Changes) -> bool {
+    let document_current = changes
+        .document
+        .as_ref()
+        .is_none_or(|change| change.base == revisions.document);
+    document_current
+        && changes.operations.iter().all(|(id, change)| {
+            change.base == revisions.operations.get(id).copied().unwrap_or_default()
+        })
+}
+
+/// The key-value space of the single-instance backends. With a data file,
+/// each namespace is kept in a JSON file beside it (`<data>.<namespace>.json`),
+/// read on first use.
+#[derive(Default)]
+struct LocalEntries {
+    namespaces: Mutex<HashMap<String, BTreeMap<String, String>>>,
+    beside: Option<PathBuf>,
+}
+
+impl LocalEntries {
+    fn beside(path: PathBuf) -> Self {
+        Self {
+            namespaces: Mutex::default(),
+            beside: Some(path),
+        }
+    }
+
+    fn file(&self, namespace: &str) -> Option<PathBuf> {
+        let path = self.beside.as_ref()?;
+        Some(path.with_extension(format!("{}.json", namespace)))
+    }
+
+    /// Runs `f` on the entries of `namespace`, writing them back to its file
+    /// when `f` says they changed.
+    fn with<T>(
+        &self,
+        namespace: &str,
+        f: impl FnOnce(&mut BTreeMap<String, String>) -> (T, bool),
+    ) -> Result<T, String> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        let entries = match namespaces.entry(namespace.to_string()) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                let loaded = match self.file(namespace) {
+                    Some(file) if file.exists() => {
+                        let raw = std::fs::read_to_string(&file).map_err(|e| e.to_string())?;
+                        serde_json::from_str(&raw).map_err(|e| e.to_string())?
+                    }
+                    _ => BTreeMap::new(),
+                };
+                entry.insert(loaded)
+            }
+        };
+        let (result, changed) = f(entries);
+        if let (true, Some(file)) = (changed, self.file(namespace)) {
+            let raw = serde_json::to_string(&*entries).map_err(|e| e.to_string())?;
+            write_atomically(&file, raw)?;
+        }
+        Ok(result)
+    }
+
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<String>, String> {
+        self.with(namespace, |entries| (entries.get(key).cloned(), false))
+    }
+
+    fn put_all(&self, namespace: &str, new: &[(String, String)]) -> Result<(), String> {
+        self.with(namespace, |entries| {
+            entries.extend(new.iter().cloned());
+            ((), !new.is_empty())
+        })
+    }
+
+    fn remove(&self, namespace: &str, key: &str) -> Result<(), String> {
+        self.with(namespace, |entries| ((), entries.remove(key).is_some()))
+    }
+
+    fn entries(&self, namespace: &str) -> Result<Vec<(String, String)>, String> {
+        self.with(namespace, |entries| {
+            (
+                entries
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect(),
+                false,
+            )
+        })
+    }
+}
+
+/// Write-then-rename so a crash never leaves a truncated file behind.
+fn write_atomically(path: &std::path::Path, raw: String) -> Result<(), String> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, raw).map_err(|e| e.to_string())?;
+    std::fs::rename(&tmp, path).map_err(|e| e.to_string())
+}
+
+/// Nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryStorage {
+    rows: Mutex<LocalRows>,
+    entries: LocalEntries,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    fn name(&self) -> &'static str {
+        "in-memory"
+    }
+
+    async fn revisions(&self) -> Result<Revisions, String> {
+        Ok(self.rows.lock().unwrap().revisions())
+    }
+
+    async fn document(&self) -> Result<Option<Row>, String> {
+        Ok(self.rows.lock().unwrap().document.clone())
+    }
+
+    async fn operations(&self, ids: &[u64]) -> Result<BTreeMap<u64, Row>, String> {
+        Ok(self.rows.lock().unwrap().operations(ids))
+    }
+
+    async fn save(&self, changes: &Changes) -> Result<(), SaveError> {
+        self.rows.lock().unwrap().save(changes)
+    }
+
+    async fn get(&self, namespace: &str, key: &str) -> Result<Option<String>, String> {
+        self.entries.get(namespace, key)
+    }
+
+    async fn put(&self, namespace: &str, key: &str, value: &str) -> Result<(), String> {
+        self.put_all(namespace, &[(key.to_string(), value.to_string())])
+            .await
+    }
+
+    async fn remove(&self, namespace: &str, key: &str) -> Result<(), String> {
+        self.entries.remove(namespace, key)
+    }
+
+    async fn entries(&self, namespace: &str) -> Result<Vec<(String, String)>, String> {
+        self.entries.entries(namespace)
+    }
+
+    async fn put_all(&self, namespace: &str, entries: &[(String, String)]) -> Result<(), String> {
+        self.entries.put_all(namespace, entries)
+    }
+}
+
+/// The data store in one JSON file (`EVE_LOOTER_DATA_FILE`), with its
+/// operations in the document's `operations` array; the key-value
+/// namespaces go in files beside it.
+pub struct JsonFileStorage {
+    path: PathBuf,
+    /// Read from the file on first use; `None` until then.
+    rows: Mutex<Option<LocalRows>>,
+    entries: LocalEntries,
+}
+
+impl JsonFileStorage {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            entries: LocalEntries::beside(path.clone()),
+            path,
+            rows: Mutex::new(None),
+        }
+    }
+
+    fn rows(&self) -> Result<MutexGuard<'_, Option<LocalRows>>, String> {
+        let mut rows = self.rows.lock().unwrap();
+        if rows.is_none() {
+            *rows = Some(self.read()?);
+        }
+        Ok(rows)
+    }
+
+    fn read(&self) -> Result<LocalRows, String> {
+        if !self.path.exists() {
+            return Ok(LocalRows::default());
+        }
+        let raw = std::fs::read_to_string(&self.path).map_err(|e| e.to_string())?;
+        let mut document: Value = serde_json::from_str(&raw).map_err(|e| e.to_string())?;
+        let operations = match document
+            .as_object_mut()
+            .and_then(|d| d.remove("operations"))
+        {
+            Some(Value::Array(operations)) => operations,
+            _ => vec![],
+        };
+        let row = |data: String| Row { data, revision: 1 };
+        Ok(LocalRows {
+            document: Some(row(document.to_string())),
+            operations: operations
+                .into_iter()
+                .map(|op| (op["id"].as_u64().unwrap_or_default(), row(op.to_string())))
+                .collect(),
+        })
+    }
+
+    fn write(&self, rows: &LocalRows) -> Result<(), String> {
+        let mut document: Value = match &rows.document {
+            Some(row) => serde_json::from_str(&row.data).map_err(|e| e.to_string())?,
+            None => Value::Object(Default::default()),
+        };
+        let operations = rows
+            .operations
+            .values()
+            .map(|row| serde_json::from_str(&row.data))
+            .collect::<Result<Vec<Value>, _>>()
+            .map_err(|e| e.to_string())?;
+        document["operations"] = Value::Array(operations);
+        write_atomically(&self.path, document.to_string())
+    }
+}
+
+#[async_trait]
+impl Storage for JsonFileStorage {
+    fn name(&self) -> &'static str {
+        "JSON file"
+    }
+
+    async fn revisions(&self) -> Result<Revisions, String> {
+        Ok(self.rows()?.as_ref().unwrap().revisions())
+    }
+
+    async fn document(&self) -> Result<Option<Row>, String> {
+        Ok(self.rows()?.as_ref().unwrap().document.clone())
+    }
+
+    async fn operations(&self, ids: &[u64]) -> Result<BTreeMap<u64, Row>, String> {
+        Ok(self.rows()?.as_ref().unwrap().operations(ids))
+    }
+
+    async fn save(&self, changes: &Changes) -> Result<(), SaveError> {
+        let mut guard = self.rows()?;
+        let rows = guard.as_mut().unwrap();
+        rows.save(changes)?;
+        if let Err(e) = self.write(rows) {
+            // Forget what didn't reach the file; the next use reads it again
+            *guard = None;
+            return Err(SaveError::Storage(e));
+        }
+        Ok(())
+    }
+
+    async fn get(&self, namespace: &str, key: &str) -> Result<Option<String>, String> {
+        self.entries.get(namespace, key)
+    }
+
+    async fn put(&self, namespace: &str, key: &str, value: &str) -> Result<(), String> {
+        self.put_all(namespace, &[(key.to_string(), value.to_string())])
+            .await
+    }
+
+    async fn remove(&self, namespace: &str, key: &str) -> Result<(), String> {
+        self.entries.remove(namespace, key)
+    }
+
+    async fn entries(&self, namespace: &str) -> Result<Vec<(String, String)>, String> {
+        self.entries.entries(namespace)
+    }
+
+    async fn put_all(&self, namespace: &str, entries: &[(String, String)]) -> Result<(), String> {
+        self.entries.put_all(namespace, entries)
+    }
+}
+
+/// A SQLite database file, shareable by replicas on the same host or volume.
+/// Queries run on tokio's blocking pool.
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))
+            .map_err(|e| e.to_string())?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS store (
+                 id INTEGER PRIMARY KEY CHECK (id = 1),
+                 revision INTEGER NOT NULL,
+                 data TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS operations (
+                 id INTEGER PRIMARY KEY,
+                 revision INTEGER NOT NULL,
+                 data TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS entries (
+                 namespace TEXT NOT NULL,
+                 key TEXT NOT NULL,
+                 value TEXT NOT NULL,
+                 PRIMARY KEY (namespace, key)
+             );",
+        )
+        .map_err(|e| e.to_string())?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    async fn with<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> Result<T, SaveError> + Send + 'static,
+    ) -> Result<T, SaveError> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+            .await
+            .map_err(|e| SaveError::Storage(e.to_string()))?
+    }
+
+    async fn query<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> Result<T, String> {
+        self.with(move |conn| f(conn).map_err(|e| SaveError::Storage(e.to_string())))
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+fn sqlite_revisions(conn: &Connection) -> rusqlite::Result<Revisions> {
+    let document = conn
+        .query_row("SELECT revision FROM store WHERE id = 1", [], |row| {
+            row.get(0)
+        })
+        .optional()?
+        .unwrap_or_default();
+    let mut statement = conn.prepare("SELECT id, revision FROM operations")?;
+    let operations = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(Revisions {
+        document,
+        operations,
+    })
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    fn name(&self) -> &'static str {
+        "SQLite"
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+
+    async fn revisions(&self) -> Result<Revisions, String> {
+        self.query(|conn| sqlite_revisions(conn)).await
+    }
+
+    async fn document(&self) -> Result<Option<Row>, String> {
+        self.query(|conn| {
+            conn.query_row("SELECT data, revision FROM store WHERE id = 1", [], |row| {
+                Ok(Row {
+                    data: row.get(0)?,
+                    revision: row.get(1)?,
+                })
+            })
+            .optional()
+        })
+        .await
+    }
+
+    async fn operations(&self, ids: &[u64]) -> Result<BTreeMap<u64, Row>, String> {
+        let ids = ids.to_vec();
+        self.query(move |conn| {
+            let mut statement =
+                conn.prepare_cached("SELECT data, revision FROM operations WHERE id = ?1")?;
+            let mut rows = BTreeMap::new();
+            for id in ids {
+                let row = statement
+                    .query_row(params![id], |row| {
+                        Ok(Row {
+                            data: row.get(0)?,
+                            revision: row.get(1)?,
+                        })
+                    })
+                    .optional()?;
+                if let Some(row) = row {
+                    rows.insert(id, row);
+                }
+            }
+            Ok(rows)
+        })
+        .await
+    }
+
+    async fn save(&self, changes: &Changes) -> Result<(), SaveError> {
+        let document = changes
+            .document
+            .as_ref()
+            .and_then(|c| Some((c.data.clone()?, c.base)));
+        let operations: Vec<(u64, Option<String>, u64)> = changes
+            .operations
+            .iter()
+            .map(|(id, c)| (*id, c.data.clone(), c.base))
+            .collect();
+        let bases = Changes {
+            document: changes.document.as_ref().map(|c| Change {
+                data: None,
+                base: c.base,
+            }),
+            operations: changes
+                .operations
+                .iter()
+                .map(|(id, c)| {
+                    (
+                        *id,
+                        Change {
+                            data: None,
+                            base: c.base,
+                        },
+                    )
+                })
+                .collect(),
+        };
+        let sql = |e: rusqlite::Error| SaveError::Storage(e.to_string());
+        self.with(move |conn| {
+            // Immediate, so no other replica writes between check and write
+            let tx = conn
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(sql)?;
+            if !is_current(&sqlite_revisions(&tx).map_err(sql)?, &bases) {
+                return Err(SaveError::Conflict);
+            }
+            if let Some((data, base)) = document {
+                tx.execute(
+                    "INSERT INTO store (id, revision, data) VALUES (1, ?1, ?2)
+                     ON CONFLICT (id) DO UPDATE SET revision = ?1, data = ?2",
+                    params![base + 1, data],
+                )
+                .map_err(sql)?;
+            }
+            for (id, data, base) in operations {
+                match data {
+                    Some(data) => tx.execute(
+                        "INSERT INTO operations (id, revision, data) VALUES (?1, ?2, ?3)
+                         ON CONFLICT (id) DO UPDATE SET revision = ?2, data = ?3",
+                        params![id, base + 1, data],
+                    ),
+                    None => tx.execute("DELETE FROM operations WHERE id = ?1", params![id]),
+                }
+                .map_err(sql)?;
+            }
+            tx.commit().map_err(sql)
+        })
+        .await
+    }
+
+    async fn get(&self, namespace: &str, key: &str) -> Result<Option<String>, String> {
+        let (namespace, key) = (namespace.to_string(), key.to_string());
+        self.query(move |conn| {
+            conn.query_row(
+                "SELECT value FROM entries WHERE namespace = ?1 AND key = ?2",
+                params![namespace, key],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn put(&self, namespace: &str, key: &str, value: &str) -> Result<(), String> {
+        self.put_all(namespace, &[(key.to_string(), value.to_string())])
+            .await
+    }
+
+    async fn remove(&self, namespace: &str, key: &str) -> Result<(), String> {
+        let (namespace, key) = (namespace.to_string(), key.to_string());
+        self.query(move |conn| {
+            conn.execute(
+                "DELETE FROM entries WHERE namespace = ?1 AND key = ?2",
+                params![namespace, key],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn entries(&self, namespace: &str) -> Result<Vec<(String, String)>, String> {
+        let namespace = namespace.to_string();
+        self.query(move |conn| {
+            let mut statement =
+                conn.prepare_cached("SELECT key, value FROM entries WHERE namespace = ?1")?;
+            let entries = statement
+                .query_map(params![namespace], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect();
+            entries
+        })
+        .await
+    }
+
+    async fn put_all(&self, namespace: &str, entries: &[(String, String)]) -> Result<(), String> {
+        let (namespace, entries) = (namespace.to_string(), entries.to_vec());
+        self.query(move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut statement = tx.prepare_cached(
+                    "INSERT OR REPLACE INTO entries (namespace, key, value) VALUES (?1, ?2, ?3)",
+                )?;
+                for (key, value) in &entries {
+                    statement.execute(params![namespace, key, value])?;
+                }
+            }
+            tx.commit()
+        })
+        .await
+    }
+}
+
+/// A Redis server, for replicas on different hosts. Keys are prefixed with
+/// `eve-looter:`.
+pub struct RedisStorage {
+    conn: redis::aio::MultiplexedConnection,
+}
+
+const REDIS_DOCUMENT: &str = "eve-looter:store:data";
+const REDIS_REVISION: &str = "eve-looter:store:revision";
+/// Hashes of the operations' JSON and revisions, by ID.
+const REDIS_OPERATIONS: &str = "eve-looter:store:operations";
+const REDIS_OPERATION_REVISIONS: &str = "eve-looter:store:operation-revisions";
+
+/// Compare-and-set of the store's rows: writes nothing unless every row is
+/// still at its base revision. ARGV holds the document's base revision and
+/// JSON (an empty base leaves the document be), then a base revision, ID
+/// and JSON for each operation (empty JSON deletes it).
+const REDIS_SAVE: &str = r"
+local function revision(value)
+    return tonumber(value or '0')
+end
+if ARGV[1] ~= '' and revision(redis.call('GET', KEYS[2])) ~= tonumber(ARGV[1]) then
+    return 0
+end
+for i = 3, #ARGV, 3 do
+    if revision(redis.call('HGET', KEYS[4], ARGV[i + 1])) ~= tonumber(ARGV[i]) then
+        return 0
+    end
+end
+if ARGV[1] ~= '' then
+    redis.call('SET', KEYS[1], ARGV[2])
+    redis.call('INCR', KEYS[2])
+end
+for i = 3, #ARGV, 3 do
+    if ARGV[i + 2] == '' then
+        redis.call('HDEL', KEYS[3], ARGV[i + 1])
+        redis.call('HDEL', KEYS[4], ARGV[i + 1])
+    else
+        redis.call('HSET', KEYS[3], ARGV[i + 1], ARGV[i + 2])
+        redis.call('HINCRBY', KEYS[4], ARGV[i + 1], 1)
+    end
+end
+return 1
+";
+
+impl RedisStorage {
+    pub async fn open(url: &str) -> Result<Self, String> {
+        let client = redis::Client::open(url).map_err(|e| e.to_string())?;
+        let conn = client
+            .get_multiplexed_tokio_connection()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(Self { conn })
+    }
+
+    fn namespace(namespace: &str) -> String {
+        format!("eve-looter:{}", namespace)
+    }
+}
+
+#[async_trait]
+impl Storage for RedisStorage {
+    fn name(&self) -> &'static str {
+        "Redis"
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+
+    async fn revisions(&self) -> Result<Revisions, String> {
+        let (document, operations): (Option<u64>, BTreeMap<u64, u64>) = redis::pipe()
+            .get(REDIS_REVISION)
+            .hgetall(REDIS_OPERATION_REVISIONS)
+            .query_async(&mut self.conn.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(Revisions {
+            document: document.unwrap_or_default(),
+            operations,
+        })
+    }
+
+    async fn document(&self) -> Result<Option<Row>, String> {
+        let (data, revision): (Option<String>, Option<u64>) = redis::pipe()
+            .get(REDIS_DOCUMENT)
+            .get(REDIS_REVISION)
+            .query_async(&mut self.conn.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(data.map(|data| Row {
+            data,
+            revision: revision.unwrap_or_default(),
+        }))
+    }
+
+    async fn operations(&self, ids: &[u64]) -> Result<BTreeMap<u64, Row>, String> {
+        if ids.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+        // HMGET even for a single ID, so both replies are lists
+        let (data, revisions): (Vec<Option<String>>, Vec<Option<u64>>) = redis::pipe()
+            .cmd("HMGET")
+            .arg(REDIS_OPERATIONS)
+            .arg(ids)
+            .cmd("HMGET")
+            .arg(REDIS_OPERATION_REVISIONS)
+            .arg(ids)
+            .query_async(&mut self.conn.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(ids
+            .iter()
+            .zip(data.into_iter().zip(revisions))
+            .filter_map(|(id, (data, revision))| {
+                let row = Row {
+                    data: data?,
+                    revision: revision.unwrap_or_default(),
+                };
+                Some((*id, row))
+            })
+            .collect())
+    }
+
+    async fn save(&self, changes: &Changes) -> Result<(), SaveError> {
+        let save = redis::Script::new(REDIS_SAVE);
+        let mut script = save.prepare_invoke();
+        script
+            .key(REDIS_DOCUMENT)
+            .key(REDIS_REVISION)
+            .key(REDIS_OPERATIONS)
+            .key(REDIS_OPERATION_REVISIONS);
+        match &changes.document {
+            Some(Change {
+                data: Some(data),
+                base,
+            }) => script.arg(base).arg(data),
+            _ => script.arg("").arg(""),
+        };
+        for (id, change) in &changes.operations {
+            script
+                .arg(change.base)
+                .arg(id)
+                .arg(change.data.as_deref().unwrap_or_default());
+        }
+        let saved: i64 = script
+            .invoke_async(&mut self.conn.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+        if saved == 0 {
+            return Err(SaveError::Conflict);
+        }
+        Ok(())
+    }
+
+    async fn get(&self, namespace: &str, key: &str) -> Result<Option<String>, String> {
+        self.conn
+            .clone()
+            .hget(Self::namespace(namespace), key)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn put(&self, namespace: &str, key: &str, value: &str) -> Result<(), String> {
+        self.conn
+            .clone()
+            .hset(Self::namespace(namespace), key, value)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn remove(&self, namespace: &str, key: &str) -> Result<(), String> {
+        self.conn
+            .clone()
+            .hdel(Self::namespace(namespace), key)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn entries(&self, namespace: &str) -> Result<Vec<(String, String)>, String> {
+        let entries: BTreeMap<String, String> = self
+            .conn
+            .clone()
+            .hgetall(Self::namespace(namespace))
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(entries.into_iter().collect())
+    }
+
+    async fn put_all(&self, namespace: &str, entries: &[(String, String)]) -> Result<(), String> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.conn
+            .clone()
+            .hset_multiple(Self::namespace(namespace), entries)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// PostgreSQL, for alliance-scale instances: besides the shared state it
+/// keeps every hydrated killmail, indexed by time, system, and the
+/// characters, corporations and alliances on it, so they survive restarts
+/// and are shared by all replicas. Queries run on the app's runtime.
+pub struct PostgresStorage {
+    pool: PgPool,
+}
+
+const POSTGRES_SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS store (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    revision BIGINT NOT NULL,
+    data JSONB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS operations (
+    id BIGINT PRIMARY KEY,
+    revision BIGINT NOT NULL,
+    end_date TEXT NOT NULL,
+    data JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS operations_end_date ON operations (end_date);
+CREATE TABLE IF NOT EXISTS entries (
+    namespace TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (namespace, key)
+);
+CREATE TABLE IF NOT EXISTS killmails (
+    killmail_id BIGINT PRIMARY KEY,
+    killmail_time TIMESTAMPTZ NOT NULL,
+    solar_system_id BIGINT NOT NULL,
+    victim_character_id BIGINT,
+    victim_corporation_id BIGINT,
+    victim_alliance_id BIGINT,
+    data JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS killmails_time ON killmails (killmail_time);
+CREATE INDEX IF NOT EXISTS killmails_system ON killmails (solar_system_id, killmail_time);
+CREATE INDEX IF NOT EXISTS killmails_victim_character ON killmails (victim_character_id);
+CREATE INDEX IF NOT EXISTS killmails_victim_corporation ON killmails (victim_corporation_id, killmail_time);
+CREATE INDEX IF NOT EXISTS killmails_victim_alliance ON killmails (victim_alliance_id, killmail_time);
+CREATE TABLE IF NOT EXISTS killmail_attackers (
+    killmail_id BIGINT NOT NULL REFERENCES killmails ON DELETE CASCADE,
+    character_id BIGINT,
+    corporation_id BIGINT,
+    alliance_id BIGINT
+);
+-- Tables created before IDs were 64-bit
+DO $$
+BEGIN
+    IF (SELECT data_type FROM information_schema.columns
+        WHERE table_name = 'killmails' AND column_name = 'killmail_id') = 'integer' THEN
+        ALTER TABLE killmails
+            ALTER COLUMN killmail_id TYPE BIGINT,
+            ALTER COLUMN solar_system_id TYPE BIGINT,
+            ALTER COLUMN victim_character_id TYPE BIGINT,
+            ALTER COLUMN victim_corporation_id TYPE BIGINT,
+            ALTER COLUMN victim_alliance_id TYPE BIGINT;
+        ALTER TABLE killmail_attackers
+            ALTER COLUMN killmail_id TYPE BIGINT,
+            ALTER COLUMN character_id TYPE BIGINT,
+            ALTER COLUMN corporation_id TYPE BIGINT,
+            ALTER COLUMN alliance_id TYPE BIGINT;
+    END IF;
+END $$;
+CREATE INDEX IF NOT EXISTS killmail_attackers_killmail ON killmail_attackers (killmail_id);
+CREATE INDEX IF NOT EXISTS killmail_attackers_character ON killmail_attackers (character_id);
+CREATE INDEX IF NOT EXISTS killmail_attackers_corporation ON killmail_attackers (corporation_id);
+CREATE INDEX IF NOT EXISTS killmail_attackers_alliance ON killmail_attackers (alliance_id);
+";
+
+/// Serializes saves across replicas for the length of their transaction, so
+/// no other save lands between the revision check and the writes.
+const POSTGRES_SAVE_LOCK: i64 = 0x6576_652d_6c6f_6f74;
+
+impl PostgresStorage {
+    pub async fn connect(url: &str, pool: &PoolConfig) -> Result<Self, String> {
+        let pool = PgPoolOptions::new()
+            .max_connections(pool.max_connections)
+            .min_connections(pool.min_connections)
+            .acquire_timeout(Duration::from_secs(pool.acquire_timeout_secs))
+            .connect(url)
+            .await
+            .map_err(|e| e.to_string())?;
+        sqlx::raw_sql(POSTGRES_SCHEMA)
+            .execute(&pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(Self { pool })
+    }
+
+    #[cfg(test)]
+    pub async fn clear(&self) -> Result<(), String> {
+        sqlx::raw_sql("TRUNCATE store, operations, entries, killmails, killmail_attackers")
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+async fn postgres_revisions(conn: &mut PgConnection) -> Result<Revisions, sqlx::Error> {
+    let document: Option<i64> = sqlx::query_scalar("SELECT revision FROM store WHERE id = 1")
+        .fetch_optional(&mut *conn)
+        .await?;
+    let operations: Vec<(i64, i64)> = sqlx::query_as("SELECT id, revision FROM operations")
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(Revisions {
+        document: document.unwrap_or_default() as u64,
+        operations: operations
+            .into_iter()
+            .map(|(id, revision)| (id as u64, revision as u64))
+            .collect(),
+    })
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    fn name(&self) -> &'static str {
+        "PostgreSQL"
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+
+    async fn revisions(&self) -> Result<Revisions, String> {
+        let mut conn = self.pool.acquire().await.map_err(|e| e.to_string())?;
+        postgres_revisions(&mut conn)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn document(&self) -> Result<Option<Row>, String> {
+        let row: Option<(String, i64)> =
+            sqlx::query_as("SELECT data::text, revision FROM store WHERE id = 1")
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(|e| e.to_string())?;
+        Ok(row.map(|(data, revision)| Row {
+            data,
+            revision: revision as u64,
+        }))
+    }
+
+    async fn operations(&self, ids: &[u64]) -> Result<BTreeMap<u64, Row>, String> {
+        let ids: Vec<i64> = ids.iter().map(|id| *id as i64).collect();
+        let rows: Vec<(i64, String, i64)> =
+            sqlx::query_as("SELECT id, data::text, revision FROM operations WHERE id = ANY($1)")
+                .bind(&ids)
+                .fetch_all(&self.pool)
+                .await
+                .map_err(|e| e.to_string())?;
+        Ok(rows
+            .into_iter()
+            .map(|(id, data, revision)| {
+                let row = Row {
+                    data,
+                    revision: revision as u64,
+                };
+                (id as u64, row)
+            })
+            .collect())
+    }
+
+    async fn save(&self, changes: &Changes) -> Result<(), SaveError> {
+        let sql = |e: sqlx::Error| SaveError::Storage(e.to_string());
+        let mut tx = self.pool.begin().await.map_err(sql)?;
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(POSTGRES_SAVE_LOCK)
+            .execute(&mut *tx)
+            .await
+            .map_err(sql)?;
+        let revisions = postgres_revisions(&mut tx).await.map_err(sql)?;
+        if !is_current(&revisions, changes) {
+            return Err(SaveError::Conflict);
+        }
+
+        if let Some(Change {
+            data: Some(data),
+            base,
+        }) = &changes.document
+        {
+            sqlx::query(
+                "INSERT INTO store (id, revision, data) VALUES (1, $1, $2::jsonb)
+                 ON CONFLICT (id) DO UPDATE SET revision = EXCLUDED.revision, data = EXCLUDED.data",
+            )
+            .bind((base + 1) as i64)
+            .bind(data)
+            .execute(&mut *tx)
+            .await
+            .map_err(sql)?;
+        }
+        let (mut ids, mut revisions, mut end_dates, mut rows, mut removed) =
+            (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        for (id, change) in &changes.operations {
+            let Some(data) = &change.data else {
+                removed.push(*id as i64);
+                continue;
+            };
+            let op: Value = serde_json::from_str(data).map_err(|e| e.to_string())?;
+            ids.push(*id as i64);
+            revisions.push((change.base + 1) as i64);
+            end_dates.push(op["end_date"].as_str().unwrap_or_default().to_string());
+            rows.push(data.clone());
+        }
+        if !ids.is_empty() {
+            sqlx::query(
+                "INSERT INTO operations (id, revision, end_date, data)
+                 SELECT id, revision, end_date, data::jsonb
+                 FROM UNNEST($1::bigint[], $2::bigint[], $3::text[], $4::text[])
+                     AS t(id, revision, end_date, data)
+                 ON CONFLICT (id) DO UPDATE SET revision = EXCLUDED.revision,
+                     end_date = EXCLUDED.end_date, data = EXCLUDED.data",
+            )
+            .bind(&ids)
+            .bind(&revisions)
+            .bind(&end_dates)
+            .bind(&rows)
+            .execute(&mut *tx)
+            .await
+            .map_err(sql)?;
+        }
+        if !removed.is_empty() {
+            sqlx::query("DELETE FROM operations WHERE id = ANY($1)")
+                .bind(&removed)
+                .execute(&mut *tx)
+                .await
+                .map_err(sql)?;
+        }
+        tx.commit().await.map_err(sql)
+    }
+
+    async fn get(&self, namespace: &str, key: &str) -> Result<Option<String>, String> {
+        sqlx::query_scalar("SELECT value FROM entries WHERE namespace = $1 AND key = $2")
+            .bind(namespace)
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn put(&self, namespace: &str, key: &str, value: &str) -> Result<(), String> {
+        self.put_all(namespace, &[(key.to_string(), value.to_string())])
+            .await
+    }
+
+    async fn remove(&self, namespace: &str, key: &str) -> Result<(), String> {
+        sqlx::query("DELETE FROM entries WHERE namespace = $1 AND key = $2")
+            .bind(namespace)
+            .bind(key)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn entries(&self, namespace: &str) -> Result<Vec<(String, String)>, String> {
+        sqlx::query_as("SELECT key, value FROM entries WHERE namespace = $1")
+            .bind(namespace)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn put_all(&self, namespace: &str, entries: &[(String, String)]) -> Result<(), String> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let (keys, values): (Vec<&str>, Vec<&str>) = entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .unzip();
+        sqlx::query(
+            "INSERT INTO entries (namespace, key, value)
+             SELECT $1, key, value FROM UNNEST($2::text[], $3::text[]) AS t(key, value)
+             ON CONFLICT (namespace, key) DO UPDATE SET value = EXCLUDED.value",
+        )
+        .bind(namespace)
+        .bind(&keys)
+        .bind(&values)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+    }
+
+    async fn cached_killmails(
+        &self,
+        ids: &[KillmailId],
+    ) -> Result<HashMap<KillmailId, EsiKillmail>, String> {
+        let ids: Vec<i64> = ids.iter().map(|id| id.0).collect();
+        let rows: Vec<(i64, sqlx::types::Json<EsiKillmail>)> =
+            sqlx::query_as("SELECT killmail_id, data FROM killmails WHERE killmail_id = ANY($1)")
+                .bind(&ids)
+                .fetch_all(&self.pool)
+                .await
+                .map_err(|e| e.to_string())?;
+        Ok(rows
+            .into_iter()
+            .map(|(id, sqlx::types::Json(km))| (KillmailId(id), km))
+            .collect())
+    }
+
+    async fn cache_killmails(&self, killmails: &[(KillmailId, EsiKillmail)]) -> Result<(), String> {
+        let mut ids = Vec::new();
+        let mut times = Vec::new();
+        let mut systems = Vec::new();
+        let mut characters = Vec::new();
+        let mut corporations = Vec::new();
+        let mut alliances = Vec::new();
+        let mut data = Vec::new();
+        for (id, km) in killmails {
+            ids.push(id.0);
+            times.push(km.killmail_time);
+            systems.push(km.solar_system_id.0);
+            characters.push(km.victim.character_id.map(|c| c.0));
+            corporations.push(km.victim.corporation_id.map(|c| c.0));
+            alliances.push(km.victim.alliance_id.map(|a| a.0));
+            data.push(serde_json::to_string(km).map_err(|e| e.to_string())?);
+        }
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        let sql = |e: sqlx::Error| e.to_string();
+        let mut tx = self.pool.begin().await.map_err(sql)?;
+        let inserted: Vec<i64> = sqlx::query_scalar(
+            "INSERT INTO killmails (killmail_id, killmail_time, solar_system_id,
+                 victim_character_id, victim_corporation_id, victim_alliance_id, data)
+             SELECT id, time, system, character, corporation, alliance, data::jsonb
+             FROM UNNEST($1::bigint[], $2::timestamptz[], $3::bigint[], $4::bigint[],
+                         $5::bigint[], $6::bigint[], $7::text[])
+                 AS t(id, time, system, character, corporation, alliance, data)
+             ON CONFLICT (killmail_id) DO NOTHING
+             RETURNING killmail_id",
+        )
+        .bind(&ids)
+        .bind(&times)
+        .bind(&systems)
+        .bind(&characters)
+        .bind(&corporations)
+        .bind(&alliances)
+        .bind(&data)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(sql)?;
+
+        // Attackers of killmails another replica stored first are already there
+        let (mut att_kills, mut att_characters, mut att_corporations, mut att_alliances) =
+            (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        for (id, km) in killmails.iter().filter(|(id, _)| inserted.contains(&id.0)) {
+            for att in &km.attackers {
+                att_kills.push(id.0);
+                att_characters.push(att.character_id.map(|c| c.0));
+                att_corporations.push(att.corporation_id.map(|c| c.0));
+                att_alliances.push(att.alliance_id.map(|a| a.0));
+            }
+        }
+        sqlx::query(
+            "INSERT INTO killmail_attackers (killmail_id, character_id, corporation_id, alliance_id)
+             SELECT * FROM UNNEST($1::bigint[], $2::bigint[], $3::bigint[], $4::bigint[])",
+        )
+        .bind(&att_kills)
+        .bind(&att_characters)
+        .bind(&att_corporations)
+        .bind(&att_alliances)
+        .execute(&mut *tx)
+        .await
+        .map_err(sql)?;
+        tx.commit().await.map_err(sql)
+    }
+
+    async fn prune_killmails(&self, before: DateTime<Utc>, dry_run: bool) -> Result<u64, String> {
+        if dry_run {
+            let count: i64 =
+                sqlx::query_scalar("SELECT COUNT(*) FROM killmails WHERE killmail_time < $1")
+                    .bind(before)
+                    .fetch_one(&self.pool)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            return Ok(count as u64);
+        }
+        sqlx::query("DELETE FROM killmails WHERE killmail_time < $1")
+            .bind(before)
+            .execute(&self.pool)
+            .await
+            .map(|result| result.rows_affected())
+            .map_err(|e| e.to_string())
+    }
+}
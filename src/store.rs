@@ -0,0 +1,1623 @@
+use crate::auth;
+use crate::models::{
+    default_true, CharacterId, EveId, Isk, IskOutOfRange, Killmail, KillmailId, SafeName, SystemId,
+    TypeId,
+};
+use crate::names::{self, NameBook, NameHistory};
+use crate::notify::Webhook;
+use crate::payout::{Adjustment, ParticipantCache, ParticipantIndex, PayoutPreset, PayoutRules};
+use crate::privacy::DeletionRequest;
+use crate::settings::WorkspaceSettings;
+use crate::storage::{Change, Changes, SaveError, Storage};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::{error, info};
+
+/// A saved fleet operation: the kills as curated at save time plus the
+/// inputs needed to recompute its payout.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Operation {
+    pub id: u64,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub zkill_link: String,
+    pub start_date: String,
+    pub end_date: String,
+    #[serde(default)]
+    pub op_start: String,
+    #[serde(default)]
+    pub op_end: String,
+    pub mapping_text: String,
+    pub kills: Vec<Killmail>,
+    #[serde(default)]
+    pub excluded_beneficiaries: HashSet<String>,
+    #[serde(default)]
+    pub adjustments: Vec<Adjustment>,
+    /// "Name = amount" lines of who holds the loot ISK (see transfers::settle).
+    #[serde(default)]
+    pub holdings_text: String,
+    /// When an FC last published the payout to members.
+    #[serde(default)]
+    pub published_at: Option<DateTime<Utc>>,
+    /// Unguessable token for read-only public views such as the embed widget.
+    #[serde(default)]
+    pub share_token: Option<String>,
+    /// The payout as signed off by an FC. Set once; see `finalize`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finalized: Option<Finalization>,
+    /// Reviewers who approved the finalized payout, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sign_offs: Vec<SignOff>,
+    /// Pilots' objections to their payout line, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub disputes: Vec<Dispute>,
+    /// Who did what to the payout, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audit_log: Vec<AuditEntry>,
+    /// What the loot actually sold for, oldest first (see sales).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sales: Vec<Sale>,
+    /// Split the realized sale proceeds instead of the estimated loot
+    /// value (see payout_kills).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub split_on_realized: bool,
+    /// What was in the corp's loot container when last imported (see
+    /// loot_can).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loot_can: Option<LootCan>,
+    /// Mains who have been paid, by main name.
+    #[serde(default)]
+    pub payments: BTreeMap<String, Payment>,
+    /// Free-text notes, e.g. what happened or who to ask about the loot.
+    #[serde(default)]
+    pub notes: String,
+    /// Who led the operation.
+    #[serde(default)]
+    pub fc: String,
+    /// Doctrine tags such as "ferox" or "t3c".
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    #[serde(default)]
+    pub op_type: Option<OpType>,
+    /// Pay out pod kills too. New operations leave them out; ones saved
+    /// before pods were left out keep paying them, as recorded.
+    #[serde(default = "default_true")]
+    pub include_pods: bool,
+    /// The preset it was saved under, with a copy of its rules, so editing
+    /// or deleting the preset doesn't change this payout. Without one the
+    /// workspace rules apply.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<PayoutPreset>,
+    /// Edit history for undo/redo, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub undo_stack: Vec<EditState>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redo_stack: Vec<EditState>,
+    /// See participants.
+    #[serde(skip)]
+    pub participant_index: ParticipantCache,
+}
+
+/// What kind of operation it was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpType {
+    Roam,
+    HomeDefense,
+    Gank,
+    StructureBash,
+}
+
+impl OpType {
+    pub const ALL: [OpType; 4] = [
+        OpType::Roam,
+        OpType::HomeDefense,
+        OpType::Gank,
+        OpType::StructureBash,
+    ];
+
+    /// Form and query value, same as the serialized name.
+    pub fn key(&self) -> &'static str {
+        match self {
+            OpType::Roam => "roam",
+            OpType::HomeDefense => "home_defense",
+            OpType::Gank => "gank",
+            OpType::StructureBash => "structure_bash",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            OpType::Roam => "Roam",
+            OpType::HomeDefense => "Home Defense",
+            OpType::Gank => "Gank",
+            OpType::StructureBash => "Structure Bash",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.key() == key)
+    }
+}
+
+/// Splits a comma-separated tag list into trimmed, lowercased tags.
+pub fn parse_tags(input: &str) -> BTreeSet<String> {
+    input
+        .split(',')
+        .map(|t| t.trim().to_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+/// A beneficiary marked as paid.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Payment {
+    /// Share at the time of payment; later edits don't rewrite history.
+    pub amount: Isk,
+    pub paid_at: DateTime<Utc>,
+    pub paid_by: Option<String>,
+}
+
+/// The payout an FC signed off on. It locks the operation's payout inputs;
+/// payments and official exports go by this snapshot from then on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Finalization {
+    pub finalized_at: DateTime<Utc>,
+    pub finalized_by: Option<String>,
+    pub total_dropped_value: Isk,
+    pub payouts: BTreeMap<String, Isk>,
+    pub corp_tax: Isk,
+    /// Hex SHA-256 of the payout inputs and this snapshot (see
+    /// `Operation::audit_hash`), so tampering with either shows.
+    pub hash: String,
+    /// Changes made since that leave the payout as it was, oldest first.
+    /// The hash above stays as finalized.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub amendments: Vec<Amendment>,
+}
+
+/// A change to a finalized operation that keeps its payout, such as
+/// erasing a pilot's data (see privacy).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Amendment {
+    pub amended_at: DateTime<Utc>,
+    pub amended_by: Option<String>,
+    pub reason: String,
+    /// The audit hash of the operation as amended.
+    pub hash: String,
+    /// Hex SHA-256 of this entry and the hash it follows, which chains
+    /// the amendments to the original hash.
+    pub signature: String,
+}
+
+impl Amendment {
+    fn sign(&self, previous: &str) -> String {
+        #[derive(Serialize)]
+        struct Signed<'a> {
+            previous: &'a str,
+            amended_at: &'a DateTime<Utc>,
+            amended_by: &'a Option<String>,
+            reason: &'a str,
+            hash: &'a str,
+        }
+        sha256_hex(&Signed {
+            previous,
+            amended_at: &self.amended_at,
+            amended_by: &self.amended_by,
+            reason: &self.reason,
+            hash: &self.hash,
+        })
+    }
+}
+
+impl Finalization {
+    /// The hash the operation should match now: the last amendment's, or
+    /// the original one.
+    fn current_hash(&self) -> &str {
+        self.amendments.last().map_or(&self.hash, |a| &a.hash)
+    }
+
+    /// Whether every amendment is signed against the hash before it.
+    fn amendments_signed(&self) -> bool {
+        let mut previous = self.hash.as_str();
+        self.amendments.iter().all(|a| {
+            let signed = a.signature == a.sign(previous);
+            previous = &a.hash;
+            signed
+        })
+    }
+}
+
+/// A reviewer's approval of a finalized payout (see SignOffSettings).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignOff {
+    pub character_id: CharacterId,
+    pub character_name: String,
+    pub signed_at: DateTime<Utc>,
+}
+
+/// A pilot's objection to the share of one main.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dispute {
+    /// The payout line disputed, by main name.
+    pub main: String,
+    pub raised_by: String,
+    pub character_id: CharacterId,
+    pub reason: String,
+    pub raised_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolution: Option<Resolution>,
+}
+
+/// Proceeds of selling some of the loot, entered by the hauler or matched
+/// in the corp wallet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sale {
+    pub amount: Isk,
+    pub note: String,
+    pub recorded_by: Option<String>,
+    pub recorded_at: DateTime<Utc>,
+    /// Corp wallet transactions the sale was matched from; empty when
+    /// entered by hand. No transaction counts towards two sales.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transaction_ids: Vec<i64>,
+}
+
+/// Snapshot of the corp's loot container from ESI assets, taken as the
+/// loot actually collected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LootCan {
+    pub imported_at: DateTime<Utc>,
+    pub imported_by: Option<String>,
+    pub location_id: i64,
+    /// Quantity in the container, by item type.
+    pub collected: BTreeMap<TypeId, i64>,
+    /// Quantity that dropped from the operation's kills at import, by item
+    /// type.
+    pub dropped: BTreeMap<TypeId, i64>,
+}
+
+/// How an FC settled a dispute.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Resolution {
+    pub resolved_at: DateTime<Utc>,
+    pub resolved_by: Option<String>,
+    pub note: String,
+    /// The adjustment made to the disputed share, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adjustment: Option<Isk>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    /// The character acting; `None` without SSO.
+    pub by: Option<String>,
+    pub action: String,
+}
+
+/// A personal token for the read-only API, bound to one character.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub character_id: CharacterId,
+    pub character_name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The user-editable part of an operation, as recorded for undo/redo.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EditState {
+    pub excluded_kills: BTreeSet<KillmailId>,
+    pub excluded_beneficiaries: BTreeSet<String>,
+    #[serde(default)]
+    pub adjustments: Vec<Adjustment>,
+    pub mapping_text: String,
+    pub op_start: String,
+    pub op_end: String,
+}
+
+const MAX_UNDO: usize = 50;
+
+/// Form states not touched for this long are dropped.
+const FORM_STATE_DAYS: i64 = 30;
+
+impl Operation {
+    /// Its kills' attackers resolved to mains, kept while the kills, the
+    /// mapping and `names` stay the same, so editing exclusions doesn't
+    /// resolve them all again.
+    pub fn participants(&self, names: &NameBook) -> Arc<ParticipantIndex> {
+        self.participant_index
+            .get_or_build(&self.kills, names, &self.mapping_text)
+    }
+
+    /// The rules this operation is paid out under: its preset's, or else
+    /// the workspace `rules`.
+    pub fn payout_rules(&self, rules: &PayoutRules) -> PayoutRules {
+        let rules = self.preset.as_ref().map_or(rules, |p| &p.rules);
+        PayoutRules {
+            include_pods: self.include_pods,
+            ..rules.clone()
+        }
+    }
+
+    /// Appends to the audit log.
+    pub fn log(&mut self, by: Option<String>, action: String) {
+        self.audit_log.push(AuditEntry {
+            at: Utc::now(),
+            by,
+            action,
+        });
+    }
+
+    pub fn is_finalized(&self) -> bool {
+        self.finalized.is_some()
+    }
+
+    /// Dropped value of the active kills, as priced when fetched.
+    pub fn estimated_value(&self) -> Result<Isk, IskOutOfRange> {
+        Isk::total(
+            self.kills
+                .iter()
+                .filter(|k| k.is_active)
+                .map(|k| k.zkb.dropped_value),
+        )
+    }
+
+    /// What the sales recorded so far brought in; `None` before the first.
+    pub fn realized_value(&self) -> Result<Option<Isk>, IskOutOfRange> {
+        if self.sales.is_empty() {
+            return Ok(None);
+        }
+        Isk::total(self.sales.iter().map(|s| s.amount)).map(Some)
+    }
+
+    /// The kills as they are split. With `split_on_realized` and sales
+    /// recorded, every kill's dropped value is scaled by realized over
+    /// estimated value, so the shares add up to what the loot sold for.
+    pub fn payout_kills(&self) -> Result<Cow<'_, [Killmail]>, IskOutOfRange> {
+        let Some(realized) = self.realized_value()?.filter(|_| self.split_on_realized) else {
+            return Ok(Cow::Borrowed(&self.kills));
+        };
+        let estimated = self.estimated_value()?;
+        let kills = self
+            .kills
+            .iter()
+            .cloned()
+            .map(|mut kill| {
+                kill.zkb.dropped_value = kill.zkb.dropped_value.scale(realized, estimated)?;
+                Ok(kill)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Cow::Owned(kills))
+    }
+
+    /// Locks the payout as it stands. Finalizing twice keeps the first
+    /// snapshot.
+    pub fn finalize(
+        &mut self,
+        total_dropped_value: Isk,
+        payouts: BTreeMap<String, Isk>,
+        corp_tax: Isk,
+        finalized_by: Option<String>,
+    ) {
+        if self.finalized.is_some() {
+            return;
+        }
+        let hash = self.audit_hash(total_dropped_value, &payouts, corp_tax);
+        self.finalized = Some(Finalization {
+            finalized_at: Utc::now(),
+            finalized_by,
+            total_dropped_value,
+            payouts,
+            corp_tax,
+            hash,
+            amendments: vec![],
+        });
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+    }
+
+    /// Whether the finalized snapshot still matches the operation, i.e.
+    /// neither was edited behind the app's back. Changes recorded with
+    /// `amend` count as made through the app.
+    pub fn finalization_intact(&self) -> bool {
+        self.finalized.as_ref().is_some_and(|f| {
+            f.amendments_signed()
+                && f.current_hash()
+                    == self.audit_hash(f.total_dropped_value, &f.payouts, f.corp_tax)
+        })
+    }
+
+    /// Records a change to the finalized operation that leaves its payout
+    /// as it was, signed and chained to the hashes before it. The original
+    /// hash is kept. Does nothing to an operation that isn't finalized.
+    pub fn amend(&mut self, amended_by: Option<String>, reason: String) {
+        let Some(f) = &self.finalized else {
+            return;
+        };
+        let hash = self.audit_hash(f.total_dropped_value, &f.payouts, f.corp_tax);
+        let Some(f) = &mut self.finalized else {
+            return;
+        };
+        let mut amendment = Amendment {
+            amended_at: Utc::now(),
+            amended_by,
+            reason,
+            hash,
+            signature: String::new(),
+        };
+        amendment.signature = amendment.sign(f.current_hash());
+        f.amendments.push(amendment);
+    }
+
+    /// Hashes everything the payout is computed from together with the
+    /// payout itself. Sets are sorted so the hash doesn't depend on order.
+    fn audit_hash(
+        &self,
+        total_dropped_value: Isk,
+        payouts: &BTreeMap<String, Isk>,
+        corp_tax: Isk,
+    ) -> String {
+        #[derive(Serialize)]
+        struct Audited<'a> {
+            kills: Vec<(KillmailId, bool)>,
+            mapping_text: &'a str,
+            excluded_beneficiaries: BTreeSet<&'a String>,
+            adjustments: &'a [Adjustment],
+            op_start: &'a str,
+            op_end: &'a str,
+            include_pods: bool,
+            preset: &'a Option<PayoutPreset>,
+            total_dropped_value: Isk,
+            payouts: &'a BTreeMap<String, Isk>,
+            corp_tax: Isk,
+            /// Only when splitting on it, so older hashes still verify.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            realized: Option<Isk>,
+        }
+        let audited = Audited {
+            kills: self
+                .kills
+                .iter()
+                .map(|k| (k.killmail_id, k.is_active))
+                .collect(),
+            mapping_text: &self.mapping_text,
+            excluded_beneficiaries: self.excluded_beneficiaries.iter().collect(),
+            adjustments: &self.adjustments,
+            op_start: &self.op_start,
+            op_end: &self.op_end,
+            include_pods: self.include_pods,
+            preset: &self.preset,
+            total_dropped_value,
+            payouts,
+            corp_tax,
+            // Sales past what a decimal holds never made a payout to finalize
+            realized: self
+                .realized_value()
+                .ok()
+                .flatten()
+                .filter(|_| self.split_on_realized),
+        };
+        sha256_hex(&audited)
+    }
+
+    pub fn edit_state(&self) -> EditState {
+        EditState {
+            excluded_kills: self
+                .kills
+                .iter()
+                .filter(|k| !k.is_active)
+                .map(|k| k.killmail_id)
+                .collect(),
+            excluded_beneficiaries: self.excluded_beneficiaries.iter().cloned().collect(),
+            adjustments: self.adjustments.clone(),
+            mapping_text: self.mapping_text.clone(),
+            op_start: self.op_start.clone(),
+            op_end: self.op_end.clone(),
+        }
+    }
+
+    fn apply_state(&mut self, state: EditState) {
+        for kill in &mut self.kills {
+            kill.is_active = !state.excluded_kills.contains(&kill.killmail_id);
+        }
+        self.excluded_beneficiaries = state.excluded_beneficiaries.into_iter().collect();
+        self.adjustments = state.adjustments;
+        self.mapping_text = state.mapping_text;
+        self.op_start = state.op_start;
+        self.op_end = state.op_end;
+    }
+
+    /// Applies an edit, remembering the previous state for undo. Edits that
+    /// change nothing leave the history alone.
+    pub fn edit(&mut self, state: EditState) {
+        let previous = self.edit_state();
+        if previous == state {
+            return;
+        }
+        self.undo_stack.push(previous);
+        if self.undo_stack.len() > MAX_UNDO {
+            self.undo_stack.remove(0);
+        }
+        self.redo_stack.clear();
+        self.apply_state(state);
+    }
+
+    pub fn undo(&mut self) -> bool {
+        let Some(state) = self.undo_stack.pop() else {
+            return false;
+        };
+        self.redo_stack.push(self.edit_state());
+        self.apply_state(state);
+        true
+    }
+
+    pub fn redo(&mut self) -> bool {
+        let Some(state) = self.redo_stack.pop() else {
+            return false;
+        };
+        self.undo_stack.push(self.edit_state());
+        self.apply_state(state);
+        true
+    }
+}
+
+/// Everything that survives a restart.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoreData {
+    #[serde(default)]
+    pub operations: Vec<Operation>,
+    #[serde(default)]
+    pub next_operation_id: u64,
+    #[serde(default)]
+    pub leaderboard_opt_outs: BTreeSet<String>,
+    #[serde(default)]
+    pub name_history: NameHistory,
+    #[serde(default)]
+    pub settings: WorkspaceSettings,
+    /// Mapping that seeds new calculations. Saved operations keep their own
+    /// snapshot in `Operation::mapping_text`, so editing this never changes
+    /// how an old operation computes.
+    #[serde(default)]
+    pub default_mapping: String,
+    /// Personal API tokens, keyed by the token itself.
+    #[serde(default)]
+    pub api_tokens: BTreeMap<String, ApiToken>,
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+    #[serde(default)]
+    pub next_webhook_id: u64,
+    /// Resolved names and rejected IDs of stores saved before they moved to
+    /// their own storage entries; moved there on open.
+    #[serde(default, skip_serializing)]
+    resolved_names: BTreeMap<EveId, ResolvedName>,
+    #[serde(default, skip_serializing)]
+    unresolvable_ids: BTreeMap<EveId, DateTime<Utc>>,
+    /// Corporation and alliance tickers by ID (the ID ranges don't overlap).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tickers: BTreeMap<EveId, SafeName>,
+    /// Security status by solar system ID.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub system_security: BTreeMap<SystemId, f64>,
+    /// Region by solar system ID.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub system_regions: BTreeMap<SystemId, i64>,
+    /// Gate jumps on the shortest route, by origin and destination system;
+    /// `None` where there is no route (see routing).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub jumps: BTreeMap<SystemId, BTreeMap<SystemId, Option<u32>>>,
+    /// ESI group of each ship type seen on a kill.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub type_groups: BTreeMap<TypeId, i64>,
+    /// Calculator forms of stores saved before they moved to their own
+    /// storage entries; moved there on open.
+    #[serde(default, skip_serializing)]
+    form_states: BTreeMap<String, FormState>,
+    /// Pilots who asked for their data to be erased, by character ID.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub deletion_requests: BTreeMap<CharacterId, DeletionRequest>,
+    /// The character whose ESI access prices loot on a structure market.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub market_grant: Option<EsiGrant>,
+    /// The character whose ESI access reads the corp wallet's market
+    /// transactions (see sales).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wallet_grant: Option<EsiGrant>,
+    /// The character whose ESI access reads the corp's assets (see
+    /// loot_can).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub assets_grant: Option<EsiGrant>,
+}
+
+impl StoreData {
+    pub fn grant_mut(&mut self, kind: GrantKind) -> &mut Option<EsiGrant> {
+        match kind {
+            GrantKind::Market => &mut self.market_grant,
+            GrantKind::Wallet => &mut self.wallet_grant,
+            GrantKind::Assets => &mut self.assets_grant,
+        }
+    }
+}
+
+/// What an admin granted ESI access for.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GrantKind {
+    /// Structure market orders (see PricingBackend::StructureMarket).
+    Market,
+    /// Corp wallet transactions (see sales::match_wallet).
+    Wallet,
+    /// Corp hangars and containers (see loot_can::import_loot_can).
+    Assets,
+}
+
+impl GrantKind {
+    pub fn scope(self) -> &'static str {
+        match self {
+            GrantKind::Market => "esi-markets.structure_markets.v1",
+            GrantKind::Wallet => "esi-wallet.read_corporation_wallets.v1",
+            GrantKind::Assets => "esi-assets.read_corporation_assets.v1",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            GrantKind::Market => "structure market",
+            GrantKind::Wallet => "corp wallet",
+            GrantKind::Assets => "corp assets",
+        }
+    }
+}
+
+/// An admin's SSO grant of an ESI scope (see auth::grant_market_access).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EsiGrant {
+    pub character_id: CharacterId,
+    pub character_name: SafeName,
+    pub refresh_token: String,
+    pub granted_at: DateTime<Utc>,
+}
+
+impl EsiGrant {
+    /// Who granted it and on what day, for Settings.
+    pub fn describe(&self) -> (SafeName, String) {
+        (
+            self.character_name.clone(),
+            self.granted_at.format("%Y-%m-%d").to_string(),
+        )
+    }
+}
+
+/// What a visitor last typed into the calculator, restored on their next visit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FormState {
+    pub zkill_link: String,
+    pub mapping_text: String,
+    pub start_date: String,
+    pub end_date: String,
+    #[serde(default)]
+    pub op_start: String,
+    #[serde(default)]
+    pub op_end: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// What ESI's names endpoint said about an ID (character, corporation,
+/// ship, system), kept so a restart doesn't resolve the same alliance again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResolvedName {
+    /// `None` when ESI rejected the ID; not retried until
+    /// `Config::unresolvable_retry_hours` have passed.
+    #[serde(default)]
+    pub name: Option<SafeName>,
+    pub resolved_at: DateTime<Utc>,
+}
+
+impl StoreData {
+    /// The personal API token `token`, compared against every token in
+    /// constant time (see auth::secret_eq).
+    pub fn api_token(&self, token: &str) -> Option<&ApiToken> {
+        self.api_tokens.iter().fold(None, |found, (t, api_token)| {
+            if auth::secret_eq(token, t) {
+                Some(api_token)
+            } else {
+                found
+            }
+        })
+    }
+}
+
+pub struct Store {
+    storage: Arc<dyn Storage>,
+    /// What the document and each operation were loaded from or last saved
+    /// as, so a save writes only the rows that changed.
+    document: Stamp,
+    operations: HashMap<u64, Stamp>,
+    pub data: StoreData,
+}
+
+/// A stored row's revision and the hash of its JSON.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Stamp {
+    revision: u64,
+    hash: u64,
+}
+
+impl Stamp {
+    /// Hashes `value` as this build serializes it rather than the row's JSON,
+    /// which a backend may have reformatted, so loading alone never makes a
+    /// row look changed.
+    fn of(revision: u64, value: &impl Serialize) -> Result<Self, String> {
+        let raw = serde_json::to_string(value).map_err(|e| e.to_string())?;
+        Ok(Self {
+            revision,
+            hash: hash(&raw),
+        })
+    }
+}
+
+/// Hex SHA-256 of a record's JSON.
+fn sha256_hex(record: &impl Serialize) -> String {
+    let bytes = serde_json::to_vec(record).expect("audit record serializes");
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn hash(raw: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    raw.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// How often update applies an edit again after losing a race to another
+/// instance before giving up.
+const SAVE_RETRIES: usize = 3;
+
+/// The store behind its lock. Locking does no I/O: requests pick up what
+/// other instances saved through sync, which the router runs before each
+/// one (see main::sync_store).
+pub struct SharedStore(Mutex<Store>);
+
+impl SharedStore {
+    pub fn new(store: Store) -> Self {
+        Self(Mutex::new(store))
+    }
+
+    pub async fn lock(&self) -> MutexGuard<'_, Store> {
+        self.0.lock().await
+    }
+
+    /// Reloads whatever other instances saved since we last looked.
+    pub async fn sync(&self) {
+        self.lock().await.sync().await;
+    }
+}
+
+/// Moves what stores saved before it had storage entries of its own kept in
+/// the data store there, returning whether there was any.
+async fn move_legacy_entries(storage: &dyn Storage, data: &mut StoreData) -> Result<bool, String> {
+    let forms: Vec<(String, String)> = std::mem::take(&mut data.form_states)
+        .into_iter()
+        .filter_map(|(key, form)| Some((key, serde_json::to_string(&form).ok()?)))
+        .collect();
+    storage.put_all(FORMS, &forms).await?;
+    let unresolvable = std::mem::take(&mut data.unresolvable_ids)
+        .into_iter()
+        .map(|(id, at)| {
+            let rejected = ResolvedName {
+                name: None,
+                resolved_at: at,
+            };
+            (id, rejected)
+        });
+    let names: Vec<(String, String)> = unresolvable
+        .chain(std::mem::take(&mut data.resolved_names))
+        .filter_map(|(id, name)| Some((id.to_string(), serde_json::to_string(&name).ok()?)))
+        .collect();
+    storage.put_all(NAMES, &names).await?;
+    Ok(!forms.is_empty() || !names.is_empty())
+}
+
+/// Storage namespace of resolved names, keyed by ID.
+const NAMES: &str = "name";
+
+/// Names resolved within `max_age`; older ones are re-resolved so
+/// character renames come through.
+pub async fn fresh_names(
+    storage: &dyn Storage,
+    max_age: chrono::Duration,
+) -> HashMap<EveId, SafeName> {
+    let entries = match storage.entries(NAMES).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Failed to load resolved names: {}", e);
+            return HashMap::new();
+        }
+    };
+    let cutoff = Utc::now() - max_age;
+    entries
+        .into_iter()
+        .filter_map(|(id, raw)| {
+            let resolved: ResolvedName = serde_json::from_str(&raw).ok()?;
+            let name = resolved.name.filter(|_| resolved.resolved_at > cutoff)?;
+            Some((EveId(id.parse().ok()?), name))
+        })
+        .collect()
+}
+
+/// When ESI last rejected `id`, if it did rather than name it.
+pub async fn rejected_at(storage: &dyn Storage, id: EveId) -> Option<DateTime<Utc>> {
+    let raw = storage.get(NAMES, &id.to_string()).await.ok()??;
+    let resolved: ResolvedName = serde_json::from_str(&raw).ok()?;
+    resolved.name.is_none().then_some(resolved.resolved_at)
+}
+
+/// Remembers the outcome of a name resolution.
+pub async fn record_resolved(
+    storage: &dyn Storage,
+    resolved: &[(EveId, SafeName)],
+    invalid: &[EveId],
+) {
+    let now = Utc::now();
+    let outcomes = resolved
+        .iter()
+        .map(|(id, name)| (*id, Some(name.clone())))
+        .chain(invalid.iter().map(|id| (*id, None)));
+    let entries: Vec<(String, String)> = outcomes
+        .filter_map(|(id, name)| {
+            let resolved = ResolvedName {
+                name,
+                resolved_at: now,
+            };
+            Some((id.to_string(), serde_json::to_string(&resolved).ok()?))
+        })
+        .collect();
+    if let Err(e) = storage.put_all(NAMES, &entries).await {
+        error!("Failed to save resolved names: {}", e);
+    }
+}
+
+/// Drops the resolved name of `id`, so it is not kept once erased.
+pub async fn forget_name(storage: &dyn Storage, id: EveId) {
+    if let Err(e) = storage.remove(NAMES, &id.to_string()).await {
+        error!("Failed to forget resolved name {}: {}", id, e);
+    }
+}
+
+/// Storage namespace of calculator forms, keyed by the form cookie, so
+/// saving one doesn't rewrite the data store.
+const FORMS: &str = "form";
+
+/// The form a browser last submitted, unless it has gone idle.
+pub async fn load_form(storage: &dyn Storage, key: &str) -> Option<FormState> {
+    let raw = match storage.get(FORMS, key).await {
+        Ok(raw) => raw?,
+        Err(e) => {
+            error!("Failed to load form: {}", e);
+            return None;
+        }
+    };
+    let form: FormState = serde_json::from_str(&raw).ok()?;
+    let cutoff = Utc::now() - chrono::Duration::days(FORM_STATE_DAYS);
+    (form.updated_at > cutoff).then_some(form)
+}
+
+/// Deletes the forms nobody came back to, returning how many.
+pub async fn forget_idle_forms(storage: &dyn Storage) -> usize {
+    let forms = match storage.entries(FORMS).await {
+        Ok(forms) => forms,
+        Err(e) => {
+            error!("Failed to list forms: {}", e);
+            return 0;
+        }
+    };
+    let cutoff = Utc::now() - chrono::Duration::days(FORM_STATE_DAYS);
+    let mut forgotten = 0;
+    for (key, raw) in forms {
+        let idle = serde_json::from_str::<FormState>(&raw).map_or(true, |f| f.updated_at <= cutoff);
+        if !idle {
+            continue;
+        }
+        match storage.remove(FORMS, &key).await {
+            Ok(()) => forgotten += 1,
+            Err(e) => error!("Failed to forget form: {}", e),
+        }
+    }
+    forgotten
+}
+
+/// Stores what a browser submitted to the calculator.
+pub async fn remember_form(storage: &dyn Storage, key: &str, form: &FormState) {
+    let raw = match serde_json::to_string(form) {
+        Ok(raw) => raw,
+        Err(e) => return error!("Failed to save form: {}", e),
+    };
+    if let Err(e) = storage.put(FORMS, key, &raw).await {
+        error!("Failed to save form: {}", e);
+    }
+}
+
+/// Self-describing archive of the whole store, independent of how the
+/// backend lays data out on disk.
+#[derive(Serialize, Deserialize)]
+pub struct Backup {
+    pub format: String,
+    pub version: u32,
+    pub created_at: DateTime<Utc>,
+    pub data: StoreData,
+}
+
+pub const BACKUP_FORMAT: &str = "eve-looter-backup";
+pub const BACKUP_VERSION: u32 = 1;
+
+impl Backup {
+    /// Whether this build can restore the archive.
+    pub fn check(&self) -> Result<(), String> {
+        if self.format != BACKUP_FORMAT {
+            return Err(format!(
+                "Not an EVE Looter backup (format '{}')",
+                self.format
+            ));
+        }
+        if self.version > BACKUP_VERSION {
+            return Err(format!(
+                "Backup version {} is newer than this build supports ({})",
+                self.version, BACKUP_VERSION
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Store {
+    /// Loads the store from `storage`, starting empty if nothing was saved
+    /// yet. A store that can't be read is an error rather than an empty
+    /// start, which the first save would write over it.
+    pub async fn open(storage: Arc<dyn Storage>) -> Result<Self, String> {
+        let mut store = Self {
+            storage,
+            document: Stamp::default(),
+            operations: HashMap::new(),
+            data: StoreData::default(),
+        };
+        store
+            .refresh(true, &[])
+            .await
+            .map_err(|e| format!("Failed to load data store: {}", e))?;
+        if store.document.revision > 0 {
+            info!("Loaded data store from {} storage", store.storage.name());
+        }
+        if move_legacy_entries(store.storage.as_ref(), &mut store.data).await? {
+            // Rewrite the document without them, or the next open moves them
+            // again; its stamp was taken as if they were gone already
+            store.document.hash = 0;
+            store.save().await.map_err(|e| e.to_string())?;
+        }
+        Ok(store)
+    }
+
+    /// Saves the rows that changed since they were loaded or last saved.
+    /// When another instance changed one of them first, nothing is written
+    /// and the store reloads what that one saved, dropping the edit;
+    /// handlers answer 409 (see SaveError), bookkeeping goes through update
+    /// instead.
+    pub async fn save(&mut self) -> Result<(), SaveError> {
+        let (changes, stamps) = self.changes()?;
+        if changes.is_empty() {
+            return Ok(());
+        }
+        match self.storage.save(&changes).await {
+            Ok(()) => {
+                if let Some(hash) = stamps.document {
+                    self.document = Stamp {
+                        revision: self.document.revision + 1,
+                        hash,
+                    };
+                }
+                for (id, change) in &changes.operations {
+                    match stamps.operations.get(id) {
+                        Some(hash) => {
+                            let stamp = Stamp {
+                                revision: change.base + 1,
+                                hash: *hash,
+                            };
+                            self.operations.insert(*id, stamp);
+                        }
+                        None => {
+                            self.operations.remove(id);
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => {
+                error!("Failed to save data store: {}", e);
+                if let SaveError::Conflict = e {
+                    let written: Vec<u64> = changes.operations.keys().copied().collect();
+                    if let Err(e) = self.refresh(changes.document.is_some(), &written).await {
+                        error!("Failed to reload data store: {}", e);
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Applies `edit` and saves, applying it again on top of what another
+    /// instance saved if that one got there first. For bookkeeping nobody
+    /// waits on, failures are only logged (by save).
+    pub async fn update(&mut self, edit: impl Fn(&mut StoreData)) -> Result<(), SaveError> {
+        let mut attempts = 0;
+        loop {
+            edit(&mut self.data);
+            match self.save().await {
+                Err(SaveError::Conflict) if attempts < SAVE_RETRIES => attempts += 1,
+                result => return result,
+            }
+        }
+    }
+
+    /// The rows that differ from their stamps, with the hashes they will be
+    /// stamped with once written (none for a deleted operation).
+    fn changes(&mut self) -> Result<(Changes, Hashes), SaveError> {
+        let json = |e: serde_json::Error| SaveError::Storage(e.to_string());
+        let mut changes = Changes::default();
+        let mut hashes = Hashes::default();
+
+        // The document is everything but the operations, which have rows of their own
+        let operations = std::mem::take(&mut self.data.operations);
+        let document = serde_json::to_string(&self.data);
+        self.data.operations = operations;
+        let document = document.map_err(json)?;
+        let document_hash = hash(&document);
+        if document_hash != self.document.hash || self.document.revision == 0 {
+            changes.document = Some(Change {
+                data: Some(document),
+                base: self.document.revision,
+            });
+            hashes.document = Some(document_hash);
+        }
+
+        for op in &self.data.operations {
+            let raw = serde_json::to_string(op).map_err(json)?;
+            let op_hash = hash(&raw);
+            let stamp = self.operations.get(&op.id).copied().unwrap_or_default();
+            if op_hash != stamp.hash || stamp.revision == 0 {
+                changes.operations.insert(
+                    op.id,
+                    Change {
+                        data: Some(raw),
+                        base: stamp.revision,
+                    },
+                );
+                hashes.operations.insert(op.id, op_hash);
+            }
+        }
+        let kept: HashSet<u64> = self.data.operations.iter().map(|op| op.id).collect();
+        for (id, stamp) in &self.operations {
+            if !kept.contains(id) {
+                changes.operations.insert(
+                    *id,
+                    Change {
+                        data: None,
+                        base: stamp.revision,
+                    },
+                );
+            }
+        }
+        Ok((changes, hashes))
+    }
+
+    /// Reloads the data if another instance saved since we last looked.
+    pub async fn sync(&mut self) {
+        if !self.storage.is_shared() {
+            return;
+        }
+        if let Err(e) = self.refresh(false, &[]).await {
+            error!("Failed to reload data store: {}", e);
+        }
+    }
+
+    /// Reloads the rows another instance changed, plus the document when
+    /// `document` is set and the operations in `operations` whatever their
+    /// revision, dropping local edits to them.
+    async fn refresh(&mut self, document: bool, operations: &[u64]) -> Result<(), String> {
+        let revisions = self.storage.revisions().await?;
+
+        if document || revisions.document != self.document.revision {
+            if let Some(row) = self.storage.document().await? {
+                let mut data: StoreData =
+                    serde_json::from_str(&row.data).map_err(|e| e.to_string())?;
+                self.document = Stamp::of(row.revision, &data)?;
+                data.operations = std::mem::take(&mut self.data.operations);
+                self.data = data;
+                info!("Reloaded data store at revision {}", row.revision);
+            }
+        }
+
+        let stale: Vec<u64> = revisions
+            .operations
+            .iter()
+            .filter(|(id, revision)| {
+                operations.contains(id)
+                    || self.operations.get(id).map(|s| s.revision) != Some(**revision)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        let rows = self.storage.operations(&stale).await?;
+        let mut loaded = BTreeMap::new();
+        let mut stamps = HashMap::new();
+        for (id, row) in rows {
+            let op: Operation = serde_json::from_str(&row.data).map_err(|e| e.to_string())?;
+            stamps.insert(id, Stamp::of(row.revision, &op)?);
+            loaded.insert(id, op);
+        }
+        let current = std::mem::take(&mut self.data.operations);
+        // Operations deleted elsewhere (or never saved) go; the others are
+        // kept or replaced, in ID order as inserted
+        let mut ops: BTreeMap<u64, Operation> = current
+            .into_iter()
+            .filter(|op| revisions.operations.contains_key(&op.id))
+            .map(|op| (op.id, op))
+            .collect();
+        ops.extend(loaded);
+        self.operations.retain(|id, _| ops.contains_key(id));
+        self.operations.extend(stamps);
+        self.data.operations = ops.into_values().collect();
+        Ok(())
+    }
+
+    pub fn backup(&self) -> Backup {
+        Backup {
+            format: BACKUP_FORMAT.to_string(),
+            version: BACKUP_VERSION,
+            created_at: Utc::now(),
+            data: self.data.clone(),
+        }
+    }
+
+    /// Writes every row again, in this build's format, whether it changed
+    /// or not.
+    pub async fn rewrite(&mut self) -> Result<(), SaveError> {
+        self.document.hash = 0;
+        for stamp in self.operations.values_mut() {
+            stamp.hash = 0;
+        }
+        self.save().await
+    }
+
+    /// Replaces all stored data with the contents of a backup.
+    pub async fn restore(&mut self, backup: Backup) -> Result<(), String> {
+        backup.check()?;
+        self.replace(backup.data).await?;
+        self.save().await.map_err(|e| e.to_string())
+    }
+
+    /// Takes `data` in place of what is held, moving what older versions
+    /// kept in the data store to its own entries. Not saved yet.
+    pub async fn replace(&mut self, mut data: StoreData) -> Result<(), String> {
+        move_legacy_entries(self.storage.as_ref(), &mut data).await?;
+        self.data = data;
+        Ok(())
+    }
+
+    /// Adds observed character names to the history, saving if anything changed.
+    pub async fn record_names(
+        &mut self,
+        observed: &[(CharacterId, String)],
+        seen_at: DateTime<Utc>,
+    ) {
+        let mut changed = false;
+        for (id, name) in observed {
+            changed |= names::record(&mut self.data.name_history, *id, name, seen_at);
+        }
+        if changed {
+            self.update(|data| {
+                for (id, name) in observed {
+                    names::record(&mut data.name_history, *id, name, seen_at);
+                }
+            })
+            .await
+            .ok();
+        }
+    }
+
+    pub fn name_book(&self) -> NameBook {
+        NameBook::from_history(&self.data.name_history)
+    }
+
+    /// Saves a new operation, returning its ID.
+    pub async fn insert_operation(&mut self, op: Operation) -> Result<u64, SaveError> {
+        let observed = names::observed_names(&op.kills);
+        self.update(|data| {
+            for (id, name) in &observed {
+                names::record(&mut data.name_history, *id, name, op.created_at);
+            }
+            data.next_operation_id += 1;
+            data.operations.push(Operation {
+                id: data.next_operation_id,
+                ..op.clone()
+            });
+        })
+        .await?;
+        Ok(self.data.next_operation_id)
+    }
+
+    pub fn operation(&self, id: u64) -> Option<&Operation> {
+        self.data.operations.iter().find(|op| op.id == id)
+    }
+
+    pub fn operation_mut(&mut self, id: u64) -> Option<&mut Operation> {
+        self.data.operations.iter_mut().find(|op| op.id == id)
+    }
+
+    pub fn operation_by_token(&self, token: &str) -> Option<&Operation> {
+        self.data
+            .operations
+            .iter()
+            .find(|op| op.share_token.as_deref() == Some(token))
+    }
+}
+
+/// Hashes of the rows a save writes (see Store::changes).
+#[derive(Default)]
+struct Hashes {
+    document: Option<u64>,
+    operations: HashMap<u64, u64>,
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::models::EsiKillmail;
+    use crate::storage::{JsonFileStorage, MemoryStorage, SqliteStorage};
+    use std::path::PathBuf;
+
+    /// A kill dropping `dropped` ISK on which each of `attackers` (ID and
+    /// name) got a blow in, the first of them the final blow.
+    pub(crate) fn kill(
+        killmail_id: i64,
+        dropped: f64,
+        attackers: &[(i64, &str)],
+    ) -> serde_json::Value {
+        let attackers: Vec<_> = attackers
+            .iter()
+            .enumerate()
+            .map(|(i, (id, name))| {
+                serde_json::json!({"character_id": id, "character_name": name,
+                    "corporation_id": 98000001, "final_blow": i == 0})
+            })
+            .collect();
+        serde_json::json!({"killmail_id": killmail_id,
+            "zkb": {"locationID": 1, "hash": "h", "fittedValue": dropped,
+                "droppedValue": dropped, "destroyedValue": 0, "totalValue": dropped},
+            "victim": null, "attackers": attackers,
+            "killmail_time": "2024-01-01T00:00:00Z", "formatted_dropped": "",
+            "solar_system_id": 30000142, "solar_system_name": "Jita"})
+    }
+
+    /// An operation named "Op" over `kills` (see kill), with no mapping.
+    pub(crate) fn operation(kills: Vec<serde_json::Value>) -> Operation {
+        serde_json::from_value(serde_json::json!({
+            "id": 1, "name": "Op", "created_at": "2024-01-01T00:00:00Z",
+            "zkill_link": "", "start_date": "2024-01-01", "end_date": "2024-01-01",
+            "mapping_text": "", "kills": kills,
+        }))
+        .unwrap()
+    }
+
+    fn data_file(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("eve-looter-{}-{}.json", name, std::process::id()));
+        for file in [
+            path.clone(),
+            path.with_extension("form.json"),
+            path.with_extension("name.json"),
+            path.with_extension("db"),
+        ] {
+            let _ = std::fs::remove_file(file);
+        }
+        path
+    }
+
+    /// Every backend a test can run without a server.
+    fn backends(name: &str) -> Vec<Arc<dyn Storage>> {
+        let path = data_file(name);
+        let sqlite = SqliteStorage::open(path.with_extension("db").to_str().unwrap()).unwrap();
+        vec![
+            Arc::new(MemoryStorage::default()),
+            Arc::new(JsonFileStorage::new(path)),
+            Arc::new(sqlite),
+        ]
+    }
+
+    #[tokio::test]
+    async fn unreadable_store_is_an_error() {
+        let path = data_file("unreadable");
+        std::fs::write(&path, "{ not json").unwrap();
+        let storage = Arc::new(JsonFileStorage::new(path.clone()));
+        assert!(Store::open(storage).await.is_err());
+        // Nothing was written over it
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{ not json");
+    }
+
+    #[tokio::test]
+    async fn edits_to_different_operations_both_save() {
+        for storage in backends("different-ops") {
+            let mut first = Store::open(storage.clone()).await.unwrap();
+            first.insert_operation(operation(vec![])).await.unwrap();
+            first.insert_operation(operation(vec![])).await.unwrap();
+            let mut second = Store::open(storage.clone()).await.unwrap();
+
+            first.operation_mut(1).unwrap().notes = "first".to_string();
+            first.save().await.unwrap();
+            second.operation_mut(2).unwrap().notes = "second".to_string();
+            second.save().await.unwrap();
+
+            let reopened = Store::open(storage.clone()).await.unwrap();
+            assert_eq!(reopened.operation(1).unwrap().notes, "first");
+            assert_eq!(reopened.operation(2).unwrap().notes, "second");
+            // Shared backends pick up the other edit on sync
+            second.sync().await;
+            if storage.is_shared() {
+                assert_eq!(second.operation(1).unwrap().notes, "first");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn conflicting_edit_to_an_operation_reloads_it() {
+        for storage in backends("same-op") {
+            let mut first = Store::open(storage.clone()).await.unwrap();
+            first.insert_operation(operation(vec![])).await.unwrap();
+            let mut second = Store::open(storage.clone()).await.unwrap();
+
+            first.operation_mut(1).unwrap().notes = "first".to_string();
+            first.save().await.unwrap();
+            second.operation_mut(1).unwrap().notes = "second".to_string();
+            assert!(matches!(second.save().await, Err(SaveError::Conflict)));
+            // The edit is dropped in favour of what the first store saved
+            assert_eq!(second.operation(1).unwrap().notes, "first");
+
+            second.operation_mut(1).unwrap().notes = "second".to_string();
+            second.save().await.unwrap();
+            let reopened = Store::open(storage).await.unwrap();
+            assert_eq!(reopened.operation(1).unwrap().notes, "second");
+        }
+    }
+
+    #[tokio::test]
+    async fn update_reapplies_an_edit_that_lost_a_race() {
+        for storage in backends("update") {
+            let mut first = Store::open(storage.clone()).await.unwrap();
+            let mut second = Store::open(storage.clone()).await.unwrap();
+
+            first.insert_operation(operation(vec![])).await.unwrap();
+            // Both insert as operation 1 until the second sees the first's
+            let id = second.insert_operation(operation(vec![])).await.unwrap();
+            assert_eq!(id, 2);
+            assert_eq!(second.data.operations.len(), 2);
+
+            first
+                .update(|data| data.default_mapping = "A = B".to_string())
+                .await
+                .unwrap();
+            second
+                .update(|data| {
+                    data.leaderboard_opt_outs.insert("Carol".to_string());
+                })
+                .await
+                .unwrap();
+            assert_eq!(second.data.default_mapping, "A = B");
+            assert!(second.data.leaderboard_opt_outs.contains("Carol"));
+        }
+    }
+
+    #[tokio::test]
+    async fn deleted_operations_are_removed_from_storage() {
+        for storage in backends("delete") {
+            let mut store = Store::open(storage.clone()).await.unwrap();
+            store.insert_operation(operation(vec![])).await.unwrap();
+            store.insert_operation(operation(vec![])).await.unwrap();
+            store.data.operations.retain(|op| op.id != 1);
+            store.save().await.unwrap();
+            let reopened = Store::open(storage).await.unwrap();
+            let ids: Vec<u64> = reopened.data.operations.iter().map(|op| op.id).collect();
+            assert_eq!(ids, [2]);
+        }
+    }
+
+    #[tokio::test]
+    async fn data_file_keeps_its_layout() {
+        let path = data_file("layout");
+        let mut op = serde_json::to_value(operation(vec![])).unwrap();
+        op["id"] = 3.into();
+        let saved = serde_json::json!({"next_operation_id": 3, "operations": [op]});
+        std::fs::write(&path, saved.to_string()).unwrap();
+
+        let mut store = Store::open(Arc::new(JsonFileStorage::new(path.clone())))
+            .await
+            .unwrap();
+        assert_eq!(store.operation(3).unwrap().name, "Op");
+        store.operation_mut(3).unwrap().notes = "edited".to_string();
+        store.save().await.unwrap();
+
+        let raw: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(raw["next_operation_id"], 3);
+        assert_eq!(raw["operations"][0]["notes"], "edited");
+    }
+
+    #[tokio::test]
+    async fn legacy_forms_and_names_move_to_their_own_entries() {
+        let path = data_file("legacy");
+        let saved = serde_json::json!({
+            "form_states": {"abc": {"zkill_link": "https://zkillboard.com/system/30000142/",
+                "mapping_text": "", "start_date": "2024-01-01", "end_date": "2024-01-02",
+                "updated_at": "2999-01-01T00:00:00Z"}},
+            "resolved_names": {"7": {"name": "Pilot", "resolved_at": "2999-01-01T00:00:00Z"}},
+            "unresolvable_ids": {"8": "2024-01-01T00:00:00Z"},
+        });
+        std::fs::write(&path, saved.to_string()).unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(JsonFileStorage::new(path.clone()));
+        Store::open(storage.clone()).await.unwrap();
+
+        let form = load_form(storage.as_ref(), "abc").await.unwrap();
+        assert_eq!(form.start_date, "2024-01-01");
+        let names = fresh_names(storage.as_ref(), chrono::Duration::days(1)).await;
+        assert_eq!(names[&EveId(7)].as_str(), "Pilot");
+        assert!(!names.contains_key(&EveId(8)));
+        assert!(rejected_at(storage.as_ref(), EveId(8)).await.is_some());
+        assert!(rejected_at(storage.as_ref(), EveId(7)).await.is_none());
+        // Dropped from the data store, so the next open doesn't move them again
+        let raw = std::fs::read_to_string(&path).unwrap();
+        assert!(!raw.contains("form_states") && !raw.contains("resolved_names"));
+    }
+
+    #[test]
+    fn ids_are_stored_as_plain_numbers() {
+        let can = serde_json::json!({"imported_at": "2024-01-01T00:00:00Z",
+            "imported_by": null, "location_id": 1, "collected": {"587": 2}, "dropped": {}});
+        let can: LootCan = serde_json::from_value(can).unwrap();
+        assert_eq!(can.collected[&TypeId(587)], 2);
+        assert_eq!(
+            serde_json::to_value(&can).unwrap()["collected"],
+            serde_json::json!({"587": 2})
+        );
+        let mut data = StoreData::default();
+        data.tickers.insert(EveId(98000001), SafeName::new("CORP"));
+        data.system_security.insert(SystemId(30000142), 0.9);
+        let stored = serde_json::to_value(&data).unwrap();
+        assert_eq!(stored["tickers"], serde_json::json!({"98000001": "CORP"}));
+        assert_eq!(
+            stored["system_security"],
+            serde_json::json!({"30000142": 0.9})
+        );
+        let op = operation(vec![kill(1, 100.0, &[(7, "Pilot")])]);
+        assert_eq!(
+            serde_json::to_value(&op.kills[0]).unwrap()["killmail_id"],
+            serde_json::json!(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_forms_are_forgotten() {
+        let storage = MemoryStorage::default();
+        for (key, days) in [("fresh", 1), ("idle", FORM_STATE_DAYS + 1)] {
+            let form = FormState {
+                zkill_link: String::new(),
+                mapping_text: String::new(),
+                start_date: String::new(),
+                end_date: String::new(),
+                op_start: String::new(),
+                op_end: String::new(),
+                updated_at: Utc::now() - chrono::Duration::days(days),
+            };
+            remember_form(&storage, key, &form).await;
+        }
+        storage.put(FORMS, "garbled", "{").await.unwrap();
+
+        assert_eq!(forget_idle_forms(&storage).await, 2);
+        assert!(load_form(&storage, "fresh").await.is_some());
+        assert!(storage.get(FORMS, "idle").await.unwrap().is_none());
+        assert_eq!(forget_idle_forms(&storage).await, 0);
+    }
+
+    /// Runs against the database in `EVE_LOOTER_TEST_POSTGRES`, which it
+    /// empties first; skipped without one.
+    #[tokio::test]
+    async fn postgres_round_trip() {
+        let Ok(url) = std::env::var("EVE_LOOTER_TEST_POSTGRES") else {
+            return;
+        };
+        let pool = crate::config::PoolConfig {
+            max_connections: 4,
+            min_connections: 0,
+            acquire_timeout_secs: 5,
+        };
+        let postgres = crate::storage::PostgresStorage::connect(&url, &pool)
+            .await
+            .unwrap();
+        postgres.clear().await.unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(postgres);
+
+        let mut first = Store::open(storage.clone()).await.unwrap();
+        first.insert_operation(operation(vec![])).await.unwrap();
+        first.insert_operation(operation(vec![])).await.unwrap();
+        let mut second = Store::open(storage.clone()).await.unwrap();
+        first.operation_mut(1).unwrap().notes = "first".to_string();
+        first.save().await.unwrap();
+        second.operation_mut(1).unwrap().notes = "second".to_string();
+        assert!(matches!(second.save().await, Err(SaveError::Conflict)));
+        second.data.operations.retain(|op| op.id != 2);
+        second.save().await.unwrap();
+
+        let reopened = Store::open(storage.clone()).await.unwrap();
+        let ids: Vec<u64> = reopened.data.operations.iter().map(|op| op.id).collect();
+        assert_eq!(ids, [1]);
+        assert_eq!(reopened.operation(1).unwrap().notes, "first");
+
+        storage.put("form", "abc", "{}").await.unwrap();
+        assert_eq!(storage.get("form", "abc").await.unwrap().unwrap(), "{}");
+        storage.remove("form", "abc").await.unwrap();
+        assert!(storage.entries("form").await.unwrap().is_empty());
+
+        let killmail: EsiKillmail = serde_json::from_value(serde_json::json!({
+            "killmail_time": "2024-01-01T00:00:00Z", "solar_system_id": 30000142,
+            "victim": {"character_id": 7, "corporation_id": 98000001, "ship_type_id": 587},
+            "attackers": [{"character_id": 8, "final_blow": true}],
+        }))
+        .unwrap();
+        storage
+            .cache_killmails(&[(KillmailId(1), killmail)])
+            .await
+            .unwrap();
+        let cached = storage
+            .cached_killmails(&[KillmailId(1), KillmailId(2)])
+            .await
+            .unwrap();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(
+            cached[&KillmailId(1)].attackers[0].character_id,
+            Some(CharacterId(8))
+        );
+    }
+
+    #[test]
+    fn recording_a_sale_changes_the_split_payout() {
+        let (names, rules) = (NameBook::default(), PayoutRules::default());
+        let mut op = operation(vec![
+            kill(1, 100_000_000.0, &[(100, "Alice"), (101, "Bob")]),
+            kill(2, 50_000_000.0, &[(101, "Bob")]),
+        ]);
+        op.split_on_realized = true;
+        let isk = |amount: f64| Isk::from_f64(amount).unwrap();
+        let estimated = crate::ops::operation_payout(&op, &names, &rules).unwrap();
+        assert_eq!(estimated.wallets["Bob"], isk(100_000_000.0));
+
+        op.sales.push(Sale {
+            amount: isk(75_000_000.0),
+            note: String::new(),
+            recorded_by: None,
+            recorded_at: Utc::now(),
+            transaction_ids: vec![],
+        });
+        let realized = crate::ops::operation_payout(&op, &names, &rules).unwrap();
+        assert_eq!(realized.total_dropped_value, isk(75_000_000.0));
+        assert_eq!(realized.wallets["Alice"], isk(25_000_000.0));
+        assert_eq!(realized.wallets["Bob"], isk(50_000_000.0));
+
+        op.sales.clear();
+        let again = crate::ops::operation_payout(&op, &names, &rules).unwrap();
+        assert_eq!(again.wallets, estimated.wallets);
+    }
+}
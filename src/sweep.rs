@@ -0,0 +1,106 @@
+use crate::models::*;
+use crate::request_id;
+use crate::store;
+
+use chrono::{DateTime, Duration, Utc};
+use std::sync::Arc;
+use tracing::info;
+
+/// How often idle state is looked for.
+const SWEEP_EVERY: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+/// What one sweep let go of.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SweepReport {
+    /// Kills of the calculator, a held fetch and fetches waiting to be
+    /// resumed.
+    pub kills: usize,
+    /// Roughly how much memory those took, by their JSON size.
+    pub bytes: usize,
+    /// Expired cached results, forms nobody came back to and names no kill
+    /// uses any more.
+    pub entries: usize,
+}
+
+/// What all sweeps since the start let go of, for `/admin`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SweepTotals {
+    pub sweeps: u64,
+    pub last_at: Option<DateTime<Utc>>,
+    pub reclaimed: SweepReport,
+}
+
+impl SweepTotals {
+    pub fn add(&mut self, report: SweepReport) {
+        self.sweeps += 1;
+        self.last_at = Some(Utc::now());
+        self.reclaimed.kills += report.kills;
+        self.reclaimed.bytes += report.bytes;
+        self.reclaimed.entries += report.entries;
+    }
+
+    pub fn reclaimed_mb(&self) -> f64 {
+        self.reclaimed.bytes as f64 / (1024.0 * 1024.0)
+    }
+}
+
+fn json_size<T: serde::Serialize>(value: &T) -> usize {
+    serde_json::to_vec(value).map_or(0, |json| json.len())
+}
+
+/// Drops the state of visitors who have gone away: the calculator's kills
+/// (and a fetch held for confirmation) once nobody has calculated for
+/// `EVE_LOOTER_SESSION_IDLE_HOURS`, rate-limited fetches nobody resumed,
+/// expired cached results, remembered forms and unused names. Sessions
+/// expire on their own (see auth).
+pub async fn sweep(state: &AppState) -> SweepReport {
+    let idle = Duration::hours(state.config.session_idle_hours);
+    let cutoff = Utc::now() - idle;
+    let mut report = SweepReport::default();
+
+    if *state.calculator_used.lock().unwrap() < cutoff {
+        let mut kills = state.current_kills.lock().unwrap();
+        report.kills += kills.len();
+        report.bytes += json_size(&*kills);
+        *kills = Vec::new();
+        *state.current_source.lock().unwrap() = None;
+        if let Some((_, fetched)) = state.pending_replace.lock().unwrap().take() {
+            report.kills += fetched.kills.len();
+            report.bytes += json_size(&fetched.kills);
+        }
+    }
+
+    state.partial_fetches.lock().unwrap().retain(|_, partial| {
+        let keep = partial.stopped_at >= cutoff;
+        if !keep {
+            report.kills += partial.raw_items.len() + partial.pending.len();
+            report.bytes += json_size(&partial.raw_items) + json_size(&partial.pending);
+        }
+        keep
+    });
+
+    report.entries += state.result_cache.lock().unwrap().prune();
+    report.entries += store::forget_idle_forms(state.storage.as_ref()).await;
+    // After the kills above, so their names go too
+    report.entries += forget_unused_names();
+
+    if report.kills > 0 || report.entries > 0 {
+        info!(
+            "Swept {} idle kills (~{} KiB) and {} expired entries",
+            report.kills,
+            report.bytes / 1024,
+            report.entries
+        );
+    }
+    state.diagnostics.record_sweep(report);
+    report
+}
+
+pub fn spawn(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(SWEEP_EVERY).await;
+            request_id::job("sweep", sweep(&state)).await;
+        }
+    });
+}
@@ -0,0 +1,109 @@
+use crate::models::{parse_isk, Isk, IskOutOfRange};
+use crate::names::NameBook;
+use std::collections::HashMap;
+
+/// One "who pays whom how much" line of the settlement.
+#[derive(Clone)]
+pub struct Transfer {
+    pub from: String,
+    pub to: String,
+    pub amount: String,
+    /// Whole ISK with separators, ready to paste into the in-game dialog.
+    pub amount_exact: String,
+}
+
+/// Result of settling the payout against the ISK people are holding.
+#[derive(Clone)]
+pub struct Settlement {
+    pub transfers: Vec<Transfer>,
+    /// Holdings minus payouts; non-zero means the numbers don't add up and
+    /// somebody ends up with a remainder.
+    pub unallocated: Option<String>,
+}
+
+/// Parses "Name = 1.5b" lines of who holds the loot ISK.
+pub fn parse_holdings(input: &str) -> Vec<(String, Isk)> {
+    input
+        .lines()
+        .filter_map(|line| {
+            let (name, amount) = line.split_once([':', '='])?;
+            Some((name.trim().to_string(), parse_isk(amount)?))
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+/// Turns wallets (what each main is owed) and holdings (who has the ISK)
+/// into transfers. Greedily matches the largest holder surplus with the
+/// largest shortfall, which needs at most one transfer fewer than the
+/// number of people involved; holders pay themselves nothing.
+pub fn settle(
+    wallets: &HashMap<String, Isk>,
+    holdings: &[(String, Isk)],
+    names: &NameBook,
+) -> Result<Settlement, IskOutOfRange> {
+    let mut balances: HashMap<String, Isk> = HashMap::new();
+    for (main, owed) in wallets {
+        balances.entry(main.clone()).or_default().decrease(*owed)?;
+    }
+    for (holder, amount) in holdings {
+        balances
+            .entry(names.canonical(holder))
+            .or_default()
+            .increase(*amount)?;
+    }
+
+    let mut payers: Vec<(String, Isk)> = Vec::new();
+    let mut payees: Vec<(String, Isk)> = Vec::new();
+    for (name, balance) in balances {
+        if balance >= Isk::ONE {
+            payers.push((name, balance));
+        } else if balance <= -Isk::ONE {
+            payees.push((name, -balance));
+        }
+    }
+    let by_amount = |a: &(String, Isk), b: &(String, Isk)| b.1.cmp(&a.1).then(a.0.cmp(&b.0));
+    payers.sort_by(by_amount);
+    payees.sort_by(by_amount);
+
+    let mut transfers = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < payers.len() && j < payees.len() {
+        let amount = payers[i].1.min(payees[j].1);
+        if amount >= Isk::ONE {
+            transfers.push(Transfer {
+                from: payers[i].0.clone(),
+                to: payees[j].0.clone(),
+                amount: amount.to_string(),
+                amount_exact: format_exact(amount),
+            });
+        }
+        payers[i].1.decrease(amount)?;
+        payees[j].1.decrease(amount)?;
+        if payers[i].1 < Isk::ONE {
+            i += 1;
+        }
+        if payees[j].1 < Isk::ONE {
+            j += 1;
+        }
+    }
+
+    let held = Isk::total(holdings.iter().map(|(_, a)| *a))?;
+    let unallocated = held.checked_sub(Isk::total(wallets.values().copied())?)?;
+    Ok(Settlement {
+        transfers,
+        unallocated: (unallocated.abs() >= Isk::ONE).then(|| unallocated.to_string()),
+    })
+}
+
+fn format_exact(amount: Isk) -> String {
+    let digits = amount.0.round().to_string();
+    let mut out = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
@@ -0,0 +1,217 @@
+use crate::models::*;
+use crate::payout::LossNetting;
+use crate::views::{isk_error, render_page};
+
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+
+#[derive(Template)]
+#[template(path = "transparency.html")]
+struct TransparencyTemplate {
+    name: String,
+    start_date: String,
+    end_date: String,
+    fc: String,
+    kill_count: usize,
+    total: String,
+    paid_out: String,
+    corp_tax: String,
+    /// (label, value) of each rule the payout was calculated under.
+    rules: Vec<(&'static str, String)>,
+    share_script: Option<String>,
+    /// (main or "Pilot N", formatted share, percent of the loot), largest first.
+    shares: Vec<(String, String, String)>,
+    named: bool,
+    finalized_at: String,
+    hash: String,
+    /// (when, why, hash) of each amendment since (see store::Amendment).
+    amendments: Vec<(String, String, String)>,
+    /// (reviewer, when) of each sign-off.
+    sign_offs: Vec<(String, String)>,
+}
+
+/// `GET /transparency/:token`: the finalized payout of an operation and
+/// the rules behind it, open to anyone with the share link, so members can
+/// redo the math. Pilots are named or numbered per the workspace setting.
+pub async fn show_transparency(
+    State(state): State<Arc<AppState>>,
+    Path(token): Path<String>,
+) -> Response {
+    let template = {
+        let store = state.store.lock().await;
+        let Some(op) = store.operation_by_token(&token) else {
+            return (StatusCode::NOT_FOUND, "Unknown transparency link").into_response();
+        };
+        let Some(finalization) = &op.finalized else {
+            return (
+                StatusCode::NOT_FOUND,
+                "Only finalized operations have a public page",
+            )
+                .into_response();
+        };
+        let named = store.data.settings.public_names;
+        let rules = op.payout_rules(&store.data.settings.rules);
+        let total = finalization.total_dropped_value;
+
+        let mut shares: Vec<(&String, Isk)> = finalization
+            .payouts
+            .iter()
+            .map(|(main, amount)| (main, *amount))
+            .collect();
+        shares.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        let percent = |amount: Isk| match amount.ratio(total) {
+            Some(ratio) => format!("{:.1}%", ratio * 100.0),
+            None => "-".to_string(),
+        };
+        let paid_out = match Isk::total(finalization.payouts.values().copied()) {
+            Ok(paid_out) => paid_out,
+            Err(e) => return isk_error(e),
+        };
+
+        let mut rule_rows = vec![
+            ("Payout mode", rules.mode.strategy().label().to_string()),
+            ("Corp tax", format!("{}%", rules.corp_tax_percent)),
+            ("Rounding", rules.rounding.label().to_string()),
+            ("Solo kill bonus", format!("{}%", rules.solo_bonus_percent)),
+            ("Friendly losses", rules.loss_netting.label().to_string()),
+        ];
+        if matches!(
+            rules.loss_netting,
+            LossNetting::Srp | LossNetting::Settlement
+        ) {
+            rule_rows.push(("SRP amount", format!("{} ISK", rules.srp_amount)));
+        }
+        if rules.min_kill_value > Isk::ZERO {
+            rule_rows.push((
+                "Minimum kill value",
+                format!("{} ISK", rules.min_kill_value),
+            ));
+        }
+        if let Some(pochven) = rules.pochven {
+            rule_rows.push((
+                "Pochven",
+                format!(
+                    "{}, {}% corp tax",
+                    pochven.mode.strategy().label(),
+                    pochven.corp_tax_percent
+                ),
+            ));
+        }
+        rule_rows.push((
+            "Pod kills",
+            if rules.include_pods {
+                "paid out"
+            } else {
+                "left out"
+            }
+            .to_string(),
+        ));
+        if let Some(preset) = &op.preset {
+            rule_rows.push(("Preset", preset.name.clone()));
+        }
+
+        TransparencyTemplate {
+            name: op.name.clone(),
+            start_date: op.start_date.clone(),
+            end_date: op.end_date.clone(),
+            fc: op.fc.clone(),
+            kill_count: op.kills.iter().filter(|k| k.is_active).count(),
+            total: total.to_string(),
+            paid_out: paid_out.to_string(),
+            corp_tax: finalization.corp_tax.to_string(),
+            rules: rule_rows,
+            share_script: rules.share_script.clone(),
+            shares: shares
+                .into_iter()
+                .enumerate()
+                .map(|(i, (main, amount))| {
+                    let who = if named {
+                        main.clone()
+                    } else {
+                        format!("Pilot {}", i + 1)
+                    };
+                    (who, amount.to_string(), percent(amount))
+                })
+                .collect(),
+            named,
+            finalized_at: finalization
+                .finalized_at
+                .format("%Y-%m-%d %H:%M UTC")
+                .to_string(),
+            hash: finalization.hash.clone(),
+            amendments: finalization
+                .amendments
+                .iter()
+                .map(|a| {
+                    (
+                        a.amended_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+                        a.reason.clone(),
+                        a.hash.clone(),
+                    )
+                })
+                .collect(),
+            sign_offs: op
+                .sign_offs
+                .iter()
+                .map(|s| {
+                    (
+                        s.character_name.clone(),
+                        s.signed_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+                    )
+                })
+                .collect(),
+        }
+    };
+    (
+        [(header::CACHE_CONTROL, "public, max-age=300")],
+        render_page(&template),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::views::tests::{assert_escaped, hostile_names};
+
+    #[test]
+    fn transparency_escapes_hostile_names() {
+        let names = hostile_names();
+        let page = TransparencyTemplate {
+            name: names[0].to_string(),
+            start_date: "2024-01-01".to_string(),
+            end_date: "2024-01-01".to_string(),
+            fc: names[1].to_string(),
+            kill_count: 1,
+            total: "100,000,000".to_string(),
+            paid_out: "100,000,000".to_string(),
+            corp_tax: "0".to_string(),
+            rules: vec![("Payout mode", "Equal".to_string())],
+            share_script: None,
+            shares: names
+                .iter()
+                .map(|name| {
+                    (
+                        name.to_string(),
+                        "25,000,000".to_string(),
+                        "25.0%".to_string(),
+                    )
+                })
+                .collect(),
+            named: true,
+            finalized_at: "2024-01-01 00:00 UTC".to_string(),
+            hash: "00".to_string(),
+            amendments: vec![],
+            sign_offs: names
+                .iter()
+                .map(|name| (name.to_string(), "2024-01-01 00:00 UTC".to_string()))
+                .collect(),
+        };
+        assert_escaped(&page.render().unwrap());
+    }
+}
@@ -0,0 +1,24 @@
+use axum::Json;
+use serde::Serialize;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Short hash of the commit built, or "unknown" (see build.rs).
+pub const GIT_COMMIT: &str = env!("EVE_LOOTER_GIT_COMMIT");
+/// When the build script last ran, UTC.
+pub const BUILT_AT: &str = env!("EVE_LOOTER_BUILT_AT");
+
+#[derive(Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub built_at: &'static str,
+}
+
+/// `GET /version`
+pub async fn show_version() -> Json<BuildInfo> {
+    Json(BuildInfo {
+        version: VERSION,
+        git_commit: GIT_COMMIT,
+        built_at: BUILT_AT,
+    })
+}
@@ -0,0 +1,945 @@
+use crate::alts::{self, AltSuggestion};
+use crate::auth::{self, Viewer};
+use crate::base_path;
+use crate::logic::{Coverage, FetchReport};
+use crate::loot_can::LootCanView;
+use crate::models::*;
+use crate::names::NameBook;
+use crate::payout::{self, Adjustment, LootFairy, MappingDiff};
+use crate::request_id;
+use crate::settings::WorkspaceSettings;
+use crate::store::{Dispute, OpType, Payment};
+use crate::transfers::{self, Settlement};
+use crate::whatif::WhatIf;
+
+use askama::Template;
+use axum::{
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use tracing::error;
+
+// --- Rendering ---
+
+/// Served when a template fails to render. Deliberately static: it must not
+/// depend on any of the data that just broke the real page.
+const FALLBACK_PAGE: &str = "<!DOCTYPE html>
+<html lang=\"en\">
+<head><meta charset=\"utf-8\"><title>EVE Looter</title></head>
+<body style=\"background: #121212; color: #e0e0e0; font-family: sans-serif; padding: 40px;\">
+<h1>Something went wrong</h1>
+<p>This page could not be displayed. The error has been logged under request ID <code>{id}</code>.</p>
+<p><a href=\"/\" style=\"color: #4caf50;\">Back to EVE Looter</a></p>
+</body>
+</html>
+";
+
+/// Renders a page, falling back to a static error page (and logging why)
+/// instead of panicking the request.
+pub fn render_page(template: &impl Template) -> Response {
+    match template.render() {
+        Ok(html) => Html(html).into_response(),
+        Err(e) => fallback_page(e),
+    }
+}
+
+pub fn fallback_page(e: askama::Error) -> Response {
+    error!("Template rendering failed: {}", e);
+    let id = request_id::current().unwrap_or_default();
+    let page = FALLBACK_PAGE.replace("{id}", &id);
+    (StatusCode::INTERNAL_SERVER_ERROR, Html(page)).into_response()
+}
+
+/// For ISK amounts past what the accounting can hold, e.g. a payout of
+/// kills zKillboard valued absurdly.
+pub fn isk_error(e: IskOutOfRange) -> Response {
+    error!("ISK accounting failed: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Html(format!("The ISK amounts could not be worked out: {}", e)),
+    )
+        .into_response()
+}
+
+// --- View Models ---
+
+#[derive(Clone)]
+pub struct BeneficiaryDisplay {
+    pub name: SafeName,
+    /// "[CORP] <ALLY>" of the main's character, when it flew itself.
+    pub tickers: String,
+    pub character_id: Option<CharacterId>,
+    /// No longer in the configured home corp/alliance (see logic::find_ex_members).
+    pub is_ex_member: bool,
+    /// Earlier names of this pilot, shown so renamed pilots stay recognisable.
+    pub former_names: String,
+    pub merit_points: String,
+    /// Own losses deducted from the share, when loss netting is on.
+    pub formatted_losses: Option<String>,
+    /// SRP added to the share, under settlement loss netting.
+    pub formatted_srp: Option<String>,
+    /// Manual adjustments as (index into the operation's list, amount, note).
+    pub adjustments: Vec<(usize, String, String)>,
+    pub formatted_amount: String,
+    pub formatted_per_hour: String,
+    pub is_active: bool,
+    /// Saved operations only: when this main was marked as paid.
+    pub paid_at: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct DailyGroup {
+    pub date_display: String,
+    /// Distinct (system ID, name) pairs of the day's kills, for bulk actions.
+    pub systems: Vec<(SystemId, SafeName)>,
+    pub kills: Vec<KillRow>,
+    /// Dropped value of the day's active kills.
+    pub dropped_str: String,
+    /// Distinct mains paid for the day's active kills.
+    pub pilot_count: usize,
+}
+
+/// A kill in the kill log. Blob kills can have hundreds of attackers, so
+/// the log lists only ours and counts the rest; the kill detail pane has
+/// the full list.
+#[derive(Clone)]
+pub struct KillRow {
+    pub kill: Killmail,
+    /// Attackers whose main is paid for the kill, final blow first. When
+    /// none is, the final blow alone.
+    pub ours: Vec<Attacker>,
+    /// Everyone else on the kill: excluded pilots, outsiders and NPCs.
+    pub others: usize,
+    /// The mains `ours` fly for.
+    pub mains: BTreeSet<String>,
+    /// Gate jumps from the kill's system to Jita, when known (see
+    /// routing::mark_distances).
+    pub jumps_from_jita: Option<u32>,
+}
+
+impl KillRow {
+    pub fn distance_from_jita(&self) -> Option<String> {
+        Some(match self.jumps_from_jita? {
+            0 => "in Jita".to_string(),
+            1 => "1 jump from Jita".to_string(),
+            n => format!("{} jumps from Jita", n),
+        })
+    }
+
+    /// `mapping` and `excluded_names` must already be canonical.
+    fn new(
+        kill: Killmail,
+        names: &NameBook,
+        mapping: &HashMap<String, String>,
+        excluded_names: &HashSet<String>,
+    ) -> Self {
+        let mut mains = BTreeSet::new();
+        let (mut ours, theirs): (Vec<Attacker>, Vec<Attacker>) =
+            kill.attackers.iter().cloned().partition(|a| {
+                let main = payout::attacker_main(names, mapping, a)
+                    .filter(|main| !excluded_names.contains(main));
+                let paid = main.is_some();
+                mains.extend(main);
+                paid
+            });
+        if ours.is_empty() {
+            ours.extend(theirs.iter().find(|a| a.final_blow).cloned());
+        }
+        ours.sort_by(|a, b| {
+            b.final_blow
+                .cmp(&a.final_blow)
+                .then_with(|| a.display_name().cmp(b.display_name()))
+        });
+        KillRow {
+            others: kill.attackers.len() - ours.len(),
+            ours,
+            mains,
+            jumps_from_jita: None,
+            kill,
+        }
+    }
+
+    /// Whether the victim, its ship, the system or any attacker contains
+    /// `needle`, which must be lowercase.
+    fn matches(&self, needle: &str) -> bool {
+        let contains = |text: &str| text.to_lowercase().contains(needle);
+        let victim = self.kill.victim.as_ref().is_some_and(|v| {
+            contains(v.display_name()) || v.ship_type_name.as_deref().is_some_and(contains)
+        });
+        victim
+            || self.kill.solar_system_name.as_deref().is_some_and(contains)
+            || self
+                .kill
+                .attackers
+                .iter()
+                .any(|a| contains(a.display_name()))
+    }
+}
+
+/// Computed payout figures shown below the configuration card.
+#[derive(Clone)]
+pub struct ResultView {
+    pub daily_groups: Vec<DailyGroup>,
+    pub total_payout_str: String,
+    pub total_humans: usize,
+    pub isk_per_hour_str: String,
+    pub op_duration_str: String,
+    pub beneficiaries: Vec<BeneficiaryDisplay>,
+    pub show_merit_points: bool,
+    pub alt_suggestions: Vec<AltSuggestion>,
+    /// Transfer instructions, once someone entered who holds the ISK.
+    pub settlement: Option<Settlement>,
+    /// How far back the fetched data is complete; only for fresh fetches.
+    pub coverage: Option<Coverage>,
+    /// Withheld for the corp wallet, when a corp tax is set.
+    pub corp_tax_str: Option<String>,
+    /// The fleet's own losses and what is left of the loot after them,
+    /// under ganking loss netting.
+    pub net_profit: Option<(String, String)>,
+    /// SRP paid out with the shares, under settlement loss netting.
+    pub srp_str: Option<String>,
+    /// Total value of the pod kills left out of the split, if any.
+    pub pod_value_str: Option<String>,
+    /// Why the share script didn't apply (see Payout::script_error).
+    pub script_error: Option<String>,
+    /// Names of the payout presets an operation can be saved under.
+    pub presets: Vec<String>,
+    /// Percent of the loot that dropped rather than burned, e.g. "52%".
+    pub drop_rate_str: Option<String>,
+    pub destroyed_str: String,
+    /// The main the loot fairy liked best, with their drop rate.
+    pub luckiest_pilot: Option<(String, String)>,
+    /// Workspace timezone for kill times.
+    pub timezone: Tz,
+    /// What the kill log is filtered by; empty shows every kill.
+    pub kill_search: String,
+    /// Excluded kills the search filtered out, kept so submitting the form
+    /// doesn't include them again.
+    pub hidden_excluded_kills: Vec<KillmailId>,
+    /// What hauling the loot to Jita would cost at the workspace's fee per
+    /// jump, and the jumps it covers.
+    pub hauling: Option<(String, u32)>,
+}
+
+impl ResultView {
+    /// Active beneficiaries with their character ID when known from the kills.
+    pub fn membership_candidates(&self) -> Vec<(String, Option<CharacterId>)> {
+        self.beneficiaries
+            .iter()
+            .filter(|b| b.is_active)
+            .map(|b| (b.name.to_string(), b.character_id))
+            .collect()
+    }
+
+    pub fn mark_ex_members(&mut self, ex_members: &HashSet<String>) {
+        for b in &mut self.beneficiaries {
+            b.is_ex_member = ex_members.contains(b.name.as_str());
+        }
+    }
+
+    pub fn ex_member_names(&self) -> Vec<&str> {
+        self.beneficiaries
+            .iter()
+            .filter(|b| b.is_ex_member)
+            .map(|b| b.name.as_str())
+            .collect()
+    }
+
+    pub fn mark_paid(&mut self, payments: &BTreeMap<String, Payment>, names: &NameBook) {
+        let paid: HashMap<String, &Payment> = payments
+            .iter()
+            .map(|(main, p)| (names.canonical(main), p))
+            .collect();
+        for b in &mut self.beneficiaries {
+            b.paid_at = paid
+                .get(b.name.as_str())
+                .map(|p| p.paid_at.format("%Y-%m-%d").to_string());
+        }
+    }
+
+    /// `jumps` from each system to Jita. The hauling fee covers one trip
+    /// from every system active kills dropped loot in.
+    pub fn mark_distances(&mut self, jumps: &HashMap<SystemId, u32>, fee_per_jump: Isk) {
+        let mut hauled = HashSet::new();
+        for row in self.daily_groups.iter_mut().flat_map(|g| &mut g.kills) {
+            row.jumps_from_jita = jumps.get(&row.kill.solar_system_id).copied();
+            if row.kill.is_active && row.kill.zkb.dropped_value > Isk::ZERO {
+                hauled.insert(row.kill.solar_system_id);
+            }
+        }
+        let total: u32 = hauled.iter().filter_map(|s| jumps.get(s)).sum();
+        self.hauling = (fee_per_jump > Isk::ZERO && total > 0)
+            .then(|| fee_per_jump.checked_mul(Decimal::from(total)).ok())
+            .flatten()
+            .map(|fee| (fee.to_string(), total));
+    }
+
+    pub fn empty() -> Self {
+        Self {
+            daily_groups: vec![],
+            total_payout_str: "0".to_string(),
+            total_humans: 0,
+            isk_per_hour_str: "-".to_string(),
+            op_duration_str: "".to_string(),
+            beneficiaries: vec![],
+            show_merit_points: false,
+            alt_suggestions: vec![],
+            settlement: None,
+            coverage: None,
+            corp_tax_str: None,
+            net_profit: None,
+            srp_str: None,
+            pod_value_str: None,
+            script_error: None,
+            presets: vec![],
+            drop_rate_str: None,
+            destroyed_str: String::new(),
+            luckiest_pilot: None,
+            timezone: Tz::UTC,
+            kill_search: String::new(),
+            hidden_excluded_kills: vec![],
+            hauling: None,
+        }
+    }
+
+    /// Narrows the kill log to kills matching `query` (see KillRow::matches).
+    /// The payout figures stay those of the whole result.
+    pub fn search_kills(&mut self, query: &str) {
+        let query = query.trim();
+        if query.is_empty() {
+            return;
+        }
+        let needle = query.to_lowercase();
+        for group in &mut self.daily_groups {
+            let (shown, hidden): (Vec<KillRow>, Vec<KillRow>) = std::mem::take(&mut group.kills)
+                .into_iter()
+                .partition(|row| row.matches(&needle));
+            group.kills = shown;
+            self.hidden_excluded_kills.extend(
+                hidden
+                    .iter()
+                    .filter(|row| !row.kill.is_active)
+                    .map(|row| row.kill.killmail_id),
+            );
+        }
+        self.daily_groups.retain(|group| !group.kills.is_empty());
+        self.kill_search = query.to_string();
+    }
+}
+
+/// The saved-operation parts of the calculator page.
+pub struct OperationView {
+    pub id: u64,
+    pub name: String,
+    /// How the snapshot differs from the default mapping.
+    pub mapping_diff: MappingDiff,
+    pub can_undo: bool,
+    pub can_redo: bool,
+    pub holdings_text: String,
+    pub notes: String,
+    pub fc: String,
+    pub tags: Vec<String>,
+    pub op_type: Option<OpType>,
+    pub published_at: Option<String>,
+    /// When and by whom the payout was finalized.
+    pub finalized: Option<String>,
+    pub audit_hash: Option<String>,
+    /// The finalized operation's amendments (see store::Amendment), as
+    /// "when by whom: why".
+    pub amendments: Vec<String>,
+    /// (reviewer, when) for each sign-off.
+    pub sign_offs: Vec<(String, String)>,
+    /// Publishing waits for a sign-off.
+    pub sign_off_required: bool,
+    pub can_sign_off: bool,
+    /// The logged-in pilot's main under this operation's mapping.
+    pub my_main: Option<String>,
+    pub disputes: Vec<DisputeView>,
+    /// (when, who, what), oldest first.
+    pub audit_log: Vec<(String, String, String)>,
+    /// (when, who, amount, note) of each sale of the loot, oldest first.
+    pub sales: Vec<(String, String, String, String)>,
+    /// Dropped value of the active kills as estimated when fetched.
+    pub estimated_str: String,
+    /// (realized, change against the estimate), once anything sold.
+    pub realized: Option<(String, String)>,
+    pub split_on_realized: bool,
+    /// Sales can be matched in the corp wallet (see sales::match_wallet).
+    pub can_match_wallet: bool,
+    /// The last imported loot can against the drops.
+    pub loot_can: Option<LootCanView>,
+    /// The loot can can be imported from corp assets (see loot_can).
+    pub can_import_loot_can: bool,
+    /// Absolute or root-relative URL of the embeddable widget, once shared.
+    pub embed_url: Option<String>,
+    /// The public page of the finalized payout, once shared.
+    pub transparency_url: Option<String>,
+    /// QR code image of the link pilots should open, once shared.
+    pub qr_url: Option<String>,
+    /// The what-if panel's starting point: the payout as it stands.
+    pub what_if: WhatIf,
+    /// Whether pod kills are paid out; `None` when there are none.
+    pub include_pods: Option<bool>,
+    /// Name of the payout preset it was saved under.
+    pub preset: Option<String>,
+}
+
+pub struct DisputeView {
+    /// Index into the operation's disputes, for resolving.
+    pub index: usize,
+    pub main: String,
+    pub raised_by: String,
+    pub reason: String,
+    pub raised_at: String,
+    /// (by, when, note, adjustment) once resolved.
+    pub resolution: Option<(String, String, String, Option<String>)>,
+}
+
+impl DisputeView {
+    pub fn new(index: usize, dispute: &Dispute) -> Self {
+        DisputeView {
+            index,
+            main: dispute.main.clone(),
+            raised_by: dispute.raised_by.clone(),
+            reason: dispute.reason.clone(),
+            raised_at: dispute.raised_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+            resolution: dispute.resolution.as_ref().map(|r| {
+                (
+                    r.resolved_by.clone().unwrap_or_else(|| "-".to_string()),
+                    r.resolved_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+                    r.note.clone(),
+                    r.adjustment.map(|a| a.to_string()),
+                )
+            }),
+        }
+    }
+}
+
+/// FCs may change the payout until the operation is finalized.
+pub fn can_edit_payout(viewer: &Viewer, operation: Option<&OperationView>) -> bool {
+    viewer.can_edit() && operation.is_none_or(|op| op.finalized.is_none())
+}
+
+impl OperationView {
+    /// Key of the selected op type, empty when unset.
+    pub fn op_type_key(&self) -> &'static str {
+        self.op_type.map_or("", |t| t.key())
+    }
+
+    pub fn rerun_start_date(&self) -> String {
+        this_week().0
+    }
+
+    pub fn rerun_end_date(&self) -> String {
+        this_week().1
+    }
+}
+
+/// The last seven days up to today, as form dates.
+pub fn this_week() -> (String, String) {
+    let today = Utc::now().date_naive();
+    (
+        (today - Duration::days(6)).format("%Y-%m-%d").to_string(),
+        today.format("%Y-%m-%d").to_string(),
+    )
+}
+
+#[derive(Template)]
+#[template(path = "index.html")]
+pub struct IndexTemplate {
+    pub viewer: Viewer,
+    /// Set when rendering a saved operation instead of a live fetch.
+    pub operation: Option<OperationView>,
+    pub mapping_text: String,
+    pub zkill_link: String,
+    pub start_date: String,
+    pub end_date: String,
+    pub op_start: String,
+    pub op_end: String,
+    pub result: ResultView,
+    pub error_msg: Option<String>,
+    /// A rate-limited fetch of this link can be resumed.
+    pub can_resume: bool,
+    /// The fetch would replace kills fetched for something else.
+    pub replace_prompt: Option<ReplacePrompt>,
+    /// Set right after a fetch; cached results have none.
+    pub fetch_report: Option<FetchReport>,
+}
+
+impl IndexTemplate {
+    /// Shown with errors, to match a report against the logs.
+    pub fn request_id(&self) -> String {
+        request_id::current().unwrap_or_default()
+    }
+
+    /// A template echoing the submitted form back with no results yet.
+    pub fn from_params(params: &FetchParams, viewer: Viewer) -> Self {
+        Self {
+            viewer,
+            operation: None,
+            mapping_text: params.mapping_input.clone(),
+            zkill_link: params.zkill_link.clone(),
+            start_date: params.start_date.clone(),
+            end_date: params.end_date.clone(),
+            op_start: params.op_start.clone(),
+            op_end: params.op_end.clone(),
+            result: ResultView::empty(),
+            error_msg: None,
+            can_resume: false,
+            replace_prompt: None,
+            fetch_report: None,
+        }
+    }
+
+    pub fn can_edit_payout(&self) -> bool {
+        can_edit_payout(&self.viewer, self.operation.as_ref())
+    }
+
+    pub fn form_action(&self) -> String {
+        match &self.operation {
+            Some(op) => base_path::url(&format!("/ops/{}", op.id)),
+            None => base_path::url("/process"),
+        }
+    }
+
+    /// Fresh for every render of the form (see FetchParams::submit_token).
+    pub fn submit_token(&self) -> String {
+        auth::random_token()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FetchParams {
+    #[serde(default)]
+    pub zkill_link: String,
+    pub mapping_input: String,
+    pub excluded_kills: Option<String>,
+    pub excluded_beneficiaries: Option<String>,
+    #[serde(default)]
+    pub start_date: String,
+    #[serde(default)]
+    pub end_date: String,
+    // Optional op boundaries (datetime-local); inferred from kills when empty
+    #[serde(default)]
+    pub op_start: String,
+    #[serde(default)]
+    pub op_end: String,
+    #[serde(default)]
+    pub operation_name: String,
+    /// Name of the payout preset to save the operation under; empty for
+    /// the workspace settings.
+    #[serde(default)]
+    pub payout_preset: String,
+    /// Continue a fetch that was aborted by ESI rate limiting.
+    #[serde(default)]
+    pub resume: bool,
+    /// Replace kills fetched for another entity or window without asking.
+    #[serde(default)]
+    pub confirm_replace: bool,
+    /// Unique per rendered form, so repeated submits of it share one fetch.
+    #[serde(default)]
+    pub submit_token: String,
+}
+
+impl FetchParams {
+    /// Start/end cutoffs of the selected date range (whole days, UTC).
+    pub fn window(&self) -> (DateTime<Utc>, DateTime<Utc>) {
+        date_window(&self.start_date, &self.end_date)
+    }
+
+    pub fn source(&self) -> KillSource {
+        let (start, end) = self.window();
+        KillSource {
+            zkill_link: self.zkill_link.trim().trim_end_matches('/').to_string(),
+            start_date: start.format("%Y-%m-%d").to_string(),
+            end_date: end.format("%Y-%m-%d").to_string(),
+        }
+    }
+
+    pub fn excluded_ids(&self) -> HashSet<KillmailId> {
+        self.excluded_kills
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|s| s.trim().parse().ok().map(KillmailId))
+            .collect()
+    }
+
+    pub fn excluded_names(&self) -> HashSet<String> {
+        self.excluded_beneficiaries
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
+}
+
+/// Start/end cutoffs of a form's date range (whole days, UTC); the last
+/// seven days when the dates are missing.
+pub fn date_window(start_date: &str, end_date: &str) -> (DateTime<Utc>, DateTime<Utc>) {
+    let start_cutoff = NaiveDate::parse_from_str(start_date, "%Y-%m-%d")
+        .unwrap_or_else(|_| (Utc::now() - Duration::days(7)).date_naive())
+        .and_time(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
+        .and_utc();
+
+    let end_cutoff = NaiveDate::parse_from_str(end_date, "%Y-%m-%d")
+        .unwrap_or_else(|_| Utc::now().date_naive())
+        .and_time(NaiveTime::from_hms_opt(23, 59, 59).unwrap())
+        .and_utc();
+
+    (start_cutoff, end_cutoff)
+}
+
+/// What the calculator's kill list was fetched for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KillSource {
+    pub zkill_link: String,
+    pub start_date: String,
+    pub end_date: String,
+}
+
+/// Asks before a fetch replaces kills fetched for another entity or window.
+pub struct ReplacePrompt {
+    pub previous: KillSource,
+    pub added: usize,
+    pub removed: usize,
+}
+
+impl ReplacePrompt {
+    /// Some when swapping `current` (fetched for `previous`) for `new` kills
+    /// fetched for `source` would drop kills. Kills only being added loses
+    /// nothing, so that goes ahead without asking.
+    pub fn check(
+        current: &[Killmail],
+        previous: Option<&KillSource>,
+        source: &KillSource,
+        new: &[Killmail],
+    ) -> Option<Self> {
+        let previous = previous.filter(|p| *p != source)?;
+        let old_ids: HashSet<KillmailId> = current.iter().map(|k| k.killmail_id).collect();
+        let new_ids: HashSet<KillmailId> = new.iter().map(|k| k.killmail_id).collect();
+        let removed = old_ids.difference(&new_ids).count();
+        (removed > 0).then(|| ReplacePrompt {
+            previous: previous.clone(),
+            added: new_ids.difference(&old_ids).count(),
+            removed,
+        })
+    }
+}
+
+/// Kills with dropped loot inside the window, flagged active unless excluded.
+/// Losses of `loss_candidates` are kept even without drops, since they are
+/// charged to the pilot (see PayoutRules::loss_netting).
+pub fn select_kills(
+    kills: &[Killmail],
+    start_cutoff: DateTime<Utc>,
+    end_cutoff: DateTime<Utc>,
+    excluded_ids: &HashSet<KillmailId>,
+    loss_candidates: &HashSet<String>,
+) -> Vec<Killmail> {
+    kills
+        .iter()
+        .filter(|k| {
+            let is_loss = k
+                .victim
+                .as_ref()
+                .and_then(|v| v.character_name.as_ref())
+                .is_some_and(|n| loss_candidates.contains(n.as_str()));
+            if k.zkb.dropped_value <= Isk::ZERO && !is_loss {
+                return false;
+            }
+            k.killmail_time >= start_cutoff && k.killmail_time <= end_cutoff
+        })
+        .map(|k| {
+            let mut km = k.clone();
+            km.is_active = !excluded_ids.contains(&k.killmail_id);
+            km
+        })
+        .collect()
+}
+
+/// Everything besides the kills that shapes a result.
+pub struct ResultInputs<'a> {
+    pub names: &'a NameBook,
+    pub settings: &'a WorkspaceSettings,
+    pub mapping: &'a HashMap<String, String>,
+    pub excluded_names: &'a HashSet<String>,
+    pub adjustments: &'a [Adjustment],
+    pub holdings: &'a [(String, Isk)],
+    pub op_start: &'a str,
+    pub op_end: &'a str,
+    /// The rules in effect: the workspace's, or an operation's own (see
+    /// Operation::payout_rules).
+    pub rules: &'a payout::PayoutRules,
+}
+
+pub fn build_result(
+    mut final_kills: Vec<Killmail>,
+    inputs: &ResultInputs,
+) -> Result<ResultView, IskOutOfRange> {
+    let ResultInputs {
+        names,
+        settings,
+        mapping,
+        excluded_names,
+        adjustments,
+        holdings,
+        op_start: op_start_input,
+        op_end: op_end_input,
+        rules,
+    } = *inputs;
+
+    // 1. Calculate Payout
+    let mut payout = payout::calculate(&final_kills, names, mapping, excluded_names, rules)?;
+    payout.apply_adjustments(names, adjustments)?;
+    let excluded_names = names.canonical_set(excluded_names);
+    let settlement = if holdings.is_empty() {
+        None
+    } else {
+        Some(transfers::settle(&payout.wallets, holdings, names)?)
+    };
+    let alt_suggestions = alts::detect_duplicate_alts(&final_kills, names, mapping);
+    for kill in &mut final_kills {
+        names.refresh_kill(kill);
+    }
+
+    // 2. Operation Duration (explicit, or inferred from first/last active kill)
+    let active_times: Vec<DateTime<Utc>> = final_kills
+        .iter()
+        .filter(|k| k.is_active)
+        .map(|k| k.killmail_time)
+        .collect();
+    let op_start = parse_op_time(op_start_input).or_else(|| active_times.iter().min().copied());
+    let op_end = parse_op_time(op_end_input).or_else(|| active_times.iter().max().copied());
+    let op_hours = match (op_start, op_end) {
+        (Some(s), Some(e)) if e > s => Some((e - s).num_seconds() as f64 / 3600.0),
+        _ => None,
+    };
+    let op_duration_str = match (op_start, op_end) {
+        (Some(s), Some(e)) if e > s => {
+            let inferred = op_start_input.is_empty() || op_end_input.is_empty();
+            format!(
+                "{}{}",
+                format_duration(e - s),
+                if inferred {
+                    " (inferred from kills)"
+                } else {
+                    ""
+                }
+            )
+        }
+        _ => "".to_string(),
+    };
+    let per_hour = |amount: Isk| match op_hours {
+        Some(h) => format_isk(amount.to_f64() / h),
+        None => "-".to_string(),
+    };
+
+    // 3. Beneficiaries List
+    let character_ids: HashMap<&str, CharacterId> = final_kills
+        .iter()
+        .flat_map(|k| &k.attackers)
+        .filter_map(|a| Some((a.character_name.as_deref()?, a.character_id?)))
+        .collect();
+    let tickers: HashMap<&str, String> = final_kills
+        .iter()
+        .flat_map(|k| &k.attackers)
+        .filter_map(|a| Some((a.character_name.as_deref()?, a.tickers())))
+        .filter(|(_, label)| !label.is_empty())
+        .collect();
+    let srp_total = Isk::total(payout.srp.values().copied())?;
+    let mut beneficiaries = Vec::new();
+    for main in payout.seen_mains {
+        let amount = payout.wallets.get(&main).copied().unwrap_or_default();
+        beneficiaries.push(BeneficiaryDisplay {
+            character_id: character_ids
+                .get(main.as_str())
+                .copied()
+                .or_else(|| names.id_of(&main)),
+            is_ex_member: false,
+            former_names: names.former_names(&main).join(", "),
+            merit_points: format!(
+                "{:.1}",
+                payout.merit_points.get(&main).copied().unwrap_or(0.0)
+            ),
+            formatted_losses: payout.losses.get(&main).map(Isk::to_string),
+            formatted_srp: payout.srp.get(&main).map(Isk::to_string),
+            adjustments: adjustments
+                .iter()
+                .enumerate()
+                .filter(|(_, a)| names.canonical(&a.main) == main)
+                .map(|(i, a)| (i, a.amount.to_string(), a.note.clone()))
+                .collect(),
+            tickers: tickers.get(main.as_str()).cloned().unwrap_or_default(),
+            name: SafeName::new(&main),
+            formatted_amount: amount.to_string(),
+            formatted_per_hour: per_hour(amount),
+            is_active: !excluded_names.contains(&main),
+            paid_at: None,
+        });
+    }
+    beneficiaries.sort_by(|a, b| a.name.cmp(&b.name));
+    let active_humans = beneficiaries.iter().filter(|b| b.is_active).count();
+
+    // 4. Grouping
+    let canonical_mapping = names.canonical_mapping(mapping);
+    let mut groups_map: HashMap<String, Vec<KillRow>> = HashMap::new();
+    for kill in final_kills {
+        groups_map
+            .entry(kill.local_date(settings.timezone))
+            .or_default()
+            .push(KillRow::new(
+                kill,
+                names,
+                &canonical_mapping,
+                &excluded_names,
+            ));
+    }
+
+    let mut daily_groups = Vec::new();
+    let mut dates: Vec<String> = groups_map.keys().cloned().collect();
+    dates.sort_by(|a, b| b.cmp(a));
+
+    for date in dates {
+        if let Some(kills) = groups_map.remove(&date) {
+            let systems: BTreeSet<(SystemId, SafeName)> = kills
+                .iter()
+                .map(|row| &row.kill)
+                .map(|k| {
+                    let name = k.solar_system_name.clone();
+                    (
+                        k.solar_system_id,
+                        name.unwrap_or_else(|| SafeName::new(&k.solar_system_id.to_string())),
+                    )
+                })
+                .collect();
+            let active = || kills.iter().filter(|row| row.kill.is_active);
+            let dropped = Isk::total(active().map(|row| row.kill.zkb.dropped_value))?;
+            let pilots: HashSet<&String> = active().flat_map(|row| &row.mains).collect();
+            daily_groups.push(DailyGroup {
+                date_display: date,
+                systems: systems.into_iter().collect(),
+                dropped_str: dropped.to_string(),
+                pilot_count: pilots.len(),
+                kills,
+            });
+        }
+    }
+
+    let luckiest_pilot = payout
+        .pilot_loot_fairy
+        .iter()
+        .filter(|(_, fairy)| fairy.kills >= LootFairy::MIN_KILLS)
+        .filter_map(|(main, fairy)| Some((main, fairy.drop_rate()?)))
+        .max_by(|a, b| a.1.total_cmp(&b.1).then_with(|| b.0.cmp(a.0)))
+        .map(|(main, rate)| (main.clone(), format!("{:.0}%", rate)));
+
+    let net_profit = if payout.fleet_losses >= Isk::ONE {
+        let net = payout
+            .total_dropped_value
+            .checked_sub(payout.fleet_losses)?;
+        Some((payout.fleet_losses.to_string(), net.to_string()))
+    } else {
+        None
+    };
+
+    Ok(ResultView {
+        daily_groups,
+        total_payout_str: payout.total_dropped_value.to_string(),
+        total_humans: active_humans,
+        isk_per_hour_str: per_hour(payout.total_dropped_value),
+        op_duration_str,
+        beneficiaries,
+        show_merit_points: settings.merit_points,
+        alt_suggestions,
+        settlement,
+        coverage: None,
+        corp_tax_str: (payout.corp_tax >= Isk::ONE).then(|| payout.corp_tax.to_string()),
+        net_profit,
+        srp_str: (srp_total >= Isk::ONE).then(|| srp_total.to_string()),
+        pod_value_str: (payout.pod_value >= Isk::ONE).then(|| payout.pod_value.to_string()),
+        script_error: payout.script_error.clone(),
+        presets: settings.presets.iter().map(|p| p.name.clone()).collect(),
+        drop_rate_str: payout
+            .loot_fairy
+            .drop_rate()
+            .map(|rate| format!("{:.0}%", rate)),
+        destroyed_str: payout.loot_fairy.destroyed.to_string(),
+        luckiest_pilot,
+        timezone: settings.timezone,
+        kill_search: String::new(),
+        hidden_excluded_kills: vec![],
+        hauling: None,
+    })
+}
+
+// --- Helpers ---
+
+fn parse_op_time(value: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M")
+        .ok()
+        .map(|t| t.and_utc())
+}
+
+fn format_duration(duration: Duration) -> String {
+    let minutes = duration.num_minutes();
+    format!("{}h {:02}m", minutes / 60, minutes % 60)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Names a pilot could pick to break a page: markup, quotes, a
+    /// right-to-left override and far more than fits.
+    pub(crate) fn hostile_names() -> Vec<SafeName> {
+        [
+            "<script>alert(1)</script>",
+            "\"Bob\" O'Neil & <b>co</b>",
+            "Alice\u{202E}gnp.exe",
+            &"W".repeat(500),
+        ]
+        .into_iter()
+        .map(SafeName::new)
+        .collect()
+    }
+
+    /// Panics unless `html` shows the hostile names as text only.
+    pub(crate) fn assert_escaped(html: &str) {
+        assert!(!html.contains("<script>alert"), "raw <script> in {}", html);
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(!html.contains("<b>co</b>") && !html.contains("\"Bob\""));
+        assert!(html.contains("&amp; &lt;b&gt;co&lt;/b&gt;"));
+        assert!(!html.contains('\u{202E}'));
+        assert!(html.contains("Alicegnp.exe"));
+        assert!(html.contains(&"W".repeat(64)) && !html.contains(&"W".repeat(65)));
+    }
+
+    #[derive(Template)]
+    #[template(source = "<p>{{ self.broken()? }}</p>", ext = "html")]
+    struct BrokenTemplate;
+
+    impl BrokenTemplate {
+        fn broken(&self) -> askama::Result<String> {
+            Err(askama::Error::Fmt(std::fmt::Error))
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_render_serves_the_static_page() {
+        let response = render_page(&BrokenTemplate);
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("Something went wrong"));
+    }
+}
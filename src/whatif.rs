@@ -0,0 +1,222 @@
+use crate::models::*;
+use crate::names::NameBook;
+use crate::ops::operation_payout;
+use crate::payout::{self, MappingDiff, PayoutMode, PayoutRules};
+use crate::store::Operation;
+use crate::views::{isk_error, render_page};
+
+use askama::Template;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+
+/// The payout settings a what-if preview varies; the panel starts out with
+/// the operation's current ones.
+#[derive(Deserialize)]
+pub struct WhatIf {
+    #[serde(default)]
+    pub mode: PayoutMode,
+    #[serde(default)]
+    pub corp_tax_percent: Decimal,
+    /// Comma-separated mains.
+    #[serde(default)]
+    pub excluded_beneficiaries: String,
+    /// Comma-separated kill IDs.
+    #[serde(default)]
+    pub excluded_kills: String,
+    /// Another "Alt = Main" mapping, e.g. with suspected alts added; the
+    /// operation's own when absent.
+    #[serde(default)]
+    pub mapping_text: Option<String>,
+}
+
+impl WhatIf {
+    pub fn current(op: &Operation, rules: &PayoutRules) -> Self {
+        WhatIf {
+            mode: rules.mode,
+            corp_tax_percent: rules.corp_tax_percent,
+            excluded_beneficiaries: op
+                .excluded_beneficiaries
+                .iter()
+                .cloned()
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect::<Vec<_>>()
+                .join(", "),
+            excluded_kills: op
+                .edit_state()
+                .excluded_kills
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            mapping_text: Some(op.mapping_text.clone()),
+        }
+    }
+
+    pub fn mapping(&self) -> &str {
+        self.mapping_text.as_deref().unwrap_or_default()
+    }
+
+    pub fn is_even(&self) -> bool {
+        self.mode == PayoutMode::Even
+    }
+
+    fn excluded_names(&self) -> HashSet<String> {
+        self.excluded_beneficiaries
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
+
+    fn excluded_ids(&self) -> HashSet<KillmailId> {
+        self.excluded_kills
+            .split(',')
+            .filter_map(|s| s.trim().parse().ok().map(KillmailId))
+            .collect()
+    }
+}
+
+pub struct WhatIfRow {
+    pub main: String,
+    pub current: String,
+    pub preview: String,
+    pub delta: String,
+    /// -1, 0 or 1, for the colour.
+    pub direction: i8,
+}
+
+#[derive(Template)]
+#[template(path = "partials/whatif_result.html")]
+struct WhatIfTemplate {
+    /// By previewed share, most first.
+    rows: Vec<WhatIfRow>,
+    current_tax: String,
+    preview_tax: String,
+    current_pilots: usize,
+    preview_pilots: usize,
+    /// How the previewed mapping differs from the operation's.
+    mapping_diff: MappingDiff,
+}
+
+fn signed_isk(amount: Isk) -> String {
+    if amount > Isk::ZERO {
+        format!("+{}", amount)
+    } else {
+        amount.to_string()
+    }
+}
+
+/// Both payouts of the operation, side by side. Nothing is saved.
+fn compare(
+    op: &Operation,
+    names: &NameBook,
+    rules: &PayoutRules,
+    what_if: &WhatIf,
+) -> Result<WhatIfTemplate, IskOutOfRange> {
+    let current = operation_payout(op, names, rules)?;
+    let rules = &op.payout_rules(rules);
+
+    let mut changed = op.clone();
+    let excluded_ids = what_if.excluded_ids();
+    for kill in &mut changed.kills {
+        kill.is_active = !excluded_ids.contains(&kill.killmail_id);
+    }
+    changed.excluded_beneficiaries = what_if.excluded_names();
+    if let Some(mapping_text) = &what_if.mapping_text {
+        changed.mapping_text = mapping_text.clone();
+    }
+    let changed_rules = PayoutRules {
+        mode: what_if.mode,
+        corp_tax_percent: what_if
+            .corp_tax_percent
+            .clamp(Decimal::ZERO, Decimal::ONE_HUNDRED),
+        ..rules.clone()
+    };
+    // Under the changed rules rather than the operation's preset
+    changed.preset = None;
+    let preview = operation_payout(&changed, names, &changed_rules)?;
+
+    let mains: BTreeSet<&String> = current
+        .wallets
+        .keys()
+        .chain(preview.wallets.keys())
+        .collect();
+    let mut amounts: Vec<(&String, Isk, Isk)> = mains
+        .into_iter()
+        .map(|main| {
+            (
+                main,
+                current.wallets.get(main).copied().unwrap_or_default(),
+                preview.wallets.get(main).copied().unwrap_or_default(),
+            )
+        })
+        .collect();
+    amounts.sort_by_key(|a| std::cmp::Reverse(a.2));
+    let rows = amounts
+        .into_iter()
+        .map(|(main, current, preview)| {
+            let delta = preview.checked_sub(current)?;
+            // Shares are whole ISK at least, so anything less is no change
+            let direction = if delta.abs() < Isk::ONE {
+                0
+            } else if delta > Isk::ZERO {
+                1
+            } else {
+                -1
+            };
+            Ok(WhatIfRow {
+                main: main.clone(),
+                current: current.to_string(),
+                preview: preview.to_string(),
+                delta: if direction == 0 {
+                    "-".to_string()
+                } else {
+                    signed_isk(delta)
+                },
+                direction,
+            })
+        })
+        .collect::<Result<_, IskOutOfRange>>()?;
+
+    let paid = |wallets: &HashMap<String, Isk>| {
+        wallets
+            .values()
+            .filter(|amount| **amount != Isk::ZERO)
+            .count()
+    };
+    Ok(WhatIfTemplate {
+        rows,
+        current_tax: current.corp_tax.to_string(),
+        preview_tax: preview.corp_tax.to_string(),
+        current_pilots: paid(&current.wallets),
+        preview_pilots: paid(&preview.wallets),
+        mapping_diff: payout::diff_mappings(&changed.mapping_text, &op.mapping_text),
+    })
+}
+
+/// `GET /ops/:id/whatif`: the operation's payout under another payout
+/// mode, corp tax, set of exclusions or alt mapping, against the current
+/// plan, before anyone commits to the change. Settles whether a disputed
+/// alt mapping moves anyone's share at all.
+pub async fn preview(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    Query(what_if): Query<WhatIf>,
+) -> Response {
+    let store = state.store.lock().await;
+    let Some(op) = store.operation(id) else {
+        return (StatusCode::NOT_FOUND, "Operation not found").into_response();
+    };
+    match compare(op, &store.name_book(), &store.data.settings.rules, &what_if) {
+        Ok(template) => render_page(&template),
+        Err(e) => isk_error(e),
+    }
+}
@@ -0,0 +1,36 @@
+// Finalized operation pages are cached as they are viewed, so a pilot can
+// reopen their payout without a connection. Everything else goes to the
+// network as usual.
+const CACHE = '{{ cache_name }}';
+const OPERATION_PAGE = new RegExp('^{{ base }}/ops/\\d+$');
+
+self.addEventListener('install', () => self.skipWaiting());
+
+self.addEventListener('activate', (event) => {
+    event.waitUntil(
+        caches.keys()
+            .then((keys) => Promise.all(
+                keys.filter((key) => key.startsWith('eve-looter-') && key !== CACHE)
+                    .map((key) => caches.delete(key))))
+            .then(() => self.clients.claim()));
+});
+
+self.addEventListener('fetch', (event) => {
+    const url = new URL(event.request.url);
+    if (event.request.method !== 'GET' || url.origin !== location.origin
+        || !OPERATION_PAGE.test(url.pathname)) {
+        return;
+    }
+    event.respondWith(
+        fetch(event.request)
+            .then((response) => {
+                if (response.ok && response.headers.has('{{ crate::pwa::OFFLINE_HEADER }}')) {
+                    const copy = response.clone();
+                    caches.open(CACHE).then((cache) => cache.put(event.request, copy));
+                }
+                return response;
+            })
+            .catch(() => caches.match(event.request)
+                .then((cached) => cached || caches.match(event.request, { ignoreSearch: true }))
+                .then((cached) => cached || Response.error())));
+});